@@ -1,106 +1,1903 @@
-use std::collections::HashSet;
+use format::{self, FileError, Reader};
+use history::Actor;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::mem;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Returns the current time as seconds since the UNIX epoch.
+pub fn unix_now() -> u64 {
+    match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(time) => time.as_secs(),
+        Err(_) => 0,
+    }
+}
 
 /// A canvas region.
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Region {
-    pub x: u32,
-    pub y: u32,
+    pub x: i32,
+    pub y: i32,
+    pub w: u32,
+    pub h: u32,
+
+    /// RGB data.
+    pub data: Vec<u8>,
+}
+
+impl Region {
+    /// Returns the area covered by the region.
+    pub fn rect(&self) -> Rect {
+        Rect {
+            x: self.x,
+            y: self.y,
+            w: self.w,
+            h: self.h,
+        }
+    }
+}
+
+/// A rectangle in canvas coordinates.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Rect {
+    pub x: i32,
+    pub y: i32,
     pub w: u32,
     pub h: u32,
+}
+
+impl Rect {
+    /// Creates a rectangle from its edges, clamped to the coordinate range.
+    fn from_edges(x1: i64, y1: i64, x2: i64, y2: i64) -> Rect {
+        let clamp = |v: i64| v.clamp(i32::MIN as i64, i32::MAX as i64);
+        let (x1, y1) = (clamp(x1), clamp(y1));
+        Rect {
+            x: x1 as i32,
+            y: y1 as i32,
+            w: (clamp(x2) - x1).max(0) as u32,
+            h: (clamp(y2) - y1).max(0) as u32,
+        }
+    }
+
+    /// Returns the x coordinate just past the right edge.
+    pub fn right(&self) -> i64 {
+        self.x as i64 + self.w as i64
+    }
+
+    /// Returns the y coordinate just past the bottom edge.
+    pub fn bottom(&self) -> i64 {
+        self.y as i64 + self.h as i64
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.w == 0 || self.h == 0
+    }
+
+    /// Returns the coordinates of every pixel inside, row by row.
+    pub fn pixels(&self) -> impl Iterator<Item = (i32, i32)> {
+        let rect = *self;
+        (rect.y as i64..rect.bottom()).flat_map(move |y| {
+            (rect.x as i64..rect.right()).map(move |x| (x as i32, y as i32))
+        })
+    }
+
+    pub fn contains(&self, x: i32, y: i32) -> bool {
+        x >= self.x && (x as i64) < self.right() && y >= self.y && (y as i64) < self.bottom()
+    }
+
+    pub fn contains_rect(&self, other: &Rect) -> bool {
+        other.x >= self.x
+            && other.right() <= self.right()
+            && other.y >= self.y
+            && other.bottom() <= self.bottom()
+    }
+
+    pub fn intersects(&self, other: &Rect) -> bool {
+        !self.is_empty()
+            && !other.is_empty()
+            && (self.x as i64) < other.right()
+            && (other.x as i64) < self.right()
+            && (self.y as i64) < other.bottom()
+            && (other.y as i64) < self.bottom()
+    }
+
+    /// Returns the overlap of two rectangles, which may be empty.
+    pub fn intersection(&self, other: &Rect) -> Rect {
+        Rect::from_edges(
+            self.x.max(other.x) as i64,
+            self.y.max(other.y) as i64,
+            self.right().min(other.right()),
+            self.bottom().min(other.bottom()),
+        )
+    }
+
+    /// Returns the smallest rectangle containing both.
+    pub fn union(&self, other: &Rect) -> Rect {
+        Rect::from_edges(
+            self.x.min(other.x) as i64,
+            self.y.min(other.y) as i64,
+            self.right().max(other.right()),
+            self.bottom().max(other.bottom()),
+        )
+    }
+
+    /// Returns the rectangle grown by `by` pixels on every side.
+    pub fn expanded(&self, by: u32) -> Rect {
+        let by = by as i64;
+        Rect::from_edges(
+            self.x as i64 - by,
+            self.y as i64 - by,
+            self.right() + by,
+            self.bottom() + by,
+        )
+    }
+
+    /// Returns up to four rectangles covering the parts of this one that aren’t in `other`.
+    pub fn subtract(&self, other: &Rect) -> Vec<Rect> {
+        if !self.intersects(other) {
+            return if self.is_empty() { Vec::new() } else { vec![*self] };
+        }
+
+        let (x1, y1, x2, y2) = (self.x as i64, self.y as i64, self.right(), self.bottom());
+        let top = (other.y as i64).max(y1);
+        let bottom = other.bottom().min(y2);
+        let left = (other.x as i64).max(x1);
+        let right = other.right().min(x2);
+
+        let parts = vec![
+            // full-width strips above and below the intersection
+            Rect::from_edges(x1, y1, x2, top),
+            Rect::from_edges(x1, bottom, x2, y2),
+            // and the pieces to its left and right
+            Rect::from_edges(x1, top, left, bottom),
+            Rect::from_edges(right, top, x2, bottom),
+        ];
+        parts.into_iter().filter(|r| !r.is_empty()).collect()
+    }
+}
+
+/// How far a canvas extends.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Extent {
+    /// A fixed size, starting at (0, 0).
+    Finite { width: u32, height: u32 },
+
+    /// Tiles are created wherever someone paints, optionally only within a world boundary.
+    Infinite {
+        boundary: Option<Rect>,
+        growth: Option<Growth>,
+    },
+}
+
+/// Automatic expansion of a world boundary.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Growth {
+    /// Pixels added on every side.
+    pub step: u32,
+
+    /// Milliseconds between expansions.
+    pub interval: u64,
+}
+
+impl Extent {
+    /// Returns the area pixels can be placed in, or None if it’s unlimited.
+    pub fn bounds(&self) -> Option<Rect> {
+        match *self {
+            Extent::Finite { width, height } => Some(Rect {
+                x: 0,
+                y: 0,
+                w: width,
+                h: height,
+            }),
+            Extent::Infinite { boundary, .. } => boundary,
+        }
+    }
+
+    /// Serializes the extent (see `format::CHUNK_CANVAS`).
+    pub fn write(&self, buf: &mut Vec<u8>) {
+        let (kind, rect, growth) = match *self {
+            Extent::Finite { .. } => (format::EXTENT_FINITE, self.bounds(), None),
+            Extent::Infinite {
+                boundary: None,
+                growth,
+            } => (format::EXTENT_INFINITE, None, growth),
+            Extent::Infinite {
+                boundary: Some(boundary),
+                growth,
+            } => (format::EXTENT_BOUNDED, Some(boundary), growth),
+        };
+        let rect = rect.unwrap_or(Rect {
+            x: 0,
+            y: 0,
+            w: 0,
+            h: 0,
+        });
+        let growth = growth.unwrap_or(Growth {
+            step: 0,
+            interval: 0,
+        });
+        buf.push(kind);
+        format::push_i32(buf, rect.x);
+        format::push_i32(buf, rect.y);
+        format::push_u32(buf, rect.w);
+        format::push_u32(buf, rect.h);
+        format::push_u32(buf, growth.step);
+        format::push_u64(buf, growth.interval);
+    }
+
+    /// Deserializes an extent.
+    pub fn read(reader: &mut Reader) -> Result<Extent, FileError> {
+        let kind = reader.u8()?;
+        let rect = Rect {
+            x: reader.i32()?,
+            y: reader.i32()?,
+            w: reader.u32()?,
+            h: reader.u32()?,
+        };
+        let growth = match (reader.u32()?, reader.u64()?) {
+            (0, _) => None,
+            (step, interval) => Some(Growth { step, interval }),
+        };
+        match kind {
+            format::EXTENT_FINITE => Ok(Extent::Finite {
+                width: rect.w,
+                height: rect.h,
+            }),
+            format::EXTENT_INFINITE => Ok(Extent::Infinite {
+                boundary: None,
+                growth,
+            }),
+            format::EXTENT_BOUNDED => Ok(Extent::Infinite {
+                boundary: Some(rect),
+                growth,
+            }),
+            kind => Err(FileError::UnsupportedExtent(kind)),
+        }
+    }
+}
+
+/// Where the old contents end up when a canvas is resized, in halves of the size difference from
+/// the left and top edges: `nw` is (0, 0), `center` is (1, 1) and `se` is (2, 2).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Anchor {
+    pub x: u8,
+    pub y: u8,
+}
+
+impl Anchor {
+    /// Parses a compass direction like `nw`, `n`, `e` or `se`, or `center`.
+    pub fn parse(s: &str) -> Option<Anchor> {
+        if s == "center" || s == "c" {
+            return Some(Anchor { x: 1, y: 1 });
+        }
+        let (mut x, mut y) = (1, 1);
+        for c in s.chars() {
+            match c {
+                'n' if y == 1 => y = 0,
+                's' if y == 1 => y = 2,
+                'w' if x == 1 => x = 0,
+                'e' if x == 1 => x = 2,
+                _ => return None,
+            }
+        }
+        if s.is_empty() {
+            None
+        } else {
+            Some(Anchor { x, y })
+        }
+    }
+
+    /// Returns how far the old contents move along one axis.
+    fn offset(halves: u8, old: u32, new: u32) -> i64 {
+        (new as i64 - old as i64) * halves as i64 / 2
+    }
+}
+
+/// A change to the canvas size and position of its contents.
+#[derive(Debug, Clone, Copy)]
+pub enum Resize {
+    /// A new (finite) size; missing dimensions stay the same.
+    Size {
+        width: Option<u32>,
+        height: Option<u32>,
+        anchor: Anchor,
+    },
+    /// Keeps only a region, which becomes the whole (finite) canvas.
+    Crop(Rect),
+    /// Moves the contents, keeping the extent.
+    Shift { dx: i32, dy: i32 },
+}
+
+/// Canvas metadata.
+#[derive(Debug, Clone, Copy)]
+pub struct Metadata {
+    /// Creation time (UNIX seconds).
+    pub created_at: u64,
+
+    /// Last modification time (UNIX seconds).
+    pub last_modified: u64,
+
+    /// Total number of pixels placed over the canvas’ lifetime.
+    pub pixels_placed: u64,
+
+    /// Position in the event log up to which events are included in this canvas.
+    pub log_position: Option<u64>,
+}
+
+impl Metadata {
+    fn new() -> Metadata {
+        let now = unix_now();
+        Metadata {
+            created_at: now,
+            last_modified: now,
+            pixels_placed: 0,
+            log_position: None,
+        }
+    }
+}
+
+/// Who last placed a pixel, and when.
+#[derive(Debug, Clone, Copy, Default)]
+struct Placement {
+    /// Index into `Canvas::actors` plus one, or 0 if the pixel was never placed.
+    actor: u32,
+
+    /// UNIX milliseconds.
+    time: u64,
+}
+
+/// Provenance of the pixels of a tile. The table is only allocated once something is placed on
+/// the tile, and keeps times in seconds relative to the earliest one so each pixel takes 8 bytes.
+#[derive(Debug, Clone, Default)]
+struct Placements {
+    /// UNIX seconds that times are relative to.
+    epoch: u64,
+
+    /// Actor (as in `Placement`) and time of each pixel.
+    table: Option<Box<[(u32, u32)]>>,
+}
+
+impl Placements {
+    /// Returns the placement of a pixel, with the time rounded down to the second.
+    fn get(&self, index: usize) -> Placement {
+        match self.table {
+            Some(ref table) if table[index].0 != 0 => Placement {
+                actor: table[index].0,
+                time: (self.epoch + table[index].1 as u64) * 1000,
+            },
+            _ => Placement::default(),
+        }
+    }
+
+    fn set(&mut self, index: usize, placement: Placement) {
+        let time = placement.time / 1000;
+        if self.table.is_none() {
+            if placement.actor == 0 {
+                return;
+            }
+            self.epoch = time;
+            self.table = Some(vec![(0, 0); TILE_PIXELS].into_boxed_slice());
+        }
+        let table = self.table.as_mut().unwrap();
+        if placement.actor != 0 && time < self.epoch {
+            let shift = self.epoch - time;
+            for entry in table.iter_mut().filter(|entry| entry.0 != 0) {
+                entry.1 = (entry.1 as u64 + shift).min(u32::MAX as u64) as u32;
+            }
+            self.epoch = time;
+        }
+        let relative = time.saturating_sub(self.epoch).min(u32::MAX as u64) as u32;
+        table[index] = (placement.actor, relative);
+    }
+
+    /// Returns true if no pixel was placed.
+    fn is_empty(&self) -> bool {
+        self.table
+            .as_ref()
+            .is_none_or(|table| table.iter().all(|entry| entry.0 == 0))
+    }
+
+    /// Returns the placed pixels by index.
+    fn placed(&self) -> Vec<(usize, Placement)> {
+        match self.table {
+            Some(ref table) => (0..TILE_PIXELS)
+                .filter(|&index| table[index].0 != 0)
+                .map(|index| (index, self.get(index)))
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+}
+
+/// A restricted set of colors.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Palette {
+    /// Between 1 and 256 colors.
+    pub colors: Vec<(u8, u8, u8)>,
+
+    /// If true, colors outside the palette are snapped to the nearest entry instead of being
+    /// rejected.
+    pub snap: bool,
+}
+
+impl Palette {
+    /// Returns the index of the closest color.
+    pub fn nearest(&self, r: u8, g: u8, b: u8) -> u8 {
+        let distance = |&(pr, pg, pb): &(u8, u8, u8)| {
+            let dr = pr as i32 - r as i32;
+            let dg = pg as i32 - g as i32;
+            let db = pb as i32 - b as i32;
+            dr * dr + dg * dg + db * db
+        };
+        let mut nearest = 0;
+        for (i, color) in self.colors.iter().enumerate() {
+            if distance(color) < distance(&self.colors[nearest]) {
+                nearest = i;
+            }
+        }
+        nearest as u8
+    }
+
+    /// Returns the palette color a requested color maps to, or None if it’s rejected.
+    pub fn resolve(&self, r: u8, g: u8, b: u8) -> Option<(u8, u8, u8)> {
+        let nearest = self.colors[self.nearest(r, g, b) as usize];
+        if self.snap || nearest == (r, g, b) {
+            Some(nearest)
+        } else {
+            None
+        }
+    }
+}
+
+/// Width and height of a tile.
+pub const TILE_SIZE: u32 = 256;
+
+/// Number of pixels in a tile.
+const TILE_PIXELS: usize = (TILE_SIZE * TILE_SIZE) as usize;
+
+/// Returns the number of tiles needed to cover a length.
+fn tiles_for(len: u32) -> u32 {
+    len.div_ceil(TILE_SIZE)
+}
+
+/// Returns the tile coordinates of a pixel and its index within the tile.
+fn locate(x: i32, y: i32) -> ((i32, i32), usize) {
+    let size = TILE_SIZE as i32;
+    let index = y.rem_euclid(size) * size + x.rem_euclid(size);
+    ((x.div_euclid(size), y.div_euclid(size)), index as usize)
+}
+
+/// Returns the area covered by a tile.
+fn tile_rect((tx, ty): (i32, i32)) -> Rect {
+    Rect {
+        x: tx * TILE_SIZE as i32,
+        y: ty * TILE_SIZE as i32,
+        w: TILE_SIZE,
+        h: TILE_SIZE,
+    }
+}
+
+/// Returns the bytes per pixel of a pixel format.
+fn format_bytes_per_pixel(pixel_format: u8, has_palette: bool) -> Result<usize, FileError> {
+    match pixel_format {
+        format::PIXEL_FORMAT_RGB8 => Ok(3),
+        format::PIXEL_FORMAT_INDEXED8 if has_palette => Ok(1),
+        format::PIXEL_FORMAT_INDEXED8 => Err(FileError::MissingChunk("PALT")),
+        _ => Err(FileError::UnsupportedPixelFormat(pixel_format)),
+    }
+}
+
+/// Checks that indexed pixel data only refers to colors the palette has.
+fn check_indices(palette: &Palette, stored: &[u8]) -> Result<(), FileError> {
+    match stored.iter().find(|&&index| index as usize >= palette.colors.len()) {
+        Some(&index) => Err(FileError::PaletteIndex(index)),
+        None => Ok(()),
+    }
+}
+
+/// Appends stored pixel data as RGB.
+fn push_rgb(palette: Option<&Palette>, stored: &[u8], out: &mut Vec<u8>) {
+    match palette {
+        Some(palette) => for index in stored {
+            let (r, g, b) = palette.colors[*index as usize];
+            out.extend_from_slice(&[r, g, b]);
+        },
+        None => out.extend_from_slice(stored),
+    }
+}
+
+/// Appends a layer name: `len: u8, name: len bytes`.
+fn push_name(buf: &mut Vec<u8>, name: &str) {
+    buf.push(name.len() as u8);
+    buf.extend_from_slice(name.as_bytes());
+}
+
+/// Reads a layer name (see `push_name`).
+fn read_name(reader: &mut Reader) -> Result<String, FileError> {
+    let len = reader.u8()? as usize;
+    Ok(String::from_utf8_lossy(reader.bytes(len)?).to_string())
+}
+
+/// A square of pixels and their provenance. Tiles are only allocated once something is painted
+/// on them; until then they read as white.
+#[derive(Debug, Clone)]
+struct Tile {
+    /// RGB data, or palette indices if the canvas has a palette.
+    pixels: Vec<u8>,
+
+    /// Provenance of each pixel.
+    placements: Placements,
+}
+
+impl Tile {
+    /// Creates a tile filled with the (stored) background color.
+    fn new(background: &[u8]) -> Tile {
+        Tile {
+            pixels: background.repeat(TILE_PIXELS),
+            placements: Placements::default(),
+        }
+    }
+
+    /// Returns true if the tile has nothing worth storing.
+    fn is_blank(&self, background: &[u8]) -> bool {
+        self.pixels.chunks(background.len()).all(|p| p == background)
+            && self.placements.is_empty()
+    }
+}
+
+/// The layer users paint on. It’s the canvas itself, so it has no transparency.
+pub const USER_LAYER: &str = "user";
+
+/// The layer admins paint on, drawn over the user layer. It always exists.
+pub const ADMIN_LAYER: &str = "admin";
+
+/// The maximum number of layers besides the user layer.
+const MAX_LAYERS: usize = 16;
+
+/// Returns true if the name can be used for a layer (and thus in tile file names).
+pub fn is_valid_layer_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= 32
+        && name
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '_')
+}
+
+/// A layer drawn over the user layer, unless it’s hidden. Its pixels are transparent until
+/// someone places them, so the placement doubles as the alpha channel.
+#[derive(Debug, Clone)]
+struct Layer {
+    name: String,
+    hidden: bool,
+    tiles: HashMap<(i32, i32), Tile>,
+
+    /// Tiles changed since the last `tiled_save`.
+    dirty_tiles: HashSet<(i32, i32)>,
+
+    /// Generation of the tile file each tile was last saved to (see `tiled_save`).
+    saved_tiles: HashMap<(i32, i32), u64>,
+}
+
+impl Layer {
+    fn new(name: &str, hidden: bool) -> Layer {
+        Layer {
+            name: name.to_string(),
+            hidden,
+            tiles: HashMap::new(),
+            dirty_tiles: HashSet::new(),
+            saved_tiles: HashMap::new(),
+        }
+    }
+}
+
+/// A change to a layer other than the user layer.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LayerChange {
+    /// Adds a layer on top of the others.
+    Add { hidden: bool },
+    /// Shows or hides a layer.
+    SetHidden(bool),
+    /// Removes a layer and everything on it.
+    Remove,
+    /// Erases a layer, within a region or entirely.
+    Clear(Option<Rect>),
+}
+
+/// The most pixels a single drawing operation may cover.
+pub const MAX_DRAW_PIXELS: u64 = 1 << 22;
+
+/// A drawing operation (see `Canvas::draw`).
+#[derive(Debug, Clone, PartialEq)]
+pub enum Draw {
+    /// Fills a rectangle.
+    Fill { rect: Rect, color: (u8, u8, u8) },
+    /// Draws a one pixel wide line between two points, both included.
+    Line {
+        from: (i32, i32),
+        to: (i32, i32),
+        color: (u8, u8, u8),
+    },
+    /// Fills the connected area of pixels with the same color as `(x, y)`, without leaving
+    /// `within`.
+    Flood {
+        x: i32,
+        y: i32,
+        within: Rect,
+        color: (u8, u8, u8),
+    },
+    /// Copies an image (RGB data) with its top left corner at `(x, y)`.
+    Paste {
+        x: i32,
+        y: i32,
+        w: u32,
+        h: u32,
+        data: Vec<u8>,
+    },
+}
+
+impl Draw {
+    /// Returns the area the operation can’t reach beyond.
+    pub fn area(&self) -> Rect {
+        match *self {
+            Draw::Fill { rect, .. } => rect,
+            Draw::Line { from, to, .. } => Rect::from_edges(
+                from.0.min(to.0) as i64,
+                from.1.min(to.1) as i64,
+                from.0.max(to.0) as i64 + 1,
+                from.1.max(to.1) as i64 + 1,
+            ),
+            Draw::Flood { within, .. } => within,
+            Draw::Paste { x, y, w, h, .. } => Rect { x, y, w, h },
+        }
+    }
+
+    /// Returns the most pixels the operation can cover within the bounds, to be checked against
+    /// `MAX_DRAW_PIXELS`.
+    pub fn max_pixels(&self, bounds: Option<Rect>) -> u64 {
+        let area = self.area();
+        let clipped = match bounds {
+            Some(bounds) => bounds.intersection(&area),
+            None => area,
+        };
+        match self {
+            // lines are walked from end to end in any case
+            Draw::Line { .. } => area.w.max(area.h) as u64,
+            _ => clipped.w as u64 * clipped.h as u64,
+        }
+    }
+}
+
+/// Canvas data for saving to a tile directory.
+pub struct TiledSave {
+    /// The canvas file, without tile data.
+    pub manifest: Vec<u8>,
+
+    /// Tiles changed since the last save by file name, each as a file of its own.
+    pub tiles: Vec<(String, Vec<u8>)>,
+
+    /// File names of all tiles the canvas file refers to. Other files in the tile directory are
+    /// only needed by backups, if at all.
+    pub live_tiles: HashSet<String>,
+}
+
+/// Returns the file name of a tile in a tile directory (`x_y.generation.tile`, or
+/// `layer.x_y.generation.tile` for layers other than the user layer).
+fn tile_file_name(layer: Option<&str>, (tx, ty): (i32, i32), generation: u64) -> String {
+    match layer {
+        Some(layer) => format!("{}.{}_{}.{}.tile", layer, tx, ty, generation),
+        None => format!("{}_{}.{}.tile", tx, ty, generation),
+    }
+}
+
+/// Returns tile coordinates in a stable order.
+fn sorted_keys(tiles: &HashMap<(i32, i32), Tile>) -> Vec<(i32, i32)> {
+    let mut keys: Vec<_> = tiles.keys().cloned().collect();
+    keys.sort_by_key(|&(tx, ty)| (ty, tx));
+    keys
+}
+
+/// Moves tiles by `(dx, dy)`, dropping anything that would end up outside the coordinate range.
+fn shift_tiles(
+    tiles: HashMap<(i32, i32), Tile>,
+    dx: i32,
+    dy: i32,
+    background: &[u8],
+) -> HashMap<(i32, i32), Tile> {
+    let size = TILE_SIZE as i32;
+    let bpp = background.len();
+    let mut shifted = HashMap::new();
+    for (key, tile) in tiles {
+        // shifting by whole tiles just renames them
+        if dx % size == 0 && dy % size == 0 {
+            shifted.insert((key.0 + dx / size, key.1 + dy / size), tile);
+            continue;
+        }
+
+        let rect = tile_rect(key);
+        for i in 0..TILE_PIXELS {
+            let pixel = &tile.pixels[i * bpp..(i + 1) * bpp];
+            let placement = tile.placements.get(i);
+            if pixel == background && placement.actor == 0 {
+                continue;
+            }
+            let x = rect.x as i64 + (i % TILE_SIZE as usize) as i64 + dx as i64;
+            let y = rect.y as i64 + (i / TILE_SIZE as usize) as i64 + dy as i64;
+            if x != x as i32 as i64 || y != y as i32 as i64 {
+                continue;
+            }
+            let (key, index) = locate(x as i32, y as i32);
+            let target = shifted
+                .entry(key)
+                .or_insert_with(|| Tile::new(background));
+            target.pixels[index * bpp..(index + 1) * bpp].copy_from_slice(pixel);
+            target.placements.set(index, placement);
+        }
+    }
+    shifted
+}
+
+/// Drops tiles outside the bounds and resets whatever is cut off in tiles on the edge, in case
+/// the canvas grows again.
+fn crop_tiles(
+    tiles: &mut HashMap<(i32, i32), Tile>,
+    dirty_tiles: &mut HashSet<(i32, i32)>,
+    bounds: Rect,
+    background: &[u8],
+) {
+    let bpp = background.len();
+    let size = TILE_SIZE as usize;
+    for key in sorted_keys(tiles) {
+        let rect = tile_rect(key);
+        if bounds.contains_rect(&rect) {
+            continue;
+        }
+        let keep = rect.intersection(&bounds);
+        if keep.is_empty() {
+            tiles.remove(&key);
+            continue;
+        }
+
+        let (x1, y1) = ((keep.x - rect.x) as usize, (keep.y - rect.y) as usize);
+        let (x2, y2) = (x1 + keep.w as usize, y1 + keep.h as usize);
+        let tile = tiles.get_mut(&key).unwrap();
+        let mut changed = false;
+        for i in 0..TILE_PIXELS {
+            let (x, y) = (i % size, i / size);
+            let kept = x >= x1 && x < x2 && y >= y1 && y < y2;
+            let pixel = &mut tile.pixels[i * bpp..(i + 1) * bpp];
+            if !kept && (&*pixel != background || tile.placements.get(i).actor != 0) {
+                pixel.copy_from_slice(background);
+                tile.placements.set(i, Placement::default());
+                changed = true;
+            }
+        }
+        if changed {
+            dirty_tiles.insert(key);
+        }
+    }
+}
+
+/// A canvas.
+#[derive(Debug, Clone)]
+pub struct Canvas {
+    extent: Extent,
+
+    /// When the world boundary was last set or grown (UNIX milliseconds).
+    last_growth: u64,
+
+    /// Allocated tiles of the user layer by tile coordinates.
+    tiles: HashMap<(i32, i32), Tile>,
+
+    /// Layers drawn over the user layer, bottom to top. The admin layer is always there.
+    layers: Vec<Layer>,
+
+    /// If set, only these colors can be used.
+    palette: Option<Palette>,
+
+    pub meta: Metadata,
+
+    /// Actors referenced by tile placements.
+    actors: Vec<Actor>,
+    actor_ids: HashMap<Actor, u32>,
+
+    /// Records changed pixels, for `compile_deltas`.
+    changed_pixels: HashSet<(i32, i32)>,
+
+    /// Tiles changed since the last `tiled_save`.
+    dirty_tiles: HashSet<(i32, i32)>,
+
+    /// Generation of the tile file each tile was last saved to (see `tiled_save`).
+    saved_tiles: HashMap<(i32, i32), u64>,
+
+    /// The newest generation of tile files that may exist.
+    tile_generation: u64,
+
+    /// If true, the tile data wasn’t in the file this canvas was read from (see `read_tile_file`).
+    external_tiles: bool,
+
+    /// Incremented on every change, so encoded copies of the canvas can be cached.
+    revision: u64,
+}
+
+impl Canvas {
+    /// Creates a new canvas from RGB data.
+    pub fn new(width: u32, height: u32, pixels: Vec<u8>) -> Canvas {
+        let mut canvas = Self::blank(width, height);
+        canvas.write_rows(&pixels);
+        canvas
+    }
+
+    /// Creates a blank canvas.
+    pub fn blank(width: u32, height: u32) -> Canvas {
+        Self::with_extent(Extent::Finite { width, height })
+    }
+
+    /// Creates a blank canvas with any extent.
+    pub fn with_extent(extent: Extent) -> Canvas {
+        Canvas {
+            extent,
+            last_growth: 0,
+            tiles: HashMap::new(),
+            layers: vec![Layer::new(ADMIN_LAYER, false)],
+            palette: None,
+            meta: Metadata::new(),
+            actors: Vec::new(),
+            actor_ids: HashMap::new(),
+            changed_pixels: HashSet::new(),
+            dirty_tiles: HashSet::new(),
+            saved_tiles: HashMap::new(),
+            tile_generation: 0,
+            external_tiles: false,
+            revision: 0,
+        }
+    }
+
+    /// Splits row-major pixel data (in the stored format) covering the whole canvas into tiles,
+    /// leaving out tiles that would be blank.
+    fn write_rows(&mut self, data: &[u8]) {
+        let (width, height) = match self.extent {
+            Extent::Finite { width, height } => (width, height),
+            Extent::Infinite { .. } => return,
+        };
+        let background = self.background();
+        let bpp = background.len();
+        for ty in 0..tiles_for(height) {
+            for tx in 0..tiles_for(width) {
+                let (x0, y0) = (tx * TILE_SIZE, ty * TILE_SIZE);
+                let w = (width - x0).min(TILE_SIZE) as usize;
+                let h = (height - y0).min(TILE_SIZE) as usize;
+
+                let mut tile = Tile::new(&background);
+                for row in 0..h {
+                    let src = ((y0 as usize + row) * width as usize + x0 as usize) * bpp;
+                    let dest = row * TILE_SIZE as usize * bpp;
+                    tile.pixels[dest..dest + w * bpp].copy_from_slice(&data[src..src + w * bpp]);
+                }
+                if !tile.is_blank(&background) {
+                    let key = (tx as i32, ty as i32);
+                    self.tiles.insert(key, tile);
+                    self.dirty_tiles.insert(key);
+                }
+            }
+        }
+    }
+
+    /// Deserializes a canvas from file data.
+    ///
+    /// If the tiles are stored separately, they must be added with `read_tile_file` (see
+    /// `tile_files`).
+    pub fn from_file(data: Vec<u8>) -> Result<Canvas, FileError> {
+        let mut reader = match format::open(&data)? {
+            Some((_, reader)) => reader,
+            None => return Self::from_legacy_file(data),
+        };
+
+        let mut meta = None;
+        let mut canvas = None;
+        let mut palette = None;
+        let mut actors = None;
+        let mut tiles = Vec::new();
+        let mut layers = None;
+        let mut layer_tiles = Vec::new();
+        let mut tile_index = None;
+        while !reader.is_empty() {
+            let (tag, mut chunk) = reader.chunk()?;
+            match tag {
+                t if t == format::CHUNK_META => {
+                    meta = Some(Metadata {
+                        created_at: chunk.u64()?,
+                        last_modified: chunk.u64()?,
+                        pixels_placed: chunk.u64()?,
+                        log_position: if chunk.is_empty() {
+                            None
+                        } else {
+                            Some(chunk.u64()?)
+                        },
+                    });
+                }
+                t if t == format::CHUNK_PALETTE => {
+                    let snap = chunk.u8()? != 0;
+                    let count = chunk.u16()?;
+                    if count == 0 || count > 256 {
+                        return Err(FileError::PaletteSize(count));
+                    }
+                    let mut colors = Vec::with_capacity(count as usize);
+                    for _ in 0..count {
+                        let rgb = chunk.bytes(3)?;
+                        colors.push((rgb[0], rgb[1], rgb[2]));
+                    }
+                    palette = Some(Palette { colors, snap });
+                }
+                t if t == format::CHUNK_CANVAS => {
+                    let pixel_format = chunk.u8()?;
+                    let bytes_per_pixel = format_bytes_per_pixel(pixel_format, palette.is_some())?;
+                    let mut c = Self::with_extent(Extent::read(&mut chunk)?);
+                    c.last_growth = chunk.u64()?;
+                    if bytes_per_pixel == 1 {
+                        c.palette = palette.take();
+                    }
+                    c.external_tiles = chunk.u8()? == format::TILES_EXTERNAL;
+                    canvas = Some(c);
+                }
+                t if t == format::CHUNK_ACTORS => actors = Some(chunk),
+                t if t == format::CHUNK_TILE => tiles.push(chunk),
+                t if t == format::CHUNK_LAYERS => layers = Some(chunk),
+                t if t == format::CHUNK_LAYER_TILE => layer_tiles.push(chunk),
+                t if t == format::CHUNK_TILE_INDEX => tile_index = Some(chunk),
+                _ => (), // unknown chunk
+            }
+        }
+
+        let mut canvas = match canvas {
+            Some(canvas) => canvas,
+            None => return Err(FileError::MissingChunk("CANV")),
+        };
+        if let Some(meta) = meta {
+            canvas.meta = meta;
+        }
+        if let Some(mut chunk) = actors {
+            canvas.read_actors(&mut chunk)?;
+        }
+        for chunk in tiles {
+            canvas.read_tile(chunk)?;
+        }
+        if let Some(chunk) = layers {
+            canvas.read_layers(chunk)?;
+        }
+        for chunk in layer_tiles {
+            canvas.read_layer_tile(chunk)?;
+        }
+        match tile_index {
+            Some(chunk) => canvas.read_tile_index(chunk)?,
+            None if canvas.external_tiles => return Err(FileError::MissingChunk("TIDX")),
+            None => (),
+        }
+
+        // none of this is in a tile directory yet
+        if !canvas.external_tiles {
+            canvas.mark_all_dirty();
+        }
+        Ok(canvas)
+    }
+
+    /// Reads an actor table (see `format::CHUNK_ACTORS`).
+    fn read_actors(&mut self, chunk: &mut Reader) -> Result<(), FileError> {
+        let count = chunk.u32()?;
+        for _ in 0..count {
+            let len = chunk.u16()? as usize;
+            let actor = String::from_utf8_lossy(chunk.bytes(len)?);
+            // an unparsable actor just makes its pixels anonymous
+            let actor = Actor::parse(&actor).unwrap_or(Actor::Anonymous(actor.to_string()));
+            self.actors.push(actor.clone());
+            let id = self.actors.len() as u32;
+            self.actor_ids.entry(actor).or_insert(id);
+        }
+        Ok(())
+    }
+
+    /// Reads a tile index (see `format::CHUNK_TILE_INDEX`). Tiles of unknown layers are ignored.
+    fn read_tile_index(&mut self, mut chunk: Reader) -> Result<(), FileError> {
+        let count = chunk.u32()?;
+        for _ in 0..count {
+            let name = read_name(&mut chunk)?;
+            let key = (chunk.i32()?, chunk.i32()?);
+            let generation = chunk.u64()?;
+            let saved_tiles = if name.is_empty() {
+                &mut self.saved_tiles
+            } else {
+                match self.layer_index(&name) {
+                    Some(index) => &mut self.layers[index].saved_tiles,
+                    None => continue,
+                }
+            };
+            saved_tiles.insert(key, generation);
+            self.tile_generation = self.tile_generation.max(generation);
+        }
+        Ok(())
+    }
+
+    /// Reads a layer table (see `format::CHUNK_LAYERS`).
+    fn read_layers(&mut self, mut chunk: Reader) -> Result<(), FileError> {
+        self.layers.clear();
+        let count = chunk.u16()?;
+        for _ in 0..count {
+            let name = read_name(&mut chunk)?;
+            let hidden = chunk.u8()? != 0;
+            self.layers.push(Layer::new(&name, hidden));
+        }
+        if self.layer_index(ADMIN_LAYER).is_none() {
+            self.layers.insert(0, Layer::new(ADMIN_LAYER, false));
+        }
+        Ok(())
+    }
+
+    /// Reads a tile chunk (see `format::CHUNK_TILE`).
+    fn read_tile(&mut self, mut chunk: Reader) -> Result<(), FileError> {
+        let key = (chunk.i32()?, chunk.i32()?);
+        let tile = self.read_tile_data(&mut chunk)?;
+        if self.bounds().is_none_or(|b| b.intersects(&tile_rect(key))) {
+            self.tiles.insert(key, tile);
+        }
+        Ok(())
+    }
+
+    /// Reads a layer tile chunk (see `format::CHUNK_LAYER_TILE`). Tiles of unknown layers are
+    /// ignored.
+    fn read_layer_tile(&mut self, mut chunk: Reader) -> Result<(), FileError> {
+        let name = read_name(&mut chunk)?;
+        let key = (chunk.i32()?, chunk.i32()?);
+        let tile = self.read_tile_data(&mut chunk)?;
+        let in_bounds = self.bounds().is_none_or(|b| b.intersects(&tile_rect(key)));
+        if let (Some(index), true) = (self.layer_index(&name), in_bounds) {
+            self.layers[index].tiles.insert(key, tile);
+        }
+        Ok(())
+    }
+
+    /// Reads the pixels and provenance of a tile chunk, after the tile coordinates.
+    fn read_tile_data(&self, chunk: &mut Reader) -> Result<Tile, FileError> {
+        let pixel_format = chunk.u8()?;
+        if pixel_format != self.pixel_format() {
+            return Err(FileError::UnsupportedPixelFormat(pixel_format));
+        }
+        let data = chunk.bytes(TILE_PIXELS * self.pixel_format_bytes())?;
+        if let Some(ref palette) = self.palette {
+            check_indices(palette, data)?;
+        }
+
+        let mut tile = Tile::new(&self.background());
+        tile.pixels = data.to_vec();
+
+        let count = chunk.u32()?;
+        for _ in 0..count {
+            let index = chunk.u32()? as usize;
+            let actor = chunk.u32()?;
+            let time = chunk.u64()?;
+            if index < TILE_PIXELS && actor as usize <= self.actors.len() {
+                tile.placements.set(index, Placement { actor, time });
+            }
+        }
+        Ok(tile)
+    }
+
+    /// Returns true if the tile data is stored separately from the canvas file.
+    pub fn has_external_tiles(&self) -> bool {
+        self.external_tiles
+    }
+
+    /// Returns the names of the tile files the canvas was saved with, if it was read from a file
+    /// that keeps its tiles in a tile directory.
+    pub fn tile_files(&self) -> Vec<String> {
+        if !self.external_tiles {
+            return Vec::new();
+        }
+        let mut names: Vec<_> = self
+            .saved_tiles
+            .iter()
+            .map(|(&key, &generation)| tile_file_name(None, key, generation))
+            .collect();
+        for layer in &self.layers {
+            names.extend(
+                layer
+                    .saved_tiles
+                    .iter()
+                    .map(|(&key, &generation)| tile_file_name(Some(&layer.name), key, generation)),
+            );
+        }
+        names
+    }
+
+    /// Makes the next `tiled_save` write tile files newer than the given generation, so it
+    /// doesn’t overwrite files that another canvas file (such as an unreadable one that was
+    /// skipped for a backup) refers to.
+    pub fn skip_tile_generations(&mut self, generation: u64) {
+        self.tile_generation = self.tile_generation.max(generation);
+    }
+
+    /// Reads a tile file from a tile directory.
+    pub fn read_tile_file(&mut self, data: &[u8]) -> Result<(), FileError> {
+        let mut reader = match format::open(data)? {
+            Some((_, reader)) => reader,
+            None => return Err(FileError::MissingChunk("TILE")),
+        };
+        while !reader.is_empty() {
+            let (tag, chunk) = reader.chunk()?;
+            if tag == format::CHUNK_TILE {
+                self.read_tile(chunk)?;
+            } else if tag == format::CHUNK_LAYER_TILE {
+                self.read_layer_tile(chunk)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Deserializes a legacy (v0) canvas file.
+    fn from_legacy_file(mut data: Vec<u8>) -> Result<Canvas, FileError> {
+        let (width, mut height) = {
+            let mut reader = Reader::new(&data);
+            (reader.u32()?, reader.u32()?)
+        };
+        let pixels = data.split_off(8);
+
+        // v0 wrote the width in place of the height, so recover it from the data length
+        if width > 0 && (width as usize * height as usize * 3) != pixels.len() {
+            height = (pixels.len() / (width as usize * 3)) as u32;
+        }
+
+        let expected = width as usize * height as usize * 3;
+        if expected != pixels.len() {
+            return Err(FileError::SizeMismatch {
+                expected,
+                found: pixels.len(),
+            });
+        }
+        Ok(Self::new(width, height, pixels))
+    }
+
+    /// Serializes everything except tile data.
+    fn write_header(&self, tile_storage: u8) -> Vec<u8> {
+        let mut buf = format::begin();
+
+        let mut meta = Vec::with_capacity(32);
+        format::push_u64(&mut meta, self.meta.created_at);
+        format::push_u64(&mut meta, self.meta.last_modified);
+        format::push_u64(&mut meta, self.meta.pixels_placed);
+        if let Some(log_position) = self.meta.log_position {
+            format::push_u64(&mut meta, log_position);
+        }
+        format::push_chunk(&mut buf, format::CHUNK_META, &meta);
+
+        if let Some(ref palette) = self.palette {
+            let mut data = Vec::with_capacity(3 + palette.colors.len() * 3);
+            data.push(palette.snap as u8);
+            format::push_u16(&mut data, palette.colors.len() as u16);
+            for &(r, g, b) in &palette.colors {
+                data.extend_from_slice(&[r, g, b]);
+            }
+            format::push_chunk(&mut buf, format::CHUNK_PALETTE, &data);
+        }
+
+        let mut data = Vec::with_capacity(39);
+        data.push(self.pixel_format());
+        self.extent.write(&mut data);
+        format::push_u64(&mut data, self.last_growth);
+        data.push(tile_storage);
+        format::push_chunk(&mut buf, format::CHUNK_CANVAS, &data);
+
+        let mut data = Vec::new();
+        format::push_u16(&mut data, self.layers.len() as u16);
+        for layer in &self.layers {
+            push_name(&mut data, &layer.name);
+            data.push(layer.hidden as u8);
+        }
+        format::push_chunk(&mut buf, format::CHUNK_LAYERS, &data);
+
+        let mut data = Vec::new();
+        format::push_u32(&mut data, self.actors.len() as u32);
+        for actor in &self.actors {
+            let actor = actor.to_string();
+            format::push_u16(&mut data, actor.len() as u16);
+            data.extend_from_slice(actor.as_bytes());
+        }
+        format::push_chunk(&mut buf, format::CHUNK_ACTORS, &data);
+
+        buf
+    }
+
+    /// Serializes a tile chunk (with sparse provenance; most pixels are never placed), or a layer
+    /// tile chunk for a layer other than the user layer.
+    fn write_tile(&self, layer: Option<&Layer>, key: (i32, i32)) -> Vec<u8> {
+        let tile = match layer {
+            Some(layer) => &layer.tiles[&key],
+            None => &self.tiles[&key],
+        };
+        let mut buf = Vec::with_capacity(46 + tile.pixels.len());
+        if let Some(layer) = layer {
+            push_name(&mut buf, &layer.name);
+        }
+        format::push_i32(&mut buf, key.0);
+        format::push_i32(&mut buf, key.1);
+        buf.push(self.pixel_format());
+        buf.extend_from_slice(&tile.pixels);
+
+        let placed = tile.placements.placed();
+        format::push_u32(&mut buf, placed.len() as u32);
+        for (index, placement) in placed {
+            format::push_u32(&mut buf, index as u32);
+            format::push_u32(&mut buf, placement.actor);
+            format::push_u64(&mut buf, placement.time);
+        }
+        buf
+    }
+
+    /// Returns tile coordinates in a stable order.
+    fn sorted_tiles(&self) -> Vec<(i32, i32)> {
+        sorted_keys(&self.tiles)
+    }
+
+    /// Serializes the canvas as a single file.
+    pub fn to_file(&self) -> Vec<u8> {
+        let mut buf = self.write_header(format::TILES_INLINE);
+        for key in self.sorted_tiles() {
+            format::push_chunk(&mut buf, format::CHUNK_TILE, &self.write_tile(None, key));
+        }
+        for layer in &self.layers {
+            for key in sorted_keys(&layer.tiles) {
+                let data = self.write_tile(Some(layer), key);
+                format::push_chunk(&mut buf, format::CHUNK_LAYER_TILE, &data);
+            }
+        }
+        format::finish(buf)
+    }
+
+    /// Serializes the canvas for a tile directory, including only the tiles that changed since
+    /// the last time.
+    ///
+    /// Changed tiles get new files named after the generation of the save, and the canvas file
+    /// lists which files it belongs with, so a save never touches the tiles an older canvas file
+    /// (or backup) refers to.
+    pub fn tiled_save(&mut self) -> TiledSave {
+        let tile_file = |tag: &[u8; 4], data: Vec<u8>| {
+            let mut buf = format::begin();
+            format::push_chunk(&mut buf, tag, &data);
+            format::finish(buf)
+        };
+        self.tile_generation += 1;
+        let generation = self.tile_generation;
+
+        let mut tiles = Vec::new();
+        let mut index = Vec::new();
+        let dirty = mem::take(&mut self.dirty_tiles);
+        let live = &self.tiles;
+        self.saved_tiles.retain(|key, _| live.contains_key(key));
+        for key in self.sorted_tiles() {
+            if dirty.contains(&key) || !self.saved_tiles.contains_key(&key) {
+                let data = tile_file(format::CHUNK_TILE, self.write_tile(None, key));
+                tiles.push((tile_file_name(None, key, generation), data));
+                self.saved_tiles.insert(key, generation);
+            }
+            index.push((None, key, self.saved_tiles[&key]));
+        }
+        for i in 0..self.layers.len() {
+            let dirty = mem::take(&mut self.layers[i].dirty_tiles);
+            let mut saved_tiles = mem::take(&mut self.layers[i].saved_tiles);
+            let layer = &self.layers[i];
+            saved_tiles.retain(|key, _| layer.tiles.contains_key(key));
+            for key in sorted_keys(&layer.tiles) {
+                if dirty.contains(&key) || !saved_tiles.contains_key(&key) {
+                    let data = self.write_tile(Some(layer), key);
+                    let name = tile_file_name(Some(&layer.name), key, generation);
+                    tiles.push((name, tile_file(format::CHUNK_LAYER_TILE, data)));
+                    saved_tiles.insert(key, generation);
+                }
+                index.push((Some(i), key, saved_tiles[&key]));
+            }
+            self.layers[i].saved_tiles = saved_tiles;
+        }
+
+        let mut live_tiles = HashSet::new();
+        let mut data = Vec::with_capacity(4 + index.len() * 20);
+        format::push_u32(&mut data, index.len() as u32);
+        for (layer, key, generation) in index {
+            let layer = layer.map(|i| self.layers[i].name.as_str());
+            push_name(&mut data, layer.unwrap_or(""));
+            format::push_i32(&mut data, key.0);
+            format::push_i32(&mut data, key.1);
+            format::push_u64(&mut data, generation);
+            live_tiles.insert(tile_file_name(layer, key, generation));
+        }
+        let mut manifest = self.write_header(format::TILES_EXTERNAL);
+        format::push_chunk(&mut manifest, format::CHUNK_TILE_INDEX, &data);
 
-    /// RGB data.
-    pub data: Vec<u8>,
-}
+        TiledSave {
+            manifest: format::finish(manifest),
+            tiles,
+            live_tiles,
+        }
+    }
 
-/// A canvas.
-#[derive(Debug, Clone)]
-pub struct Canvas {
-    pub width: u32,
-    pub height: u32,
+    /// Marks every tile as changed, so the next `tiled_save` writes all of them.
+    pub fn mark_all_dirty(&mut self) {
+        self.dirty_tiles = self.tiles.keys().cloned().collect();
+        for layer in &mut self.layers {
+            layer.dirty_tiles = layer.tiles.keys().cloned().collect();
+        }
+    }
 
-    /// RGB data.
-    pub pixels: Vec<u8>,
+    /// Returns the pixel format of stored pixel data.
+    fn pixel_format(&self) -> u8 {
+        match self.palette {
+            Some(_) => format::PIXEL_FORMAT_INDEXED8,
+            None => format::PIXEL_FORMAT_RGB8,
+        }
+    }
 
-    /// Records changed pixels, for `compile_deltas`.
-    changed_pixels: HashSet<(u32, u32)>,
-}
+    /// Returns the stored representation of a color.
+    fn encode_color(&self, r: u8, g: u8, b: u8) -> Vec<u8> {
+        match self.palette {
+            Some(ref palette) => vec![palette.nearest(r, g, b)],
+            None => vec![r, g, b],
+        }
+    }
 
-impl Canvas {
-    /// Creates a new canvas.
-    pub fn new(width: u32, height: u32, pixels: Vec<u8>) -> Canvas {
-        Canvas {
-            width,
-            height,
-            pixels,
-            changed_pixels: HashSet::new(),
+    /// Returns the stored representation of the color of unpainted areas.
+    fn background(&self) -> Vec<u8> {
+        self.encode_color(255, 255, 255)
+    }
+
+    /// Returns a tile, allocating it if necessary.
+    fn tile_mut(&mut self, key: (i32, i32)) -> &mut Tile {
+        let background = self.background();
+        self.tiles
+            .entry(key)
+            .or_insert_with(|| Tile::new(&background))
+    }
+
+    /// Returns how far the canvas extends.
+    pub fn extent(&self) -> Extent {
+        self.extent
+    }
+
+    /// Returns the area pixels can be placed in, or None if it’s unlimited.
+    pub fn bounds(&self) -> Option<Rect> {
+        self.extent.bounds()
+    }
+
+    /// Returns true if the canvas is infinite (with or without a world boundary).
+    pub fn is_infinite(&self) -> bool {
+        match self.extent {
+            Extent::Finite { .. } => false,
+            Extent::Infinite { .. } => true,
         }
     }
 
-    /// Creates a blank canvas.
-    pub fn blank(width: u32, height: u32) -> Canvas {
-        let mut pixels = Vec::with_capacity((width * height * 3) as usize);
-        pixels.resize((width * height * 3) as usize, 255);
-        Self::new(width, height, pixels)
+    /// Returns true if pixels can be placed at the coordinates.
+    pub fn contains(&self, x: i32, y: i32) -> bool {
+        self.bounds().is_none_or(|bounds| bounds.contains(x, y))
     }
 
-    /// Deserializes a canvas from file data, or returns None if the file is invalid.
-    pub fn from_file(mut data: Vec<u8>) -> Option<Canvas> {
-        if data.len() < 8 {
-            return None;
+    /// Returns the area covered by allocated tiles (within the bounds), if there are any.
+    pub fn populated(&self) -> Option<Rect> {
+        let visible_layers = self.layers.iter().filter(|layer| !layer.hidden);
+        let populated = self
+            .tiles
+            .keys()
+            .chain(visible_layers.flat_map(|layer| layer.tiles.keys()))
+            .map(|&key| tile_rect(key))
+            .fold(None, |acc: Option<Rect>, rect| match acc {
+                Some(acc) => Some(acc.union(&rect)),
+                None => Some(rect),
+            })?;
+        match self.bounds() {
+            Some(bounds) => Some(populated.intersection(&bounds)).filter(|r| !r.is_empty()),
+            None => Some(populated),
         }
-        let width: u32 = u32::from_be(unsafe { *(&data[0..4] as *const _ as *const u32) });
-        let height: u32 = u32::from_be(unsafe { *(&data[4..8] as *const _ as *const u32) });
-        let pixels = data.split_off(8);
-        if (width * height) as usize * 3 != pixels.len() {
+    }
+
+    /// Returns the areas of all allocated tiles.
+    pub fn tile_rects(&self) -> Vec<Rect> {
+        self.sorted_tiles().into_iter().map(tile_rect).collect()
+    }
+
+    /// Returns true if a region is within the canvas. Without a boundary, it only has to fit in
+    /// the coordinate range.
+    fn contains_rect(&self, rect: &Rect) -> bool {
+        match self.bounds() {
+            Some(bounds) => bounds.contains_rect(rect),
+            None => {
+                rect.right() <= i32::MAX as i64 && rect.bottom() <= i32::MAX as i64
+            }
+        }
+    }
+
+    /// Returns a region as it’s seen, with visible layers drawn over the user layer, if it's
+    /// within the canvas.
+    ///
+    /// On an infinite canvas without a boundary, any region is valid, so callers must limit its
+    /// size.
+    pub fn region(&self, x: i32, y: i32, w: u32, h: u32) -> Option<Region> {
+        let rect = Rect { x, y, w, h };
+        if !self.contains_rect(&rect) {
+            // nope
             return None;
         }
-        Some(Self::new(width, height, pixels))
+
+        let mut data = self.user_region(rect);
+        for layer in self.layers.iter().filter(|layer| !layer.hidden) {
+            self.draw_layer(layer, rect, &mut data, 3);
+        }
+        Some(Region { x, y, w, h, data })
     }
 
-    /// Serializes the canvas for storage.
-    pub fn to_file(&self) -> Vec<u8> {
-        let mut vec = Vec::with_capacity(8);
-        unsafe {
-            vec.set_len(8);
-            *(&mut vec[0..4] as *mut _ as *mut u32) = self.width.to_be();
-            *(&mut vec[4..8] as *mut _ as *mut u32) = self.width.to_be();
+    /// Returns the RGB data of the user layer in an area.
+    fn user_region(&self, rect: Rect) -> Vec<u8> {
+        let mut data = Vec::with_capacity((rect.w as u64 * rect.h as u64 * 3) as usize);
+        let background = self.background();
+        let bpp = background.len();
+
+        for iy in rect.y..(rect.bottom() as i32) {
+            let mut ix = rect.x as i64;
+            while ix < rect.right() {
+                let (key, index) = locate(ix as i32, iy);
+                let span = (TILE_SIZE as usize - index % TILE_SIZE as usize)
+                    .min((rect.right() - ix) as usize);
+                match self.tiles.get(&key) {
+                    Some(tile) => push_rgb(
+                        self.palette.as_ref(),
+                        &tile.pixels[index * bpp..(index + span) * bpp],
+                        &mut data,
+                    ),
+                    None => push_rgb(self.palette.as_ref(), &background.repeat(span), &mut data),
+                }
+                ix += span as i64;
+            }
+        }
+        data
+    }
+
+    /// Draws the placed pixels of a layer over RGB (3 channels) or RGBA (4 channels) data that
+    /// covers an area.
+    fn draw_layer(&self, layer: &Layer, rect: Rect, data: &mut [u8], channels: usize) {
+        let bpp = self.pixel_format_bytes();
+        for (key, tile) in &layer.tiles {
+            let tile_area = tile_rect(*key);
+            if !tile_area.intersects(&rect) {
+                continue;
+            }
+            for (x, y) in tile_area.intersection(&rect).pixels() {
+                let (_, index) = locate(x, y);
+                if tile.placements.get(index).actor == 0 {
+                    continue;
+                }
+                let (r, g, b) = self.decode_color(&tile.pixels[index * bpp..(index + 1) * bpp]);
+                let offset = (y - rect.y) as usize * rect.w as usize + (x - rect.x) as usize;
+                let offset = offset * channels;
+                data[offset..offset + 3].copy_from_slice(&[r, g, b]);
+                if channels == 4 {
+                    data[offset + 3] = 255;
+                }
+            }
         }
-        vec.append(&mut self.pixels.clone());
-        vec
     }
 
-    /// Returns the index of the coordinates in `pixels`.
-    fn index(&self, x: u32, y: u32) -> usize {
-        (self.width * y + x) as usize * 3
+    /// Returns a region of a single layer as RGBA, if the layer exists and the region is within
+    /// the canvas. Pixels nobody placed on layers other than the user layer are transparent.
+    pub fn layer_region(&self, layer: &str, x: i32, y: i32, w: u32, h: u32) -> Option<Vec<u8>> {
+        let rect = Rect { x, y, w, h };
+        if !self.contains_rect(&rect) {
+            return None;
+        }
+        if layer == USER_LAYER {
+            let rgb = self.user_region(rect);
+            let mut rgba = Vec::with_capacity(rgb.len() / 3 * 4);
+            for pixel in rgb.chunks(3) {
+                rgba.extend_from_slice(pixel);
+                rgba.push(255);
+            }
+            return Some(rgba);
+        }
+        let layer = &self.layers[self.layer_index(layer)?];
+        let mut rgba = vec![0; w as usize * h as usize * 4];
+        self.draw_layer(layer, rect, &mut rgba, 4);
+        Some(rgba)
     }
 
-    /// Returns a region, if it's within the canvas.
-    pub fn region(&self, x: u32, y: u32, w: u32, h: u32) -> Option<Region> {
-        if x >= self.width || x + w > self.width || y >= self.height || y + h > self.height {
-            // nope
+    /// Returns a single pixel of the user layer. Pixels outside the canvas read as unpainted.
+    pub fn pixel(&self, x: i32, y: i32) -> (u8, u8, u8) {
+        let (key, index) = locate(x, y);
+        match self.tiles.get(&key) {
+            Some(tile) => {
+                let bpp = self.pixel_format_bytes();
+                self.decode_color(&tile.pixels[index * bpp..(index + 1) * bpp])
+            }
+            None => self.decode_color(&self.background()),
+        }
+    }
+
+    /// Returns a single pixel of a layer, or None if it’s transparent or there is no such layer.
+    pub fn layer_pixel(&self, layer: &str, x: i32, y: i32) -> Option<(u8, u8, u8)> {
+        if layer == USER_LAYER {
+            return Some(self.pixel(x, y));
+        }
+        let (key, index) = locate(x, y);
+        let tile = self.layers[self.layer_index(layer)?].tiles.get(&key)?;
+        if tile.placements.get(index).actor == 0 {
             return None;
         }
+        let bpp = self.pixel_format_bytes();
+        Some(self.decode_color(&tile.pixels[index * bpp..(index + 1) * bpp]))
+    }
 
-        let mut data = Vec::with_capacity((w * h) as usize * 3);
+    /// Returns the color of a stored pixel.
+    fn decode_color(&self, stored: &[u8]) -> (u8, u8, u8) {
+        match self.palette {
+            Some(ref palette) => palette.colors[stored[0] as usize],
+            None => (stored[0], stored[1], stored[2]),
+        }
+    }
 
-        for iy in y..(y + h) {
-            data.extend_from_slice(&self.pixels[self.index(x, iy)..self.index(x + w, iy)]);
+    /// Returns the number of bytes per stored pixel.
+    fn pixel_format_bytes(&self) -> usize {
+        match self.palette {
+            Some(_) => 1,
+            None => 3,
         }
+    }
 
-        Some(Region { x, y, w, h, data })
+    /// Returns the color that would be stored for a placement, or None if the palette rejects it.
+    pub fn resolve_color(&self, r: u8, g: u8, b: u8) -> Option<(u8, u8, u8)> {
+        match self.palette {
+            Some(ref palette) => palette.resolve(r, g, b),
+            None => Some((r, g, b)),
+        }
     }
 
-    /// Sets a single pixel.
-    pub fn set_pixel(&mut self, x: u32, y: u32, r: u8, g: u8, b: u8) {
-        if x >= self.width || y >= self.height {
+    /// Sets a single pixel. With a palette, the color is snapped to the nearest entry.
+    pub fn set_pixel(&mut self, x: i32, y: i32, r: u8, g: u8, b: u8) {
+        if !self.contains(x, y) {
             // nope
             return;
         }
         self.changed_pixels.insert((x, y));
-        let index = self.index(x, y);
-        self.pixels[index + 0] = r;
-        self.pixels[index + 1] = g;
-        self.pixels[index + 2] = b;
+        self.meta.pixels_placed += 1;
+        self.revision += 1;
+
+        let (key, index) = locate(x, y);
+        let color = self.encode_color(r, g, b);
+        let bpp = color.len();
+        self.tile_mut(key).pixels[index * bpp..(index + 1) * bpp].copy_from_slice(&color);
+        self.dirty_tiles.insert(key);
+    }
+
+    /// Returns the palette.
+    pub fn palette(&self) -> Option<&Palette> {
+        self.palette.as_ref()
+    }
+
+    /// Sets or removes the palette, remapping every pixel to its nearest color.
+    pub fn set_palette(&mut self, palette: Option<Palette>) {
+        let layer_tiles = self.layers.iter_mut().flat_map(|layer| layer.tiles.values_mut());
+        for tile in self.tiles.values_mut().chain(layer_tiles) {
+            let mut rgb = Vec::with_capacity(TILE_PIXELS * 3);
+            push_rgb(self.palette.as_ref(), &tile.pixels, &mut rgb);
+            tile.pixels = match palette {
+                Some(ref palette) => rgb
+                    .chunks(3)
+                    .map(|c| palette.nearest(c[0], c[1], c[2]))
+                    .collect(),
+                None => rgb,
+            };
+        }
+        self.palette = palette;
+        self.mark_all_dirty();
+        self.changed_pixels.clear();
+        self.revision += 1;
+    }
+
+    /// Returns a number that changes whenever the canvas contents or size change.
+    pub fn revision(&self) -> u64 {
+        self.revision
+    }
+
+    /// Returns true if there are changed pixels that haven’t been compiled into deltas yet.
+    pub fn has_changes(&self) -> bool {
+        !self.changed_pixels.is_empty()
+    }
+
+    /// Sets a single pixel on behalf of an actor, recording its provenance.
+    pub fn place(&mut self, x: i32, y: i32, color: (u8, u8, u8), actor: &Actor, time: u64) {
+        if !self.contains(x, y) {
+            return;
+        }
+        self.set_pixel(x, y, color.0, color.1, color.2);
+        let actor = self.intern_actor(actor);
+        let (key, index) = locate(x, y);
+        self.tile_mut(key).placements.set(index, Placement { actor, time });
+    }
+
+    /// Returns who last placed the pixel on the user layer and when (UNIX milliseconds), if anyone
+    /// did.
+    pub fn provenance(&self, x: i32, y: i32) -> Option<(&Actor, u64)> {
+        if !self.contains(x, y) {
+            return None;
+        }
+        let (key, index) = locate(x, y);
+        self.placement_actor(self.tiles.get(&key)?.placements.get(index))
+    }
+
+    /// Returns who placed the pixel that can be seen, which may be on a layer.
+    pub fn visible_provenance(&self, x: i32, y: i32) -> Option<(&Actor, u64)> {
+        match self.covering_placement(x, y) {
+            Some(placement) => self.placement_actor(placement),
+            None => self.provenance(x, y),
+        }
+    }
+
+    /// Returns the topmost placement on a visible layer at the coordinates.
+    fn covering_placement(&self, x: i32, y: i32) -> Option<Placement> {
+        if !self.contains(x, y) {
+            return None;
+        }
+        let (key, index) = locate(x, y);
+        self.layers
+            .iter()
+            .rev()
+            .filter(|layer| !layer.hidden)
+            .filter_map(|layer| layer.tiles.get(&key))
+            .map(|tile| tile.placements.get(index))
+            .find(|placement| placement.actor != 0)
+    }
+
+    /// Returns true if a pixel is covered by a visible layer, so painting it on the user layer
+    /// would make no difference.
+    pub fn is_covered(&self, x: i32, y: i32) -> bool {
+        self.covering_placement(x, y).is_some()
+    }
+
+    /// Returns the actor and time of a placement, if it was placed.
+    fn placement_actor(&self, placement: Placement) -> Option<(&Actor, u64)> {
+        if placement.actor == 0 {
+            return None;
+        }
+        let actor = self.actors.get(placement.actor as usize - 1)?;
+        Some((actor, placement.time))
+    }
+
+    /// Returns the index of a layer other than the user layer in `layers`.
+    fn layer_index(&self, name: &str) -> Option<usize> {
+        self.layers.iter().position(|layer| layer.name == name)
+    }
+
+    /// Returns true if there is a layer with the name other than the user layer.
+    pub fn has_layer(&self, name: &str) -> bool {
+        self.layer_index(name).is_some()
+    }
+
+    /// Returns the layers drawn over the user layer, bottom to top, with whether they’re hidden.
+    pub fn layers(&self) -> Vec<(String, bool)> {
+        self.layers
+            .iter()
+            .map(|layer| (layer.name.clone(), layer.hidden))
+            .collect()
+    }
+
+    /// Returns the areas of all allocated tiles of a layer.
+    pub fn layer_tile_rects(&self, layer: &str) -> Vec<Rect> {
+        if layer == USER_LAYER {
+            return self.tile_rects();
+        }
+        match self.layer_index(layer) {
+            Some(index) => sorted_keys(&self.layers[index].tiles)
+                .into_iter()
+                .map(tile_rect)
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Places a pixel on a layer on behalf of an actor, or erases it if there is no color (which
+    /// makes it transparent). Placing on the user layer is the same as `place`.
+    pub fn place_on(
+        &mut self,
+        layer: &str,
+        x: i32,
+        y: i32,
+        color: Option<(u8, u8, u8)>,
+        actor: &Actor,
+        time: u64,
+    ) {
+        if layer == USER_LAYER {
+            if let Some(color) = color {
+                self.place(x, y, color, actor, time);
+            }
+            return;
+        }
+        let index = match self.layer_index(layer) {
+            Some(index) => index,
+            None => return,
+        };
+        if !self.contains(x, y) {
+            return;
+        }
+
+        let (key, i) = locate(x, y);
+        let (stored, placement) = match color {
+            Some((r, g, b)) => {
+                self.meta.pixels_placed += 1;
+                let actor = self.intern_actor(actor);
+                (self.encode_color(r, g, b), Placement { actor, time })
+            }
+            // nothing to erase
+            None if !self.layers[index].tiles.contains_key(&key) => return,
+            None => (self.background(), Placement::default()),
+        };
+        let background = self.background();
+        let bpp = stored.len();
+        let layer = &mut self.layers[index];
+        let tile = layer
+            .tiles
+            .entry(key)
+            .or_insert_with(|| Tile::new(&background));
+        tile.pixels[i * bpp..(i + 1) * bpp].copy_from_slice(&stored);
+        tile.placements.set(i, placement);
+        layer.dirty_tiles.insert(key);
+        if !layer.hidden {
+            self.changed_pixels.insert((x, y));
+        }
+        self.revision += 1;
+    }
+
+    /// Adds, shows, hides, removes or clears a layer other than the user layer. The visible
+    /// canvas may change as a whole, so this doesn’t produce deltas.
+    pub fn change_layer(&mut self, name: &str, change: LayerChange) -> Result<(), &'static str> {
+        if name == USER_LAYER {
+            return Err("The user layer can only be painted on");
+        }
+        match (change, self.layer_index(name)) {
+            (LayerChange::Add { .. }, Some(_)) => return Err("That layer already exists"),
+            (LayerChange::Add { hidden }, None) => {
+                if !is_valid_layer_name(name) {
+                    return Err("Layer names may only contain a-z, 0-9, - and _");
+                }
+                if self.layers.len() >= MAX_LAYERS {
+                    return Err("There are too many layers");
+                }
+                self.layers.push(Layer::new(name, hidden));
+            }
+            (_, None) => return Err("No such layer"),
+            (LayerChange::SetHidden(hidden), Some(index)) => self.layers[index].hidden = hidden,
+            (LayerChange::Remove, Some(_)) if name == ADMIN_LAYER => {
+                return Err("The admin layer can’t be removed")
+            }
+            (LayerChange::Remove, Some(index)) => {
+                self.layers.remove(index);
+            }
+            (LayerChange::Clear(None), Some(index)) => {
+                // tile files of removed tiles are dropped on the next save
+                self.layers[index].tiles.clear();
+            }
+            (LayerChange::Clear(Some(area)), Some(index)) => {
+                let background = self.background();
+                let bpp = background.len();
+                let layer = &mut self.layers[index];
+                for (key, tile) in layer.tiles.iter_mut() {
+                    let tile_area = tile_rect(*key);
+                    if !tile_area.intersects(&area) {
+                        continue;
+                    }
+                    for (x, y) in tile_area.intersection(&area).pixels() {
+                        let (_, i) = locate(x, y);
+                        tile.pixels[i * bpp..(i + 1) * bpp].copy_from_slice(&background);
+                        tile.placements.set(i, Placement::default());
+                    }
+                    layer.dirty_tiles.insert(*key);
+                }
+            }
+        }
+        self.changed_pixels.clear();
+        self.revision += 1;
+        Ok(())
+    }
+
+    /// Returns the pixels a drawing operation would set on a layer, clipped to the canvas, along
+    /// with their colors.
+    pub fn draw_pixels(&self, layer: &str, draw: &Draw) -> Vec<(i32, i32, (u8, u8, u8))> {
+        let area = match self.bounds() {
+            Some(bounds) => bounds.intersection(&draw.area()),
+            None => draw.area(),
+        };
+        let mut pixels = Vec::new();
+        match *draw {
+            Draw::Fill { color, .. } => {
+                pixels.extend(area.pixels().map(|(x, y)| (x, y, color)));
+            }
+            Draw::Line { from, to, color } => {
+                // Bresenham’s algorithm
+                let (mut x, mut y) = (from.0 as i64, from.1 as i64);
+                let (x2, y2) = (to.0 as i64, to.1 as i64);
+                let (dx, dy) = ((x2 - x).abs(), -(y2 - y).abs());
+                let (sx, sy) = ((x2 - x).signum(), (y2 - y).signum());
+                let mut err = dx + dy;
+                loop {
+                    if area.contains(x as i32, y as i32) {
+                        pixels.push((x as i32, y as i32, color));
+                    }
+                    if x == x2 && y == y2 {
+                        break;
+                    }
+                    let e2 = 2 * err;
+                    if e2 >= dy {
+                        err += dy;
+                        x += sx;
+                    }
+                    if e2 <= dx {
+                        err += dx;
+                        y += sy;
+                    }
+                }
+            }
+            Draw::Flood { x, y, color, .. } => {
+                if !area.contains(x, y) {
+                    return pixels;
+                }
+                let target = self.layer_pixel(layer, x, y);
+                if target == Some(color) {
+                    return pixels;
+                }
+                let mut seen = HashSet::new();
+                let mut stack = vec![(x, y)];
+                seen.insert((x, y));
+                while let Some((x, y)) = stack.pop() {
+                    pixels.push((x, y, color));
+                    let neighbors = [
+                        (x.checked_sub(1), Some(y)),
+                        (x.checked_add(1), Some(y)),
+                        (Some(x), y.checked_sub(1)),
+                        (Some(x), y.checked_add(1)),
+                    ];
+                    for &neighbor in &neighbors {
+                        if let (Some(nx), Some(ny)) = neighbor {
+                            if area.contains(nx, ny)
+                                && !seen.contains(&(nx, ny))
+                                && self.layer_pixel(layer, nx, ny) == target
+                            {
+                                seen.insert((nx, ny));
+                                stack.push((nx, ny));
+                            }
+                        }
+                    }
+                }
+            }
+            Draw::Paste {
+                x, y, w, ref data, ..
+            } => {
+                for (px, py) in area.pixels() {
+                    let i = ((py - y) as usize * w as usize + (px - x) as usize) * 3;
+                    if let Some(rgb) = data.get(i..i + 3) {
+                        pixels.push((px, py, (rgb[0], rgb[1], rgb[2])));
+                    }
+                }
+            }
+        }
+        pixels
+    }
+
+    /// Performs a drawing operation on a layer on behalf of an actor. With a palette, colors are
+    /// snapped to the nearest entry.
+    pub fn draw(&mut self, layer: &str, draw: &Draw, actor: &Actor, time: u64) {
+        for (x, y, color) in self.draw_pixels(layer, draw) {
+            self.place_on(layer, x, y, Some(color), actor, time);
+        }
+    }
+
+    /// Returns the id of an actor in `actors` (plus one), adding it if necessary.
+    fn intern_actor(&mut self, actor: &Actor) -> u32 {
+        if let Some(id) = self.actor_ids.get(actor) {
+            return *id;
+        }
+        self.actors.push(actor.clone());
+        let id = self.actors.len() as u32;
+        self.actor_ids.insert(actor.clone(), id);
+        id
     }
 
     /// Compiles delta regions, resetting changed_pixels.
@@ -108,68 +1905,268 @@ impl Canvas {
         // instead of sending each changed pixel to the client, this will compile
         // a list of regions that have changed pixels using a simple quad tree.
 
-        let mut quad_tree = QuadNode::new(0, 0, self.width, self.height);
+        let pixels: Vec<_> = self
+            .changed_pixels
+            .iter()
+            .take(max_pixels.unwrap_or(usize::MAX))
+            .cloned()
+            .collect();
+        for pixel in &pixels {
+            self.changed_pixels.remove(pixel);
+        }
+
+        changed_areas(&pixels)
+            .iter()
+            .filter_map(|area| self.region(area.x, area.y, area.w, area.h))
+            .collect()
+    }
+
+    /// Returns the pixels that differ between two canvases on any layer. Pixels outside one of
+    /// them count as unpainted there.
+    pub fn diff(&self, other: &Canvas) -> Vec<(i32, i32)> {
+        let mut layers = vec![USER_LAYER.to_string()];
+        for (name, _) in self.layers().into_iter().chain(other.layers()) {
+            if !layers.contains(&name) {
+                layers.push(name);
+            }
+        }
+
+        // anything outside allocated tiles is unpainted (or transparent) in both
+        let mut areas = HashSet::new();
+        for layer in &layers {
+            areas.extend(self.layer_tile_rects(layer));
+            areas.extend(other.layer_tile_rects(layer));
+        }
 
-        let mut i = 0;
-        for pixel in self.changed_pixels.clone() {
-            if let Some(max_pixels) = max_pixels {
-                if i == max_pixels {
-                    break;
+        let color = |canvas: &Canvas, layer: &str, x: i32, y: i32| {
+            if canvas.contains(x, y) {
+                canvas.layer_pixel(layer, x, y)
+            } else if layer == USER_LAYER {
+                Some(canvas.decode_color(&canvas.background()))
+            } else {
+                None
+            }
+        };
+        let mut pixels = Vec::new();
+        for area in areas {
+            for (x, y) in area.pixels() {
+                if !self.contains(x, y) && !other.contains(x, y) {
+                    continue;
+                }
+                let differs = layers
+                    .iter()
+                    .any(|layer| color(self, layer, x, y) != color(other, layer, x, y));
+                if differs {
+                    pixels.push((x, y));
                 }
             }
+        }
+        pixels
+    }
 
-            quad_tree.insert_data(pixel.0, pixel.1);
-            self.changed_pixels.remove(&pixel);
+    /// Sets a new size, keeping the pixels that are still inside. New regions will be white.
+    pub fn set_size(&mut self, new_width: u32, new_height: u32) {
+        let last_growth = self.last_growth;
+        self.set_extent(
+            Extent::Finite {
+                width: new_width,
+                height: new_height,
+            },
+            last_growth,
+        );
+    }
 
-            i += 1;
+    /// Sets a new extent, keeping the pixels that are still inside. `time` (UNIX milliseconds) is
+    /// when the boundary will have last grown.
+    pub fn set_extent(&mut self, extent: Extent, time: u64) {
+        if let Some(bounds) = extent.bounds() {
+            self.crop(bounds);
         }
+        self.extent = extent;
+        self.last_growth = time;
+        self.changed_pixels.clear();
+        self.revision += 1;
+    }
 
-        quad_tree.reduce();
-        quad_tree
-            .regions()
-            .iter()
-            .map(|(x, y, width, height)| self.region(*x, *y, *width, *height).unwrap())
-            .collect()
+    /// Returns the extent after the world boundary is due to grow, if it is.
+    pub fn grown_extent(&self, now: u64) -> Option<Extent> {
+        match self.extent {
+            Extent::Infinite {
+                boundary: Some(boundary),
+                growth: Some(growth),
+            } if now >= self.last_growth.saturating_add(growth.interval) => {
+                Some(Extent::Infinite {
+                    boundary: Some(boundary.expanded(growth.step)),
+                    growth: Some(growth),
+                })
+            }
+            _ => None,
+        }
     }
 
-    /// Sets a new size, copying as much data as possible. New regions will be white.
-    pub fn set_size(&mut self, new_width: u32, new_height: u32) {
-        let mut new_pixels = Vec::with_capacity((new_width * new_height * 3) as usize);
+    /// Works out the new extent and how far the contents move for a resize. Returns None if the
+    /// new size can’t be determined (an empty infinite canvas without a given size).
+    pub fn plan_resize(&self, resize: Resize) -> Option<(Extent, i32, i32)> {
+        let clamp = |v: i64| v.clamp(i32::MIN as i64, i32::MAX as i64) as i32;
+        match resize {
+            Resize::Size {
+                width,
+                height,
+                anchor,
+            } => {
+                // the new canvas starts at (0, 0), so the current contents move there first
+                let current = self.bounds().or_else(|| self.populated());
+                let current = match (current, width, height) {
+                    (Some(current), _, _) => current,
+                    (None, Some(_), Some(_)) => Rect {
+                        x: 0,
+                        y: 0,
+                        w: 0,
+                        h: 0,
+                    },
+                    _ => return None,
+                };
+                let width = width.unwrap_or(current.w);
+                let height = height.unwrap_or(current.h);
+                let dx = Anchor::offset(anchor.x, current.w, width) - current.x as i64;
+                let dy = Anchor::offset(anchor.y, current.h, height) - current.y as i64;
+                Some((Extent::Finite { width, height }, clamp(dx), clamp(dy)))
+            }
+            Resize::Crop(rect) => Some((
+                Extent::Finite {
+                    width: rect.w,
+                    height: rect.h,
+                },
+                clamp(-(rect.x as i64)),
+                clamp(-(rect.y as i64)),
+            )),
+            Resize::Shift { dx, dy } => Some((self.extent, dx, dy)),
+        }
+    }
 
-        let max_x = if self.width > new_width {
-            new_width
-        } else {
-            self.width
-        };
-        let add_x = new_width.saturating_sub(self.width);
-        let mut add_x_pixels = Vec::new();
-        add_x_pixels.resize((add_x * 3) as usize, 255);
-        let mut full_x_pixels = Vec::new();
-        full_x_pixels.resize((new_width * 3) as usize, 255);
+    /// Moves all pixels (with their provenance) by `(dx, dy)` and sets a new extent. Anything that
+    /// ends up outside is dropped, and parts of the new bounds that weren’t in the old ones are
+    /// filled with a color.
+    pub fn transform(&mut self, extent: Extent, dx: i32, dy: i32, fill: (u8, u8, u8)) {
+        let old_bounds = self.bounds();
+        if (dx, dy) != (0, 0) {
+            let background = self.background();
+            let tiles = mem::take(&mut self.tiles);
+            self.tiles = shift_tiles(tiles, dx, dy, &background);
+            for layer in &mut self.layers {
+                let tiles = mem::take(&mut layer.tiles);
+                layer.tiles = shift_tiles(tiles, dx, dy, &background);
+            }
+            self.mark_all_dirty();
+        }
 
-        for y in 0..self.height {
-            if y >= new_height {
-                break;
+        let last_growth = self.last_growth;
+        self.set_extent(extent, last_growth);
+
+        if let (Some(new_bounds), Some(old)) = (extent.bounds(), old_bounds) {
+            let moved = Rect::from_edges(
+                old.x as i64 + dx as i64,
+                old.y as i64 + dy as i64,
+                old.right() + dx as i64,
+                old.bottom() + dy as i64,
+            );
+            for area in new_bounds.subtract(&moved) {
+                self.fill_rect(area, fill);
             }
+        }
+    }
 
-            new_pixels.extend_from_slice(&self.pixels[self.index(0, y)..self.index(max_x, y)]);
-            new_pixels.extend_from_slice(&add_x_pixels);
+    /// Fills an area with a color, as if it had never been painted.
+    fn fill_rect(&mut self, rect: Rect, (r, g, b): (u8, u8, u8)) {
+        let color = self.encode_color(r, g, b);
+        let background = self.background();
+        let bpp = color.len();
+        let size = TILE_SIZE as i64;
+        let (tx1, ty1) = locate(rect.x, rect.y).0;
+        let tx2 = (rect.right() - 1).div_euclid(size) as i32;
+        let ty2 = (rect.bottom() - 1).div_euclid(size) as i32;
+        for ty in ty1..ty2 + 1 {
+            for tx in tx1..tx2 + 1 {
+                let key = (tx, ty);
+                let tile_area = tile_rect(key);
+                let area = tile_area.intersection(&rect);
+                // unallocated tiles are already blank
+                if color == background && !self.tiles.contains_key(&key) {
+                    continue;
+                }
+                let tile = self.tile_mut(key);
+                for y in area.y..area.bottom() as i32 {
+                    let row = (y - tile_area.y) as usize * TILE_SIZE as usize;
+                    let start = row + (area.x - tile_area.x) as usize;
+                    for i in start..start + area.w as usize {
+                        tile.pixels[i * bpp..(i + 1) * bpp].copy_from_slice(&color);
+                        tile.placements.set(i, Placement::default());
+                    }
+                }
+                self.dirty_tiles.insert(key);
+            }
         }
+        self.revision += 1;
+    }
 
-        for _ in self.height..new_height {
-            new_pixels.extend_from_slice(&full_x_pixels);
+    /// Drops everything outside the bounds, on every layer.
+    fn crop(&mut self, bounds: Rect) {
+        let background = self.background();
+        crop_tiles(&mut self.tiles, &mut self.dirty_tiles, bounds, &background);
+        for layer in &mut self.layers {
+            crop_tiles(&mut layer.tiles, &mut layer.dirty_tiles, bounds, &background);
         }
+    }
+}
 
-        self.width = new_width;
-        self.height = new_height;
-        self.pixels = new_pixels;
-        self.changed_pixels.clear();
+/// Groups pixels into a few rectangular areas that cover all of them, using a quad tree. Areas
+/// don’t span tiles, so changes far apart on an unbounded canvas don’t end up in one huge area.
+pub fn changed_areas(pixels: &[(i32, i32)]) -> Vec<Rect> {
+    let mut tiles: BTreeMap<(i32, i32), Vec<(i32, i32)>> = BTreeMap::new();
+    for &(x, y) in pixels {
+        tiles.entry(locate(x, y).0).or_default().push((x, y));
+    }
+    tiles
+        .values()
+        .flat_map(|pixels| tile_changed_areas(pixels))
+        .collect()
+}
+
+/// Groups pixels within one tile.
+fn tile_changed_areas(pixels: &[(i32, i32)]) -> Vec<Rect> {
+    // the tree covers the bounding box of the changes, which is at most a tile
+    let min_x = pixels.iter().map(|p| p.0).min().unwrap();
+    let min_y = pixels.iter().map(|p| p.1).min().unwrap();
+    let max_x = pixels.iter().map(|p| p.0).max().unwrap();
+    let max_y = pixels.iter().map(|p| p.1).max().unwrap();
+    let offset = |v: i32, min: i32| (v - min) as u32;
+    let mut quad_tree = QuadNode::new(
+        0,
+        0,
+        offset(max_x, min_x) + 1,
+        offset(max_y, min_y) + 1,
+    );
+
+    for pixel in pixels {
+        quad_tree.insert_data(offset(pixel.0, min_x), offset(pixel.1, min_y));
     }
+
+    quad_tree.reduce();
+    quad_tree
+        .regions()
+        .iter()
+        .map(|&(x, y, w, h)| Rect {
+            x: min_x + x as i32,
+            y: min_y + y as i32,
+            w,
+            h,
+        })
+        .collect()
 }
 
 /// QuadTree node.
 ///
-/// ```
+/// ```text
 ///   0 1
 /// 0 a b
 /// 1 c d
@@ -279,7 +2276,7 @@ impl QuadNode {
             data_count += d.reduce();
         }
 
-        let merge = data_count > (self.width * self.height / 8) as usize
+        let merge = data_count as u64 > self.width as u64 * self.height as u64 / 8
             || (self.a.is_some() && self.b.is_some() && self.c.is_some() && self.d.is_some());
 
         if merge {
@@ -324,3 +2321,200 @@ impl QuadNode {
         regions
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn palette(snap: bool) -> Palette {
+        Palette {
+            colors: vec![(0, 0, 0), (250, 250, 250), (200, 0, 0)],
+            snap,
+        }
+    }
+
+    #[test]
+    fn nearest_palette_colors() {
+        let palette = palette(false);
+        assert_eq!(palette.nearest(10, 10, 10), 0);
+        assert_eq!(palette.nearest(255, 255, 255), 1);
+        assert_eq!(palette.nearest(180, 40, 30), 2);
+        assert_eq!(palette.nearest(200, 0, 0), 2);
+    }
+
+    #[test]
+    fn palettes_snap_or_reject_other_colors() {
+        assert_eq!(palette(false).resolve(200, 0, 0), Some((200, 0, 0)));
+        assert_eq!(palette(false).resolve(201, 0, 0), None);
+        assert_eq!(palette(true).resolve(201, 0, 0), Some((200, 0, 0)));
+    }
+
+    #[test]
+    fn setting_a_palette_remaps_pixels() {
+        let mut canvas = Canvas::blank(4, 4);
+        canvas.set_pixel(1, 1, 190, 10, 10);
+        canvas.set_palette(Some(palette(true)));
+        assert_eq!(canvas.pixel(1, 1), (200, 0, 0));
+        assert_eq!(canvas.pixel(0, 0), (250, 250, 250));
+
+        canvas.set_pixel(2, 2, 20, 20, 20);
+        assert_eq!(canvas.pixel(2, 2), (0, 0, 0));
+
+        canvas.set_palette(None);
+        assert_eq!(canvas.pixel(1, 1), (200, 0, 0));
+        assert_eq!(canvas.pixel(0, 0), (250, 250, 250));
+    }
+
+    #[test]
+    fn unpainted_pixels_read_like_regions() {
+        let mut canvas = Canvas::blank(300, 4);
+        canvas.set_palette(Some(palette(true)));
+        // only the first tile is allocated
+        canvas.set_pixel(1, 1, 0, 0, 0);
+        let region = canvas.region(299, 3, 1, 1).unwrap();
+        assert_eq!(region.data, vec![250, 250, 250]);
+        assert_eq!(canvas.pixel(299, 3), (250, 250, 250));
+    }
+
+    fn rect(x: i32, y: i32, w: u32, h: u32) -> Rect {
+        Rect { x, y, w, h }
+    }
+
+    #[test]
+    fn subtracting_rects_leaves_the_rest() {
+        let outer = rect(-2, -2, 10, 10);
+        assert_eq!(outer.subtract(&rect(20, 20, 5, 5)), vec![outer]);
+        assert!(outer.subtract(&rect(-5, -5, 20, 20)).is_empty());
+        assert!(rect(0, 0, 0, 5).subtract(&outer).is_empty());
+
+        let hole = rect(1, 1, 3, 2);
+        let parts = outer.subtract(&hole);
+        assert_eq!(parts.len(), 4);
+        for (i, part) in parts.iter().enumerate() {
+            assert!(outer.contains_rect(part));
+            assert!(!part.intersects(&hole));
+            for other in &parts[i + 1..] {
+                assert!(!part.intersects(other));
+            }
+        }
+        let area: u32 = parts.iter().map(|r| r.w * r.h).sum();
+        assert_eq!(area, 100 - 6);
+
+        // overlapping an edge only leaves the rest of that side
+        assert_eq!(outer.subtract(&rect(-4, -4, 20, 5)), vec![rect(-2, 1, 10, 7)]);
+        assert_eq!(outer.subtract(&rect(6, -4, 20, 20)), vec![rect(-2, -2, 8, 10)]);
+    }
+
+    #[test]
+    fn anchors_parse_compass_directions() {
+        assert_eq!(Anchor::parse("nw"), Some(Anchor { x: 0, y: 0 }));
+        assert_eq!(Anchor::parse("e"), Some(Anchor { x: 2, y: 1 }));
+        assert_eq!(Anchor::parse("center"), Some(Anchor { x: 1, y: 1 }));
+        assert_eq!(Anchor::parse(""), None);
+        assert_eq!(Anchor::parse("nn"), None);
+        assert_eq!(Anchor::parse("x"), None);
+    }
+
+    #[test]
+    fn resizes_move_contents_by_the_anchor() {
+        let canvas = Canvas::blank(10, 10);
+        let size = |anchor| Resize::Size {
+            width: Some(20),
+            height: None,
+            anchor: Anchor::parse(anchor).unwrap(),
+        };
+        let twenty_by_ten = Extent::Finite {
+            width: 20,
+            height: 10,
+        };
+        assert_eq!(canvas.plan_resize(size("nw")), Some((twenty_by_ten, 0, 0)));
+        assert_eq!(canvas.plan_resize(size("c")), Some((twenty_by_ten, 5, 0)));
+        assert_eq!(canvas.plan_resize(size("se")), Some((twenty_by_ten, 10, 0)));
+
+        let crop = Resize::Crop(Rect {
+            x: 2,
+            y: 3,
+            w: 4,
+            h: 5,
+        });
+        let cropped = Extent::Finite {
+            width: 4,
+            height: 5,
+        };
+        assert_eq!(canvas.plan_resize(crop), Some((cropped, -2, -3)));
+
+        let shift = Resize::Shift { dx: 1, dy: -1 };
+        assert_eq!(canvas.plan_resize(shift), Some((canvas.extent, 1, -1)));
+    }
+
+    fn positions(pixels: Vec<(i32, i32, (u8, u8, u8))>) -> Vec<(i32, i32)> {
+        let mut positions: Vec<_> = pixels.into_iter().map(|(x, y, _)| (x, y)).collect();
+        positions.sort_by_key(|&(x, y)| (y, x));
+        positions
+    }
+
+    #[test]
+    fn lines_include_both_ends_and_are_clipped() {
+        let canvas = Canvas::blank(8, 8);
+        let red = (255, 0, 0);
+        let line = |from, to| {
+            let pixels = canvas.draw_pixels(USER_LAYER, &Draw::Line { from, to, color: red });
+            positions(pixels)
+        };
+        assert_eq!(line((1, 1), (1, 1)), vec![(1, 1)]);
+        assert_eq!(line((3, 2), (0, 2)), vec![(0, 2), (1, 2), (2, 2), (3, 2)]);
+        assert_eq!(line((0, 0), (2, 2)), vec![(0, 0), (1, 1), (2, 2)]);
+        assert_eq!(line((0, 0), (1, 3)), vec![(0, 0), (0, 1), (1, 2), (1, 3)]);
+        assert_eq!(line((-2, 1), (1, 1)), vec![(0, 1), (1, 1)]);
+    }
+
+    #[test]
+    fn floods_stop_at_other_colors_and_their_area() {
+        let mut canvas = Canvas::blank(6, 6);
+        let wall = (0, 0, 0);
+        let red = (255, 0, 0);
+        // a wall down the third column, with a gap in the last row
+        canvas.draw(
+            USER_LAYER,
+            &Draw::Fill {
+                rect: rect(2, 0, 1, 5),
+                color: wall,
+            },
+            &Actor::Server("test".into()),
+            0,
+        );
+        let flood = |canvas: &Canvas, x, y, within| {
+            let draw = Draw::Flood {
+                x,
+                y,
+                within,
+                color: red,
+            };
+            positions(canvas.draw_pixels(USER_LAYER, &draw))
+        };
+
+        let everywhere = rect(0, 0, 6, 6);
+        assert_eq!(flood(&canvas, 0, 0, everywhere).len(), 36 - 5);
+        assert_eq!(flood(&canvas, 2, 1, everywhere).len(), 5);
+        let left = flood(&canvas, 0, 0, rect(0, 0, 6, 5));
+        assert_eq!(left.len(), 10);
+        assert!(left.iter().all(|&(x, _)| x < 2));
+        assert_eq!(flood(&canvas, 1, 1, rect(1, 1, 2, 2)), vec![(1, 1), (1, 2)]);
+        assert!(flood(&canvas, 9, 9, everywhere).is_empty());
+
+        canvas.draw(
+            USER_LAYER,
+            &Draw::Flood {
+                x: 0,
+                y: 0,
+                within: everywhere,
+                color: red,
+            },
+            &Actor::Server("test".into()),
+            0,
+        );
+        assert_eq!(canvas.pixel(5, 0), red);
+        // flooding with the color that’s already there does nothing
+        assert!(flood(&canvas, 0, 0, everywhere).is_empty());
+    }
+}
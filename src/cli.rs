@@ -0,0 +1,272 @@
+use canvas::{self, Extent};
+use config::Config;
+use console;
+use history::{self, Actor, LogContents};
+use image;
+use rooms;
+use std::path::PathBuf;
+use std::{fs, io, process};
+use store::{CanvasStore, Storage};
+use timelapse::{self, Plan};
+use {run_save, MAX_EXPORT_SCALE};
+
+/// Prints usage and exits.
+fn usage() -> ! {
+    eprintln!("Usage: place [<options>] [<subcommand>]");
+    eprintln!("  place                           run the server");
+    eprintln!("  place restore <time> [<canvas>] restore canvas.place to a point in time");
+    eprintln!("                                  (UNIX seconds or -30s/-15m/-2h/-1d)");
+    eprintln!("  place import <file.png> [--at x,y] [<canvas>]");
+    eprintln!("                                  replace the canvas with an image, or paste it");
+    eprintln!("  place timelapse <out> [--interval 1h] [--from <time>] [--to <time>]");
+    eprintln!("                  [--region x,y,w,h] [--scale n] [--delay ms] [<canvas>]");
+    eprintln!("                                  render the history as an animated PNG (if out");
+    eprintln!("                                  ends in .png) or a directory of frames");
+    eprintln!("Options (all of which can also be set in place.toml, with _ instead of -):");
+    eprintln!("  --config <file>                 read settings from a TOML file");
+    eprintln!("                                  (default: place.toml, if there is one)");
+    eprintln!("  --bind <addr>                   address to listen on (127.0.0.1:8000)");
+    eprintln!("  --data-dir <dir>                canvas files (the working directory)");
+    eprintln!("  --static-dir <dir>              web page (static/ in the data directory)");
+    eprintln!("  --logins <file>                 logins (logins.json in the data directory)");
+    eprintln!("  --storage <tiles|file|memory>   how canvases are stored (tiles)");
+    eprintln!("  --default-width <w>             size of new canvases (500)");
+    eprintln!("  --default-height <h>            (500)");
+    eprintln!("  --max-width <w>                 largest size of finite canvases (10000)");
+    eprintln!("  --max-height <h>                (10000)");
+    eprintln!("  --max-pixels-per-frame <n>      most changed pixels sent per tick (3000)");
+    eprintln!("  --save-interval-secs <s>        time between saves (5)");
+    eprintln!("  --tick-ms <ms>                  time between updates to clients (33)");
+    eprintln!("  --trusted-proxy <ip>            reverse proxy whose X-Forwarded-For is believed");
+    eprintln!("  --cooldown-ms <ms>              rate limits of canvases that weren’t given");
+    eprintln!("                                  their own with rate-limit: time between");
+    eprintln!("                                  pixels (100)");
+    eprintln!("  --burst <n>, --rate <n/s>       pixels per connection (20 at 2/s)");
+    eprintln!("  --ip-burst <n>, --ip-rate <n/s> pixels per IP address (60 at 6/s)");
+    process::exit(2);
+}
+
+/// Runs a subcommand. The server must not be running at the same time.
+pub fn run(config: &Config, args: &[String]) {
+    match &*args[0] {
+        "restore" => restore(config, &args[1..]),
+        "import" => import(config, &args[1..]),
+        "timelapse" => timelapse(config, &args[1..]),
+        _ => usage(),
+    }
+}
+
+/// Opens the store of a named canvas, or the default one.
+fn open_store(config: &Config, name: Option<&String>) -> Box<dyn CanvasStore> {
+    let name = match name {
+        Some(name) if rooms::is_valid_name(name) => name,
+        Some(_) => usage(),
+        None => rooms::DEFAULT,
+    };
+    if config.storage == Storage::Memory {
+        eprintln!("There are no stored canvases with storage = \"memory\"");
+        process::exit(2);
+    }
+    config.storage.open(&rooms::room_dir(&config.data_dir, name))
+}
+
+/// Loads the canvas from a store, exiting if that fails.
+fn load(store: &mut dyn CanvasStore, config: &Config) -> canvas::Canvas {
+    match store.load(config.default_size) {
+        Ok(canvas) => canvas,
+        Err(err) => {
+            eprintln!("Failed to load the canvas: {}", err);
+            process::exit(1);
+        }
+    }
+}
+
+/// Saves the entire canvas, exiting if that fails.
+fn save(store: &mut dyn CanvasStore, canvas: &mut canvas::Canvas) {
+    let saved = match store.save(canvas) {
+        Ok(job) => run_save(job),
+        Err(err) => {
+            eprintln!("Failed to save! {:?}", err);
+            false
+        }
+    };
+    if !saved {
+        process::exit(1);
+    }
+}
+
+fn restore(config: &Config, args: &[String]) {
+    let time = match args.first().and_then(|t| history::parse_time(t, history::now_millis())) {
+        Some(time) => time,
+        None => usage(),
+    };
+
+    let mut store = open_store(config, args.get(1));
+    let mut canvas = load(&mut *store, config);
+
+    match history::restore(&mut canvas, &mut *store, time) {
+        Ok(changed) => eprintln!("Restored {} pixels", changed),
+        Err(err) => {
+            eprintln!("Failed to restore: {}", err);
+            process::exit(1);
+        }
+    }
+
+    save(&mut *store, &mut canvas);
+}
+
+fn import(config: &Config, args: &[String]) {
+    let mut args = args.iter();
+    let mut file_path = None;
+    let mut at = None;
+    let mut name = None;
+    while let Some(arg) = args.next() {
+        if arg == "--at" {
+            let offset = args.next().and_then(|at| {
+                let mut parts = at.splitn(2, ',');
+                let x = parts.next()?.trim().parse().ok()?;
+                let y = parts.next()?.trim().parse().ok()?;
+                Some((x, y))
+            });
+            at = Some(offset.unwrap_or_else(|| usage()));
+        } else if file_path.is_none() {
+            file_path = Some(arg);
+        } else if name.is_none() {
+            name = Some(arg);
+        } else {
+            usage();
+        }
+    }
+    let file_path = file_path.unwrap_or_else(|| usage());
+
+    let image = match fs::read(file_path) {
+        Ok(file) => image::decode_png(&file, canvas::MAX_DRAW_PIXELS),
+        Err(err) => {
+            eprintln!("Failed to read {}: {}", file_path, err);
+            process::exit(1);
+        }
+    };
+    let image = match image {
+        Ok(image) => image,
+        Err(err) => {
+            eprintln!("Failed to read {}: {}", file_path, err);
+            process::exit(1);
+        }
+    };
+
+    let (width, height) = (image.width, image.height);
+    if at.is_none() {
+        if let Err(err) = config.check_extent(Extent::Finite { width, height }) {
+            eprintln!("{}", err);
+            process::exit(1);
+        }
+    }
+
+    let mut store = open_store(config, name);
+    let mut canvas = load(&mut *store, config);
+
+    let actor = Actor::Server("import".into());
+    for event in image::import_events(&canvas, image, at, actor, history::now_millis()) {
+        event.apply(&mut canvas);
+        if let Err(err) = store.append(&event) {
+            eprintln!("Failed to write to canvas.log: {}", err);
+            process::exit(1);
+        }
+    }
+    eprintln!("Imported a {}×{} image", width, height);
+
+    save(&mut *store, &mut canvas);
+}
+
+fn timelapse(config: &Config, args: &[String]) {
+    let now = history::now_millis();
+    let mut args = args.iter();
+    let mut out_path = None;
+    let mut name = None;
+    let mut options = timelapse::Options::default();
+    let mut scale = 1;
+    let mut delay = 100;
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().unwrap_or_else(|| usage());
+        match &**arg {
+            "--interval" => options.interval = history::parse_duration(value()).filter(|&i| i > 0),
+            "--from" => options.from = history::parse_time(value(), now),
+            "--to" => options.to = history::parse_time(value(), now),
+            "--region" => options.region = console::parse_region(value()),
+            "--scale" => {
+                scale = value()
+                    .parse()
+                    .ok()
+                    .filter(|s| (1..=MAX_EXPORT_SCALE).contains(s))
+                    .unwrap_or(0)
+            }
+            "--delay" => delay = value().parse().ok().filter(|&d| d >= 1).unwrap_or(0),
+            _ if out_path.is_none() => out_path = Some(PathBuf::from(arg)),
+            _ if name.is_none() => name = Some(arg),
+            _ => usage(),
+        }
+        // invalid values are left unset
+        let invalid = match &**arg {
+            "--interval" => options.interval.is_none(),
+            "--from" => options.from.is_none(),
+            "--to" => options.to.is_none(),
+            "--region" => options.region.is_none(),
+            "--scale" => scale == 0,
+            "--delay" => delay == 0,
+            _ => false,
+        };
+        if invalid {
+            usage();
+        }
+    }
+    let out_path = out_path.unwrap_or_else(|| usage());
+
+    let contents = match open_store(config, name).history() {
+        Ok(contents) => contents,
+        Err(err) => {
+            eprintln!("Failed to read the history: {}", err);
+            process::exit(1);
+        }
+    };
+    let max_pixels = image::MAX_EXPORT_PIXELS / (scale as u64 * scale as u64);
+    let plan = match Plan::new(&contents, &options, max_pixels) {
+        Ok(plan) => plan,
+        Err(err) => {
+            eprintln!("{}", err);
+            process::exit(1);
+        }
+    };
+
+    let is_png = out_path.extension().is_some_and(|ext| ext == "png");
+    let result = if is_png {
+        fs::File::create(&out_path).and_then(|file| {
+            let file = io::BufWriter::new(file);
+            timelapse::write_apng(file, &contents, &plan, scale, delay)
+                .map_err(io::Error::other)
+        })
+    } else {
+        write_frames(&out_path, &contents, &plan, scale)
+    };
+    if let Err(err) = result {
+        eprintln!("Failed to write {}: {}", out_path.display(), err);
+        process::exit(1);
+    }
+    eprintln!(
+        "Rendered {} frames of {}×{} pixels",
+        plan.frames, plan.region.w, plan.region.h
+    );
+}
+
+/// Writes each frame of a timelapse to `frame-00000.png` and so on in a directory.
+fn write_frames(dir: &PathBuf, contents: &LogContents, plan: &Plan, scale: u32) -> io::Result<()> {
+    fs::create_dir_all(dir)?;
+    let mut index = 0;
+    let mut result = Ok(());
+    plan.render(contents, |_, region| {
+        let path = dir.join(format!("frame-{:05}.png", index));
+        index += 1;
+        result = fs::write(path, image::encode_png(&region, scale));
+        result.is_ok()
+    });
+    result
+}
@@ -0,0 +1,434 @@
+//! Server configuration.
+//!
+//! Settings come from command-line flags, then a TOML file (`--config <file>`, or `place.toml` in
+//! the working directory if there is one), then defaults. Relative paths in the file are relative
+//! to the file; on the command line, to the working directory. `static_dir` and `logins` default
+//! to `static/` and `logins.json` in the data directory.
+
+use canvas::Extent;
+use ratelimit::RateLimits;
+use std::env;
+use std::fmt;
+use std::fs;
+use std::net::{IpAddr, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use store::Storage;
+use toml;
+
+/// Name of the config file that is read from the working directory if none is given.
+const DEFAULT_CONFIG: &str = "place.toml";
+
+/// Settings as given in the config file or on the command line, where anything may be left out.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Settings {
+    bind: Option<String>,
+    data_dir: Option<PathBuf>,
+    static_dir: Option<PathBuf>,
+    logins: Option<PathBuf>,
+    storage: Option<String>,
+    default_width: Option<u32>,
+    default_height: Option<u32>,
+    max_width: Option<u32>,
+    max_height: Option<u32>,
+    max_pixels_per_frame: Option<usize>,
+    save_interval_secs: Option<u64>,
+    tick_ms: Option<u64>,
+    trusted_proxy: Option<String>,
+    cooldown_ms: Option<u64>,
+    burst: Option<f64>,
+    rate: Option<f64>,
+    ip_burst: Option<f64>,
+    ip_rate: Option<f64>,
+}
+
+impl Settings {
+    /// Makes relative paths relative to `base`.
+    fn resolve_paths(&mut self, base: &Path) {
+        let resolve = |path: &mut Option<PathBuf>| *path = path.take().map(|p| base.join(p));
+        resolve(&mut self.data_dir);
+        resolve(&mut self.static_dir);
+        resolve(&mut self.logins);
+    }
+
+    /// Fills in whatever is left out with the other settings.
+    fn or(self, other: Settings) -> Settings {
+        Settings {
+            bind: self.bind.or(other.bind),
+            data_dir: self.data_dir.or(other.data_dir),
+            static_dir: self.static_dir.or(other.static_dir),
+            logins: self.logins.or(other.logins),
+            storage: self.storage.or(other.storage),
+            default_width: self.default_width.or(other.default_width),
+            default_height: self.default_height.or(other.default_height),
+            max_width: self.max_width.or(other.max_width),
+            max_height: self.max_height.or(other.max_height),
+            max_pixels_per_frame: self.max_pixels_per_frame.or(other.max_pixels_per_frame),
+            save_interval_secs: self.save_interval_secs.or(other.save_interval_secs),
+            tick_ms: self.tick_ms.or(other.tick_ms),
+            trusted_proxy: self.trusted_proxy.or(other.trusted_proxy),
+            cooldown_ms: self.cooldown_ms.or(other.cooldown_ms),
+            burst: self.burst.or(other.burst),
+            rate: self.rate.or(other.rate),
+            ip_burst: self.ip_burst.or(other.ip_burst),
+            ip_rate: self.ip_rate.or(other.ip_rate),
+        }
+    }
+
+    /// Sets the value of a command-line flag (without the leading `--`).
+    fn set_flag(&mut self, flag: &str, value: &str) -> Result<(), ConfigError> {
+        fn number<T: ::std::str::FromStr>(flag: &str, value: &str) -> Result<T, ConfigError> {
+            value
+                .parse()
+                .map_err(|_| ConfigError(format!("--{} must be a number, not {}", flag, value)))
+        }
+        match flag {
+            "bind" => self.bind = Some(value.into()),
+            "data-dir" => self.data_dir = Some(value.into()),
+            "static-dir" => self.static_dir = Some(value.into()),
+            "logins" => self.logins = Some(value.into()),
+            "storage" => self.storage = Some(value.into()),
+            "default-width" => self.default_width = Some(number(flag, value)?),
+            "default-height" => self.default_height = Some(number(flag, value)?),
+            "max-width" => self.max_width = Some(number(flag, value)?),
+            "max-height" => self.max_height = Some(number(flag, value)?),
+            "max-pixels-per-frame" => self.max_pixels_per_frame = Some(number(flag, value)?),
+            "save-interval-secs" => self.save_interval_secs = Some(number(flag, value)?),
+            "tick-ms" => self.tick_ms = Some(number(flag, value)?),
+            "trusted-proxy" => self.trusted_proxy = Some(value.into()),
+            "cooldown-ms" => self.cooldown_ms = Some(number(flag, value)?),
+            "burst" => self.burst = Some(number(flag, value)?),
+            "rate" => self.rate = Some(number(flag, value)?),
+            "ip-burst" => self.ip_burst = Some(number(flag, value)?),
+            "ip-rate" => self.ip_rate = Some(number(flag, value)?),
+            _ => return Err(ConfigError(format!("Unknown option --{} (see --help)", flag))),
+        }
+        Ok(())
+    }
+}
+
+/// The server configuration, with absolute paths.
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Address to listen on.
+    pub bind: String,
+    /// Directory with `canvas.place` and the other files of the default canvas, `rooms/` and
+    /// `archive/`.
+    pub data_dir: PathBuf,
+    /// Directory with the web page, which subcommands don’t need (see `check_static_dir`).
+    pub static_dir: PathBuf,
+    /// Login file, which doesn’t have to exist unless it was configured.
+    pub logins: PathBuf,
+    /// Where canvases are kept.
+    pub storage: Storage,
+    /// Size of canvases created without one.
+    pub default_size: (u32, u32),
+    /// The largest width and height of a finite canvas.
+    pub max_size: (u32, u32),
+    /// The most changed pixels sent to clients per tick.
+    pub max_pixels_per_frame: usize,
+    /// Time between saves of a changed canvas.
+    pub save_interval: Duration,
+    /// Time between updates sent to clients.
+    pub tick: Duration,
+    /// Address of a reverse proxy whose `X-Forwarded-For` header is believed. Connections from
+    /// anywhere else are identified by their own address.
+    pub trusted_proxy: Option<IpAddr>,
+    /// Pixel placement limits of canvases that weren’t given their own with `rate-limit`.
+    pub rate_limits: RateLimits,
+}
+
+impl Config {
+    /// Reads the configuration from leading command-line flags and the config file, and returns
+    /// it along with the remaining arguments (a subcommand, if any).
+    pub fn from_args(args: &[String]) -> Result<(Config, &[String]), ConfigError> {
+        let cwd = env::current_dir()
+            .map_err(|err| ConfigError(format!("Failed to get the working directory: {}", err)))?;
+
+        let mut flags = Settings::default();
+        let mut config_path = None;
+        let mut rest = args;
+        while let Some(flag) = rest.first().filter(|arg| arg.starts_with("--")) {
+            if flag == "--help" {
+                break;
+            }
+            let value = match rest.get(1) {
+                Some(value) => value,
+                None => return Err(ConfigError(format!("{} needs a value", flag))),
+            };
+            if flag == "--config" {
+                config_path = Some(cwd.join(value));
+            } else {
+                flags.set_flag(&flag[2..], value)?;
+            }
+            rest = &rest[2..];
+        }
+        flags.resolve_paths(&cwd);
+
+        let config_path = config_path.or_else(|| {
+            Some(cwd.join(DEFAULT_CONFIG)).filter(|path| path.exists())
+        });
+        let file = match config_path {
+            Some(path) => read_file(&path)?,
+            None => Settings::default(),
+        };
+
+        Ok((Config::new(flags.or(file), &cwd)?, rest))
+    }
+
+    /// Returns an error message if a canvas with this extent would be larger than allowed.
+    pub fn check_extent(&self, extent: Extent) -> Result<(), String> {
+        let (max_width, max_height) = self.max_size;
+        match extent {
+            Extent::Finite { width, height } if width > max_width || height > max_height => Err(
+                format!("Canvases can be at most {}×{}", max_width, max_height),
+            ),
+            _ => Ok(()),
+        }
+    }
+
+    /// Makes sure the static directory exists, for serving the web page.
+    pub fn check_static_dir(&mut self) -> Result<(), ConfigError> {
+        self.static_dir = existing_dir(&self.static_dir, "static_dir")?;
+        Ok(())
+    }
+
+    /// Checks settings and fills in defaults.
+    fn new(settings: Settings, cwd: &Path) -> Result<Config, ConfigError> {
+        let data_dir = settings.data_dir.unwrap_or_else(|| cwd.to_path_buf());
+        let data_dir = existing_dir(&data_dir, "data_dir")?;
+        let static_dir = settings
+            .static_dir
+            .unwrap_or_else(|| data_dir.join("static"));
+
+        let logins = match settings.logins {
+            Some(logins) => {
+                if !logins.is_file() {
+                    return Err(ConfigError(format!(
+                        "logins: {} does not exist",
+                        logins.display()
+                    )));
+                }
+                logins
+            }
+            None => data_dir.join("logins.json"),
+        };
+
+        let storage = match settings.storage {
+            Some(name) => match Storage::parse(&name) {
+                Some(storage) => storage,
+                None => {
+                    return Err(ConfigError(format!(
+                        "storage must be tiles, file or memory, not {}",
+                        name
+                    )))
+                }
+            },
+            None => Storage::Tiles,
+        };
+
+        let bind = settings.bind.unwrap_or_else(|| "127.0.0.1:8000".into());
+        let has_addr = bind.to_socket_addrs().map(|mut addrs| addrs.next().is_some());
+        if !has_addr.unwrap_or(false) {
+            return Err(ConfigError(format!(
+                "bind: {} is not an address like 127.0.0.1:8000",
+                bind
+            )));
+        }
+
+        let trusted_proxy = match settings.trusted_proxy {
+            Some(addr) => match addr.parse() {
+                Ok(addr) => Some(addr),
+                Err(_) => {
+                    return Err(ConfigError(format!(
+                        "trusted_proxy: {} is not an IP address",
+                        addr
+                    )))
+                }
+            },
+            None => None,
+        };
+
+        let positive = |value: Option<u64>, default: u64, name: &str| match value {
+            Some(0) => Err(ConfigError(format!("{} must be greater than zero", name))),
+            Some(value) => Ok(value),
+            None => Ok(default),
+        };
+        let width = positive(settings.default_width.map(|w| w as u64), 500, "default_width")?;
+        let height = positive(settings.default_height.map(|h| h as u64), 500, "default_height")?;
+        let max_width = positive(settings.max_width.map(|w| w as u64), 10_000, "max_width")?;
+        let max_height = positive(settings.max_height.map(|h| h as u64), 10_000, "max_height")?;
+        if width > max_width || height > max_height {
+            return Err(ConfigError(
+                "default_width and default_height must be at most max_width and max_height".into(),
+            ));
+        }
+        let max_pixels = settings.max_pixels_per_frame.map(|n| n as u64);
+        let max_pixels_per_frame = positive(max_pixels, 3000, "max_pixels_per_frame")?;
+        let tick_ms = positive(settings.tick_ms, 33, "tick_ms")?;
+        if tick_ms > 1000 {
+            return Err(ConfigError("tick_ms must be at most 1000".into()));
+        }
+
+        let defaults = RateLimits::default();
+        let non_negative = |value: Option<f64>, default: f64, name: &str| match value {
+            Some(value) if value >= 0. => Ok(value),
+            Some(_) => Err(ConfigError(format!("{} must not be negative", name))),
+            None => Ok(default),
+        };
+        let rate_limits = RateLimits {
+            cooldown: settings
+                .cooldown_ms
+                .map_or(defaults.cooldown, Duration::from_millis),
+            conn_burst: non_negative(settings.burst, defaults.conn_burst, "burst")?.max(1.),
+            conn_rate: non_negative(settings.rate, defaults.conn_rate, "rate")?,
+            ip_burst: non_negative(settings.ip_burst, defaults.ip_burst, "ip_burst")?.max(1.),
+            ip_rate: non_negative(settings.ip_rate, defaults.ip_rate, "ip_rate")?,
+        };
+
+        Ok(Config {
+            bind,
+            data_dir,
+            static_dir,
+            logins,
+            storage,
+            default_size: (width as u32, height as u32),
+            max_size: (max_width as u32, max_height as u32),
+            max_pixels_per_frame: max_pixels_per_frame as usize,
+            save_interval: Duration::from_secs(settings.save_interval_secs.unwrap_or(5)),
+            tick: Duration::from_millis(tick_ms),
+            trusted_proxy,
+            rate_limits,
+        })
+    }
+}
+
+/// Reads a config file, making its paths relative to it.
+fn read_file(path: &Path) -> Result<Settings, ConfigError> {
+    let data = fs::read_to_string(path)
+        .map_err(|err| ConfigError(format!("Failed to read {}: {}", path.display(), err)))?;
+    let mut settings: Settings = toml::from_str(&data)
+        .map_err(|err| ConfigError(format!("Invalid config file {}: {}", path.display(), err)))?;
+    settings.resolve_paths(path.parent().unwrap_or(Path::new(".")));
+    Ok(settings)
+}
+
+/// Returns the absolute path of a directory that must exist.
+fn existing_dir(path: &Path, name: &str) -> Result<PathBuf, ConfigError> {
+    match path.canonicalize() {
+        Ok(ref path) if path.is_dir() => Ok(path.clone()),
+        Ok(_) => Err(ConfigError(format!(
+            "{}: {} is not a directory",
+            name,
+            path.display()
+        ))),
+        Err(err) => Err(ConfigError(format!("{}: {}: {}", name, path.display(), err))),
+    }
+}
+
+/// An invalid setting, described for the user.
+#[derive(Debug)]
+pub struct ConfigError(String);
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("place-test-{}-{}", process::id(), name));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn settings_come_from_flags_then_the_file() {
+        let dir = temp_dir("config-settings");
+        fs::create_dir_all(dir.join("data")).unwrap();
+        let file = dir.join("place.toml");
+        let settings = "data_dir = \"data\"\nstorage = \"memory\"\n";
+        fs::write(&file, format!("{}tick_ms = 50\nmax_width = 2000\n", settings)).unwrap();
+
+        let args = args(&[
+            "--config",
+            file.to_str().unwrap(),
+            "--tick-ms",
+            "20",
+            "--default-width",
+            "100",
+            "timelapse",
+            "--out",
+        ]);
+        let (config, rest) = Config::from_args(&args).unwrap();
+        let data_dir = dir.join("data").canonicalize().unwrap();
+        assert_eq!(config.data_dir, data_dir);
+        assert_eq!(config.static_dir, data_dir.join("static"));
+        assert_eq!(config.logins, data_dir.join("logins.json"));
+        assert_eq!(config.storage, Storage::Memory);
+        assert_eq!(config.tick, Duration::from_millis(20));
+        assert_eq!(config.default_size, (100, 500));
+        assert_eq!(config.max_size, (2000, 10_000));
+        assert_eq!(config.bind, "127.0.0.1:8000");
+        assert_eq!(rest, &args[6..]);
+
+        assert!(config.check_extent(Extent::Finite { width: 2000, height: 1 }).is_ok());
+        assert!(config.check_extent(Extent::Finite { width: 2001, height: 1 }).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn invalid_settings_are_rejected() {
+        let dir = temp_dir("config-invalid");
+        let file = dir.join("place.toml");
+        let error = |settings: &str| {
+            fs::write(&file, format!("data_dir = \".\"\n{}", settings)).unwrap();
+            let args = args(&["--config", file.to_str().unwrap()]);
+            Config::from_args(&args).unwrap_err().0
+        };
+        assert!(error("colour = \"red\"").starts_with("Invalid config file"));
+        assert!(error("storage = \"disk\"").starts_with("storage must be"));
+        assert!(error("bind = \"nowhere\"").starts_with("bind:"));
+        assert!(error("trusted_proxy = \"proxy\"").starts_with("trusted_proxy:"));
+        assert!(error("logins = \"missing.json\"").starts_with("logins:"));
+        assert_eq!(error("tick_ms = 2000"), "tick_ms must be at most 1000");
+        assert_eq!(error("max_height = 0"), "max_height must be greater than zero");
+        assert!(error("max_width = 100").starts_with("default_width and default_height"));
+        fs::write(&file, "data_dir = \"missing\"").unwrap();
+        let missing = args(&["--config", file.to_str().unwrap()]);
+        assert!(Config::from_args(&missing).unwrap_err().0.starts_with("data_dir:"));
+
+        let error = |flags: &[&str]| Config::from_args(&args(flags)).unwrap_err().0;
+        assert_eq!(error(&["--tick-ms"]), "--tick-ms needs a value");
+        assert_eq!(error(&["--tick-ms", "fast"]), "--tick-ms must be a number, not fast");
+        assert_eq!(error(&["--colour", "red"]), "Unknown option --colour (see --help)");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn rate_limits_come_from_flags_then_the_file() {
+        let dir = temp_dir("config-limits");
+        let file = dir.join("place.toml");
+        fs::write(&file, "cooldown_ms = 250\nburst = 5\nrate = 0.5\n").unwrap();
+
+        let args = args(&["--config", file.to_str().unwrap(), "--rate", "3"]);
+        let (config, _) = Config::from_args(&args).unwrap();
+        let limits = config.rate_limits;
+        assert_eq!(limits.cooldown, Duration::from_millis(250));
+        assert_eq!((limits.conn_burst, limits.conn_rate), (5., 3.));
+        assert_eq!(limits.ip_rate, RateLimits::default().ip_rate);
+
+        assert!(Config::from_args(&["--ip-rate".into(), "-1".into()]).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
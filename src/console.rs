@@ -1,10 +1,18 @@
+use base64;
+use canvas::{self, Anchor, Canvas, Draw, Extent, Growth, LayerChange, Palette, Rect, Resize};
+use history::{self, Actor};
+use image;
+use locks::{Lock, LockPolicy};
 use messages::ClientMessage;
+use rooms;
+use snapshots::{Policy, Tier};
 use std::collections::HashMap;
-use std::mem;
 use std::sync::mpsc::Sender;
-use std::sync::{Weak, Mutex};
+use std::sync::{Arc, Weak, Mutex};
+use std::time::Duration;
+use std::{fs, mem};
 use ws;
-use {UpdateMsg, GlobalState};
+use {describe_extent, describe_rate_limits, open_room, run_save, UpdateMsg, GlobalState};
 
 /// Splits the string into parts, respecting quoted text.
 fn split_command(cmd: &str) -> Vec<String> {
@@ -15,7 +23,7 @@ fn split_command(cmd: &str) -> Vec<String> {
 
     fn push_part(parts: &mut Vec<String>, part: &mut String) {
         if !part.is_empty() {
-            parts.push(mem::replace(part, String::new()));
+            parts.push(mem::take(part));
         }
     }
 
@@ -25,7 +33,7 @@ fn split_command(cmd: &str) -> Vec<String> {
         } else if Some(c) == quotes && !prev_was_backslash {
             quotes = None;
             push_part(&mut parts, &mut part);
-        } else if !prev_was_backslash && quotes == None && (c == '\'' || c == '"') {
+        } else if !prev_was_backslash && quotes.is_none() && (c == '\'' || c == '"') {
             push_part(&mut parts, &mut part);
             quotes = Some(c);
         } else if c != '\\' || prev_was_backslash {
@@ -43,7 +51,7 @@ fn split_command(cmd: &str) -> Vec<String> {
 struct CmdCall {
     command: String,
 
-    /// Parameters like `--a b`
+    /// Parameters like `--a b`, or flags like `--a` (with an empty value)
     params: HashMap<String, String>,
 
     /// Arguments.
@@ -52,7 +60,7 @@ struct CmdCall {
 
 /// Parses command parts (see above).
 fn parse_parts(mut parts: Vec<String>) -> Option<CmdCall> {
-    let command = match parts.get(0) {
+    let command = match parts.first() {
         Some(c) => c.to_string(),
         None => return None,
     };
@@ -61,8 +69,12 @@ fn parse_parts(mut parts: Vec<String>) -> Option<CmdCall> {
     let mut args = Vec::new();
     let mut param_name = None;
     for part in parts {
-        if part.starts_with("--") {
-            param_name = Some(part[2..].to_string());
+        if let Some(name) = part.strip_prefix("--") {
+            // a parameter directly followed by another one is a flag
+            if let Some(flag) = param_name {
+                params.insert(flag, String::new());
+            }
+            param_name = Some(name.to_string());
         } else if let Some(param) = param_name {
             params.insert(param, part);
             param_name = None;
@@ -70,6 +82,9 @@ fn parse_parts(mut parts: Vec<String>) -> Option<CmdCall> {
             args.push(part);
         }
     }
+    if let Some(flag) = param_name {
+        params.insert(flag, String::new());
+    }
     Some(CmdCall {
         command,
         params,
@@ -77,11 +92,59 @@ fn parse_parts(mut parts: Vec<String>) -> Option<CmdCall> {
     })
 }
 
-/// Runs a command in the given “context” (out, update_tx, global_weak).
+/// Parses snapshot retention tiers like `1h:1d,1d:30d` (one per hour for a day, then one per day
+/// for 30 days).
+fn parse_tiers(s: &str) -> Option<Vec<Tier>> {
+    s.split(',')
+        .map(|tier| {
+            let mut parts = tier.trim().splitn(2, ':');
+            let every = history::parse_duration(parts.next()?).filter(|&every| every > 0)?;
+            let keep_for = history::parse_duration(parts.next()?)?;
+            Some(Tier { every, keep_for })
+        })
+        .collect()
+}
+
+/// Parses a region like `x,y,w,h` (x and y may be negative).
+pub fn parse_region(s: &str) -> Option<Rect> {
+    let parts: Vec<&str> = s.split(',').map(|p| p.trim()).collect();
+    if parts.len() != 4 {
+        return None;
+    }
+    Some(Rect {
+        x: parts[0].parse().ok()?,
+        y: parts[1].parse().ok()?,
+        w: parts[2].parse().ok()?,
+        h: parts[3].parse().ok()?,
+    })
+}
+
+/// Parses a color like `#rrggbb` or `rrggbb`.
+fn parse_color(s: &str) -> Option<(u8, u8, u8)> {
+    let s = s.trim_start_matches('#');
+    if s.len() != 6 || !s.is_ascii() {
+        return None;
+    }
+    let channel = |i: usize| u8::from_str_radix(&s[i..i + 2], 16).ok();
+    Some((channel(0)?, channel(2)?, channel(4)?))
+}
+
+/// Parses a pair like `dx,dy`.
+fn parse_offset(s: &str) -> Option<(i32, i32)> {
+    let mut parts = s.splitn(2, ',');
+    let x = parts.next()?.trim().parse().ok()?;
+    let y = parts.next()?.trim().parse().ok()?;
+    Some((x, y))
+}
+
+/// Runs a command in the given “context” (out, room, update_tx, global_weak) on behalf of an
+/// admin.
 pub fn run_command(
     out: &ws::Sender,
+    room: &str,
     update_tx: &Sender<UpdateMsg>,
     global_weak: &Weak<Mutex<GlobalState>>,
+    actor: &Actor,
     command: &str,
 ) {
     let call = match parse_parts(split_command(command)) {
@@ -93,39 +156,586 @@ pub fn run_command(
         Ok(_) => (),
         Err(err) => eprintln!("Send error: {:?}", err),
     };
+    // the update thread is gone if the canvas was closed in the meantime
+    let update = |msg: UpdateMsg| {
+        if update_tx.send(msg).is_err() {
+            send_line("This canvas has been closed");
+        }
+    };
 
     match &*call.command {
         "help" => {
-            send_line("Commands: set-size, broadcast, list-clients");
+            send_line("Commands: set-size, crop, shift, set-bounds, broadcast, list-clients, restore, rollback, rate-limit, set-palette, list-layers, add-layer, show-layer, hide-layer, remove-layer, clear-layer, list-locks, lock, unlock, snapshot, list-snapshots, diff-snapshots, restore-snapshot, set-snapshots, fill, line, flood, paste, import-png, create-canvas, list-canvases, archive-canvas, delete-canvas");
         }
-        "set-size" => {
-            if call.args.len() < 1 {
-                send_line("set-size <size>");
-                return;
-            }
-            let size: u32 = match call.args[0].parse() {
-                Ok(s) => s,
-                Err(_) => return send_line("set-size <size: integer>"),
+        "set-size" | "crop" | "shift" => {
+            let usage = match &*call.command {
+                "set-size" => "set-size <size> | set-size [--width <w>] [--height <h>] [--anchor nw|n|ne|w|center|e|sw|s|se] [--fill #rrggbb]",
+                "crop" => "crop <x,y,w,h>",
+                _ => "shift <dx,dy> [--fill #rrggbb]",
+            };
+            let fill = match call.params.get("fill") {
+                Some(fill) => match parse_color(fill) {
+                    Some(fill) => fill,
+                    None => return send_line(usage),
+                },
+                None => (255, 255, 255),
             };
-            update_tx.send(UpdateMsg::SetSize(size)).unwrap();
+            let resize = match &*call.command {
+                "set-size" => {
+                    // a single size is a square
+                    let size = match call.args.first() {
+                        Some(size) => match size.parse() {
+                            Ok(size) => Some(size),
+                            Err(_) => return send_line(usage),
+                        },
+                        None => None,
+                    };
+                    let dimension = |name: &str| match call.params.get(name) {
+                        Some(value) => value.parse().map(Some).map_err(|_| ()),
+                        None => Ok(size),
+                    };
+                    let (width, height) = match (dimension("width"), dimension("height")) {
+                        (Ok(width), Ok(height)) if width.is_some() || height.is_some() => {
+                            (width, height)
+                        }
+                        _ => return send_line(usage),
+                    };
+                    let anchor = match call.params.get("anchor") {
+                        Some(anchor) => match Anchor::parse(anchor) {
+                            Some(anchor) => anchor,
+                            None => return send_line(usage),
+                        },
+                        None => Anchor { x: 0, y: 0 },
+                    };
+                    Resize::Size {
+                        width,
+                        height,
+                        anchor,
+                    }
+                }
+                "crop" => match call.args.first().and_then(|r| parse_region(r)) {
+                    Some(region) => Resize::Crop(region),
+                    None => return send_line(usage),
+                },
+                _ => match call.args.first().and_then(|o| parse_offset(o)) {
+                    Some((dx, dy)) => Resize::Shift { dx, dy },
+                    None => return send_line(usage),
+                },
+            };
+            update(UpdateMsg::Resize {
+                resize,
+                fill,
+                out: out.clone(),
+            });
+        }
+        "set-bounds" => {
+            let usage = "set-bounds <x,y,w,h> [--grow <pixels> --every <duration: 30s/15m/2h/1d>] | set-bounds none";
+            let boundary = match call.args.first().map(|a| &**a) {
+                Some("none") => None,
+                Some(region) => match parse_region(region) {
+                    Some(region) if !region.is_empty() => Some(region),
+                    _ => return send_line(usage),
+                },
+                None => return send_line(usage),
+            };
+            let growth = match (call.params.get("grow"), call.params.get("every")) {
+                (None, None) => None,
+                (Some(step), Some(every)) if boundary.is_some() => {
+                    match (step.parse(), history::parse_duration(every)) {
+                        (Ok(step), Some(interval)) if step > 0 && interval > 0 => {
+                            Some(Growth { step, interval })
+                        }
+                        _ => return send_line(usage),
+                    }
+                }
+                _ => return send_line(usage),
+            };
+            update(UpdateMsg::SetExtent {
+                extent: Extent::Infinite { boundary, growth },
+                out: out.clone(),
+            });
         }
         "broadcast" => {
-            if call.args.len() < 1 {
+            if call.args.is_empty() {
                 send_line("broadcast <message>");
                 return;
             }
-            update_tx
-                .send(UpdateMsg::Broadcast {
-                    text: call.args[0].clone(),
-                })
-                .unwrap();
+            update(UpdateMsg::Broadcast {
+                text: call.args[0].clone(),
+            });
+        }
+        "restore" => {
+            if call.args.is_empty() {
+                send_line("restore <time: UNIX seconds or -30s/-15m/-2h/-1d>");
+                return;
+            }
+            let time = match history::parse_time(&call.args[0], history::now_millis()) {
+                Some(time) => time,
+                None => return send_line("restore <time: UNIX seconds or -30s/-15m/-2h/-1d>"),
+            };
+            update(UpdateMsg::Restore {
+                time,
+                out: out.clone(),
+            });
+        }
+        "snapshot" => {
+            update(UpdateMsg::Snapshot { out: out.clone() });
+        }
+        "list-snapshots" => {
+            update(UpdateMsg::ListSnapshots { out: out.clone() });
+        }
+        "diff-snapshots" => {
+            let usage = "diff-snapshots <snapshot> [<snapshot> | live] (compares with the live canvas by default)";
+            let snapshot = |arg: Option<&String>| match arg.map(|a| &**a) {
+                None | Some("live") => Some(None),
+                Some(time) => time.parse().ok().map(Some),
+            };
+            let (from, to) = match (call.args.first(), snapshot(call.args.get(1))) {
+                (Some(from), Some(to)) => match snapshot(Some(from)) {
+                    Some(from) => (from, to),
+                    None => return send_line(usage),
+                },
+                _ => return send_line(usage),
+            };
+            update(UpdateMsg::DiffSnapshots {
+                from,
+                to,
+                out: out.clone(),
+            });
+        }
+        "restore-snapshot" => {
+            let time = match call.args.first().and_then(|time| time.parse().ok()) {
+                Some(time) => time,
+                None => return send_line("restore-snapshot <snapshot>"),
+            };
+            update(UpdateMsg::RestoreSnapshot {
+                time,
+                out: out.clone(),
+            });
+        }
+        "set-snapshots" => {
+            let usage = "set-snapshots --every <duration> [--keep <every>:<for>,…] | set-snapshots none (e.g. --every 1h --keep 1h:1d,1d:30d)";
+            let policy = if call.args.first().map(|a| &**a) == Some("none") {
+                Policy {
+                    interval: None,
+                    keep: Vec::new(),
+                }
+            } else {
+                let interval = match call.params.get("every").and_then(|e| history::parse_duration(e)) {
+                    Some(interval) if interval > 0 => interval,
+                    _ => return send_line(usage),
+                };
+                let keep = match call.params.get("keep") {
+                    Some(keep) => match parse_tiers(keep) {
+                        Some(keep) => keep,
+                        None => return send_line(usage),
+                    },
+                    None => Policy::default().keep,
+                };
+                Policy {
+                    interval: Some(interval),
+                    keep,
+                }
+            };
+            update(UpdateMsg::SetSnapshotPolicy {
+                policy,
+                out: out.clone(),
+            });
+        }
+        "rollback" => {
+            let usage = "rollback --user <login or login:…/anon:…> --since <time> [--region x,y,w,h] [--apply]";
+            let actor = match call.params.get("user") {
+                Some(user) => Actor::parse(user).unwrap_or_else(|| Actor::Login(user.clone())),
+                None => return send_line(usage),
+            };
+            let since = match call
+                .params
+                .get("since")
+                .and_then(|t| history::parse_time(t, history::now_millis()))
+            {
+                Some(since) => since,
+                None => return send_line(usage),
+            };
+            let region = match call.params.get("region") {
+                Some(region) => match parse_region(region) {
+                    Some(region) => Some(region),
+                    None => return send_line(usage),
+                },
+                None => None,
+            };
+            update(UpdateMsg::Rollback {
+                actor,
+                since,
+                region,
+                apply: call.params.contains_key("apply"),
+                out: out.clone(),
+            });
+        }
+        "rate-limit" => {
+            let global_lock = global_weak.upgrade().unwrap();
+            let mut limits = match global_lock.lock().unwrap().rooms.get(room) {
+                Some(room) => room.rate_limits,
+                None => return send_line("This canvas has been closed"),
+            };
+            if call.params.is_empty() {
+                return send_line(&describe_rate_limits(&limits));
+            }
+
+            for (name, value) in &call.params {
+                let value: f64 = match value.parse() {
+                    Ok(value) if value >= 0. => value,
+                    _ => return send_line(&format!("--{}: expected a non-negative number", name)),
+                };
+                match &**name {
+                    "cooldown" => limits.cooldown = Duration::from_millis(value as u64),
+                    "burst" => limits.conn_burst = value.max(1.),
+                    "rate" => limits.conn_rate = value,
+                    "ip-burst" => limits.ip_burst = value.max(1.),
+                    "ip-rate" => limits.ip_rate = value,
+                    _ => {
+                        return send_line(
+                            "rate-limit [--cooldown <ms>] [--burst <pixels>] [--rate <pixels/s>] [--ip-burst <pixels>] [--ip-rate <pixels/s>]",
+                        )
+                    }
+                }
+            }
+            update(UpdateMsg::SetRateLimits {
+                limits,
+                out: out.clone(),
+            });
+        }
+        "set-palette" => {
+            let usage = "set-palette <#rrggbb…> [--snap] | set-palette none";
+            if call.args.is_empty() || call.args.len() > 256 {
+                return send_line(usage);
+            }
+            let palette = if call.args.len() == 1 && call.args[0] == "none" {
+                None
+            } else {
+                let colors: Option<Vec<_>> = call.args.iter().map(|c| parse_color(c)).collect();
+                match colors {
+                    Some(colors) => Some(Palette {
+                        colors,
+                        snap: call.params.contains_key("snap"),
+                    }),
+                    None => return send_line(usage),
+                }
+            };
+            update(UpdateMsg::SetPalette {
+                palette,
+                out: out.clone(),
+            });
+        }
+        "list-layers" => {
+            update(UpdateMsg::ListLayers { out: out.clone() });
+        }
+        "add-layer" | "show-layer" | "hide-layer" | "remove-layer" | "clear-layer" => {
+            let usage = match &*call.command {
+                "add-layer" => "add-layer <name> [--visible] (new layers are hidden)",
+                "clear-layer" => "clear-layer <name> [--region x,y,w,h]",
+                "show-layer" => "show-layer <name>",
+                "hide-layer" => "hide-layer <name>",
+                _ => "remove-layer <name>",
+            };
+            let layer = match call.args.first() {
+                Some(layer) => layer.clone(),
+                None => return send_line(usage),
+            };
+            let change = match &*call.command {
+                "add-layer" => LayerChange::Add {
+                    hidden: !call.params.contains_key("visible"),
+                },
+                "show-layer" => LayerChange::SetHidden(false),
+                "hide-layer" => LayerChange::SetHidden(true),
+                "remove-layer" => LayerChange::Remove,
+                _ => match call.params.get("region") {
+                    Some(region) => match parse_region(region) {
+                        Some(region) => LayerChange::Clear(Some(region)),
+                        None => return send_line(usage),
+                    },
+                    None => LayerChange::Clear(None),
+                },
+            };
+            update(UpdateMsg::ChangeLayer {
+                layer,
+                change,
+                out: out.clone(),
+            });
+        }
+        "list-locks" => {
+            update(UpdateMsg::ListLocks { out: out.clone() });
+        }
+        "lock" => {
+            let usage = "lock <name> <x,y,w,h> [--admins | --logins <login,…>] (locked for everyone by default)";
+            let rect = call.args.get(1).and_then(|r| parse_region(r));
+            let (name, rect) = match (call.args.first(), rect) {
+                (Some(name), Some(rect)) if !rect.is_empty() => (name.clone(), rect),
+                _ => return send_line(usage),
+            };
+            let policy = match (call.params.contains_key("admins"), call.params.get("logins")) {
+                (false, None) => LockPolicy::Everyone,
+                (true, None) => LockPolicy::Admins,
+                (false, Some(logins)) => {
+                    let logins: Vec<_> = logins
+                        .split(',')
+                        .map(|l| l.trim().to_string())
+                        .filter(|l| !l.is_empty())
+                        .collect();
+                    if logins.is_empty() {
+                        return send_line(usage);
+                    }
+                    LockPolicy::Logins(logins)
+                }
+                (true, Some(_)) => return send_line(usage),
+            };
+            update(UpdateMsg::Lock {
+                lock: Lock { name, rect, policy },
+                out: out.clone(),
+            });
+        }
+        "unlock" => {
+            let name = match call.args.first() {
+                Some(name) => name.clone(),
+                None => return send_line("unlock <name>"),
+            };
+            update(UpdateMsg::Unlock {
+                name,
+                out: out.clone(),
+            });
+        }
+        "fill" | "line" | "flood" | "paste" => {
+            let usage = match &*call.command {
+                "fill" => "fill <x,y,w,h> <#rrggbb> [--layer <name>]",
+                "line" => "line <x1,y1> <x2,y2> <#rrggbb> [--layer <name>]",
+                "flood" => "flood <x,y> <#rrggbb> [--within x,y,w,h] [--layer <name>] (within the canvas by default)",
+                _ => "paste <x,y> <w,h> <base64 RGB data> [--layer <name>]",
+            };
+            let arg = |i: usize| call.args.get(i).map(|a| &**a).unwrap_or("");
+            let draw = match &*call.command {
+                "fill" => match (parse_region(arg(0)), parse_color(arg(1))) {
+                    (Some(rect), Some(color)) => Draw::Fill { rect, color },
+                    _ => return send_line(usage),
+                },
+                "line" => match (parse_offset(arg(0)), parse_offset(arg(1)), parse_color(arg(2))) {
+                    (Some(from), Some(to), Some(color)) => Draw::Line { from, to, color },
+                    _ => return send_line(usage),
+                },
+                "flood" => {
+                    let within = match call.params.get("within") {
+                        Some(within) => parse_region(within),
+                        None => {
+                            let global_lock = global_weak.upgrade().unwrap();
+                            let global = global_lock.lock().unwrap();
+                            let extent = global.rooms.get(room).and_then(|room| room.extent);
+                            match extent.and_then(|extent| extent.bounds()) {
+                                Some(bounds) => Some(bounds),
+                                None => {
+                                    return send_line("The canvas is unbounded; give --within")
+                                }
+                            }
+                        }
+                    };
+                    match (parse_offset(arg(0)), within, parse_color(arg(1))) {
+                        (Some((x, y)), Some(within), Some(color)) => Draw::Flood {
+                            x,
+                            y,
+                            within,
+                            color,
+                        },
+                        _ => return send_line(usage),
+                    }
+                }
+                _ => {
+                    let size = parse_offset(arg(1)).filter(|&(w, h)| w > 0 && h > 0);
+                    match (parse_offset(arg(0)), size, base64::decode(arg(2))) {
+                        (Some((x, y)), Some((w, h)), Ok(data))
+                            if data.len() as u64 == w as u64 * h as u64 * 3 =>
+                        {
+                            Draw::Paste {
+                                x,
+                                y,
+                                w: w as u32,
+                                h: h as u32,
+                                data,
+                            }
+                        }
+                        _ => return send_line(usage),
+                    }
+                }
+            };
+            let layer = match call.params.get("layer") {
+                Some(layer) => layer.clone(),
+                None => canvas::USER_LAYER.to_string(),
+            };
+            update(UpdateMsg::Draw {
+                layer,
+                draw,
+                actor: actor.clone(),
+                out: out.clone(),
+            });
+        }
+        "import-png" => {
+            let usage = "import-png <base64 PNG data> [--at x,y] (replaces the canvas unless --at is given)";
+            let file = match call.args.first().map(base64::decode) {
+                Some(Ok(file)) => file,
+                _ => return send_line(usage),
+            };
+            let at = match call.params.get("at") {
+                Some(at) => match parse_offset(at) {
+                    Some(at) => Some(at),
+                    None => return send_line(usage),
+                },
+                None => None,
+            };
+            let image = match image::decode_png(&file, canvas::MAX_DRAW_PIXELS) {
+                Ok(image) => image,
+                Err(err) => return send_line(&format!("Failed to read the image: {}", err)),
+            };
+            let msg = match at {
+                // pasting is drawing, which respects locks
+                Some((x, y)) => UpdateMsg::Draw {
+                    layer: canvas::USER_LAYER.into(),
+                    draw: Draw::Paste {
+                        x,
+                        y,
+                        w: image.width,
+                        h: image.height,
+                        data: image.data,
+                    },
+                    actor: actor.clone(),
+                    out: out.clone(),
+                },
+                None => UpdateMsg::Import {
+                    image,
+                    actor: actor.clone(),
+                    out: out.clone(),
+                },
+            };
+            update(msg);
         }
         "list-clients" => {
             let global_lock = global_weak.upgrade().unwrap();
             let global = global_lock.lock().unwrap();
 
-            for (_, client) in &global.clients {
-                send_line(&client.id_info);
+            if let Some(room) = global.rooms.get(room) {
+                for client in room.clients.values() {
+                    send_line(&client.id_info);
+                }
+            }
+        }
+        "create-canvas" => {
+            let usage = "create-canvas <name: a-z, 0-9, - and _> [--width <w> --height <h> | --infinite]";
+            let name = match call.args.first() {
+                Some(name) if rooms::is_valid_name(name) => name,
+                _ => return send_line(usage),
+            };
+            let global_lock = global_weak.upgrade().unwrap();
+            let config = {
+                let global = global_lock.lock().unwrap();
+                if global.shutting_down {
+                    return send_line("The server is shutting down");
+                }
+                if global.rooms.contains_key(&**name) {
+                    return send_line(&format!("{} already exists", name));
+                }
+                Arc::clone(&global.config)
+            };
+
+            let extent = if call.params.contains_key("infinite") {
+                Extent::Infinite {
+                    boundary: None,
+                    growth: None,
+                }
+            } else {
+                let (default_width, default_height) = config.default_size;
+                let dimension = |name: &str, default| match call.params.get(name) {
+                    Some(value) => value.parse().ok().filter(|value| *value > 0),
+                    None => Some(default),
+                };
+                match (dimension("width", default_width), dimension("height", default_height)) {
+                    (Some(width), Some(height)) => Extent::Finite { width, height },
+                    _ => return send_line(usage),
+                }
+            };
+            if let Err(err) = config.check_extent(extent) {
+                return send_line(&err);
+            }
+
+            let dir = rooms::room_dir(&config.data_dir, name);
+            // a canvas that is still being archived or deleted
+            if dir.exists() {
+                return send_line(&format!("{} already exists", name));
+            }
+            if let Err(err) = fs::create_dir_all(&dir) {
+                return send_line(&format!("Failed to create {}: {}", name, err));
+            }
+            let mut store = global_lock.lock().unwrap().open_store(name);
+            let saved = store
+                .save(&mut Canvas::with_extent(extent))
+                .map(run_save);
+            if !saved.unwrap_or(false) {
+                return send_line(&format!("Failed to create {}", name));
+            }
+            if let Err(err) = open_room(&global_lock, name, store) {
+                return send_line(&err);
+            }
+            send_line(&format!(
+                "Created {} ({}) at /canvas/{}",
+                name,
+                describe_extent(extent),
+                name
+            ));
+        }
+        "list-canvases" => {
+            let global_lock = global_weak.upgrade().unwrap();
+            let global = global_lock.lock().unwrap();
+
+            let mut names: Vec<&String> = global.rooms.keys().collect();
+            names.sort();
+            for name in names {
+                let room = &global.rooms[name];
+                send_line(&format!(
+                    "{}: {}, {} clients",
+                    rooms::display_name(name),
+                    room.extent.map_or("loading".into(), describe_extent),
+                    room.clients.len()
+                ));
+            }
+            match rooms::list_archived(&global.config.data_dir) {
+                Ok(ref archived) if archived.is_empty() => (),
+                Ok(archived) => send_line(&format!("Archived: {}", archived.join(", "))),
+                Err(err) => send_line(&format!("Failed to list archived canvases: {}", err)),
+            }
+        }
+        "archive-canvas" | "delete-canvas" => {
+            let archive = call.command == "archive-canvas";
+            let name = match call.args.first() {
+                Some(name) => name,
+                None if archive => return send_line("archive-canvas <name>"),
+                None => return send_line("delete-canvas <name or archived name>"),
+            };
+
+            let global_lock = global_weak.upgrade().unwrap();
+            let update_tx = {
+                let global = global_lock.lock().unwrap();
+                global.rooms.get(&**name).map(|room| room.update_tx.clone())
+            };
+            match update_tx {
+                // the default canvas has no name, so it can’t be closed
+                Some(update_tx) => {
+                    let _ = update_tx.send(UpdateMsg::Close {
+                        archive,
+                        out: out.clone(),
+                    });
+                }
+                None if !archive => {
+                    let data_dir = global_lock.lock().unwrap().config.data_dir.clone();
+                    match rooms::delete_archived(&data_dir, name) {
+                        Ok(_) => send_line(&format!("Deleted archived canvas {}", name)),
+                        Err(err) => send_line(&format!("Failed to delete {}: {}", name, err)),
+                    }
+                }
+                None => send_line(&format!("No canvas named {}", name)),
             }
         }
         _ => send_line("Unknown command, `help` for help"),
@@ -0,0 +1,84 @@
+//! PNG exports of canvases.
+//!
+//! HTTP requests are answered on the event loop, which can’t wait for the update thread or spend
+//! seconds encoding a large image. Exports are made on a thread of their own instead and kept
+//! here for a little while: the first request for an image starts it and is told to try again,
+//! and later ones are served what it made.
+
+use canvas::Rect;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// How many exports may be made at once.
+pub const MAX_RUNNING: usize = 4;
+
+/// How long a finished export is served before it’s made again.
+const MAX_AGE: Duration = Duration::from_secs(10);
+
+/// How many finished exports are kept; the oldest ones are dropped first.
+const MAX_KEPT: usize = 16;
+
+/// What was exported.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Key {
+    pub room: String,
+    pub rect: Option<Rect>,
+    pub scale: u32,
+}
+
+/// An encoded PNG, or why there isn’t one.
+pub type Export = Result<Arc<Vec<u8>>, String>;
+
+#[derive(Debug)]
+pub enum Status {
+    Done(Export),
+    /// The export is still being made.
+    Running,
+    /// The caller should make the export and hand it to `finish`.
+    Start,
+    /// Too many exports are being made already.
+    Busy,
+}
+
+#[derive(Debug, Default)]
+pub struct Exports {
+    done: HashMap<Key, (Instant, Export)>,
+    running: HashSet<Key>,
+}
+
+impl Exports {
+    /// Returns a recent export, or whether the caller should make it.
+    pub fn get(&mut self, key: &Key, now: Instant) -> Status {
+        if let Some(&(time, ref export)) = self.done.get(key) {
+            if now.duration_since(time) < MAX_AGE {
+                return Status::Done(export.clone());
+            }
+        }
+        if self.running.contains(key) {
+            Status::Running
+        } else if self.running.len() >= MAX_RUNNING {
+            Status::Busy
+        } else {
+            self.running.insert(key.clone());
+            Status::Start
+        }
+    }
+
+    /// Keeps an export that `get` said to make.
+    pub fn finish(&mut self, key: Key, export: Export, now: Instant) {
+        self.running.remove(&key);
+        self.done
+            .retain(|_, &mut (time, _)| now.duration_since(time) < MAX_AGE);
+        while self.done.len() >= MAX_KEPT {
+            let oldest = self
+                .done
+                .iter()
+                .min_by_key(|&(_, &(time, _))| time)
+                .map(|(key, _)| key.clone())
+                .unwrap();
+            self.done.remove(&oldest);
+        }
+        self.done.insert(key, (now, export));
+    }
+}
@@ -0,0 +1,418 @@
+//! The `canvas.place` file format.
+//!
+//! ```text
+//! magic         8 bytes  "\x89PLACE\r\n"
+//! version       u16
+//! chunks        { tag: 4 bytes, length: u32, data: length bytes }*
+//! checksum      u32      CRC-32 of everything before it
+//! ```
+//!
+//! All integers are big-endian. Unknown chunks are skipped when reading.
+//!
+//! Pixel data is stored in tiles of `canvas::TILE_SIZE`² pixels, and only tiles that have been
+//! painted on are stored. Tiles are either stored inline as `TILE` chunks or, for the server’s
+//! own saves, as separate files in a tile directory (each a file with one `TILE` chunk), so that
+//! only changed tiles have to be written. The canvas file then lists the tile files it belongs
+//! with (`TIDX`). Tile files are never overwritten, so backups of the canvas file keep their
+//! tiles.
+//!
+//! The canvas can be infinite, in which case tile coordinates may be negative (they’re stored as
+//! two’s complement) and there may be a world boundary instead of a size.
+//!
+//! Layers drawn over the canvas (see `canvas::Layer`) have their tiles in `LTIL` chunks, and in a
+//! tile directory each is a file with one `LTIL` chunk.
+//!
+//! Legacy (v0) files have no magic: just `width: u32, height: u32` followed by RGB data.
+
+use std::error::Error;
+use std::fmt;
+
+/// File magic. The leading non-ASCII byte and the CRLF catch 7-bit and newline mangling.
+pub const MAGIC: &[u8; 8] = b"\x89PLACE\r\n";
+
+/// The current format version.
+pub const VERSION: u16 = 1;
+
+/// Canvas metadata: `created_at: u64, last_modified: u64, pixels_placed: u64[, log_position: u64]`.
+pub const CHUNK_META: &[u8; 4] = b"META";
+
+/// Canvas size: `format: u8, extent, last growth: u64 (UNIX milliseconds), tile storage: u8`,
+/// where the extent is `kind: u8, x: i32, y: i32, w: u32, h: u32, growth step: u32,
+/// growth interval: u64 (milliseconds)`. The kind is one of the `EXTENT_` constants; a growth
+/// step of 0 means the boundary doesn’t grow.
+pub const CHUNK_CANVAS: &[u8; 4] = b"CANV";
+
+/// Actors referenced by tile provenance: `count: u32, { len: u16, actor: len bytes }*`.
+pub const CHUNK_ACTORS: &[u8; 4] = b"ACTR";
+
+/// A tile: `tx: i32, ty: i32, format: u8, data, count: u32,
+/// { index: u32, actor: u32 (1-based), time: u64 }*` (the sparse provenance of its pixels).
+pub const CHUNK_TILE: &[u8; 4] = b"TILE";
+
+/// Layers drawn over the user layer, bottom to top:
+/// `count: u16, { len: u8, name: len bytes, hidden: u8 }*`. Without it, there is just a visible
+/// admin layer.
+pub const CHUNK_LAYERS: &[u8; 4] = b"LAYR";
+
+/// A tile of a layer: `len: u8, layer name: len bytes`, then the same as `TILE`. Pixels
+/// nobody placed are transparent.
+pub const CHUNK_LAYER_TILE: &[u8; 4] = b"LTIL";
+
+/// Tile files in the tile directory: `count: u32, { len: u8, layer name: len bytes (empty for
+/// the user layer), tx: i32, ty: i32, generation: u64 }*`. A tile saved in generation `g` is in
+/// `tx_ty.g.tile`, or `layer.tx_ty.g.tile`. Required if the tiles are in a tile directory.
+pub const CHUNK_TILE_INDEX: &[u8; 4] = b"TIDX";
+
+/// Palette, required for indexed pixel data: `snap: u8, count: u16, { r, g, b: u8 }*`.
+pub const CHUNK_PALETTE: &[u8; 4] = b"PALT";
+
+/// Pixel formats.
+pub const PIXEL_FORMAT_RGB8: u8 = 0;
+pub const PIXEL_FORMAT_INDEXED8: u8 = 1;
+
+/// Tile storage: `TILE` chunks in the same file, or files in the tile directory.
+pub const TILES_INLINE: u8 = 0;
+pub const TILES_EXTERNAL: u8 = 1;
+
+/// Extent kinds: a fixed size from (0, 0) (`w` × `h`), infinite, or infinite within the
+/// boundary `x, y, w, h`.
+pub const EXTENT_FINITE: u8 = 0;
+pub const EXTENT_INFINITE: u8 = 1;
+pub const EXTENT_BOUNDED: u8 = 2;
+
+/// An error encountered while reading a canvas file.
+#[derive(Debug)]
+pub enum FileError {
+    /// The file ended unexpectedly.
+    Truncated,
+    /// The file has a magic number but a version this build doesn't know.
+    UnsupportedVersion(u16),
+    /// The pixel data is stored in an unknown format.
+    UnsupportedPixelFormat(u8),
+    /// The checksum doesn't match the file contents.
+    ChecksumMismatch { stored: u32, computed: u32 },
+    /// A required chunk is missing.
+    MissingChunk(&'static str),
+    /// The pixel data length doesn't match the canvas dimensions.
+    SizeMismatch { expected: usize, found: usize },
+    /// The canvas extent is of an unknown kind.
+    UnsupportedExtent(u8),
+    /// The palette has no colors or more than 256.
+    PaletteSize(u16),
+    /// A pixel refers to a color the palette doesn't have.
+    PaletteIndex(u8),
+}
+
+impl fmt::Display for FileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FileError::Truncated => write!(f, "file is truncated"),
+            FileError::UnsupportedVersion(v) => write!(f, "unsupported format version {}", v),
+            FileError::UnsupportedPixelFormat(p) => write!(f, "unsupported pixel format {}", p),
+            FileError::ChecksumMismatch { stored, computed } => write!(
+                f,
+                "checksum mismatch (stored {:08x}, computed {:08x})",
+                stored, computed
+            ),
+            FileError::MissingChunk(tag) => write!(f, "missing {} chunk", tag),
+            FileError::SizeMismatch { expected, found } => write!(
+                f,
+                "pixel data is {} bytes, expected {}",
+                found, expected
+            ),
+            FileError::UnsupportedExtent(k) => write!(f, "unsupported canvas extent {}", k),
+            FileError::PaletteSize(n) => write!(f, "palette has {} colors", n),
+            FileError::PaletteIndex(i) => write!(f, "pixel refers to missing palette color {}", i),
+        }
+    }
+}
+
+impl Error for FileError {}
+
+/// Reads big-endian values from a byte slice.
+pub struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(data: &'a [u8]) -> Reader<'a> {
+        Reader { data, pos: 0 }
+    }
+
+    /// Returns the number of bytes read so far.
+    pub fn position(&self) -> usize {
+        self.pos
+    }
+
+    /// Returns true if there's nothing left to read.
+    pub fn is_empty(&self) -> bool {
+        self.pos >= self.data.len()
+    }
+
+    pub fn bytes(&mut self, len: usize) -> Result<&'a [u8], FileError> {
+        if self.data.len() - self.pos < len {
+            return Err(FileError::Truncated);
+        }
+        let bytes = &self.data[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    pub fn u8(&mut self) -> Result<u8, FileError> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    pub fn u16(&mut self) -> Result<u16, FileError> {
+        let b = self.bytes(2)?;
+        Ok((b[0] as u16) << 8 | b[1] as u16)
+    }
+
+    pub fn u32(&mut self) -> Result<u32, FileError> {
+        let b = self.bytes(4)?;
+        Ok((b[0] as u32) << 24 | (b[1] as u32) << 16 | (b[2] as u32) << 8 | b[3] as u32)
+    }
+
+    pub fn i32(&mut self) -> Result<i32, FileError> {
+        Ok(self.u32()? as i32)
+    }
+
+    pub fn u64(&mut self) -> Result<u64, FileError> {
+        Ok((self.u32()? as u64) << 32 | self.u32()? as u64)
+    }
+
+    /// Reads a chunk, returning its tag and contents.
+    pub fn chunk(&mut self) -> Result<(&'a [u8], Reader<'a>), FileError> {
+        let tag = self.bytes(4)?;
+        let len = self.u32()? as usize;
+        Ok((tag, Reader::new(self.bytes(len)?)))
+    }
+}
+
+pub fn push_u16(buf: &mut Vec<u8>, value: u16) {
+    buf.push((value >> 8) as u8);
+    buf.push(value as u8);
+}
+
+pub fn push_u32(buf: &mut Vec<u8>, value: u32) {
+    push_u16(buf, (value >> 16) as u16);
+    push_u16(buf, value as u16);
+}
+
+pub fn push_i32(buf: &mut Vec<u8>, value: i32) {
+    push_u32(buf, value as u32);
+}
+
+pub fn push_u64(buf: &mut Vec<u8>, value: u64) {
+    push_u32(buf, (value >> 32) as u32);
+    push_u32(buf, value as u32);
+}
+
+/// Appends a chunk.
+pub fn push_chunk(buf: &mut Vec<u8>, tag: &[u8; 4], data: &[u8]) {
+    buf.extend_from_slice(tag);
+    push_u32(buf, data.len() as u32);
+    buf.extend_from_slice(data);
+}
+
+/// Computes the CRC-32 (IEEE) of the data.
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+/// Checks and strips the magic and the checksum trailer, returning the version and the chunk data.
+/// Returns Ok(None) if the data has no magic (i.e. it's a legacy file).
+pub fn open<'a>(data: &'a [u8]) -> Result<Option<(u16, Reader<'a>)>, FileError> {
+    if data.len() < MAGIC.len() || &data[..MAGIC.len()] != MAGIC {
+        return Ok(None);
+    }
+    if data.len() < MAGIC.len() + 2 + 4 {
+        return Err(FileError::Truncated);
+    }
+
+    let (body, trailer) = data.split_at(data.len() - 4);
+    let stored = Reader::new(trailer).u32()?;
+    let computed = crc32(body);
+    if stored != computed {
+        return Err(FileError::ChecksumMismatch { stored, computed });
+    }
+
+    let mut reader = Reader::new(&body[MAGIC.len()..]);
+    let version = reader.u16()?;
+    if version > VERSION {
+        return Err(FileError::UnsupportedVersion(version));
+    }
+    Ok(Some((version, reader)))
+}
+
+/// Starts a new file buffer with the magic and version.
+pub fn begin() -> Vec<u8> {
+    let mut buf = Vec::new();
+    buf.extend_from_slice(MAGIC);
+    push_u16(&mut buf, VERSION);
+    buf
+}
+
+/// Appends the checksum trailer.
+pub fn finish(mut buf: Vec<u8>) -> Vec<u8> {
+    let crc = crc32(&buf);
+    push_u32(&mut buf, crc);
+    buf
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use canvas::{Canvas, Palette};
+    use history::Actor;
+
+    fn sample() -> Vec<u8> {
+        let mut buf = begin();
+        push_chunk(&mut buf, b"TEST", &[1, 2, 3]);
+        let mut data = Vec::new();
+        push_u16(&mut data, 0xbeef);
+        push_i32(&mut data, -5);
+        push_u64(&mut data, 1 << 40);
+        push_chunk(&mut buf, b"NUMS", &data);
+        finish(buf)
+    }
+
+    #[test]
+    fn crc32_matches_the_check_value() {
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+    }
+
+    #[test]
+    fn chunks_round_trip() {
+        let data = sample();
+        let (version, mut reader) = open(&data).unwrap().unwrap();
+        assert_eq!(version, VERSION);
+
+        let (tag, mut chunk) = reader.chunk().unwrap();
+        assert_eq!(tag, b"TEST");
+        assert_eq!(chunk.bytes(3).unwrap(), &[1, 2, 3]);
+        assert!(chunk.is_empty());
+
+        let (tag, mut chunk) = reader.chunk().unwrap();
+        assert_eq!(tag, b"NUMS");
+        assert_eq!(chunk.u16().unwrap(), 0xbeef);
+        assert_eq!(chunk.i32().unwrap(), -5);
+        assert_eq!(chunk.u64().unwrap(), 1 << 40);
+        assert!(reader.is_empty());
+    }
+
+    #[test]
+    fn damaged_files_are_rejected() {
+        let data = sample();
+        for i in MAGIC.len()..data.len() {
+            let mut damaged = data.clone();
+            damaged[i] ^= 0x10;
+            match open(&damaged) {
+                Err(FileError::ChecksumMismatch { .. }) => (),
+                other => panic!("byte {} flipped: {:?}", i, other.map(|_| ())),
+            }
+        }
+    }
+
+    #[test]
+    fn truncated_files_are_rejected() {
+        let data = sample();
+        match open(&data[..MAGIC.len() + 3]) {
+            Err(FileError::Truncated) => (),
+            other => panic!("{:?}", other.map(|_| ())),
+        }
+        match open(&data[..data.len() - 1]) {
+            Err(FileError::ChecksumMismatch { .. }) => (),
+            other => panic!("{:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn newer_versions_are_rejected() {
+        let mut buf = MAGIC.to_vec();
+        push_u16(&mut buf, VERSION + 1);
+        match open(&finish(buf)) {
+            Err(FileError::UnsupportedVersion(version)) => assert_eq!(version, VERSION + 1),
+            other => panic!("{:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn files_without_magic_are_legacy() {
+        assert!(open(&[0, 0, 0, 1, 0, 0, 0, 1, 255, 255, 255]).unwrap().is_none());
+    }
+
+    #[test]
+    fn canvases_round_trip() {
+        let mut canvas = Canvas::blank(300, 20);
+        let actor = Actor::Login("someone".into());
+        canvas.place(3, 4, (10, 20, 30), &actor, 5_000);
+        canvas.place(299, 19, (1, 2, 3), &Actor::Anonymous("abc".into()), 6_000);
+
+        let read = Canvas::from_file(canvas.to_file()).unwrap();
+        assert!(canvas.diff(&read).is_empty());
+        assert_eq!(read.pixel(3, 4), (10, 20, 30));
+        assert_eq!(read.pixel(0, 0), (255, 255, 255));
+        assert_eq!(read.provenance(3, 4), Some((&actor, 5_000)));
+        assert_eq!(read.provenance(0, 0), None);
+        assert!(read.contains(299, 19) && !read.contains(300, 19));
+    }
+
+    #[test]
+    fn damaged_canvases_are_rejected() {
+        let mut data = Canvas::blank(10, 10).to_file();
+        let middle = data.len() / 2;
+        data[middle] ^= 1;
+        assert!(Canvas::from_file(data).is_err());
+    }
+
+    /// Rebuilds a file with the contents of a chunk replaced.
+    fn replace_chunk(data: &[u8], tag: &[u8; 4], contents: &[u8]) -> Vec<u8> {
+        let (_, mut reader) = open(data).unwrap().unwrap();
+        let mut buf = begin();
+        while !reader.is_empty() {
+            let (chunk_tag, chunk) = reader.chunk().unwrap();
+            let mut copied_tag = [0; 4];
+            copied_tag.copy_from_slice(chunk_tag);
+            if chunk_tag == tag {
+                push_chunk(&mut buf, tag, contents);
+            } else {
+                push_chunk(&mut buf, &copied_tag, &chunk.data[chunk.pos..]);
+            }
+        }
+        finish(buf)
+    }
+
+    #[test]
+    fn bad_palettes_are_rejected() {
+        let mut canvas = Canvas::blank(10, 10);
+        canvas.set_palette(Some(Palette {
+            colors: vec![(0, 0, 0), (255, 255, 255)],
+            snap: true,
+        }));
+        canvas.set_pixel(1, 1, 0, 0, 0);
+        let data = canvas.to_file();
+        assert!(Canvas::from_file(data.clone()).is_ok());
+
+        match Canvas::from_file(replace_chunk(&data, CHUNK_PALETTE, &[1, 0, 0])) {
+            Err(FileError::PaletteSize(0)) => (),
+            other => panic!("{:?}", other.map(|_| ())),
+        }
+        // the white background is color 1, which this palette doesn't have
+        match Canvas::from_file(replace_chunk(&data, CHUNK_PALETTE, &[1, 0, 1, 0, 0, 0])) {
+            Err(FileError::PaletteIndex(1)) => (),
+            other => panic!("{:?}", other.map(|_| ())),
+        }
+    }
+}
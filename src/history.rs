@@ -0,0 +1,1270 @@
+//! The append-only pixel event log (`canvas.log`).
+//!
+//! ```text
+//! magic         8 bytes  "\x89PLLOG\r\n"
+//! version       u16
+//! base          u64 length, then a complete canvas file (the state the log starts from)
+//! records       { kind: u8, time: u64 (UNIX milliseconds), ... }*
+//! ```
+//!
+//! A truncated record at the end (e.g. after a crash) is ignored and overwritten.
+
+use canvas::{self, Canvas, Draw, Extent, LayerChange, Palette, Rect};
+use format::{self, FileError, Reader};
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use store::{CanvasStore, StoreError};
+
+const MAGIC: &[u8; 8] = b"\x89PLLOG\r\n";
+const VERSION: u16 = 1;
+
+const RECORD_SET_PIXEL: u8 = 1;
+const RECORD_SET_PALETTE: u8 = 3;
+const RECORD_SET_EXTENT: u8 = 4;
+const RECORD_RESIZE: u8 = 5;
+const RECORD_LAYER_PIXEL: u8 = 6;
+const RECORD_CHANGE_LAYER: u8 = 7;
+const RECORD_DRAW: u8 = 8;
+
+/// Layer change kinds in `RECORD_CHANGE_LAYER` records.
+const LAYER_ADD: u8 = 0;
+const LAYER_SET_HIDDEN: u8 = 1;
+const LAYER_REMOVE: u8 = 2;
+const LAYER_CLEAR: u8 = 3;
+
+/// Drawing operations in `RECORD_DRAW` records.
+const DRAW_FILL: u8 = 0;
+const DRAW_LINE: u8 = 1;
+const DRAW_FLOOD: u8 = 2;
+const DRAW_PASTE: u8 = 3;
+
+/// Returns the current time as milliseconds since the UNIX epoch.
+pub fn now_millis() -> u64 {
+    match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(time) => time.as_secs() * 1000 + time.subsec_millis() as u64,
+        Err(_) => 0,
+    }
+}
+
+/// Parses a duration like `90s`, `15m`, `2h` or `3d`, returning milliseconds.
+pub fn parse_duration(s: &str) -> Option<u64> {
+    let (num, unit) = s.split_at(s.char_indices().last()?.0);
+    let secs: u64 = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => return None,
+    };
+    let num: u64 = num.parse().ok()?;
+    num.checked_mul(secs * 1000)
+}
+
+/// Formats a duration (milliseconds) like `2h` or `1d 6h`, leaving out anything smaller than the
+/// second-largest unit.
+pub fn format_duration(ms: u64) -> String {
+    let units = [(24 * 60 * 60, "d"), (60 * 60, "h"), (60, "m"), (1, "s")];
+    let secs = ms / 1000;
+    let largest = units
+        .iter()
+        .position(|&(unit, _)| secs >= unit)
+        .unwrap_or(units.len() - 1);
+    let (unit, name) = units[largest];
+    let mut s = format!("{}{}", secs / unit, name);
+    if let Some(&(next, next_name)) = units.get(largest + 1) {
+        let rest = secs % unit / next;
+        if rest > 0 {
+            s += &format!(" {}{}", rest, next_name);
+        }
+    }
+    s
+}
+
+/// Parses a point in time: either UNIX seconds or a duration before `now` like `-90s`, `-15m`,
+/// `-2h` or `-3d`. Returns UNIX milliseconds.
+pub fn parse_time(s: &str, now: u64) -> Option<u64> {
+    if let Some(duration) = s.strip_prefix('-') {
+        parse_duration(duration).map(|duration| now.saturating_sub(duration))
+    } else {
+        s.parse::<u64>().ok()?.checked_mul(1000)
+    }
+}
+
+/// Whoever caused an event.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Actor {
+    /// A logged-in user.
+    Login(String),
+    /// An anonymous connection, identified by a fingerprint of its address and user agent.
+    Anonymous(String),
+    /// The server itself, e.g. when restoring.
+    Server(String),
+}
+
+impl fmt::Display for Actor {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Actor::Login(name) => write!(f, "login:{}", name),
+            Actor::Anonymous(fingerprint) => write!(f, "anon:{}", fingerprint),
+            Actor::Server(what) => write!(f, "server:{}", what),
+        }
+    }
+}
+
+impl Actor {
+    /// Parses the `Display` representation.
+    pub fn parse(s: &str) -> Option<Actor> {
+        let (kind, name) = s.split_once(':')?;
+        match kind {
+            "login" => Some(Actor::Login(name.to_string())),
+            "anon" => Some(Actor::Anonymous(name.to_string())),
+            "server" => Some(Actor::Server(name.to_string())),
+            _ => None,
+        }
+    }
+}
+
+/// A logged event.
+#[derive(Debug, Clone)]
+pub enum Event {
+    SetPixel {
+        time: u64,
+        x: i32,
+        y: i32,
+        r: u8,
+        g: u8,
+        b: u8,
+        actor: Actor,
+    },
+    SetPalette {
+        time: u64,
+        palette: Option<Palette>,
+    },
+    SetExtent {
+        time: u64,
+        extent: Extent,
+    },
+    /// Moves the contents by `(dx, dy)` and sets a new extent (see `Canvas::transform`).
+    Resize {
+        time: u64,
+        extent: Extent,
+        dx: i32,
+        dy: i32,
+        fill: (u8, u8, u8),
+    },
+    /// Places a pixel on a layer other than the user layer, or erases it if there is no color.
+    LayerPixel {
+        time: u64,
+        layer: String,
+        x: i32,
+        y: i32,
+        color: Option<(u8, u8, u8)>,
+        actor: Actor,
+    },
+    ChangeLayer {
+        time: u64,
+        layer: String,
+        change: LayerChange,
+    },
+    /// A drawing operation on a layer (see `Canvas::draw`).
+    Draw {
+        time: u64,
+        layer: String,
+        draw: Draw,
+        actor: Actor,
+    },
+}
+
+/// Appends a string: `len: u16, data: len bytes`.
+fn push_str(buf: &mut Vec<u8>, s: &str) {
+    format::push_u16(buf, s.len() as u16);
+    buf.extend_from_slice(s.as_bytes());
+}
+
+/// Reads a string (see `push_str`).
+fn read_str(reader: &mut Reader) -> Result<String, FileError> {
+    let len = reader.u16()? as usize;
+    Ok(String::from_utf8_lossy(reader.bytes(len)?).to_string())
+}
+
+/// Appends a rectangle: `x: i32, y: i32, w: u32, h: u32`.
+fn push_rect(buf: &mut Vec<u8>, rect: Rect) {
+    format::push_i32(buf, rect.x);
+    format::push_i32(buf, rect.y);
+    format::push_u32(buf, rect.w);
+    format::push_u32(buf, rect.h);
+}
+
+/// Reads a rectangle (see `push_rect`).
+fn read_rect(reader: &mut Reader) -> Result<Rect, FileError> {
+    Ok(Rect {
+        x: reader.i32()?,
+        y: reader.i32()?,
+        w: reader.u32()?,
+        h: reader.u32()?,
+    })
+}
+
+/// Reads a color: `r: u8, g: u8, b: u8`.
+fn read_rgb(reader: &mut Reader) -> Result<(u8, u8, u8), FileError> {
+    let rgb = reader.bytes(3)?;
+    Ok((rgb[0], rgb[1], rgb[2]))
+}
+
+impl Event {
+    /// Returns the event time (UNIX milliseconds).
+    pub fn time(&self) -> u64 {
+        match self {
+            Event::SetPixel { time, .. }
+            | Event::SetPalette { time, .. }
+            | Event::SetExtent { time, .. }
+            | Event::Resize { time, .. }
+            | Event::LayerPixel { time, .. }
+            | Event::ChangeLayer { time, .. }
+            | Event::Draw { time, .. } => *time,
+        }
+    }
+
+    /// Applies the event to a canvas.
+    pub fn apply(&self, canvas: &mut Canvas) {
+        match self {
+            Event::SetPixel {
+                time,
+                x,
+                y,
+                r,
+                g,
+                b,
+                actor,
+            } => canvas.place(*x, *y, (*r, *g, *b), actor, *time),
+            Event::SetPalette { palette, .. } => canvas.set_palette(palette.clone()),
+            Event::SetExtent { time, extent } => canvas.set_extent(*extent, *time),
+            Event::Resize {
+                extent,
+                dx,
+                dy,
+                fill,
+                ..
+            } => canvas.transform(*extent, *dx, *dy, *fill),
+            Event::LayerPixel {
+                time,
+                layer,
+                x,
+                y,
+                color,
+                actor,
+            } => canvas.place_on(layer, *x, *y, *color, actor, *time),
+            Event::ChangeLayer { layer, change, .. } => {
+                // only valid changes are logged
+                let _ = canvas.change_layer(layer, *change);
+            }
+            Event::Draw {
+                time,
+                layer,
+                draw,
+                actor,
+            } => canvas.draw(layer, draw, actor, *time),
+        }
+    }
+
+    fn encode(&self, buf: &mut Vec<u8>) {
+        match self {
+            Event::SetPixel {
+                time,
+                x,
+                y,
+                r,
+                g,
+                b,
+                actor,
+            } => {
+                buf.push(RECORD_SET_PIXEL);
+                format::push_u64(buf, *time);
+                format::push_i32(buf, *x);
+                format::push_i32(buf, *y);
+                buf.extend_from_slice(&[*r, *g, *b]);
+                push_str(buf, &actor.to_string());
+            }
+            Event::SetPalette { time, palette } => {
+                buf.push(RECORD_SET_PALETTE);
+                format::push_u64(buf, *time);
+                // no colors means no palette
+                match palette {
+                    Some(palette) => {
+                        buf.push(palette.snap as u8);
+                        format::push_u16(buf, palette.colors.len() as u16);
+                        for &(r, g, b) in &palette.colors {
+                            buf.extend_from_slice(&[r, g, b]);
+                        }
+                    }
+                    None => {
+                        buf.push(0);
+                        format::push_u16(buf, 0);
+                    }
+                }
+            }
+            Event::SetExtent { time, extent } => {
+                buf.push(RECORD_SET_EXTENT);
+                format::push_u64(buf, *time);
+                extent.write(buf);
+            }
+            Event::Resize {
+                time,
+                extent,
+                dx,
+                dy,
+                fill: (r, g, b),
+            } => {
+                buf.push(RECORD_RESIZE);
+                format::push_u64(buf, *time);
+                extent.write(buf);
+                format::push_i32(buf, *dx);
+                format::push_i32(buf, *dy);
+                buf.extend_from_slice(&[*r, *g, *b]);
+            }
+            Event::LayerPixel {
+                time,
+                layer,
+                x,
+                y,
+                color,
+                actor,
+            } => {
+                buf.push(RECORD_LAYER_PIXEL);
+                format::push_u64(buf, *time);
+                push_str(buf, layer);
+                format::push_i32(buf, *x);
+                format::push_i32(buf, *y);
+                // a flag, then the color (ignored when erasing)
+                let (r, g, b) = color.unwrap_or((0, 0, 0));
+                buf.extend_from_slice(&[color.is_some() as u8, r, g, b]);
+                push_str(buf, &actor.to_string());
+            }
+            Event::ChangeLayer {
+                time,
+                layer,
+                change,
+            } => {
+                buf.push(RECORD_CHANGE_LAYER);
+                format::push_u64(buf, *time);
+                push_str(buf, layer);
+                match change {
+                    LayerChange::Add { hidden } => {
+                        buf.extend_from_slice(&[LAYER_ADD, *hidden as u8])
+                    }
+                    LayerChange::SetHidden(hidden) => {
+                        buf.extend_from_slice(&[LAYER_SET_HIDDEN, *hidden as u8])
+                    }
+                    LayerChange::Remove => buf.push(LAYER_REMOVE),
+                    LayerChange::Clear(region) => {
+                        buf.push(LAYER_CLEAR);
+                        // a flag, then the region (ignored when clearing everything)
+                        let area = region.unwrap_or(Rect {
+                            x: 0,
+                            y: 0,
+                            w: 0,
+                            h: 0,
+                        });
+                        buf.push(region.is_some() as u8);
+                        push_rect(buf, area);
+                    }
+                }
+            }
+            Event::Draw {
+                time,
+                layer,
+                draw,
+                actor,
+            } => {
+                buf.push(RECORD_DRAW);
+                format::push_u64(buf, *time);
+                push_str(buf, layer);
+                push_str(buf, &actor.to_string());
+                match draw {
+                    Draw::Fill {
+                        rect,
+                        color: (r, g, b),
+                    } => {
+                        buf.push(DRAW_FILL);
+                        push_rect(buf, *rect);
+                        buf.extend_from_slice(&[*r, *g, *b]);
+                    }
+                    Draw::Line {
+                        from,
+                        to,
+                        color: (r, g, b),
+                    } => {
+                        buf.push(DRAW_LINE);
+                        for &v in &[from.0, from.1, to.0, to.1] {
+                            format::push_i32(buf, v);
+                        }
+                        buf.extend_from_slice(&[*r, *g, *b]);
+                    }
+                    Draw::Flood {
+                        x,
+                        y,
+                        within,
+                        color: (r, g, b),
+                    } => {
+                        buf.push(DRAW_FLOOD);
+                        format::push_i32(buf, *x);
+                        format::push_i32(buf, *y);
+                        push_rect(buf, *within);
+                        buf.extend_from_slice(&[*r, *g, *b]);
+                    }
+                    Draw::Paste { x, y, w, h, data } => {
+                        // the data is w × h × 3 bytes
+                        buf.push(DRAW_PASTE);
+                        push_rect(
+                            buf,
+                            Rect {
+                                x: *x,
+                                y: *y,
+                                w: *w,
+                                h: *h,
+                            },
+                        );
+                        buf.extend_from_slice(data);
+                    }
+                }
+            }
+        }
+    }
+
+    fn decode(reader: &mut Reader) -> Result<Event, LogError> {
+        let kind = reader.u8()?;
+        let time = reader.u64()?;
+        match kind {
+            RECORD_SET_PIXEL => {
+                let x = reader.i32()?;
+                let y = reader.i32()?;
+                let rgb = reader.bytes(3)?;
+                let actor = read_str(reader)?;
+                Ok(Event::SetPixel {
+                    time,
+                    x,
+                    y,
+                    r: rgb[0],
+                    g: rgb[1],
+                    b: rgb[2],
+                    actor: Actor::parse(&actor).ok_or(LogError::InvalidRecord)?,
+                })
+            }
+            RECORD_SET_PALETTE => {
+                let snap = reader.u8()? != 0;
+                let count = reader.u16()?;
+                if count > 256 {
+                    return Err(LogError::InvalidRecord);
+                }
+                let mut colors = Vec::with_capacity(count as usize);
+                for _ in 0..count {
+                    let rgb = reader.bytes(3)?;
+                    colors.push((rgb[0], rgb[1], rgb[2]));
+                }
+                Ok(Event::SetPalette {
+                    time,
+                    palette: if colors.is_empty() {
+                        None
+                    } else {
+                        Some(Palette { colors, snap })
+                    },
+                })
+            }
+            RECORD_SET_EXTENT => Ok(Event::SetExtent {
+                time,
+                extent: Extent::read(reader)?,
+            }),
+            RECORD_RESIZE => {
+                let extent = Extent::read(reader)?;
+                let dx = reader.i32()?;
+                let dy = reader.i32()?;
+                let rgb = reader.bytes(3)?;
+                Ok(Event::Resize {
+                    time,
+                    extent,
+                    dx,
+                    dy,
+                    fill: (rgb[0], rgb[1], rgb[2]),
+                })
+            }
+            RECORD_LAYER_PIXEL => {
+                let layer = read_str(reader)?;
+                let x = reader.i32()?;
+                let y = reader.i32()?;
+                let color = reader.bytes(4)?;
+                let actor = read_str(reader)?;
+                Ok(Event::LayerPixel {
+                    time,
+                    layer,
+                    x,
+                    y,
+                    color: if color[0] != 0 {
+                        Some((color[1], color[2], color[3]))
+                    } else {
+                        None
+                    },
+                    actor: Actor::parse(&actor).ok_or(LogError::InvalidRecord)?,
+                })
+            }
+            RECORD_CHANGE_LAYER => {
+                let layer = read_str(reader)?;
+                let change = match reader.u8()? {
+                    LAYER_ADD => LayerChange::Add {
+                        hidden: reader.u8()? != 0,
+                    },
+                    LAYER_SET_HIDDEN => LayerChange::SetHidden(reader.u8()? != 0),
+                    LAYER_REMOVE => LayerChange::Remove,
+                    LAYER_CLEAR => {
+                        let has_region = reader.u8()? != 0;
+                        let area = read_rect(reader)?;
+                        LayerChange::Clear(if has_region { Some(area) } else { None })
+                    }
+                    _ => return Err(LogError::InvalidRecord),
+                };
+                Ok(Event::ChangeLayer {
+                    time,
+                    layer,
+                    change,
+                })
+            }
+            RECORD_DRAW => {
+                let layer = read_str(reader)?;
+                let actor = read_str(reader)?;
+                let draw = match reader.u8()? {
+                    DRAW_FILL => Draw::Fill {
+                        rect: read_rect(reader)?,
+                        color: read_rgb(reader)?,
+                    },
+                    DRAW_LINE => Draw::Line {
+                        from: (reader.i32()?, reader.i32()?),
+                        to: (reader.i32()?, reader.i32()?),
+                        color: read_rgb(reader)?,
+                    },
+                    DRAW_FLOOD => Draw::Flood {
+                        x: reader.i32()?,
+                        y: reader.i32()?,
+                        within: read_rect(reader)?,
+                        color: read_rgb(reader)?,
+                    },
+                    DRAW_PASTE => {
+                        let Rect { x, y, w, h } = read_rect(reader)?;
+                        if w as u64 * h as u64 > canvas::MAX_DRAW_PIXELS {
+                            return Err(LogError::InvalidRecord);
+                        }
+                        let data = reader.bytes(w as usize * h as usize * 3)?.to_vec();
+                        Draw::Paste { x, y, w, h, data }
+                    }
+                    _ => return Err(LogError::InvalidRecord),
+                };
+                Ok(Event::Draw {
+                    time,
+                    layer,
+                    draw,
+                    actor: Actor::parse(&actor).ok_or(LogError::InvalidRecord)?,
+                })
+            }
+            _ => Err(LogError::InvalidRecord),
+        }
+    }
+}
+
+/// An event log error.
+#[derive(Debug)]
+pub enum LogError {
+    Io(io::Error),
+    /// The log or its base canvas is damaged.
+    File(FileError),
+    /// The log has no magic number.
+    NotALog,
+    /// A record has an unknown kind or content.
+    InvalidRecord,
+}
+
+impl fmt::Display for LogError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LogError::Io(err) => write!(f, "{}", err),
+            LogError::File(err) => write!(f, "{}", err),
+            LogError::NotALog => write!(f, "not an event log"),
+            LogError::InvalidRecord => write!(f, "invalid record"),
+        }
+    }
+}
+
+impl Error for LogError {}
+
+impl From<io::Error> for LogError {
+    fn from(err: io::Error) -> LogError {
+        LogError::Io(err)
+    }
+}
+
+impl From<FileError> for LogError {
+    fn from(err: FileError) -> LogError {
+        LogError::File(err)
+    }
+}
+
+/// The decoded contents of an event log.
+pub struct LogContents {
+    /// The canvas the log starts from.
+    pub base: Canvas,
+
+    /// Events with the log position right after each one.
+    pub events: Vec<(u64, Event)>,
+
+    /// Length of the valid part of the log.
+    pub len: u64,
+}
+
+impl LogContents {
+    /// Reads and decodes a log file. A truncated last record is dropped.
+    pub fn read(path: &Path) -> Result<LogContents, LogError> {
+        let data = fs::read(path)?;
+        let mut reader = Reader::new(&data);
+
+        if data.len() < MAGIC.len() || reader.bytes(MAGIC.len())? != MAGIC {
+            return Err(LogError::NotALog);
+        }
+        let version = reader.u16()?;
+        if version > VERSION {
+            return Err(FileError::UnsupportedVersion(version).into());
+        }
+        let base_len = reader.u64()? as usize;
+        let base = Canvas::from_file(reader.bytes(base_len)?.to_vec())?;
+
+        let mut events = Vec::new();
+        let mut len = reader.position() as u64;
+        while !reader.is_empty() {
+            match Event::decode(&mut reader) {
+                Ok(event) => {
+                    len = reader.position() as u64;
+                    events.push((len, event));
+                }
+                Err(LogError::File(FileError::Truncated)) => break,
+                Err(err) => return Err(err),
+            }
+        }
+
+        Ok(LogContents { base, events, len })
+    }
+
+    /// Returns the canvas as it was at the given time (UNIX milliseconds).
+    pub fn state_at(&self, time: u64) -> Canvas {
+        let mut canvas = self.base.clone();
+        for (_, event) in &self.events {
+            if event.time() > time {
+                break;
+            }
+            event.apply(&mut canvas);
+        }
+        canvas
+    }
+}
+
+/// An open event log, for appending.
+pub struct EventLog {
+    path: PathBuf,
+    file: BufWriter<File>,
+    len: u64,
+}
+
+impl EventLog {
+    /// Creates a new log starting from the given canvas, replacing any existing file.
+    pub fn create(path: &Path, base: &Canvas) -> Result<EventLog, LogError> {
+        let mut header = Vec::new();
+        header.extend_from_slice(MAGIC);
+        format::push_u16(&mut header, VERSION);
+        let base = base.to_file();
+        format::push_u64(&mut header, base.len() as u64);
+        header.extend_from_slice(&base);
+
+        let mut file = File::create(path)?;
+        file.write_all(&header)?;
+        file.sync_all()?;
+
+        Ok(EventLog {
+            path: path.to_path_buf(),
+            file: BufWriter::new(file),
+            len: header.len() as u64,
+        })
+    }
+
+    /// Opens an existing log for appending, cutting off anything past `valid_len` (see
+    /// `LogContents::len`).
+    pub fn open(path: &Path, valid_len: u64) -> Result<EventLog, LogError> {
+        let file = OpenOptions::new().write(true).open(path)?;
+        file.set_len(valid_len)?;
+        let mut file = BufWriter::new(file);
+        io::Seek::seek(file.get_mut(), io::SeekFrom::End(0))?;
+
+        Ok(EventLog {
+            path: path.to_path_buf(),
+            file,
+            len: valid_len,
+        })
+    }
+
+    /// Returns the log file path.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Returns the position after the last appended event.
+    pub fn position(&self) -> u64 {
+        self.len
+    }
+
+    /// Appends an event (buffered; see `flush`).
+    pub fn append(&mut self, event: &Event) -> io::Result<()> {
+        let mut buf = Vec::with_capacity(64);
+        event.encode(&mut buf);
+        self.file.write_all(&buf)?;
+        self.len += buf.len() as u64;
+        Ok(())
+    }
+
+    /// Writes buffered events to the file.
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+
+    /// Writes buffered events and syncs the file to disk.
+    pub fn sync(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        self.file.get_ref().sync_data()
+    }
+}
+
+/// Opens the log at `path` and brings `canvas` up to date with it.
+///
+/// If the canvas is None, it's rebuilt entirely from the log. If there is no log, a new one is
+/// started from the canvas. Returns the number of replayed events.
+pub fn open_and_replay(
+    path: &Path,
+    canvas: &mut Option<Canvas>,
+) -> Result<(EventLog, usize), LogError> {
+    if !path.exists() {
+        let log = match canvas {
+            Some(canvas) => {
+                let log = EventLog::create(path, canvas)?;
+                canvas.meta.log_position = Some(log.position());
+                log
+            }
+            None => return Err(io::Error::new(io::ErrorKind::NotFound, "no canvas").into()),
+        };
+        return Ok((log, 0));
+    }
+
+    let contents = LogContents::read(path)?;
+    let mut replayed = 0;
+
+    let position = match canvas {
+        Some(canvas) => match canvas.meta.log_position {
+            Some(position) => position,
+            // the canvas predates the log (or the log position was lost); assume it's current
+            None => contents.len,
+        },
+        None => 0,
+    };
+    if canvas.is_none() {
+        *canvas = Some(contents.base.clone());
+    }
+    let canvas = canvas.as_mut().unwrap();
+
+    for (end, event) in &contents.events {
+        if *end > position {
+            event.apply(canvas);
+            replayed += 1;
+        }
+    }
+    canvas.meta.log_position = Some(contents.len);
+
+    Ok((EventLog::open(path, contents.len)?, replayed))
+}
+
+/// Restores the canvas and its layers to their state at the given time by logging and applying
+/// the difference. Returns the number of changed pixels.
+pub fn restore(
+    canvas: &mut Canvas,
+    store: &mut dyn CanvasStore,
+    time: u64,
+) -> Result<usize, StoreError> {
+    let target = store.history()?.state_at(time);
+    restore_to(canvas, store, &target)
+}
+
+/// Makes the canvas and its layers look like the target (such as a snapshot) by logging and
+/// applying the difference. Returns the number of changed pixels.
+pub fn restore_to(
+    canvas: &mut Canvas,
+    store: &mut dyn CanvasStore,
+    target: &Canvas,
+) -> Result<usize, StoreError> {
+    let now = now_millis();
+    let actor = Actor::Server("restore".into());
+
+    if target.extent() != canvas.extent() {
+        let event = Event::SetExtent {
+            time: now,
+            extent: target.extent(),
+        };
+        event.apply(canvas);
+        store.append(&event)?;
+    }
+
+    // layers are restored before their pixels, since removing a layer drops them
+    let target_layers = target.layers();
+    for (name, hidden) in canvas.layers() {
+        let change = match target_layers.iter().find(|(n, _)| *n == name) {
+            None => LayerChange::Remove,
+            Some(&(_, h)) if h != hidden => LayerChange::SetHidden(h),
+            Some(_) => continue,
+        };
+        let event = Event::ChangeLayer {
+            time: now,
+            layer: name,
+            change,
+        };
+        event.apply(canvas);
+        store.append(&event)?;
+    }
+    for (name, hidden) in target_layers {
+        if !canvas.layers().iter().any(|(n, _)| *n == name) {
+            let event = Event::ChangeLayer {
+                time: now,
+                layer: name,
+                change: LayerChange::Add { hidden },
+            };
+            event.apply(canvas);
+            store.append(&event)?;
+        }
+    }
+
+    let mut changed = 0;
+    let mut layers = vec![canvas::USER_LAYER.to_string()];
+    layers.extend(canvas.layers().into_iter().map(|(name, _)| name));
+    for layer in layers {
+        // anything outside allocated tiles is unpainted (or transparent) in both
+        let mut areas = target.layer_tile_rects(&layer);
+        areas.extend(canvas.layer_tile_rects(&layer));
+        let areas: HashSet<_> = areas
+            .into_iter()
+            .map(|area| match target.bounds() {
+                Some(bounds) => area.intersection(&bounds),
+                None => area,
+            })
+            .collect();
+
+        for area in areas {
+            for (x, y) in area.pixels() {
+                let color = target.layer_pixel(&layer, x, y);
+                if canvas.layer_pixel(&layer, x, y) == color {
+                    continue;
+                }
+                let event = match color {
+                    Some((r, g, b)) if layer == canvas::USER_LAYER => Event::SetPixel {
+                        time: now,
+                        x,
+                        y,
+                        r,
+                        g,
+                        b,
+                        actor: actor.clone(),
+                    },
+                    _ => Event::LayerPixel {
+                        time: now,
+                        layer: layer.clone(),
+                        x,
+                        y,
+                        color,
+                        actor: actor.clone(),
+                    },
+                };
+                event.apply(canvas);
+                store.append(&event)?;
+                changed += 1;
+            }
+        }
+    }
+    store.flush()?;
+
+    Ok(changed)
+}
+
+/// Pixels to revert to undo an actor’s placements.
+pub struct RollbackPlan {
+    /// Pixels to change, with the color each one had before the actor touched it.
+    pub changes: Vec<(i32, i32, (u8, u8, u8))>,
+
+    /// Number of pixels the actor placed in the time window.
+    pub touched: usize,
+
+    /// Pixels that have been painted over by someone else since and are left alone.
+    pub skipped: usize,
+}
+
+/// Finds the pixels placed by `actor` since `since` (UNIX milliseconds), optionally only within
+/// a region, along with the color each one had before the actor first touched it. This replays
+/// the whole log.
+pub fn rollback_targets(
+    contents: &LogContents,
+    actor: &Actor,
+    since: u64,
+    region: Option<Rect>,
+) -> HashMap<(i32, i32), (u8, u8, u8)> {
+    let in_region = |x: i32, y: i32| match region {
+        Some(region) => region.contains(x, y),
+        None => true,
+    };
+
+    // replay the log, remembering each pixel’s color before the actor first touched it
+    let mut replay = contents.base.clone();
+    let mut before = HashMap::new();
+    for (_, event) in &contents.events {
+        let touched = match *event {
+            Event::SetPixel {
+                time,
+                x,
+                y,
+                actor: ref event_actor,
+                ..
+            } if time >= since && event_actor == actor => vec![(x, y)],
+            Event::Draw {
+                time,
+                ref layer,
+                ref draw,
+                actor: ref event_actor,
+            } if time >= since && event_actor == actor && layer == canvas::USER_LAYER => replay
+                .draw_pixels(layer, draw)
+                .into_iter()
+                .map(|(x, y, _)| (x, y))
+                .collect(),
+            _ => Vec::new(),
+        };
+        for (x, y) in touched {
+            if in_region(x, y) && replay.contains(x, y) && !before.contains_key(&(x, y)) {
+                before.insert((x, y), replay.pixel(x, y));
+            }
+        }
+        event.apply(&mut replay);
+    }
+    before
+}
+
+/// Works out how to revert the pixels found by `rollback_targets`, leaving alone those that
+/// someone else has painted over since.
+pub fn plan_rollback(
+    targets: &HashMap<(i32, i32), (u8, u8, u8)>,
+    canvas: &Canvas,
+    actor: &Actor,
+) -> RollbackPlan {
+    let mut changes = Vec::new();
+    let mut skipped = 0;
+    for ((x, y), color) in targets {
+        match canvas.provenance(*x, *y) {
+            Some((last_actor, _)) if last_actor == actor => {
+                if canvas.pixel(*x, *y) != *color {
+                    changes.push((*x, *y, *color));
+                }
+            }
+            _ => skipped += 1,
+        }
+    }
+    changes.sort_by_key(|&(x, y, _)| (y, x));
+
+    RollbackPlan {
+        changes,
+        touched: targets.len(),
+        skipped,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use canvas::{Growth, ADMIN_LAYER, USER_LAYER};
+    use std::process;
+
+    fn encoded(event: &Event) -> Vec<u8> {
+        let mut buf = Vec::new();
+        event.encode(&mut buf);
+        buf
+    }
+
+    fn temp_path(name: &str) -> PathBuf {
+        ::std::env::temp_dir().join(format!("place-test-{}-{}", process::id(), name))
+    }
+
+    fn pixel(time: u64, x: i32, y: i32, r: u8) -> Event {
+        Event::SetPixel {
+            time,
+            x,
+            y,
+            r,
+            g: 0,
+            b: 0,
+            actor: Actor::Anonymous("abc".into()),
+        }
+    }
+
+    fn events() -> Vec<Event> {
+        let rect = Rect {
+            x: -3,
+            y: 4,
+            w: 5,
+            h: 6,
+        };
+        let actor = Actor::Login("someone".into());
+        vec![
+            pixel(1, -1, 2, 3),
+            Event::SetPalette {
+                time: 3,
+                palette: Some(Palette {
+                    colors: vec![(0, 0, 0), (255, 255, 255)],
+                    snap: true,
+                }),
+            },
+            Event::SetPalette {
+                time: 4,
+                palette: None,
+            },
+            Event::SetExtent {
+                time: 5,
+                extent: Extent::Infinite {
+                    boundary: Some(rect),
+                    growth: Some(Growth {
+                        step: 2,
+                        interval: 60_000,
+                    }),
+                },
+            },
+            Event::Resize {
+                time: 6,
+                extent: Extent::Finite {
+                    width: 20,
+                    height: 30,
+                },
+                dx: -4,
+                dy: 7,
+                fill: (1, 2, 3),
+            },
+            Event::LayerPixel {
+                time: 7,
+                layer: ADMIN_LAYER.into(),
+                x: 1,
+                y: 2,
+                color: Some((4, 5, 6)),
+                actor: actor.clone(),
+            },
+            Event::LayerPixel {
+                time: 8,
+                layer: ADMIN_LAYER.into(),
+                x: 1,
+                y: 2,
+                color: None,
+                actor: Actor::Server("restore".into()),
+            },
+            Event::ChangeLayer {
+                time: 9,
+                layer: "sketch".into(),
+                change: LayerChange::Add { hidden: true },
+            },
+            Event::ChangeLayer {
+                time: 10,
+                layer: "sketch".into(),
+                change: LayerChange::Clear(Some(rect)),
+            },
+            Event::Draw {
+                time: 11,
+                layer: USER_LAYER.into(),
+                draw: Draw::Fill {
+                    rect,
+                    color: (7, 8, 9),
+                },
+                actor: actor.clone(),
+            },
+            Event::Draw {
+                time: 12,
+                layer: USER_LAYER.into(),
+                draw: Draw::Paste {
+                    x: 1,
+                    y: 1,
+                    w: 2,
+                    h: 1,
+                    data: vec![1, 2, 3, 4, 5, 6],
+                },
+                actor,
+            },
+        ]
+    }
+
+    #[test]
+    fn events_round_trip() {
+        for event in events() {
+            let data = encoded(&event);
+            let mut reader = Reader::new(&data);
+            let decoded = Event::decode(&mut reader).unwrap();
+            assert!(reader.is_empty(), "{:?} left data", event);
+            assert_eq!(decoded.time(), event.time());
+            assert_eq!(encoded(&decoded), data, "{:?} decoded as {:?}", event, decoded);
+        }
+    }
+
+    #[test]
+    fn truncated_events_are_detected() {
+        for event in events() {
+            let data = encoded(&event);
+            let mut reader = Reader::new(&data[..data.len() - 1]);
+            match Event::decode(&mut reader) {
+                Err(LogError::File(FileError::Truncated)) => (),
+                other => panic!("{:?} decoded as {:?}", event, other),
+            }
+        }
+    }
+
+    #[test]
+    fn logs_replay_onto_checkpoints() {
+        let path = temp_path("replay.log");
+        let _ = fs::remove_file(&path);
+
+        let mut canvas = Some(Canvas::blank(16, 16));
+        let (mut log, replayed) = open_and_replay(&path, &mut canvas).unwrap();
+        assert_eq!(replayed, 0);
+        let checkpoint = canvas.unwrap();
+
+        let events = vec![
+            pixel(1_000, 1, 1, 10),
+            pixel(2_000, 2, 2, 20),
+            Event::Resize {
+                time: 3_000,
+                extent: Extent::Finite {
+                    width: 20,
+                    height: 20,
+                },
+                dx: 0,
+                dy: 0,
+                fill: (255, 255, 255),
+            },
+            Event::LayerPixel {
+                time: 4_000,
+                layer: ADMIN_LAYER.into(),
+                x: 18,
+                y: 18,
+                color: Some((1, 2, 3)),
+                actor: Actor::Login("admin".into()),
+            },
+        ];
+        for event in &events {
+            log.append(event).unwrap();
+        }
+        log.sync().unwrap();
+        drop(log);
+
+        let mut canvas = Some(checkpoint.clone());
+        let (_, replayed) = open_and_replay(&path, &mut canvas).unwrap();
+        let canvas = canvas.unwrap();
+        assert_eq!(replayed, events.len());
+        assert_eq!(canvas.pixel(1, 1), (10, 0, 0));
+        assert_eq!(canvas.pixel(2, 2), (20, 0, 0));
+        assert!(canvas.contains(19, 19));
+        assert_eq!(canvas.layer_pixel(ADMIN_LAYER, 18, 18), Some((1, 2, 3)));
+
+        // an up-to-date canvas gets nothing, and none at all gets everything
+        let mut current = Some(canvas.clone());
+        assert_eq!(open_and_replay(&path, &mut current).unwrap().1, 0);
+        let mut rebuilt = None;
+        assert_eq!(open_and_replay(&path, &mut rebuilt).unwrap().1, events.len());
+        assert!(rebuilt.unwrap().diff(&canvas).is_empty());
+
+        let contents = LogContents::read(&path).unwrap();
+        let earlier = contents.state_at(1_500);
+        assert_eq!(earlier.pixel(1, 1), (10, 0, 0));
+        assert_eq!(earlier.pixel(2, 2), (255, 255, 255));
+        assert!(!earlier.contains(19, 19));
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn torn_records_are_cut_off() {
+        let path = temp_path("torn.log");
+        let _ = fs::remove_file(&path);
+        let mut log = EventLog::create(&path, &Canvas::blank(4, 4)).unwrap();
+        log.append(&pixel(1_000, 1, 1, 10)).unwrap();
+        log.append(&pixel(2_000, 2, 2, 20)).unwrap();
+        log.sync().unwrap();
+        let len = log.position();
+        drop(log);
+
+        let file = OpenOptions::new().write(true).open(&path).unwrap();
+        file.set_len(len - 2).unwrap();
+        let contents = LogContents::read(&path).unwrap();
+        assert_eq!(contents.events.len(), 1);
+        assert!(contents.len < len - 2);
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn times_parse() {
+        assert_eq!(parse_time("1500000000", 0), Some(1_500_000_000_000));
+        assert_eq!(parse_time("-15m", 1_000_000), Some(100_000));
+        assert_eq!(parse_time("99999999999999999999", 0), None);
+        assert_eq!(parse_time("18446744073709552", 0), None);
+    }
+
+    #[test]
+    fn rollbacks_skip_pixels_painted_over() {
+        let vandal = Actor::Anonymous("vandal".into());
+        let fixer = Actor::Login("fixer".into());
+        let base = Canvas::blank(4, 4);
+        let blank = base.pixel(0, 0);
+        let place = |time, x, y, (r, g, b), actor: &Actor| Event::SetPixel {
+            time,
+            x,
+            y,
+            r,
+            g,
+            b,
+            actor: actor.clone(),
+        };
+        let events = vec![
+            place(500, 0, 0, (1, 0, 0), &vandal),
+            place(1_000, 2, 1, (1, 0, 0), &vandal),
+            place(1_000, 1, 1, (1, 0, 0), &vandal),
+            place(1_000, 2, 2, (1, 0, 0), &vandal),
+            place(2_000, 2, 2, (2, 0, 0), &fixer),
+            place(3_000, 3, 3, blank, &vandal),
+        ];
+        let contents = LogContents {
+            base,
+            len: events.len() as u64,
+            events: events.into_iter().enumerate().map(|(i, e)| (i as u64, e)).collect(),
+        };
+        let canvas = contents.state_at(3_000);
+
+        let targets = rollback_targets(&contents, &vandal, 1_000, None);
+        let plan = plan_rollback(&targets, &canvas, &vandal);
+        // sorted by row, then column
+        assert_eq!(plan.changes, vec![(1, 1, blank), (2, 1, blank)]);
+        assert_eq!(plan.touched, 4);
+        assert_eq!(plan.skipped, 1);
+
+        let region = Rect {
+            x: 2,
+            y: 0,
+            w: 2,
+            h: 4,
+        };
+        let targets = rollback_targets(&contents, &vandal, 1_000, Some(region));
+        let plan = plan_rollback(&targets, &canvas, &vandal);
+        assert_eq!(plan.changes, vec![(2, 1, blank)]);
+        assert_eq!(plan.touched, 3);
+    }
+}
@@ -0,0 +1,252 @@
+//! Callbacks for programs embedding the server, for house rules, bots and integrations.
+//!
+//! Hooks are registered with `ServerBuilder::hook` and called in the order they were added.
+//! Everything except `on_connect` and `on_disconnect` runs on the update thread of the canvas,
+//! `on_set_pixel`, `on_pixel_placed` and `on_chat_message` while the server state is locked: a
+//! slow hook there holds up every canvas, and one that uses the `ServerHandle` never returns.
+//!
+//! A hook that panics doesn’t take the server down. The panic is logged, and the pixel, chat
+//! message or connection it was asked about is rejected (`on_tick` places nothing).
+
+use canvas::Canvas;
+use history::Actor;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Arc;
+
+/// What pixels from `on_tick` are attributed to, as `Actor::Server`.
+pub const ACTOR: &str = "hooks";
+
+/// A pixel on the user layer.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Pixel {
+    pub x: i32,
+    pub y: i32,
+    pub color: (u8, u8, u8),
+}
+
+/// What to do with a pixel a user wants to place.
+#[derive(Debug, Clone, PartialEq)]
+pub enum PixelAction {
+    Allow,
+    /// Rejects the pixel, telling the user why.
+    Deny(String),
+    /// Places a different pixel instead, which is still subject to locks and the palette.
+    Rewrite(Pixel),
+}
+
+/// What to do with a chat message.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ChatAction {
+    Allow,
+    /// Drops the message silently.
+    Drop,
+    /// Sends different text instead.
+    Replace(String),
+}
+
+/// Gets told about things happening on the server, and may change some of them. Every method
+/// does nothing by default.
+pub trait Hooks: Send + Sync {
+    /// Called when a client connects to the canvas with the given name, before it gets the
+    /// canvas. Returning false disconnects it.
+    fn on_connect(&self, canvas: &str, id: u64, addr: &str) -> bool {
+        let _ = (canvas, id, addr);
+        true
+    }
+
+    /// Called when a connected client goes away.
+    fn on_disconnect(&self, canvas: &str, id: u64) {
+        let _ = (canvas, id);
+    }
+
+    /// Called when a user wants to place a pixel inside the canvas. Hooks after one that
+    /// rewrote the pixel see the new one, and none are called after one that denied it.
+    fn on_set_pixel(&self, canvas: &str, pixel: Pixel, actor: &Actor) -> PixelAction {
+        let _ = (canvas, pixel, actor);
+        PixelAction::Allow
+    }
+
+    /// Called after a user placed a pixel on the canvas with the given name.
+    fn on_pixel_placed(&self, canvas: &str, x: i32, y: i32, color: (u8, u8, u8), actor: &Actor) {
+        let _ = (canvas, x, y, color, actor);
+    }
+
+    /// Called when someone sends a chat message, with surrounding whitespace removed.
+    fn on_chat_message(&self, canvas: &str, text: &str, actor: &Actor) -> ChatAction {
+        let _ = (canvas, text, actor);
+        ChatAction::Allow
+    }
+
+    /// Called about once per tick while the canvas is busy, and at least every five seconds
+    /// otherwise. Returns pixels to place as the server, e.g. for a bot. They are checked like
+    /// pixels from users (by `on_set_pixel` of every hook, locks, the admin layer and the
+    /// palette) but not rate limited, and rejected ones are dropped.
+    fn on_tick(&self, canvas: &str, state: &Canvas) -> Vec<Pixel> {
+        let _ = (canvas, state);
+        Vec::new()
+    }
+}
+
+/// Calls a hook, returning None if it panicked. The panic itself is printed by the panic hook.
+fn call<T, F: FnOnce() -> T>(method: &str, f: F) -> Option<T> {
+    let result = panic::catch_unwind(AssertUnwindSafe(f)).ok();
+    if result.is_none() {
+        eprintln!("A hook panicked in {}", method);
+    }
+    result
+}
+
+/// Runs the `on_connect` hooks. Returns whether the client may connect.
+pub fn check_connect(hooks: &[Arc<dyn Hooks>], canvas: &str, id: u64, addr: &str) -> bool {
+    hooks
+        .iter()
+        .all(|hook| call("on_connect", || hook.on_connect(canvas, id, addr)).unwrap_or(false))
+}
+
+/// Runs the `on_disconnect` hooks.
+pub fn disconnected(hooks: &[Arc<dyn Hooks>], canvas: &str, id: u64) {
+    for hook in hooks {
+        call("on_disconnect", || hook.on_disconnect(canvas, id));
+    }
+}
+
+/// Runs the `on_set_pixel` hooks. Returns the pixel to place, or why it was denied.
+pub fn check_pixel(
+    hooks: &[Arc<dyn Hooks>],
+    canvas: &str,
+    mut pixel: Pixel,
+    actor: &Actor,
+) -> Result<Pixel, String> {
+    for hook in hooks {
+        match call("on_set_pixel", || hook.on_set_pixel(canvas, pixel, actor)) {
+            Some(PixelAction::Allow) => (),
+            Some(PixelAction::Deny(reason)) => return Err(reason),
+            Some(PixelAction::Rewrite(rewritten)) => pixel = rewritten,
+            None => return Err("Pixel rejected".into()),
+        }
+    }
+    Ok(pixel)
+}
+
+/// Runs the `on_pixel_placed` hooks.
+pub fn pixel_placed(hooks: &[Arc<dyn Hooks>], canvas: &str, pixel: Pixel, actor: &Actor) {
+    for hook in hooks {
+        call("on_pixel_placed", || {
+            hook.on_pixel_placed(canvas, pixel.x, pixel.y, pixel.color, actor)
+        });
+    }
+}
+
+/// Runs the `on_chat_message` hooks. Returns the text to send, if any.
+pub fn check_chat(
+    hooks: &[Arc<dyn Hooks>],
+    canvas: &str,
+    mut text: String,
+    actor: &Actor,
+) -> Option<String> {
+    for hook in hooks {
+        match call("on_chat_message", || hook.on_chat_message(canvas, &text, actor))? {
+            ChatAction::Allow => (),
+            ChatAction::Drop => return None,
+            ChatAction::Replace(replacement) => text = replacement,
+        }
+    }
+    Some(text)
+}
+
+/// Runs the `on_tick` hooks. Returns the pixels they want to place, in order.
+pub fn tick(hooks: &[Arc<dyn Hooks>], canvas: &str, state: &Canvas) -> Vec<Pixel> {
+    hooks
+        .iter()
+        .flat_map(|hook| call("on_tick", || hook.on_tick(canvas, state)).unwrap_or_default())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Allows pixels at x = 0, denies them at x = 1, moves them from x = 2 to x = 0 and panics
+    /// about the rest. Chat messages and ticks work the same way.
+    struct Rules;
+
+    impl Hooks for Rules {
+        fn on_set_pixel(&self, _: &str, pixel: Pixel, _: &Actor) -> PixelAction {
+            match pixel.x {
+                0 => PixelAction::Allow,
+                1 => PixelAction::Deny("no".into()),
+                2 => PixelAction::Rewrite(Pixel { x: 0, ..pixel }),
+                _ => panic!("unexpected pixel"),
+            }
+        }
+
+        fn on_chat_message(&self, _: &str, text: &str, _: &Actor) -> ChatAction {
+            match text {
+                "hi" => ChatAction::Allow,
+                "spam" => ChatAction::Drop,
+                "heck" => ChatAction::Replace("hi".into()),
+                _ => panic!("unexpected message"),
+            }
+        }
+
+        fn on_tick(&self, _: &str, _: &Canvas) -> Vec<Pixel> {
+            vec![pixel(0)]
+        }
+    }
+
+    /// Panics about everything.
+    struct Panics;
+
+    impl Hooks for Panics {
+        fn on_connect(&self, _: &str, _: u64, _: &str) -> bool {
+            panic!("on_connect")
+        }
+
+        fn on_tick(&self, _: &str, _: &Canvas) -> Vec<Pixel> {
+            panic!("on_tick")
+        }
+    }
+
+    fn pixel(x: i32) -> Pixel {
+        Pixel {
+            x,
+            y: 5,
+            color: (1, 2, 3),
+        }
+    }
+
+    fn rules() -> Vec<Arc<dyn Hooks>> {
+        vec![Arc::new(Rules), Arc::new(Rules)]
+    }
+
+    #[test]
+    fn pixels_are_allowed_denied_or_rewritten() {
+        let actor = Actor::Anonymous("someone".into());
+        assert_eq!(check_pixel(&rules(), "", pixel(0), &actor), Ok(pixel(0)));
+        assert_eq!(check_pixel(&rules(), "", pixel(1), &actor), Err("no".into()));
+        // the second hook sees the rewritten pixel
+        assert_eq!(check_pixel(&rules(), "", pixel(2), &actor), Ok(pixel(0)));
+        assert_eq!(
+            check_pixel(&rules(), "", pixel(3), &actor),
+            Err("Pixel rejected".into())
+        );
+    }
+
+    #[test]
+    fn chat_messages_are_allowed_dropped_or_replaced() {
+        let actor = Actor::Anonymous("someone".into());
+        let check = |text: &str| check_chat(&rules(), "", text.into(), &actor);
+        assert_eq!(check("hi"), Some("hi".into()));
+        assert_eq!(check("spam"), None);
+        assert_eq!(check("heck"), Some("hi".into()));
+        assert_eq!(check("what"), None);
+    }
+
+    #[test]
+    fn ticks_collect_pixels_from_every_hook() {
+        let hooks: Vec<Arc<dyn Hooks>> = vec![Arc::new(Rules), Arc::new(Panics), Arc::new(Rules)];
+        assert_eq!(tick(&hooks, "", &Canvas::blank(1, 1)), vec![pixel(0), pixel(0)]);
+        assert!(!check_connect(&hooks, "", 1, "127.0.0.1"));
+        assert!(check_connect(&rules(), "", 1, "127.0.0.1"));
+    }
+}
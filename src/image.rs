@@ -0,0 +1,173 @@
+//! PNG export of canvas regions and import of images.
+
+use canvas::{self, Canvas, Draw, Extent, Region};
+use history::{Actor, Event};
+use png;
+use std::fmt;
+
+/// The most pixels an exported image may have, after scaling.
+pub const MAX_EXPORT_PIXELS: u64 = 1 << 24;
+
+/// An image with RGB data.
+#[derive(Debug, Clone)]
+pub struct Image {
+    pub width: u32,
+    pub height: u32,
+    pub data: Vec<u8>,
+}
+
+/// An error encountered while decoding an image.
+#[derive(Debug)]
+pub enum ImageError {
+    Png(png::DecodingError),
+    /// The image has more pixels than allowed.
+    TooLarge {
+        width: u32,
+        height: u32,
+    },
+}
+
+impl fmt::Display for ImageError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ImageError::Png(err) => write!(f, "{}", err),
+            ImageError::TooLarge { width, height } => {
+                write!(f, "the image is too large ({}×{})", width, height)
+            }
+        }
+    }
+}
+
+impl From<png::DecodingError> for ImageError {
+    fn from(err: png::DecodingError) -> ImageError {
+        ImageError::Png(err)
+    }
+}
+
+/// Returns the RGB data of a region with each pixel scaled up to a `scale` × `scale` square.
+pub fn scale_rgb(region: &Region, scale: u32) -> Vec<u8> {
+    let (w, h, scale) = (region.w as usize, region.h as usize, scale.max(1) as usize);
+    if scale == 1 {
+        return region.data.clone();
+    }
+    let mut data = Vec::with_capacity(w * h * scale * scale * 3);
+    for row in region.data.chunks(w * 3) {
+        let start = data.len();
+        for pixel in row.chunks(3) {
+            for _ in 0..scale {
+                data.extend_from_slice(pixel);
+            }
+        }
+        let line = data[start..].to_vec();
+        for _ in 1..scale {
+            data.extend_from_slice(&line);
+        }
+    }
+    data
+}
+
+/// Encodes a region as an RGB PNG, scaling each pixel up to a `scale` × `scale` square.
+pub fn encode_png(region: &Region, scale: u32) -> Vec<u8> {
+    let data = scale_rgb(region, scale);
+    let scale = scale.max(1);
+    let mut file = Vec::new();
+    {
+        let mut encoder = png::Encoder::new(&mut file, region.w * scale, region.h * scale);
+        encoder.set_color(png::ColorType::Rgb);
+        encoder.set_depth(png::BitDepth::Eight);
+        // writing to a Vec can’t fail
+        let mut writer = encoder.write_header().expect("failed to write PNG header");
+        writer
+            .write_image_data(&data)
+            .expect("failed to write PNG data");
+    }
+    file
+}
+
+/// Decodes a PNG with at most `max_pixels` pixels. Transparent pixels are drawn over white.
+pub fn decode_png(file: &[u8], max_pixels: u64) -> Result<Image, ImageError> {
+    let mut decoder = png::Decoder::new(file);
+    decoder.set_transformations(png::Transformations::normalize_to_color8());
+    let mut reader = decoder.read_info()?;
+    let (width, height) = (reader.info().width, reader.info().height);
+    if width as u64 * height as u64 > max_pixels {
+        return Err(ImageError::TooLarge { width, height });
+    }
+
+    let mut buf = vec![0; reader.output_buffer_size()];
+    let frame = reader.next_frame(&mut buf)?;
+    let over_white = |v: u8, alpha: u8| {
+        let alpha = alpha as u32;
+        ((v as u32 * alpha + 255 * (255 - alpha)) / 255) as u8
+    };
+    let mut data = Vec::with_capacity(width as usize * height as usize * 3);
+    for row in buf.chunks(frame.line_size).take(height as usize) {
+        let row = &row[..width as usize * frame.color_type.samples()];
+        match frame.color_type {
+            png::ColorType::Grayscale => {
+                for &v in row {
+                    data.extend_from_slice(&[v, v, v]);
+                }
+            }
+            png::ColorType::GrayscaleAlpha => {
+                for p in row.chunks(2) {
+                    let v = over_white(p[0], p[1]);
+                    data.extend_from_slice(&[v, v, v]);
+                }
+            }
+            png::ColorType::Rgba => {
+                for p in row.chunks(4) {
+                    for &v in &p[..3] {
+                        data.push(over_white(v, p[3]));
+                    }
+                }
+            }
+            // indexed images are expanded to RGB(A)
+            png::ColorType::Rgb | png::ColorType::Indexed => data.extend_from_slice(row),
+        }
+    }
+    Ok(Image {
+        width,
+        height,
+        data,
+    })
+}
+
+/// Returns the events that import an image, either pasted with its top left corner at a point or
+/// replacing the canvas, which is resized to fit.
+pub fn import_events(
+    canvas: &Canvas,
+    image: Image,
+    at: Option<(i32, i32)>,
+    actor: Actor,
+    time: u64,
+) -> Vec<Event> {
+    let mut events = Vec::new();
+    let (x, y) = at.unwrap_or((0, 0));
+    let extent = Extent::Finite {
+        width: image.width,
+        height: image.height,
+    };
+    if at.is_none() && canvas.extent() != extent {
+        events.push(Event::Resize {
+            time,
+            extent,
+            dx: 0,
+            dy: 0,
+            fill: (255, 255, 255),
+        });
+    }
+    events.push(Event::Draw {
+        time,
+        layer: canvas::USER_LAYER.into(),
+        draw: Draw::Paste {
+            x,
+            y,
+            w: image.width,
+            h: image.height,
+            data: image.data,
+        },
+        actor,
+    });
+    events
+}
@@ -0,0 +1,2292 @@
+//! A shared canvas that anyone can paint on, served over WebSockets.
+//!
+//! The `place` binary runs it from the command line; other programs can embed it with
+//! [`Server`](struct.Server.html).
+
+extern crate ws;
+#[macro_use]
+extern crate serde_derive;
+extern crate base64;
+extern crate crypto_hash;
+extern crate png;
+extern crate serde;
+extern crate serde_json;
+extern crate toml;
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Mutex, Weak};
+use std::{fs, io, thread, time};
+
+pub mod canvas;
+pub mod cli;
+pub mod config;
+mod console;
+mod exports;
+mod format;
+pub mod history;
+mod hooks;
+mod image;
+pub mod locks;
+mod login;
+pub mod messages;
+mod outbox;
+mod ratelimit;
+mod rooms;
+mod server;
+pub mod snapshots;
+mod storage;
+pub mod store;
+mod timelapse;
+
+pub use canvas::Canvas;
+pub use config::Config;
+pub use hooks::{ChatAction, Hooks, Pixel, PixelAction};
+pub use login::{AuthProvider, Logins};
+pub use ratelimit::RateLimits;
+pub use server::{Server, ServerBuilder, ServerError, ServerHandle};
+pub use store::{CanvasStore, Storage, StoreFactory};
+
+use canvas::{Draw, Extent, LayerChange, Palette, Rect, Region, Resize};
+use crypto_hash::{hex_digest, Algorithm};
+use exports::Exports;
+use history::{Actor, Event, LogContents};
+use image::Image;
+use locks::{Lock, LockPolicy, Locks};
+use messages::{CanvasUpdate, ClientMessage, ClientRequest, Outgoing, PixelRequest, RGBARegion};
+use outbox::{Outbox, Receipts};
+use ratelimit::ConnLimiter;
+use rooms::Room;
+use snapshots::{Policy, Snapshots};
+use store::{Job, StoreError};
+
+/// The largest viewport width and height clients get updates for.
+const MAX_VIEWPORT_SIZE: u32 = 4096;
+
+/// The largest scale factor of exported images.
+const MAX_EXPORT_SCALE: u32 = 32;
+
+/// How many timelapses may be streamed at once.
+const MAX_TIMELAPSE_STREAMS: usize = 4;
+
+/// Returns a duration in whole milliseconds, rounded up.
+fn duration_millis(duration: time::Duration) -> u64 {
+    duration.as_secs() * 1000 + (duration.subsec_nanos() as u64).div_ceil(1_000_000)
+}
+
+/// Describes a canvas extent for humans.
+pub fn describe_extent(extent: Extent) -> String {
+    match extent {
+        Extent::Finite { width, height } => format!("{}×{}", width, height),
+        Extent::Infinite { boundary: None, .. } => "infinite".into(),
+        Extent::Infinite {
+            boundary: Some(b),
+            growth,
+        } => {
+            let growth = match growth {
+                Some(g) => format!(", growing by {} px every {} s", g.step, g.interval / 1000),
+                None => String::new(),
+            };
+            format!("infinite ({},{} {}×{}{})", b.x, b.y, b.w, b.h, growth)
+        }
+    }
+}
+
+/// Describes a lock’s area and policy for the console.
+fn describe_lock(lock: &Lock) -> String {
+    let who = match lock.policy {
+        LockPolicy::Everyone => "for everyone".to_string(),
+        LockPolicy::Admins => "admins only".to_string(),
+        LockPolicy::Logins(ref logins) => format!("only {}", logins.join(", ")),
+    };
+    let rect = lock.rect;
+    format!("{},{} {}×{}, {}", rect.x, rect.y, rect.w, rect.h, who)
+}
+
+/// Lists snapshots and the policy for the console.
+fn list_snapshots(snapshots: &Snapshots, store: &dyn CanvasStore) -> String {
+    let mut lines = vec![describe_snapshot_policy(snapshots.policy())];
+    match store.snapshots() {
+        Ok(ref times) if times.is_empty() => lines.push("No snapshots".into()),
+        Ok(times) => {
+            let now = history::now_millis();
+            for time in times {
+                let age = history::format_duration(now.saturating_sub(time * 1000));
+                lines.push(format!("{} ({} ago)", time, age));
+            }
+        }
+        Err(err) => lines.push(format!("Failed to list snapshots: {}", err)),
+    }
+    lines.join("\n")
+}
+
+/// Describes when snapshots are taken and how long they are kept.
+fn describe_snapshot_policy(policy: &Policy) -> String {
+    let every = match policy.interval {
+        Some(interval) => format!("Snapshots every {}", history::format_duration(interval)),
+        None => "No automatic snapshots".to_string(),
+    };
+    let keep: Vec<_> = policy
+        .keep
+        .iter()
+        .map(|tier| {
+            format!(
+                "one per {} for {}",
+                history::format_duration(tier.every),
+                history::format_duration(tier.keep_for)
+            )
+        })
+        .collect();
+    if keep.is_empty() {
+        format!("{}, keeping only the newest", every)
+    } else {
+        format!("{}, keeping {}", every, keep.join(", then "))
+    }
+}
+
+/// Describes the rate limits of a canvas.
+fn describe_rate_limits(limits: &RateLimits) -> String {
+    format!(
+        "cooldown {} ms, per connection {} burst at {}/s, per IP {} burst at {}/s",
+        limits.cooldown.as_secs() * 1000 + limits.cooldown.subsec_millis() as u64,
+        limits.conn_burst,
+        limits.conn_rate,
+        limits.ip_burst,
+        limits.ip_rate
+    )
+}
+
+/// Summarizes changed pixels as a count and the areas they are in.
+fn describe_diff(pixels: &[(i32, i32)]) -> String {
+    const MAX_AREAS: usize = 50;
+    if pixels.is_empty() {
+        return "No changes".into();
+    }
+    let areas = canvas::changed_areas(pixels);
+    let mut lines = vec![format!(
+        "{} pixels changed in {} areas",
+        pixels.len(),
+        areas.len()
+    )];
+    for area in areas.iter().take(MAX_AREAS) {
+        let count = pixels.iter().filter(|&&(x, y)| area.contains(x, y)).count();
+        lines.push(format!(
+            "{},{} {}×{}: {} pixels",
+            area.x, area.y, area.w, area.h, count
+        ));
+    }
+    if areas.len() > MAX_AREAS {
+        lines.push(format!("… and {} more", areas.len() - MAX_AREAS));
+    }
+    lines.join("\n")
+}
+
+/// Tells a client that a pixel is inside a lock it may not paint in.
+fn region_locked(lock: &Lock) -> ClientMessage {
+    ClientMessage::Error {
+        code: "region-locked".into(),
+        message: format!("That area is locked ({})", lock.name),
+    }
+}
+
+/// Places a pixel on the user layer if the hooks, the locks, the admin layer and the palette
+/// allow it, and tells the hooks. Returns whether it was placed (pixels outside the canvas are
+/// ignored), or the error to send to whoever placed it.
+fn place_pixel(
+    canvas: &mut Canvas,
+    locks: &Locks,
+    store: &mut dyn CanvasStore,
+    hooks: &[Arc<dyn Hooks>],
+    name: &str,
+    pixel: Pixel,
+    actor: &Actor,
+) -> Result<bool, ClientMessage> {
+    if !canvas.contains(pixel.x, pixel.y) {
+        return Ok(false);
+    }
+    let Pixel { x, y, color } = match hooks::check_pixel(hooks, name, pixel, actor) {
+        Ok(pixel) => pixel,
+        Err(reason) => {
+            return Err(ClientMessage::Error {
+                code: "denied".into(),
+                message: reason,
+            })
+        }
+    };
+    if !canvas.contains(x, y) {
+        return Ok(false);
+    }
+    if let Some(lock) = locks.blocking(x, y, actor) {
+        return Err(region_locked(lock));
+    }
+    if canvas.is_covered(x, y) {
+        return Err(ClientMessage::Error {
+            code: "protected".into(),
+            message: "That pixel is protected".into(),
+        });
+    }
+    let (r, g, b) = match canvas.resolve_color(color.0, color.1, color.2) {
+        Some(color) => color,
+        None => {
+            return Err(ClientMessage::Error {
+                code: "color-not-in-palette".into(),
+                message: "That color is not in the palette".into(),
+            })
+        }
+    };
+    let event = Event::SetPixel {
+        time: history::now_millis(),
+        x,
+        y,
+        r,
+        g,
+        b,
+        actor: actor.clone(),
+    };
+    event.apply(canvas);
+    if let Err(err) = store.append(&event) {
+        eprintln!("Failed to write to canvas.log: {:?}", err);
+    }
+    let placed = Pixel {
+        x,
+        y,
+        color: (r, g, b),
+    };
+    hooks::pixel_placed(hooks, name, placed, actor);
+    Ok(true)
+}
+
+fn save_locks(locks: &Locks, store: &mut dyn CanvasStore) {
+    if let Err(err) = locks.save(store) {
+        eprintln!("Failed to save the locks: {}", err);
+    }
+}
+
+/// Returns the full update, making it again if the canvas changed since. Returns None for
+/// infinite and very large canvases, which clients fetch by viewport instead.
+fn cached_full_update<'a>(
+    cache: &'a mut Option<(u64, Option<Outgoing>)>,
+    canvas: &Canvas,
+) -> Option<&'a Outgoing> {
+    let revision = canvas.revision();
+    if cache.as_ref().is_none_or(|(r, _)| *r != revision) {
+        *cache = Some((revision, CanvasUpdate::full(canvas).map(Outgoing::from)));
+    }
+    cache.as_ref().and_then(|(_, update)| update.as_ref())
+}
+
+/// Queues everything a client needs to show the canvas but the canvas itself: the palette, the
+/// bounds and the locks. The client is marked as synced if it gets the whole canvas, which the
+/// caller queues once it’s encoded; otherwise, it has to send its viewport.
+fn send_canvas(client: &mut ClientSender, canvas: &Canvas, locks: &Locks) {
+    client.outbox.push(ClientMessage::from(canvas.palette()));
+    client.outbox.push(ClientMessage::from(canvas));
+    client.outbox.push(ClientMessage::from(locks));
+    client.synced = CanvasUpdate::is_sent_whole(canvas);
+}
+
+/// Reads the history on a worker thread, since that can take a while, and sends the message
+/// `then` makes of it back to the update thread. Errors are reported to `out`.
+fn with_history<F>(
+    store: &mut dyn CanvasStore,
+    update_tx: &mpsc::Sender<UpdateMsg>,
+    out: ws::Sender,
+    then: F,
+) where
+    F: FnOnce(LogContents, ws::Sender) -> UpdateMsg + Send + 'static,
+{
+    let report = |out: &ws::Sender, err: StoreError| {
+        let line = format!("Failed to read the history: {}", err);
+        let _ = out.send(ClientMessage::Console(line));
+    };
+    let read = match store.read_history() {
+        Ok(read) => read,
+        Err(err) => return report(&out, err),
+    };
+    let update_tx = update_tx.clone();
+    thread::spawn(move || match read() {
+        Ok(contents) => {
+            let _ = update_tx.send(then(contents, out));
+        }
+        Err(err) => report(&out, err),
+    });
+}
+
+/// Writes a prepared save; errors are logged. Returns false if saving failed.
+fn run_save(job: Job<()>) -> bool {
+    match job() {
+        Ok(_) => true,
+        Err(err) => {
+            eprintln!("Failed to save! {:?}", err);
+            false
+        }
+    }
+}
+
+/// Writes a snapshot taken at time `now` (UNIX milliseconds) on a worker thread, after the
+/// previous one is written. The outcome is reported to `out`, or only logged if it failed and
+/// there is nobody to tell.
+fn take_snapshot(
+    snapshots: &mut Snapshots,
+    store: &mut dyn CanvasStore,
+    canvas: &Canvas,
+    now: u64,
+    snapshot_thread: &mut Option<thread::JoinHandle<()>>,
+    out: Option<ws::Sender>,
+) {
+    let report = move |result: Result<u64, StoreError>| match (result, out) {
+        (Ok(time), Some(out)) => {
+            let _ = out.send(ClientMessage::Console(format!("Took snapshot {}", time)));
+        }
+        (Err(err), Some(out)) => {
+            let line = format!("Failed to take a snapshot: {}", err);
+            let _ = out.send(ClientMessage::Console(line));
+        }
+        (Err(err), None) => eprintln!("Failed to take a snapshot: {}", err),
+        (Ok(_), None) => (),
+    };
+    let (time, job) = match snapshots.take(store, canvas, now) {
+        Ok(prepared) => prepared,
+        Err(err) => return report(Err(err)),
+    };
+    let prev = snapshot_thread.take();
+    *snapshot_thread = Some(thread::spawn(move || {
+        if let Some(prev) = prev {
+            let _ = prev.join();
+        }
+        report(job().map(|_| time));
+    }));
+}
+
+/// Loads the canvas with the given name and its locks, and starts serving it on its own update
+/// thread. Returns why it couldn’t be loaded otherwise.
+fn open_room(
+    global_lock: &Arc<Mutex<GlobalState>>,
+    name: &str,
+    mut store: Box<dyn CanvasStore>,
+) -> Result<(), String> {
+    let config = Arc::clone(&global_lock.lock().unwrap().config);
+    let failed = |err: String| format!("Failed to load {}: {}", rooms::display_name(name), err);
+    let canvas = store
+        .load(config.default_size)
+        .map_err(|err| failed(err.to_string()))?;
+    let locks = Locks::load(&*store)
+        .map_err(|err| failed(format!("Failed to read the locks: {}", err)))?;
+
+    let (update_tx, update_rx) = mpsc::channel();
+    let rate_limits = match store.rate_limits() {
+        Ok(limits) => limits.unwrap_or(config.rate_limits),
+        Err(err) => {
+            eprintln!("Failed to read the rate limits: {}", err);
+            config.rate_limits
+        }
+    };
+    let mut room = Room::new(update_tx, rate_limits);
+    room.extent = Some(canvas.extent());
+
+    // the thread looks itself up by name, so it can’t start before the room is registered
+    let mut global = global_lock.lock().unwrap();
+    let thread_global = Arc::clone(global_lock);
+    let thread_name = name.to_string();
+    let thread_tx = room.update_tx.clone();
+    room.thread = Some(thread::spawn(move || {
+        update_thread(update_rx, thread_tx, thread_global, thread_name, store, canvas, locks)
+    }));
+    global.rooms.insert(name.to_string(), room);
+    Ok(())
+}
+
+/// Saves every canvas, notifies clients and stops the server.
+fn shut_down(global_lock: &Arc<Mutex<GlobalState>>, broadcaster: &ws::Sender) {
+    let threads: Vec<_> = {
+        let mut global = global_lock.lock().unwrap();
+        if global.shutting_down {
+            return;
+        }
+        global.shutting_down = true;
+        global
+            .rooms
+            .values_mut()
+            .filter_map(|room| {
+                let _ = room.update_tx.send(UpdateMsg::Shutdown);
+                room.thread.take()
+            })
+            .collect()
+    };
+    // update threads need the lock to finish up
+    for thread in threads {
+        let _ = thread.join();
+    }
+
+    let _ = broadcaster.broadcast(ClientMessage::Broadcast {
+        text: "The server is going down".into(),
+    });
+    let _ = broadcaster.close_with_reason(ws::CloseCode::Away, "Server shutting down");
+
+    // give the event loop a moment to flush the close frames
+    thread::sleep(time::Duration::from_millis(500));
+    let _ = broadcaster.shutdown();
+}
+
+/// Removes a closed room, disconnects its clients and archives or deletes its files. Reports to
+/// whoever closed it.
+fn close_room(
+    global_lock: &Arc<Mutex<GlobalState>>,
+    name: &str,
+    dir: &PathBuf,
+    archive: bool,
+    out: &ws::Sender,
+) {
+    let send_line = |line: String| match out.send(ClientMessage::Console(line)) {
+        Ok(_) => (),
+        Err(err) => eprintln!("Send error: {:?}", err),
+    };
+
+    if let Some(mut room) = global_lock.lock().unwrap().rooms.remove(name) {
+        for client in room.clients.values_mut() {
+            client.outbox.close(
+                Some("This canvas has been closed"),
+                ws::CloseCode::Away,
+                "Canvas closed",
+            );
+        }
+    }
+
+    let base = global_lock.lock().unwrap().config.data_dir.clone();
+    let result = if archive {
+        rooms::archive(&base, name, canvas::unix_now())
+            .map(|archived| format!("Archived {} as {}", name, archived))
+    } else {
+        fs::remove_dir_all(dir).map(|_| format!("Deleted {}", name))
+    };
+    match result {
+        Ok(line) => send_line(line),
+        Err(err) => send_line(format!("Closed {}, but failed to move its files: {}", name, err)),
+    }
+}
+
+pub struct ClientSender {
+    id: u64,
+    id_info: String,
+    /// Canvas updates and broadcasts waiting to be sent.
+    outbox: Outbox,
+    /// Whether the client negotiated the binary protocol.
+    binary: bool,
+    /// The area the client is looking at, if it told us; deltas elsewhere are not sent.
+    viewport: Option<Rect>,
+    /// Whether the client has received the whole canvas (only possible if it’s finite).
+    synced: bool,
+}
+
+impl ClientSender {
+    /// Returns the visible part of the canvas, or None if the client didn’t send a viewport.
+    fn visible_rect(&self, canvas: &Canvas) -> Option<Rect> {
+        self.viewport.map(|viewport| match canvas.bounds() {
+            Some(bounds) => viewport.intersection(&bounds),
+            None => viewport,
+        })
+    }
+}
+
+pub enum UpdateMsg {
+    FullUpdate(ClientSender),
+    Remove(u64),
+    /// Sets a client’s viewport and sends it the newly exposed area.
+    Viewport {
+        id: u64,
+        x: i32,
+        y: i32,
+        w: u32,
+        h: u32,
+    },
+    SetPixel {
+        x: i32,
+        y: i32,
+        r: u8,
+        g: u8,
+        b: u8,
+        actor: Actor,
+        out: Arc<ws::Sender>,
+    },
+    /// Places (or with no color, erases) a pixel on a layer other than the user layer.
+    LayerPixel {
+        layer: String,
+        x: i32,
+        y: i32,
+        color: Option<(u8, u8, u8)>,
+        actor: Actor,
+        out: Arc<ws::Sender>,
+    },
+    /// Sends a region of a single layer.
+    FetchLayer {
+        layer: String,
+        x: i32,
+        y: i32,
+        w: u32,
+        h: u32,
+        out: Arc<ws::Sender>,
+    },
+    /// Adds, shows, hides, removes or clears a layer.
+    ChangeLayer {
+        layer: String,
+        change: LayerChange,
+        out: ws::Sender,
+    },
+    /// Lists the layers.
+    ListLayers { out: ws::Sender },
+    /// Adds or replaces a region lock.
+    Lock { lock: Lock, out: ws::Sender },
+    /// Removes a region lock.
+    Unlock { name: String, out: ws::Sender },
+    /// Lists the region locks.
+    ListLocks { out: ws::Sender },
+    /// Sends a region (the whole canvas by default) with at most `max_pixels` pixels for
+    /// exporting.
+    Export {
+        rect: Option<Rect>,
+        max_pixels: u64,
+        reply: mpsc::Sender<Result<Region, &'static str>>,
+    },
+    /// Replaces the canvas with an image, resizing it to fit.
+    Import {
+        image: Image,
+        actor: Actor,
+        out: ws::Sender,
+    },
+    /// Fills, draws a line, flood fills or pastes an image on a layer.
+    Draw {
+        layer: String,
+        draw: Draw,
+        actor: Actor,
+        out: ws::Sender,
+    },
+    ChatMessage {
+        x: f32,
+        y: f32,
+        text: String,
+        actor: Actor,
+    },
+    Broadcast { text: String },
+    /// Resizes, crops or shifts the canvas, filling new areas with a color.
+    Resize {
+        resize: Resize,
+        fill: (u8, u8, u8),
+        out: ws::Sender,
+    },
+    /// Makes the canvas infinite, optionally with a (growing) world boundary.
+    SetExtent { extent: Extent, out: ws::Sender },
+    /// Sets or removes the palette, remapping existing pixels.
+    SetPalette {
+        palette: Option<Palette>,
+        out: ws::Sender,
+    },
+    /// Looks up who placed a pixel. Only admins get to see full identities.
+    PixelInfo {
+        x: i32,
+        y: i32,
+        is_admin: bool,
+        out: Arc<ws::Sender>,
+    },
+    /// Restores the canvas to its state at the given time (UNIX milliseconds).
+    Restore { time: u64, out: ws::Sender },
+    /// Reverts pixels placed by an actor since a point in time (UNIX milliseconds), optionally
+    /// within a region. Only reports what would change unless `apply` is set.
+    Rollback {
+        actor: Actor,
+        since: u64,
+        region: Option<Rect>,
+        apply: bool,
+        out: ws::Sender,
+    },
+    /// Restores the canvas to the state a worker found in the log for `Restore`, or read from a
+    /// snapshot for `RestoreSnapshot`.
+    ApplyRestore {
+        target: Box<Canvas>,
+        snapshot: Option<u64>,
+        out: ws::Sender,
+    },
+    /// Reverts the pixels a worker found in the log for `Rollback`.
+    ApplyRollback {
+        actor: Actor,
+        targets: HashMap<(i32, i32), (u8, u8, u8)>,
+        apply: bool,
+        out: ws::Sender,
+    },
+    /// Takes a snapshot now.
+    Snapshot { out: ws::Sender },
+    /// Lists the snapshots and the snapshot policy.
+    ListSnapshots { out: ws::Sender },
+    /// Summarizes the changes between two snapshots (UNIX seconds), or a snapshot and the live
+    /// canvas (None).
+    DiffSnapshots {
+        from: Option<u64>,
+        to: Option<u64>,
+        out: ws::Sender,
+    },
+    /// Restores the canvas to a snapshot (UNIX seconds).
+    RestoreSnapshot { time: u64, out: ws::Sender },
+    /// Sets when snapshots are taken and kept.
+    SetSnapshotPolicy { policy: Policy, out: ws::Sender },
+    /// Sets and saves the pixel placement limits.
+    SetRateLimits { limits: RateLimits, out: ws::Sender },
+    /// Sends a copy of the canvas.
+    GetCanvas { reply: mpsc::Sender<Canvas> },
+    /// Sends a job for reading the history.
+    History {
+        reply: mpsc::Sender<Result<Job<LogContents>, StoreError>>,
+    },
+    /// Stops accepting pixels and saves; the server is going down.
+    Shutdown,
+    /// Stops accepting pixels, saves, disconnects the room’s clients and then archives or deletes
+    /// the canvas.
+    Close { archive: bool, out: ws::Sender },
+}
+
+fn update_thread(
+    rx: mpsc::Receiver<UpdateMsg>,
+    tx: mpsc::Sender<UpdateMsg>,
+    global_lock: Arc<Mutex<GlobalState>>,
+    name: String,
+    mut store: Box<dyn CanvasStore>,
+    mut canvas: Canvas,
+    mut locks: Locks,
+) {
+    let (config, hooks) = {
+        let global = global_lock.lock().unwrap();
+        (Arc::clone(&global.config), global.hooks.clone())
+    };
+    let dir = rooms::room_dir(&config.data_dir, &name);
+    let mut snapshots = Snapshots::open(&*store);
+
+    let mut last_save = time::Instant::now();
+    // save once in any case, to include replayed events and upgrade old file formats
+    let mut dirty = true;
+    let mut save_thread: Option<thread::JoinHandle<bool>> = None;
+    // writes the latest snapshot
+    let mut snapshot_thread: Option<thread::JoinHandle<()>> = None;
+    // set once a save failed, so the next one writes everything
+    let mut full_save = false;
+    let mut shutting_down = false;
+    // set by a Close message (whether to archive, who asked)
+    let mut closing: Option<(bool, ws::Sender)> = None;
+    // the encoded full update, kept until the canvas changes (canvas revision, message)
+    let mut full_update: Option<(u64, Option<Outgoing>)> = None;
+    // what clients were last told about the bounds
+    let mut last_bounds = Some((canvas.extent(), canvas.populated()));
+    // set while some client has updates queued or not yet confirmed
+    let mut backlog = false;
+
+    // pseudonyms shouldn’t be reversible by hashing guessed login names
+    let pseudonym_salt = hex_digest(
+        Algorithm::SHA256,
+        format!("{:?} {}", time::SystemTime::now(), std::process::id()).as_bytes(),
+    );
+
+    loop {
+        // don’t wait around if there are deltas left over from the last frame, or clients to
+        // send more to
+        let timeout = if canvas.has_changes() || backlog {
+            time::Duration::new(0, 0)
+        } else {
+            time::Duration::new(5, 0)
+        };
+
+        let mut messages = Vec::new();
+        if let Ok(msg) = rx.recv_timeout(timeout) {
+            messages.push(msg);
+            while let Ok(msg) = rx.try_recv() {
+                messages.push(msg);
+            }
+        }
+
+        if !shutting_down {
+            let pixels = hooks::tick(&hooks, &name, &canvas);
+            let actor = Actor::Server(hooks::ACTOR.into());
+            for pixel in pixels {
+                // rejected pixels are dropped; there’s nobody to tell
+                let placed = place_pixel(
+                    &mut canvas,
+                    &locks,
+                    &mut *store,
+                    &hooks,
+                    &name,
+                    pixel,
+                    &actor,
+                );
+                dirty |= placed.unwrap_or(false);
+            }
+        }
+
+        let grown = if shutting_down {
+            None
+        } else {
+            canvas.grown_extent(history::now_millis())
+        };
+
+        if !messages.is_empty() || canvas.has_changes() || grown.is_some() || backlog {
+            let mut global = global_lock.lock().unwrap();
+            let room = match global.rooms.get_mut(&name) {
+                Some(room) => room,
+                None => return,
+            };
+
+            if let Some(extent) = grown {
+                let event = Event::SetExtent {
+                    time: history::now_millis(),
+                    extent,
+                };
+                event.apply(&mut canvas);
+                if let Err(err) = store.append(&event) {
+                    eprintln!("Failed to write to canvas.log: {:?}", err);
+                }
+                dirty = true;
+            }
+
+            let mut broadcasts: Vec<Outgoing> = Vec::new();
+            // set if clients need to get the canvas again
+            let mut resync = false;
+            // encoding canvas data can take a while, so it’s queued once the lock was released and
+            // taken again: clients to send the whole canvas to, and areas clients scrolled to
+            let mut needs_full: Vec<u64> = Vec::new();
+            let mut exposed_areas: Vec<(u64, Outgoing)> = Vec::new();
+            for message in messages {
+                match message {
+                    UpdateMsg::FullUpdate(mut sender) => {
+                        send_canvas(&mut sender, &canvas, &locks);
+                        if sender.synced {
+                            needs_full.push(sender.id);
+                        }
+                        room.clients.insert(sender.id, sender);
+                    }
+                    UpdateMsg::Remove(id) => {
+                        room.clients.remove(&id);
+                    }
+                    UpdateMsg::Viewport { id, x, y, w, h } => {
+                        let client = match room.clients.get_mut(&id) {
+                            Some(client) => client,
+                            None => continue,
+                        };
+                        let prev = client.visible_rect(&canvas);
+                        client.viewport = Some(Rect {
+                            x,
+                            y,
+                            w: w.min(MAX_VIEWPORT_SIZE),
+                            h: h.min(MAX_VIEWPORT_SIZE),
+                        });
+                        let visible = match client.visible_rect(&canvas) {
+                            Some(visible) => visible,
+                            None => continue,
+                        };
+
+                        // a client that got a full update already has everything
+                        let exposed = match prev {
+                            Some(prev) => visible.subtract(&prev),
+                            None if client.synced => Vec::new(),
+                            None => vec![visible],
+                        };
+                        let regions: Vec<_> = exposed
+                            .iter()
+                            .filter(|r| !r.is_empty())
+                            .filter_map(|r| canvas.region(r.x, r.y, r.w, r.h))
+                            .collect();
+                        if !regions.is_empty() {
+                            exposed_areas.push((id, CanvasUpdate::Regions(regions).into()));
+                        }
+                    }
+                    UpdateMsg::SetPixel {
+                        x,
+                        y,
+                        r,
+                        g,
+                        b,
+                        actor,
+                        out,
+                    } => {
+                        if shutting_down {
+                            continue;
+                        }
+                        let pixel = Pixel {
+                            x,
+                            y,
+                            color: (r, g, b),
+                        };
+                        match place_pixel(
+                            &mut canvas,
+                            &locks,
+                            &mut *store,
+                            &hooks,
+                            &name,
+                            pixel,
+                            &actor,
+                        ) {
+                            Ok(placed) => dirty |= placed,
+                            Err(err) => {
+                                let _ = out.send(err);
+                            }
+                        }
+                    }
+                    UpdateMsg::LayerPixel {
+                        layer,
+                        x,
+                        y,
+                        color,
+                        actor,
+                        out,
+                    } => {
+                        if shutting_down || !canvas.contains(x, y) {
+                            continue;
+                        }
+                        // the user layer is painted with SetPixel, which is rate limited
+                        if !canvas.has_layer(&layer) {
+                            let _ = out.send(ClientMessage::Error {
+                                code: "no-such-layer".into(),
+                                message: format!("There is no layer named {}", layer),
+                            });
+                            continue;
+                        }
+                        if let Some(lock) = locks.blocking(x, y, &actor) {
+                            let _ = out.send(region_locked(lock));
+                            continue;
+                        }
+                        let color = match color.map(|(r, g, b)| canvas.resolve_color(r, g, b)) {
+                            Some(None) => {
+                                let _ = out.send(ClientMessage::Error {
+                                    code: "color-not-in-palette".into(),
+                                    message: "That color is not in the palette".into(),
+                                });
+                                continue;
+                            }
+                            Some(color) => color,
+                            None => None,
+                        };
+                        let event = Event::LayerPixel {
+                            time: history::now_millis(),
+                            layer,
+                            x,
+                            y,
+                            color,
+                            actor,
+                        };
+                        event.apply(&mut canvas);
+                        if let Err(err) = store.append(&event) {
+                            eprintln!("Failed to write to canvas.log: {:?}", err);
+                        }
+                        dirty = true;
+                    }
+                    UpdateMsg::FetchLayer {
+                        layer,
+                        x,
+                        y,
+                        w,
+                        h,
+                        out,
+                    } => {
+                        let (w, h) = (w.min(MAX_VIEWPORT_SIZE), h.min(MAX_VIEWPORT_SIZE));
+                        match canvas.layer_region(&layer, x, y, w, h) {
+                            Some(rgba) => {
+                                let _ = out.send(ClientMessage::Layer {
+                                    layer,
+                                    region: RGBARegion::from_rgba(x, y, w, h, &rgba),
+                                });
+                            }
+                            None => {
+                                let _ = out.send(ClientMessage::Error {
+                                    code: "fetch-layer".into(),
+                                    message: "No such layer, or the region is out of bounds".into(),
+                                });
+                            }
+                        }
+                    }
+                    UpdateMsg::ChangeLayer { layer, change, out } => {
+                        if let Err(err) = canvas.change_layer(&layer, change) {
+                            let _ = out.send(ClientMessage::Console(err.into()));
+                            continue;
+                        }
+                        let line = match change {
+                            LayerChange::Add { .. } => format!("Added layer {}", layer),
+                            LayerChange::SetHidden(true) => format!("Hid layer {}", layer),
+                            LayerChange::SetHidden(false) => format!("Showing layer {}", layer),
+                            LayerChange::Remove => format!("Removed layer {}", layer),
+                            LayerChange::Clear(_) => format!("Cleared layer {}", layer),
+                        };
+                        let event = Event::ChangeLayer {
+                            time: history::now_millis(),
+                            layer,
+                            change,
+                        };
+                        if let Err(err) = store.append(&event) {
+                            eprintln!("Failed to write to canvas.log: {:?}", err);
+                        }
+                        dirty = true;
+                        resync = true;
+                        let _ = out.send(ClientMessage::Console(line));
+                    }
+                    UpdateMsg::ListLayers { out } => {
+                        let mut layers = vec![format!("{} (bottom)", canvas::USER_LAYER)];
+                        for (name, hidden) in canvas.layers() {
+                            layers.push(if hidden { format!("{} (hidden)", name) } else { name });
+                        }
+                        let _ = out.send(ClientMessage::Console(layers.join(", ")));
+                    }
+                    UpdateMsg::Lock { lock, out } => {
+                        let line = format!("Locked {} ({})", lock.name, describe_lock(&lock));
+                        locks.set(lock);
+                        save_locks(&locks, &mut *store);
+                        let _ = out.send(ClientMessage::Console(line));
+                        broadcasts.push(ClientMessage::from(&locks).into());
+                    }
+                    UpdateMsg::Unlock { name, out } => {
+                        if !locks.remove(&name) {
+                            let line = format!("No lock named {}", name);
+                            let _ = out.send(ClientMessage::Console(line));
+                            continue;
+                        }
+                        save_locks(&locks, &mut *store);
+                        let _ = out.send(ClientMessage::Console(format!("Unlocked {}", name)));
+                        broadcasts.push(ClientMessage::from(&locks).into());
+                    }
+                    UpdateMsg::ListLocks { out } => {
+                        let lines: Vec<_> = locks
+                            .locks()
+                            .iter()
+                            .map(|lock| format!("{}: {}", lock.name, describe_lock(lock)))
+                            .collect();
+                        let text = if lines.is_empty() {
+                            "No locks".to_string()
+                        } else {
+                            lines.join("\n")
+                        };
+                        let _ = out.send(ClientMessage::Console(text));
+                    }
+                    UpdateMsg::Draw {
+                        layer,
+                        draw,
+                        actor,
+                        out,
+                    } => {
+                        let send_line = |line: String| {
+                            let _ = out.send(ClientMessage::Console(line));
+                        };
+                        if shutting_down {
+                            continue;
+                        }
+                        if layer != canvas::USER_LAYER && !canvas.has_layer(&layer) {
+                            send_line(format!("There is no layer named {}", layer));
+                            continue;
+                        }
+                        if draw.max_pixels(canvas.bounds()) > canvas::MAX_DRAW_PIXELS {
+                            send_line(format!(
+                                "That covers too much; at most {} pixels at once",
+                                canvas::MAX_DRAW_PIXELS
+                            ));
+                            continue;
+                        }
+                        let color = match draw {
+                            Draw::Fill { color, .. }
+                            | Draw::Line { color, .. }
+                            | Draw::Flood { color, .. } => Some(color),
+                            // pasted images are snapped to the palette
+                            Draw::Paste { .. } => None,
+                        };
+                        if let Some((r, g, b)) = color {
+                            if canvas.resolve_color(r, g, b).is_none() {
+                                send_line("That color is not in the palette".into());
+                                continue;
+                            }
+                        }
+                        let pixels = canvas.draw_pixels(&layer, &draw);
+                        let blocking = pixels
+                            .iter()
+                            .find_map(|&(x, y, _)| locks.blocking(x, y, &actor));
+                        if let Some(lock) = blocking {
+                            send_line(format!("That touches a locked area ({})", lock.name));
+                            continue;
+                        }
+
+                        let event = Event::Draw {
+                            time: history::now_millis(),
+                            layer,
+                            draw,
+                            actor,
+                        };
+                        event.apply(&mut canvas);
+                        if let Err(err) = store.append(&event) {
+                            eprintln!("Failed to write to canvas.log: {:?}", err);
+                        }
+                        dirty = true;
+                        send_line(format!("Drew {} pixels", pixels.len()));
+                    }
+                    UpdateMsg::Export {
+                        rect,
+                        max_pixels,
+                        reply,
+                    } => {
+                        let rect = rect.or_else(|| canvas.bounds()).or_else(|| canvas.populated());
+                        let region = match rect {
+                            Some(rect) if rect.is_empty() => Err("That region is empty"),
+                            Some(rect) if rect.w as u64 * rect.h as u64 > max_pixels => {
+                                Err("That region is too large")
+                            }
+                            Some(rect) => canvas
+                                .region(rect.x, rect.y, rect.w, rect.h)
+                                .ok_or("That region is outside the canvas"),
+                            None => Err("The canvas is empty"),
+                        };
+                        let _ = reply.send(region);
+                    }
+                    UpdateMsg::Import { image, actor, out } => {
+                        if shutting_down {
+                            continue;
+                        }
+                        let (width, height) = (image.width, image.height);
+                        if let Err(err) = config.check_extent(Extent::Finite { width, height }) {
+                            let _ = out.send(ClientMessage::Console(err));
+                            continue;
+                        }
+                        let extent = canvas.extent();
+                        let now = history::now_millis();
+                        for event in image::import_events(&canvas, image, None, actor, now) {
+                            event.apply(&mut canvas);
+                            if let Err(err) = store.append(&event) {
+                                eprintln!("Failed to write to canvas.log: {:?}", err);
+                            }
+                        }
+                        dirty = true;
+                        resync = true;
+                        if canvas.extent() != extent {
+                            broadcasts.push(
+                                ClientMessage::Resize {
+                                    world: canvas.bounds(),
+                                    dx: 0,
+                                    dy: 0,
+                                }.into(),
+                            );
+                        }
+                        let _ = out.send(ClientMessage::Console(format!(
+                            "Imported a {}×{} image as the canvas",
+                            width, height
+                        )));
+                    }
+                    UpdateMsg::ChatMessage { x, y, text, actor } => {
+                        let text = text.trim().to_string();
+                        if text.is_empty() {
+                            continue;
+                        }
+                        if let Some(text) = hooks::check_chat(&hooks, &name, text, &actor) {
+                            broadcasts.push(
+                                ClientMessage::ChatMessage {
+                                    x,
+                                    y,
+                                    text,
+                                    id_hue: None,
+                                    is_admin: false,
+                                }.into(),
+                            );
+                        }
+                    }
+                    UpdateMsg::Broadcast { text } => {
+                        broadcasts.push(ClientMessage::Broadcast { text }.into());
+                    }
+                    UpdateMsg::Resize { resize, fill, out } => {
+                        let (extent, dx, dy) = match canvas.plan_resize(resize) {
+                            Some(plan) => plan,
+                            None => {
+                                let _ = out.send(ClientMessage::Console(
+                                    "The canvas is empty; give both --width and --height".into(),
+                                ));
+                                continue;
+                            }
+                        };
+                        if let Err(err) = config.check_extent(extent) {
+                            let _ = out.send(ClientMessage::Console(err));
+                            continue;
+                        }
+                        let event = Event::Resize {
+                            time: history::now_millis(),
+                            extent,
+                            dx,
+                            dy,
+                            fill,
+                        };
+                        event.apply(&mut canvas);
+                        if let Err(err) = store.append(&event) {
+                            eprintln!("Failed to write to canvas.log: {:?}", err);
+                        }
+                        dirty = true;
+                        resync = true;
+                        if (dx, dy) != (0, 0) && !locks.locks().is_empty() {
+                            locks.shift(dx, dy);
+                            save_locks(&locks, &mut *store);
+                            broadcasts.push(ClientMessage::from(&locks).into());
+                        }
+
+                        let _ = out.send(ClientMessage::Console(format!(
+                            "Canvas is now {}, contents moved by {},{}",
+                            describe_extent(canvas.extent()),
+                            dx,
+                            dy
+                        )));
+                        broadcasts.push(
+                            ClientMessage::Resize {
+                                world: canvas.bounds(),
+                                dx,
+                                dy,
+                            }.into(),
+                        );
+                    }
+                    UpdateMsg::SetExtent { extent, out } => {
+                        // switching between finite and infinite changes how clients get the
+                        // canvas, while boundary changes just show up in the bounds
+                        resync |= !canvas.is_infinite();
+                        let event = Event::SetExtent {
+                            time: history::now_millis(),
+                            extent,
+                        };
+                        event.apply(&mut canvas);
+                        if let Err(err) = store.append(&event) {
+                            eprintln!("Failed to write to canvas.log: {:?}", err);
+                        }
+                        dirty = true;
+
+                        let _ = out.send(ClientMessage::Console(format!(
+                            "Canvas is now {}",
+                            describe_extent(canvas.extent())
+                        )));
+                    }
+                    UpdateMsg::SetPalette { palette, out } => {
+                        let event = Event::SetPalette {
+                            time: history::now_millis(),
+                            palette,
+                        };
+                        event.apply(&mut canvas);
+                        if let Err(err) = store.append(&event) {
+                            eprintln!("Failed to write to canvas.log: {:?}", err);
+                        }
+                        dirty = true;
+
+                        let _ = out.send(ClientMessage::Console(match canvas.palette() {
+                            Some(palette) => format!(
+                                "Palette set to {} colors ({})",
+                                palette.colors.len(),
+                                if palette.snap { "snapping" } else { "rejecting others" }
+                            ),
+                            None => "Palette removed".into(),
+                        }));
+
+                        broadcasts.push(ClientMessage::from(canvas.palette()).into());
+                        resync = true;
+                    }
+                    UpdateMsg::PixelInfo {
+                        x,
+                        y,
+                        is_admin,
+                        out,
+                    } => {
+                        let (placed_by, time) = match canvas.visible_provenance(x, y) {
+                            Some((actor, time)) => {
+                                let actor = actor.to_string();
+                                let placed_by = if is_admin {
+                                    actor
+                                } else {
+                                    let digest = hex_digest(
+                                        Algorithm::SHA256,
+                                        (actor + &pseudonym_salt).as_bytes(),
+                                    );
+                                    format!("user-{}", &digest[..8])
+                                };
+                                (Some(placed_by), Some(time))
+                            }
+                            None => (None, None),
+                        };
+                        let _ = out.send(ClientMessage::PixelInfo {
+                            x,
+                            y,
+                            placed_by,
+                            time,
+                        });
+                    }
+                    UpdateMsg::Restore { time, out } => {
+                        with_history(&mut *store, &tx, out, move |contents, out| {
+                            UpdateMsg::ApplyRestore {
+                                target: Box::new(contents.state_at(time)),
+                                snapshot: None,
+                                out,
+                            }
+                        });
+                    }
+                    UpdateMsg::ApplyRestore {
+                        target,
+                        snapshot,
+                        out,
+                    } => {
+                        let prev = (canvas.extent(), canvas.layers());
+                        let line = match history::restore_to(&mut canvas, &mut *store, &target) {
+                            Ok(changed) => {
+                                dirty = true;
+                                match snapshot {
+                                    Some(time) => format!(
+                                        "Restored {} pixels from snapshot {}",
+                                        changed, time
+                                    ),
+                                    None => format!("Restored {} pixels", changed),
+                                }
+                            }
+                            Err(err) => format!("Failed to restore: {}", err),
+                        };
+                        let _ = out.send(ClientMessage::Console(line));
+
+                        if (canvas.extent(), canvas.layers()) != prev {
+                            resync = true;
+                        }
+                    }
+                    UpdateMsg::Snapshot { out } => {
+                        take_snapshot(
+                            &mut snapshots,
+                            &mut *store,
+                            &canvas,
+                            history::now_millis(),
+                            &mut snapshot_thread,
+                            Some(out),
+                        );
+                    }
+                    UpdateMsg::ListSnapshots { out } => {
+                        let _ = out.send(ClientMessage::Console(list_snapshots(&snapshots, &*store)));
+                    }
+                    UpdateMsg::DiffSnapshots { from, to, out } => {
+                        // reading and comparing canvases is slow, so the live one is copied and
+                        // compared on a worker thread
+                        let load = |time: Option<u64>| -> Result<Job<Canvas>, StoreError> {
+                            match time {
+                                Some(time) => store.load_snapshot(time),
+                                None => {
+                                    let canvas = canvas.clone();
+                                    Ok(Box::new(move || Ok(canvas)))
+                                }
+                            }
+                        };
+                        let loads = load(from).and_then(|a| Ok((a, load(to)?)));
+                        let (load_a, load_b) = match loads {
+                            Ok(loads) => loads,
+                            Err(err) => {
+                                let line = format!("Failed to read snapshot: {}", err);
+                                let _ = out.send(ClientMessage::Console(line));
+                                continue;
+                            }
+                        };
+                        thread::spawn(move || {
+                            let line = match (load_a(), load_b()) {
+                                (Ok(a), Ok(b)) => describe_diff(&a.diff(&b)),
+                                (Err(err), _) | (_, Err(err)) => {
+                                    format!("Failed to read snapshot: {}", err)
+                                }
+                            };
+                            let _ = out.send(ClientMessage::Console(line));
+                        });
+                    }
+                    UpdateMsg::RestoreSnapshot { time, out } => {
+                        let report = |out: &ws::Sender, err: StoreError| {
+                            let line = format!("Failed to read snapshot: {}", err);
+                            let _ = out.send(ClientMessage::Console(line));
+                        };
+                        let load = match store.load_snapshot(time) {
+                            Ok(load) => load,
+                            Err(err) => {
+                                report(&out, err);
+                                continue;
+                            }
+                        };
+                        let tx = tx.clone();
+                        thread::spawn(move || match load() {
+                            Ok(target) => {
+                                let _ = tx.send(UpdateMsg::ApplyRestore {
+                                    target: Box::new(target),
+                                    snapshot: Some(time),
+                                    out,
+                                });
+                            }
+                            Err(err) => report(&out, err),
+                        });
+                    }
+                    UpdateMsg::SetSnapshotPolicy { policy, out } => {
+                        let line = match snapshots.set_policy(&mut *store, policy) {
+                            Ok(_) => describe_snapshot_policy(snapshots.policy()),
+                            Err(err) => format!("Failed to save the snapshot policy: {}", err),
+                        };
+                        let _ = out.send(ClientMessage::Console(line));
+                    }
+                    UpdateMsg::SetRateLimits { limits, out } => {
+                        room.rate_limits = limits;
+                        let line = match store.set_rate_limits(&limits) {
+                            Ok(_) => describe_rate_limits(&limits),
+                            Err(err) => format!("Failed to save the rate limits: {}", err),
+                        };
+                        let _ = out.send(ClientMessage::Console(line));
+                    }
+                    UpdateMsg::Rollback {
+                        actor,
+                        since,
+                        region,
+                        apply,
+                        out,
+                    } => {
+                        with_history(&mut *store, &tx, out, move |contents, out| {
+                            let targets =
+                                history::rollback_targets(&contents, &actor, since, region);
+                            UpdateMsg::ApplyRollback {
+                                actor,
+                                targets,
+                                apply,
+                                out,
+                            }
+                        });
+                    }
+                    UpdateMsg::ApplyRollback {
+                        actor,
+                        targets,
+                        apply,
+                        out,
+                    } => {
+                        let send_line = |line: String| {
+                            let _ = out.send(ClientMessage::Console(line));
+                        };
+                        let plan = history::plan_rollback(&targets, &canvas, &actor);
+                        send_line(format!(
+                            "{} placed {} pixels in that time; {} would be reverted, {} have been changed by someone else since",
+                            actor,
+                            plan.touched,
+                            plan.changes.len(),
+                            plan.skipped
+                        ));
+
+                        if !apply {
+                            send_line("Dry run; add `--apply` to roll back".into());
+                            continue;
+                        }
+
+                        let time = history::now_millis();
+                        let rollback_actor = Actor::Server("rollback".into());
+                        for (x, y, (r, g, b)) in plan.changes {
+                            let event = Event::SetPixel {
+                                time,
+                                x,
+                                y,
+                                r,
+                                g,
+                                b,
+                                actor: rollback_actor.clone(),
+                            };
+                            event.apply(&mut canvas);
+                            if let Err(err) = store.append(&event) {
+                                eprintln!("Failed to write to canvas.log: {:?}", err);
+                            }
+                            dirty = true;
+                        }
+                        send_line("Rolled back".into());
+                    }
+                    UpdateMsg::GetCanvas { reply } => {
+                        let _ = reply.send(canvas.clone());
+                    }
+                    UpdateMsg::History { reply } => {
+                        let _ = reply.send(store.read_history());
+                    }
+                    UpdateMsg::Shutdown => {
+                        shutting_down = true;
+                        room.shutting_down = true;
+                    }
+                    UpdateMsg::Close { archive, out } => {
+                        shutting_down = true;
+                        room.shutting_down = true;
+                        closing = Some((archive, out));
+                    }
+                }
+            }
+
+            let bounds = (canvas.extent(), canvas.populated());
+            if last_bounds != Some(bounds) {
+                broadcasts.push(ClientMessage::from(&canvas).into());
+                last_bounds = Some(bounds);
+                room.extent = Some(bounds.0);
+            }
+
+            // clients that fell behind get the whole canvas again once they’ve caught up
+            for client in room.clients.values_mut() {
+                if client.outbox.catch_up() {
+                    send_canvas(client, &canvas, &locks);
+                    if client.synced {
+                        needs_full.push(client.id);
+                    } else {
+                        client.outbox.push(ClientMessage::Reset);
+                        client.viewport = None;
+                    }
+                }
+            }
+
+            // which protocols the clients speak (text, binary)
+            let protocols = [false, true]
+                .iter()
+                .cloned()
+                .filter(|binary| room.clients.values().any(|client| client.binary == *binary))
+                .collect::<Vec<_>>();
+            drop(global);
+
+            // while other threads can use the lock, encode what clients are about to get
+            let full = if resync || !needs_full.is_empty() {
+                cached_full_update(&mut full_update, &canvas)
+            } else {
+                None
+            };
+
+            let regions = canvas.compile_deltas(Some(config.max_pixels_per_frame));
+            let all_regions = if regions.is_empty() {
+                None
+            } else {
+                Some(Outgoing::from(CanvasUpdate::Regions(regions.clone())))
+            };
+
+            for binary in protocols {
+                for update in full.into_iter().chain(all_regions.as_ref()) {
+                    update.prepare(binary);
+                }
+            }
+            for (_, update) in &exposed_areas {
+                update.prepare(true);
+                update.prepare(false);
+            }
+
+            let mut global = global_lock.lock().unwrap();
+            let room = match global.rooms.get_mut(&name) {
+                Some(room) => room,
+                None => return,
+            };
+            let full_updates = needs_full.iter().filter_map(|id| full.map(|full| (id, full)));
+            let exposed_areas = exposed_areas.iter().map(|(id, update)| (id, update));
+            for (id, update) in full_updates.chain(exposed_areas) {
+                if let Some(client) = room.clients.get_mut(id) {
+                    client.outbox.push(update.for_client(client.binary));
+                }
+            }
+            // clients looking at the same regions share an encoded message
+            let mut visible_regions: HashMap<Vec<usize>, Outgoing> = HashMap::new();
+
+            // encoded once, then sent in whichever protocol each client speaks
+            for client in room.clients.values_mut() {
+                let update = match client.visible_rect(&canvas) {
+                    None if client.synced => all_regions.as_ref(),
+                    None => None,
+                    Some(rect) => {
+                        let visible: Vec<usize> = (0..regions.len())
+                            .filter(|i| rect.intersects(&regions[*i].rect()))
+                            .collect();
+                        if visible.is_empty() {
+                            None
+                        } else {
+                            let update = visible_regions.entry(visible).or_insert_with_key(|visible| {
+                                let regions = visible.iter().map(|i| regions[*i].clone()).collect();
+                                CanvasUpdate::Regions(regions).into()
+                            });
+                            Some(&*update)
+                        }
+                    }
+                };
+                if let Some(update) = update {
+                    client.outbox.push(update.for_client(client.binary));
+                }
+
+                for broadcast in &broadcasts {
+                    client.outbox.push(broadcast.for_client(client.binary));
+                }
+
+                if resync {
+                    let message = match full {
+                        Some(full) => full.for_client(client.binary),
+                        None => ClientMessage::Reset.into(),
+                    };
+                    client.outbox.push(message);
+                    // infinite canvases are sent again once the client tells us its viewport
+                    client.synced = full.is_some();
+                    if full.is_none() {
+                        client.viewport = None;
+                    }
+                }
+            }
+
+            let now = time::Instant::now();
+            room.clients.retain(|_, client| {
+                let connected = client.outbox.flush(now);
+                if !connected {
+                    eprintln!("Disconnected a client that stopped reading ({})", client.id_info);
+                }
+                connected
+            });
+            backlog = room.clients.values().any(|client| !client.outbox.is_idle());
+        }
+
+        if let Err(err) = store.flush() {
+            eprintln!("Failed to write to canvas.log: {:?}", err);
+        }
+
+        if (last_save.elapsed() >= config.save_interval || shutting_down) && dirty {
+            canvas.meta.last_modified = canvas::unix_now();
+
+            // don’t let two saves race on the temporary files
+            if let Some(prev) = save_thread.take() {
+                if !prev.join().unwrap_or(false) {
+                    // what it was supposed to write is no longer marked as changed
+                    full_save = true;
+                }
+            }
+            let job = if full_save {
+                store.save(&mut canvas)
+            } else {
+                store.save_changes(&mut canvas)
+            };
+            full_save = false;
+            match job {
+                Ok(job) => {
+                    eprintln!("Saving");
+                    if shutting_down {
+                        run_save(job);
+                    } else {
+                        save_thread = Some(thread::spawn(move || run_save(job)));
+                    }
+                }
+                Err(err) => {
+                    eprintln!("Failed to save! {:?}", err);
+                    full_save = true;
+                }
+            }
+            last_save = time::Instant::now();
+            dirty = false;
+        }
+
+        let now = history::now_millis();
+        if !shutting_down && snapshots.is_due(now) {
+            take_snapshot(
+                &mut snapshots,
+                &mut *store,
+                &canvas,
+                now,
+                &mut snapshot_thread,
+                None,
+            );
+        }
+
+        if shutting_down {
+            if let Some(prev) = save_thread.take() {
+                let _ = prev.join();
+            }
+            if let Some(prev) = snapshot_thread.take() {
+                let _ = prev.join();
+            }
+            if let Some((archive, out)) = closing.take() {
+                drop(store);
+                close_room(&global_lock, &name, &dir, archive, &out);
+            }
+            return;
+        }
+
+        // ~30fps by default
+        thread::sleep(config.tick);
+    }
+}
+
+pub struct GlobalState {
+    config: Arc<Config>,
+    rooms: HashMap<String, Room>,
+    auth: Box<dyn AuthProvider>,
+    hooks: Vec<Arc<dyn Hooks>>,
+    /// Opens the store of a canvas in its directory.
+    stores: StoreFactory,
+    /// Set once shutdown has begun; no more canvases are opened.
+    shutting_down: bool,
+    /// Number of timelapses being streamed.
+    timelapse_streams: usize,
+    /// PNG exports that are being made or were made recently.
+    exports: Exports,
+}
+
+impl GlobalState {
+    fn new(
+        config: Config,
+        auth: Box<dyn AuthProvider>,
+        hooks: Vec<Arc<dyn Hooks>>,
+        stores: StoreFactory,
+    ) -> GlobalState {
+        GlobalState {
+            config: Arc::new(config),
+            rooms: HashMap::new(),
+            auth,
+            hooks,
+            stores,
+            shutting_down: false,
+            timelapse_streams: 0,
+            exports: Exports::default(),
+        }
+    }
+
+    /// Opens the store of the canvas with the given name.
+    fn open_store(&self, name: &str) -> Box<dyn CanvasStore> {
+        let dir = rooms::room_dir(&self.config.data_dir, name);
+        (self.stores)(&dir)
+    }
+}
+
+/// Splits a query string into its parameters.
+fn parse_query(query: &str) -> HashMap<&str, &str> {
+    query
+        .split('&')
+        .filter(|param| !param.is_empty())
+        .map(|param| {
+            let mut parts = param.splitn(2, '=');
+            (parts.next().unwrap_or(""), parts.next().unwrap_or(""))
+        }).collect()
+}
+
+/// Returns the region given by the query parameters `x`, `y`, `w` and `h`, if there is one.
+fn query_region(params: &HashMap<&str, &str>) -> Result<Option<Rect>, &'static str> {
+    let get = |name| params.get(name).map(|value| value.parse::<i64>());
+    match (get("x"), get("y"), get("w"), get("h")) {
+        (None, None, None, None) => Ok(None),
+        (Some(Ok(x)), Some(Ok(y)), Some(Ok(w)), Some(Ok(h))) => {
+            let coord = |v: i64| (i32::MIN as i64..=i32::MAX as i64).contains(&v);
+            let size = |v: i64| (0..=u32::MAX as i64).contains(&v);
+            if !coord(x) || !coord(y) || !size(w) || !size(h) {
+                return Err("Invalid region");
+            }
+            Ok(Some(Rect {
+                x: x as i32,
+                y: y as i32,
+                w: w as u32,
+                h: h as u32,
+            }))
+        }
+        _ => Err("Give all of x, y, w and h, or none"),
+    }
+}
+
+/// Reads the timelapse options and frame delay from a query string.
+fn timelapse_query(query: &str) -> Result<(timelapse::Options, time::Duration), &'static str> {
+    let params = parse_query(query);
+    let now = history::now_millis();
+    let time = |name| match params.get(name) {
+        Some(value) => match history::parse_time(value, now) {
+            Some(time) => Ok(Some(time)),
+            None => Err("Invalid time"),
+        },
+        None => Ok(None),
+    };
+    let interval = match params.get("interval") {
+        Some(value) => match history::parse_duration(value) {
+            Some(interval) => Some(interval),
+            None => return Err("Invalid interval"),
+        },
+        None => None,
+    };
+    let delay = match params.get("delay").map(|delay| delay.parse::<u64>()) {
+        Some(Ok(delay)) => delay.clamp(10, 10_000),
+        Some(Err(_)) => return Err("Invalid delay"),
+        None => 100,
+    };
+    let options = timelapse::Options {
+        region: query_region(&params)?,
+        interval,
+        from: time("from")?,
+        to: time("to")?,
+    };
+    Ok((options, time::Duration::from_millis(delay)))
+}
+
+/// Returns the IP address of a client: that of the connection, unless it comes from the trusted
+/// proxy, which puts the client’s address last in `X-Forwarded-For` (anything before it comes
+/// from the client and can’t be trusted).
+fn client_addr(shake: &ws::Handshake, trusted_proxy: Option<IpAddr>) -> String {
+    let peer = match shake.peer_addr {
+        Some(addr) => addr.ip(),
+        None => return String::new(),
+    };
+    let forwarded = shake.request.header("X-Forwarded-For").and_then(|header| {
+        let header = String::from_utf8_lossy(header);
+        header.rsplit(',').next()?.trim().parse::<IpAddr>().ok()
+    });
+    match forwarded {
+        Some(addr) if Some(peer) == trusted_proxy => addr.to_string(),
+        _ => peer.to_string(),
+    }
+}
+
+struct ConnHandler {
+    out: Arc<ws::Sender>,
+    global: Weak<Mutex<GlobalState>>,
+    /// The room this connection is in, and its update thread (set when the request comes in).
+    room: String,
+    update_tx: Option<mpsc::Sender<UpdateMsg>>,
+    id: u64,
+    prev_login_attempt: Option<time::Instant>,
+    login: Option<String>,
+    id_info: String,
+    /// Identifies anonymous connections in the event log.
+    fingerprint: String,
+    /// The client’s IP address, for rate limiting.
+    addr: String,
+    limiter: ConnLimiter,
+    /// Whether the client negotiated the binary protocol.
+    binary: bool,
+    /// Set if the client asked for a timelapse instead: what to show and the delay between
+    /// frames.
+    timelapse: Option<(timelapse::Options, time::Duration)>,
+    /// Stops the timelapse stream when the client goes away.
+    cancel_timelapse: Arc<AtomicBool>,
+    /// Whether the hooks were told about this connection.
+    connected: bool,
+    /// What the client confirmed receiving, for its outbox.
+    receipts: Arc<Mutex<Receipts>>,
+}
+
+impl ConnHandler {
+    fn new(global: Weak<Mutex<GlobalState>>, out: ws::Sender, id: u64) -> ConnHandler {
+        let out = Arc::new(out);
+        ConnHandler {
+            out,
+            global,
+            room: String::new(),
+            update_tx: None,
+            id,
+            prev_login_attempt: None,
+            login: None,
+            id_info: String::new(),
+            fingerprint: String::new(),
+            addr: String::new(),
+            limiter: ConnLimiter::new(),
+            binary: false,
+            timelapse: None,
+            cancel_timelapse: Arc::new(AtomicBool::new(false)),
+            connected: false,
+            receipts: Arc::default(),
+        }
+    }
+
+    /// Returns who this connection acts as.
+    fn actor(&self) -> Actor {
+        match self.login {
+            Some(ref login) => Actor::Login(login.clone()),
+            None => Actor::Anonymous(self.fingerprint.clone()),
+        }
+    }
+
+    fn not_found() -> ws::Response {
+        ws::Response::new(404, "Not Found", b"Not found".to_vec())
+    }
+
+    fn forbidden() -> ws::Response {
+        ws::Response::new(403, "Forbidden", b"Forbidden".to_vec())
+    }
+
+    fn bad_request(message: &str) -> ws::Response {
+        ws::Response::new(400, "Bad Request", message.as_bytes().to_vec())
+    }
+
+    /// Asks the client to repeat the request in a second. Browsers showing the page reload it.
+    fn try_again(message: &str) -> ws::Response {
+        let body = format!(
+            "<!doctype html><meta http-equiv=\"refresh\" content=\"1\"><p>{}</p>",
+            message
+        );
+        let mut res = ws::Response::new(503, "Service Unavailable", body.into_bytes());
+        res.headers_mut().push(("Retry-After".into(), b"1".to_vec()));
+        res.headers_mut()
+            .push(("Content-Type".into(), b"text/html".to_vec()));
+        res
+    }
+
+    /// Serves a region of a canvas as PNG. The query may contain a region (`x`, `y`, `w` and
+    /// `h`, the whole canvas otherwise) and a `scale`. The image is made on another thread, so
+    /// the first request is told to try again (see `exports`).
+    fn export_png(&self, room: &str, query: &str) -> ws::Response {
+        let params = parse_query(query);
+        let scale = match params.get("scale").map(|scale| scale.parse()) {
+            Some(Ok(scale)) if (1..=MAX_EXPORT_SCALE).contains(&scale) => scale,
+            Some(_) => return ConnHandler::bad_request("Invalid scale"),
+            None => 1,
+        };
+        let rect = match query_region(&params) {
+            Ok(rect) => rect,
+            Err(message) => return ConnHandler::bad_request(message),
+        };
+
+        let global = self.global.upgrade().unwrap();
+        let key = exports::Key {
+            room: room.into(),
+            rect,
+            scale,
+        };
+        let update_tx = {
+            let mut global = global.lock().unwrap();
+            let update_tx = match global.rooms.get(room) {
+                Some(room) => room.update_tx.clone(),
+                None => return ConnHandler::not_found(),
+            };
+            match global.exports.get(&key, time::Instant::now()) {
+                exports::Status::Done(Ok(png)) => {
+                    let mut res = ws::Response::new(200, "OK", png.to_vec());
+                    res.headers_mut()
+                        .push(("Content-Type".into(), b"image/png".to_vec()));
+                    return res;
+                }
+                exports::Status::Done(Err(message)) => {
+                    return ConnHandler::bad_request(&message);
+                }
+                exports::Status::Running => {
+                    return ConnHandler::try_again("The image is being made");
+                }
+                exports::Status::Busy => {
+                    return ConnHandler::try_again("Too many exports; try later");
+                }
+                exports::Status::Start => update_tx,
+            }
+        };
+
+        // waiting for the canvas and encoding it happen on another thread
+        thread::spawn(move || {
+            let (reply_tx, reply_rx) = mpsc::channel();
+            let msg = UpdateMsg::Export {
+                rect,
+                max_pixels: image::MAX_EXPORT_PIXELS / (scale as u64 * scale as u64),
+                reply: reply_tx,
+            };
+            let export = match update_tx.send(msg).map(|_| reply_rx.recv()) {
+                Ok(Ok(Ok(region))) => Ok(Arc::new(image::encode_png(&region, scale))),
+                Ok(Ok(Err(message))) => Err(message.into()),
+                _ => Err("Canvas closed".into()),
+            };
+            let mut global = global.lock().unwrap();
+            global.exports.finish(key, export, time::Instant::now());
+        });
+        ConnHandler::try_again("The image is being made")
+    }
+
+    /// Starts streaming a timelapse of the canvas from its event log on another thread.
+    fn stream_timelapse(&mut self, options: timelapse::Options, delay: time::Duration) {
+        let global = self.global.upgrade().unwrap();
+        {
+            let mut global = global.lock().unwrap();
+            if global.timelapse_streams >= MAX_TIMELAPSE_STREAMS {
+                let reason = "Too many timelapses; try later";
+                if let Err(err) = self.out.close_with_reason(ws::CloseCode::Again, reason) {
+                    eprintln!("Close error: {:?}", err);
+                }
+                return;
+            }
+            global.timelapse_streams += 1;
+        }
+
+        // the history is read on this thread rather than the update thread
+        let (reply_tx, reply_rx) = mpsc::channel();
+        self.update(UpdateMsg::History { reply: reply_tx });
+        let out = self.out.clone();
+        let binary = self.binary;
+        let cancel = Arc::clone(&self.cancel_timelapse);
+        thread::spawn(move || {
+            match reply_rx.recv() {
+                Ok(history) => {
+                    let contents = history.and_then(|read| read());
+                    timelapse::stream(&out, binary, contents, &options, delay, &cancel);
+                }
+                Err(_) => {
+                    let _ = out.close_with_reason(ws::CloseCode::Away, "Canvas closed");
+                }
+            }
+            global.lock().unwrap().timelapse_streams -= 1;
+        });
+    }
+
+    fn internal_error() -> ws::Response {
+        ws::Response::new(
+            500,
+            "Internal Server Error",
+            b"Internal server error".to_vec(),
+        )
+    }
+
+    fn send(&self, msg: ClientMessage) {
+        match self.out.send(msg) {
+            Ok(_) => (),
+            Err(err) => eprintln!("Send error: {:?}", err),
+        }
+    }
+
+    fn send_error(&self, code: &str, message: &str) {
+        self.send(ClientMessage::Error {
+            code: code.to_string(),
+            message: message.to_string(),
+        });
+    }
+
+    /// Sends a message to the room’s update thread. Returns false if the room has been closed.
+    fn update(&self, msg: UpdateMsg) -> bool {
+        match self.update_tx {
+            Some(ref update_tx) => update_tx.send(msg).is_ok(),
+            None => false,
+        }
+    }
+
+    /// Places pixels on behalf of this connection, stopping at the first one that is rate limited.
+    fn set_pixels(&mut self, pixels: &[PixelRequest]) {
+        let global_lock = self.global.upgrade().unwrap();
+        let mut global = global_lock.lock().unwrap();
+        let room = match global.rooms.get_mut(&self.room) {
+            Some(room) => room,
+            None => return self.send_error("canvas-closed", "This canvas has been closed"),
+        };
+        if room.shutting_down {
+            self.send_error("shutting-down", "The server is shutting down");
+            return;
+        }
+
+        let mut cooldown = None;
+        for &(x, y, r, g, b) in pixels {
+            // admins aren’t rate limited
+            if self.login.is_none() {
+                let limits = room.rate_limits;
+                let result = self.limiter.try_place(
+                    &mut room.ip_buckets,
+                    &self.addr,
+                    &limits,
+                    time::Instant::now(),
+                );
+                match result {
+                    Ok(wait) => cooldown = Some(wait),
+                    Err(wait) => {
+                        self.send_error("rate-limited", "Placing pixels too quickly");
+                        cooldown = Some(wait);
+                        break;
+                    }
+                }
+            }
+
+            self.update(UpdateMsg::SetPixel {
+                x,
+                y,
+                r,
+                g,
+                b,
+                actor: self.actor(),
+                out: Arc::clone(&self.out),
+            });
+        }
+
+        if let Some(wait) = cooldown {
+            self.send(ClientMessage::Cooldown {
+                wait: duration_millis(wait),
+            });
+        }
+    }
+
+    /// Places or erases a pixel on a layer other than the user layer (admins only).
+    fn paint_layer(&self, layer: &str, x: i32, y: i32, color: Option<(u8, u8, u8)>) {
+        if self.login.is_none() {
+            return self.send_error("forbidden", "Only admins can paint on layers");
+        }
+        self.update(UpdateMsg::LayerPixel {
+            layer: layer.to_string(),
+            x,
+            y,
+            color,
+            actor: self.actor(),
+            out: Arc::clone(&self.out),
+        });
+    }
+}
+
+impl ws::Handler for ConnHandler {
+    fn on_request(&mut self, req: &ws::Request) -> ws::Result<ws::Response> {
+        let mut user_agent = String::from("?");
+        for (header, data) in req.headers() {
+            if header == "User-Agent" {
+                user_agent = String::from_utf8_lossy(data).to_string();
+            }
+        }
+        self.id_info = format!("addr: {:?}, ua: {}", req.client_addr(), user_agent);
+
+        // the query string is only for the page’s scripts and exports
+        let mut resource = req.resource().splitn(2, '?');
+        let path = resource.next().unwrap_or("/");
+        let query = resource.next().unwrap_or("");
+
+        if path == "/canvas.png" {
+            return Ok(self.export_png(rooms::DEFAULT, query));
+        } else if path.starts_with("/canvas/") && path.ends_with(".png") {
+            return Ok(self.export_png(&path["/canvas/".len()..path.len() - ".png".len()], query));
+        }
+        let room = if path == "/canvas" || path == "/timelapse" {
+            Some(rooms::DEFAULT)
+        } else {
+            path.strip_prefix("/canvas/").or_else(|| path.strip_prefix("/timelapse/"))
+        };
+        if path.starts_with("/timelapse") && room.is_some() {
+            match timelapse_query(query) {
+                Ok(timelapse) => self.timelapse = Some(timelapse),
+                Err(message) => return Ok(ConnHandler::bad_request(message)),
+            }
+        }
+
+        match room {
+            Some(room) => {
+                let global = self.global.upgrade().unwrap();
+                let global = global.lock().unwrap();
+                match global.rooms.get(room) {
+                    Some(r) if !r.shutting_down => {
+                        self.room = room.to_string();
+                        self.update_tx = Some(r.update_tx.clone());
+                    }
+                    _ => return Ok(ConnHandler::not_found()),
+                }
+
+                let mut res = ws::Response::from_request(req)?;
+                if req.protocols()?.contains(&messages::BINARY_PROTOCOL) {
+                    res.set_protocol(messages::BINARY_PROTOCOL);
+                    self.binary = true;
+                }
+                Ok(res)
+            }
+            None => {
+                let path = if path == "/" { "/index.html" } else { path };
+
+                let global = self.global.upgrade().unwrap();
+                let static_dir = global.lock().unwrap().config.static_dir.clone();
+
+                let file_path = match static_dir
+                    .join(match PathBuf::from(path).strip_prefix("/") {
+                        Ok(path) => path,
+                        Err(_) => return Ok(ConnHandler::not_found()),
+                    })
+                    .canonicalize()
+                {
+                    Ok(path) => path,
+                    Err(_) => return Ok(ConnHandler::not_found()),
+                };
+
+                if file_path.strip_prefix(&static_dir).is_err() {
+                    return Ok(ConnHandler::not_found());
+                }
+
+                match fs::read(&file_path) {
+                    Ok(file) => {
+                        let mut res = ws::Response::new(200, "OK", file);
+                        if let Some(ext) = file_path.extension() {
+                            if let Some(ext) = ext.to_str() {
+                                match ext {
+                                    "html" => res.headers_mut().push((
+                                        "Content-Type".into(),
+                                        b"text/html; charset=utf-8".to_vec(),
+                                    )),
+                                    "css" => res.headers_mut().push((
+                                        "Content-Type".into(),
+                                        b"text/css; charset=utf-8".to_vec(),
+                                    )),
+                                    "js" => res.headers_mut().push((
+                                        "Content-Type".into(),
+                                        b"application/javascript; charset=utf-8".to_vec(),
+                                    )),
+                                    _ => (),
+                                }
+                            }
+                        }
+                        Ok(res)
+                    }
+                    Err(err) => match err.kind() {
+                        io::ErrorKind::NotFound => Ok(ConnHandler::not_found()),
+                        io::ErrorKind::PermissionDenied => Ok(ConnHandler::forbidden()),
+                        _ => Ok(ConnHandler::internal_error()),
+                    },
+                }
+            }
+        }
+    }
+
+    fn on_open(&mut self, shake: ws::Handshake) -> ws::Result<()> {
+        let trusted_proxy = match self.global.upgrade() {
+            Some(global) => global.lock().unwrap().config.trusted_proxy,
+            None => None,
+        };
+        let addr = client_addr(&shake, trusted_proxy);
+        let user_agent = shake
+            .request
+            .header("User-Agent")
+            .map(|ua| String::from_utf8_lossy(ua).to_string())
+            .unwrap_or_default();
+        let digest = hex_digest(Algorithm::SHA256, format!("{} {}", addr, user_agent).as_bytes());
+        self.fingerprint = digest[..12].to_string();
+        self.addr = addr;
+
+        if let Some((options, delay)) = self.timelapse.clone() {
+            self.stream_timelapse(options, delay);
+            return Ok(());
+        }
+
+        let hooks = self.global.upgrade().unwrap().lock().unwrap().hooks.clone();
+        if !hooks::check_connect(&hooks, &self.room, self.id, &self.addr) {
+            return self
+                .out
+                .close_with_reason(ws::CloseCode::Policy, "Connection refused");
+        }
+        self.connected = true;
+
+        let opened = self.update(UpdateMsg::FullUpdate(ClientSender {
+            id: self.id,
+            id_info: self.id_info.clone(),
+            outbox: Outbox::new(Arc::clone(&self.out), Arc::clone(&self.receipts)),
+            binary: self.binary,
+            viewport: None,
+            synced: false,
+        }));
+        if !opened {
+            self.out.close_with_reason(ws::CloseCode::Away, "Canvas closed")?;
+        }
+        Ok(())
+    }
+
+    fn on_frame(&mut self, frame: ws::Frame) -> ws::Result<Option<ws::Frame>> {
+        if frame.opcode() == ws::OpCode::Pong {
+            self.receipts.lock().unwrap().confirm(frame.payload());
+        }
+        // as the default implementation does
+        if frame.has_rsv1() || frame.has_rsv2() || frame.has_rsv3() {
+            return Err(ws::Error::new(
+                ws::ErrorKind::Protocol,
+                "Encountered frame with reserved bits set.",
+            ));
+        }
+        Ok(Some(frame))
+    }
+
+    fn on_close(&mut self, _: ws::CloseCode, _: &str) {
+        self.cancel_timelapse.store(true, Ordering::Relaxed);
+        self.update(UpdateMsg::Remove(self.id));
+
+        if self.connected {
+            if let Some(global) = self.global.upgrade() {
+                let hooks = global.lock().unwrap().hooks.clone();
+                hooks::disconnected(&hooks, &self.room, self.id);
+            }
+        }
+    }
+
+    fn on_message(&mut self, message: ws::Message) -> ws::Result<()> {
+        if self.timelapse.is_some() {
+            // timelapse viewers can’t do anything
+            return Ok(());
+        }
+        let message = match message {
+            ws::Message::Text(message) => message,
+            ws::Message::Binary(data) => {
+                match messages::decode_binary_request(&data) {
+                    Ok(pixels) => self.set_pixels(&pixels),
+                    Err(err) => self.send_error("binary-message", err),
+                }
+                return Ok(());
+            }
+        };
+
+        let client_request: ClientRequest = match serde_json::from_str(&message) {
+            Ok(req) => req,
+            Err(err) => {
+                self.send_error("message-json", &format!("Invalid message: {}", err));
+                return Ok(());
+            }
+        };
+
+        match client_request {
+            ClientRequest::SetPixel {
+                x,
+                y,
+                r,
+                g,
+                b,
+                layer,
+            } => match layer {
+                Some(ref layer) if layer != canvas::USER_LAYER => {
+                    self.paint_layer(layer, x, y, Some((r, g, b)))
+                }
+                _ => self.set_pixels(&[(x, y, r, g, b)]),
+            },
+            ClientRequest::ErasePixel { x, y, layer } => self.paint_layer(&layer, x, y, None),
+            ClientRequest::FetchLayer { layer, x, y, w, h } => {
+                if self.login.is_none() {
+                    self.send_error("forbidden", "Only admins can fetch layers");
+                    return Ok(());
+                }
+                self.update(UpdateMsg::FetchLayer {
+                    layer,
+                    x,
+                    y,
+                    w,
+                    h,
+                    out: Arc::clone(&self.out),
+                });
+            }
+            ClientRequest::ChatMessage { x, y, text } => {
+                self.update(UpdateMsg::ChatMessage {
+                    x,
+                    y,
+                    text,
+                    actor: self.actor(),
+                });
+            }
+            ClientRequest::Viewport { x, y, w, h } => {
+                self.update(UpdateMsg::Viewport {
+                    id: self.id,
+                    x,
+                    y,
+                    w,
+                    h,
+                });
+            }
+            ClientRequest::PixelInfo { x, y } => {
+                self.update(UpdateMsg::PixelInfo {
+                    x,
+                    y,
+                    is_admin: self.login.is_some(),
+                    out: Arc::clone(&self.out),
+                });
+            }
+            ClientRequest::Auth { login, password } => {
+                if let Some(prev_time) = self.prev_login_attempt {
+                    if prev_time.elapsed().as_secs() < 3 {
+                        self.send(ClientMessage::Auth(None));
+                        return Ok(());
+                    }
+                }
+
+                let global_lock = self.global.upgrade().unwrap();
+                if global_lock.lock().unwrap().auth.verify(&login, &password) {
+                    self.send(ClientMessage::Auth(Some(true)));
+                    self.send(ClientMessage::Console(format!("Logged in ({})", login)));
+                    self.login = Some(login);
+                } else {
+                    self.prev_login_attempt = Some(time::Instant::now());
+                    self.send(ClientMessage::Auth(Some(false)));
+                }
+            }
+            ClientRequest::Console(cmd) => {
+                if self.login.is_none() {
+                    self.send(ClientMessage::Console("Not logged in".into()));
+                    return Ok(());
+                }
+                if let Some(ref update_tx) = self.update_tx {
+                    let actor = self.actor();
+                    console::run_command(
+                        &self.out,
+                        &self.room,
+                        update_tx,
+                        &self.global,
+                        &actor,
+                        &cmd,
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+}
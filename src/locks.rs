@@ -0,0 +1,178 @@
+//! Region locks: named rectangles that only some (or no) actors may paint in.
+//!
+//! Locks are kept by the canvas store (the file stores keep them in `canvas.locks`, as JSON next
+//! to the canvas file) and saved whenever they change.
+
+use canvas::Rect;
+use history::Actor;
+use hooks;
+use store::{CanvasStore, StoreError};
+
+/// Who may still paint inside a lock.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum LockPolicy {
+    /// Nobody.
+    #[serde(rename = "everyone")]
+    Everyone,
+    /// Only logged-in admins.
+    #[serde(rename = "admins")]
+    Admins,
+    /// Only the listed logins.
+    #[serde(rename = "logins")]
+    Logins(Vec<String>),
+}
+
+impl LockPolicy {
+    /// Returns whether the actor may paint inside a lock with this policy.
+    pub fn allows(&self, actor: &Actor) -> bool {
+        match (self, actor) {
+            // restores and rollbacks must be able to touch anything, but bots placing pixels
+            // through hooks are held to locks like everyone else
+            (_, Actor::Server(what)) => what != hooks::ACTOR,
+            (LockPolicy::Everyone, _) => false,
+            (LockPolicy::Admins, Actor::Login(_)) => true,
+            (LockPolicy::Logins(logins), Actor::Login(login)) => logins.contains(login),
+            _ => false,
+        }
+    }
+
+    /// Returns the policy name as sent to clients, which don’t get to see the logins.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            LockPolicy::Everyone => "everyone",
+            LockPolicy::Admins => "admins",
+            LockPolicy::Logins(_) => "logins",
+        }
+    }
+}
+
+/// A locked region.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Lock {
+    pub name: String,
+    pub rect: Rect,
+    pub policy: LockPolicy,
+}
+
+/// The locks of one canvas.
+#[derive(Debug)]
+pub struct Locks {
+    locks: Vec<Lock>,
+}
+
+impl Locks {
+    /// Loads the locks kept in a store, if there are any. Fails if they can’t be read, since
+    /// carrying on without them would silently unlock everything.
+    pub fn load(store: &dyn CanvasStore) -> Result<Locks, StoreError> {
+        Ok(Locks {
+            locks: store.locks()?,
+        })
+    }
+
+    pub fn save(&self, store: &mut dyn CanvasStore) -> Result<(), StoreError> {
+        store.set_locks(&self.locks)
+    }
+
+    pub fn locks(&self) -> &[Lock] {
+        &self.locks
+    }
+
+    /// Adds a lock, replacing any lock with the same name.
+    pub fn set(&mut self, lock: Lock) {
+        match self.locks.iter_mut().find(|l| l.name == lock.name) {
+            Some(existing) => *existing = lock,
+            None => self.locks.push(lock),
+        }
+    }
+
+    /// Removes a lock and returns whether it existed.
+    pub fn remove(&mut self, name: &str) -> bool {
+        let len = self.locks.len();
+        self.locks.retain(|l| l.name != name);
+        self.locks.len() != len
+    }
+
+    /// Returns the first lock that keeps the actor from painting the pixel, if any.
+    pub fn blocking(&self, x: i32, y: i32, actor: &Actor) -> Option<&Lock> {
+        self.locks
+            .iter()
+            .find(|l| l.rect.contains(x, y) && !l.policy.allows(actor))
+    }
+
+    /// Moves all locks along with canvas contents moved by `(dx, dy)`.
+    pub fn shift(&mut self, dx: i32, dy: i32) {
+        for lock in &mut self.locks {
+            lock.rect.x = lock.rect.x.saturating_add(dx);
+            lock.rect.y = lock.rect.y.saturating_add(dy);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn login(name: &str) -> Actor {
+        Actor::Login(name.into())
+    }
+
+    #[test]
+    fn policies_allow_their_actors() {
+        let anonymous = Actor::Anonymous("abc".into());
+        let restore = Actor::Server("restore".into());
+        let bot = Actor::Server(hooks::ACTOR.into());
+        let logins = LockPolicy::Logins(vec!["someone".into()]);
+
+        for policy in &[LockPolicy::Everyone, LockPolicy::Admins, logins.clone()] {
+            assert!(!policy.allows(&anonymous));
+            assert!(policy.allows(&restore));
+            assert!(!policy.allows(&bot));
+        }
+        assert!(!LockPolicy::Everyone.allows(&login("admin")));
+        assert!(LockPolicy::Admins.allows(&login("admin")));
+        assert!(logins.allows(&login("someone")));
+        assert!(!logins.allows(&login("admin")));
+    }
+
+    #[test]
+    fn the_first_blocking_lock_is_found() {
+        let mut locks = Locks { locks: Vec::new() };
+        let rect = Rect {
+            x: 0,
+            y: 0,
+            w: 4,
+            h: 4,
+        };
+        locks.set(Lock {
+            name: "logo".into(),
+            rect,
+            policy: LockPolicy::Logins(vec!["someone".into()]),
+        });
+        locks.set(Lock {
+            name: "frame".into(),
+            rect: Rect { x: 2, ..rect },
+            policy: LockPolicy::Admins,
+        });
+        let someone = login("someone");
+        assert_eq!(locks.blocking(1, 1, &someone).map(|l| &*l.name), None);
+        assert_eq!(locks.blocking(5, 1, &someone).map(|l| &*l.name), None);
+
+        let anonymous = Actor::Anonymous("abc".into());
+        assert_eq!(locks.blocking(3, 1, &anonymous).map(|l| &*l.name), Some("logo"));
+        assert_eq!(locks.blocking(5, 1, &anonymous).map(|l| &*l.name), Some("frame"));
+        assert_eq!(locks.blocking(6, 1, &anonymous).map(|l| &*l.name), None);
+
+        // replacing a lock keeps its place, and moving shifts every lock
+        locks.set(Lock {
+            name: "logo".into(),
+            rect,
+            policy: LockPolicy::Everyone,
+        });
+        assert_eq!(locks.blocking(1, 1, &someone).map(|l| &*l.name), Some("logo"));
+        locks.shift(10, 0);
+        assert_eq!(locks.blocking(11, 1, &someone).map(|l| &*l.name), Some("logo"));
+        assert!(locks.remove("logo"));
+        assert!(!locks.remove("logo"));
+        assert_eq!(locks.locks().len(), 1);
+    }
+}
@@ -3,6 +3,7 @@ use serde::{Serialize, Serializer};
 use serde_json;
 use std::collections::{BTreeMap, HashMap};
 use std::fs;
+use std::path::Path;
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Logins {
@@ -11,15 +12,14 @@ pub struct Logins {
 }
 
 impl Logins {
-    pub fn init() -> Logins {
-        match fs::read("logins.json") {
-            Ok(file) => match serde_json::from_slice(&file) {
-                Ok(logins) => logins,
-                Err(err) => panic!("Failed to read logins.json: {:?}", err),
-            },
-            Err(_) => Logins {
+    /// Reads the logins file, if there is one.
+    pub fn load(path: &Path) -> Result<Logins, String> {
+        match fs::read(path) {
+            Ok(file) => serde_json::from_slice(&file)
+                .map_err(|err| format!("Failed to read {}: {}", path.display(), err)),
+            Err(_) => Ok(Logins {
                 logins: HashMap::new(),
-            },
+            }),
         }
     }
 
@@ -31,6 +31,18 @@ impl Logins {
     }
 }
 
+/// Checks admin logins.
+pub trait AuthProvider: Send {
+    /// Returns true if the password is right for the login.
+    fn verify(&self, login: &str, password: &str) -> bool;
+}
+
+impl AuthProvider for Logins {
+    fn verify(&self, login: &str, password: &str) -> bool {
+        self.verify_login(login, password)
+    }
+}
+
 fn ser_hash_map<S>(value: &HashMap<String, Login>, serializer: S) -> Result<S::Ok, S::Error>
 where
     S: Serializer,
@@ -1,13 +1,39 @@
 use base64;
-use canvas::Region;
+use canvas::{Canvas, Extent, Palette, Rect, Region};
+use format;
+use locks::Locks;
 use serde_json;
+use std::cell::RefCell;
 use ws;
 
 #[derive(Debug, Deserialize)]
 #[serde(tag = "type", content = "data")]
 pub enum ClientRequest {
+    /// Places a pixel, on the user layer unless an admin asks for another one.
     #[serde(rename = "set-pixel")]
-    SetPixel { x: u32, y: u32, r: u8, g: u8, b: u8 },
+    SetPixel {
+        x: i32,
+        y: i32,
+        r: u8,
+        g: u8,
+        b: u8,
+        #[serde(default)]
+        layer: Option<String>,
+    },
+
+    /// Makes a pixel on a layer other than the user layer transparent again (admins only).
+    #[serde(rename = "erase-pixel")]
+    ErasePixel { x: i32, y: i32, layer: String },
+
+    /// Requests a region of a single layer, answered with a `layer` message (admins only).
+    #[serde(rename = "fetch-layer")]
+    FetchLayer {
+        layer: String,
+        x: i32,
+        y: i32,
+        w: u32,
+        h: u32,
+    },
 
     #[serde(rename = "chat-message")]
     ChatMessage { x: f32, y: f32, text: String },
@@ -17,35 +43,46 @@ pub enum ClientRequest {
 
     #[serde(rename = "console")]
     Console(String),
+
+    #[serde(rename = "pixel-info")]
+    PixelInfo { x: i32, y: i32 },
+
+    /// The area the client is looking at. Once sent, the client only receives deltas there.
+    /// On an infinite canvas, this is also how clients get the canvas contents.
+    #[serde(rename = "viewport")]
+    Viewport { x: i32, y: i32, w: u32, h: u32 },
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct RGBARegion {
-    pub x: u32,
-    pub y: u32,
+    pub x: i32,
+    pub y: i32,
     pub w: u32,
     pub h: u32,
     pub data: String,
 }
 
-impl From<Region> for RGBARegion {
-    fn from(region: Region) -> RGBARegion {
-        let mut rgba_data = Vec::with_capacity(region.data.len() * 4 / 3);
-
-        for i in 0..(region.data.len() / 3) {
-            rgba_data.push(region.data[i * 3 + 0]);
-            rgba_data.push(region.data[i * 3 + 1]);
-            rgba_data.push(region.data[i * 3 + 2]);
-            rgba_data.push(255);
+impl RGBARegion {
+    /// Creates a region from RGBA data.
+    pub fn from_rgba(x: i32, y: i32, w: u32, h: u32, rgba: &[u8]) -> RGBARegion {
+        RGBARegion {
+            x,
+            y,
+            w,
+            h,
+            data: base64::encode(rgba),
         }
+    }
+}
 
-        RGBARegion {
-            x: region.x,
-            y: region.y,
-            w: region.w,
-            h: region.h,
-            data: base64::encode(&rgba_data),
+impl From<&Region> for RGBARegion {
+    fn from(region: &Region) -> RGBARegion {
+        let mut rgba_data = Vec::with_capacity(region.data.len() * 4 / 3);
+        for rgb in region.data.chunks(3) {
+            rgba_data.extend_from_slice(rgb);
+            rgba_data.push(255);
         }
+        RGBARegion::from_rgba(region.x, region.y, region.w, region.h, &rgba_data)
     }
 }
 
@@ -78,10 +115,421 @@ pub enum ClientMessage {
 
     #[serde(rename = "console")]
     Console(String),
+
+    /// The colors clients may use, or null if any color is allowed. If `snap` is set, other
+    /// colors are snapped to the nearest palette color instead of being rejected.
+    #[serde(rename = "palette")]
+    Palette {
+        colors: Option<Vec<(u8, u8, u8)>>,
+        snap: bool,
+    },
+
+    /// Milliseconds until the client may place its next pixel.
+    #[serde(rename = "cooldown")]
+    Cooldown { wait: u64 },
+
+    /// Who last placed a pixel. `placed_by` is the full identity for admins and a pseudonym
+    /// otherwise; both it and `time` (UNIX milliseconds) are null if the pixel was never placed.
+    #[serde(rename = "pixel-info")]
+    PixelInfo {
+        x: i32,
+        y: i32,
+        placed_by: Option<String>,
+        time: Option<u64>,
+    },
+
+    /// Where pixels can be placed: `world` is the boundary (null if unlimited), and `populated`
+    /// covers everything painted so far (in whole tiles, null if nothing was).
+    #[serde(rename = "bounds")]
+    Bounds {
+        infinite: bool,
+        world: Option<Rect>,
+        populated: Option<Rect>,
+    },
+
+    /// The canvas was resized and its contents moved by `(dx, dy)`, so clients can keep looking
+    /// at the same spot. A full update or a reset follows.
+    #[serde(rename = "resize")]
+    Resize {
+        world: Option<Rect>,
+        dx: i32,
+        dy: i32,
+    },
+
+    /// The canvas changed as a whole; clients should discard what they have and send their
+    /// viewport again (infinite canvases only, finite ones get a full update instead).
+    #[serde(rename = "reset")]
+    Reset,
+
+    /// A region of a single layer, in reply to `fetch-layer`. Transparent pixels have an alpha
+    /// of 0.
+    #[serde(rename = "layer")]
+    Layer { layer: String, region: RGBARegion },
+
+    /// A timelapse frame follows, showing the canvas at `time` (UNIX milliseconds).
+    #[serde(rename = "timelapse-frame")]
+    TimelapseFrame { time: u64, index: u64, frames: u64 },
+
+    /// The locked regions, sent on connect and whenever they change.
+    #[serde(rename = "locks")]
+    Locks(Vec<LockInfo>),
 }
 
-impl Into<ws::Message> for ClientMessage {
-    fn into(self) -> ws::Message {
-        ws::Message::Text(serde_json::to_string(&self).unwrap())
+/// A locked region as clients see it. `policy` is `everyone`, `admins` or `logins`.
+#[derive(Debug, Clone, Serialize)]
+pub struct LockInfo {
+    pub name: String,
+    pub rect: Rect,
+    pub policy: &'static str,
+}
+
+impl From<&Canvas> for ClientMessage {
+    /// Creates a bounds message.
+    fn from(canvas: &Canvas) -> ClientMessage {
+        ClientMessage::Bounds {
+            infinite: canvas.is_infinite(),
+            world: canvas.bounds(),
+            populated: canvas.populated(),
+        }
+    }
+}
+
+impl From<&Locks> for ClientMessage {
+    fn from(locks: &Locks) -> ClientMessage {
+        ClientMessage::Locks(
+            locks
+                .locks()
+                .iter()
+                .map(|lock| LockInfo {
+                    name: lock.name.clone(),
+                    rect: lock.rect,
+                    policy: lock.policy.kind(),
+                }).collect(),
+        )
+    }
+}
+
+impl From<Option<&Palette>> for ClientMessage {
+    fn from(palette: Option<&Palette>) -> ClientMessage {
+        ClientMessage::Palette {
+            colors: palette.map(|palette| palette.colors.clone()),
+            snap: palette.is_some_and(|palette| palette.snap),
+        }
+    }
+}
+
+impl From<ClientMessage> for ws::Message {
+    fn from(message: ClientMessage) -> ws::Message {
+        ws::Message::Text(serde_json::to_string(&message).unwrap())
+    }
+}
+
+// Binary protocol (WebSocket subprotocol `place.binary`). Each binary frame starts with its type.
+// All integers are big-endian. Pixel data from the server is run-length encoded (see `rle_encode`).
+
+/// The WebSocket subprotocol clients request to use the binary protocol.
+pub const BINARY_PROTOCOL: &str = "place.binary";
+
+/// Server → client: `w: u32, h: u32, RLE data`.
+pub const BINARY_FULL_UPDATE: u8 = 0x01;
+
+/// Server → client: `{ x: i32, y: i32, w: u32, h: u32, len: u32, RLE data: len bytes }*`.
+pub const BINARY_REGIONS: u8 = 0x02;
+
+/// Client → server: `{ x: i32, y: i32, r: u8, g: u8, b: u8 }*`.
+pub const BINARY_SET_PIXELS: u8 = 0x10;
+
+/// The largest finite canvases (in pixels) that clients get whole. Larger ones are sent by
+/// viewport, like infinite canvases.
+pub const MAX_FULL_UPDATE_AREA: u64 = 1024 * 1024;
+
+/// Canvas data for clients, which is encoded differently for each protocol.
+#[derive(Debug, Clone)]
+pub enum CanvasUpdate {
+    /// The whole canvas.
+    Full(Region),
+    Regions(Vec<Region>),
+}
+
+impl CanvasUpdate {
+    /// Returns whether clients get the whole canvas at once, which is only the case for finite
+    /// canvases that aren’t too large. Clients fetch the rest by viewport.
+    pub fn is_sent_whole(canvas: &Canvas) -> bool {
+        match canvas.extent() {
+            Extent::Finite { width, height } => width as u64 * height as u64 <= MAX_FULL_UPDATE_AREA,
+            _ => false,
+        }
+    }
+
+    /// Creates a full update, if the canvas is sent whole.
+    pub fn full(canvas: &Canvas) -> Option<CanvasUpdate> {
+        if !CanvasUpdate::is_sent_whole(canvas) {
+            return None;
+        }
+        let bounds = canvas.bounds()?;
+        canvas
+            .region(bounds.x, bounds.y, bounds.w, bounds.h)
+            .map(CanvasUpdate::Full)
+    }
+
+    /// Encodes the update as a JSON message.
+    pub fn to_json(&self) -> ClientMessage {
+        match self {
+            CanvasUpdate::Full(region) => ClientMessage::FullUpdate {
+                w: region.w,
+                h: region.h,
+                data: RGBARegion::from(region).data,
+            },
+            CanvasUpdate::Regions(regions) => {
+                ClientMessage::Regions(regions.iter().map(RGBARegion::from).collect())
+            }
+        }
+    }
+
+    /// Encodes the update as a binary message.
+    pub fn to_binary(&self) -> Vec<u8> {
+        match self {
+            CanvasUpdate::Full(region) => {
+                let mut buf = vec![BINARY_FULL_UPDATE];
+                format::push_u32(&mut buf, region.w);
+                format::push_u32(&mut buf, region.h);
+                buf.extend_from_slice(&rle_encode(&region.data));
+                buf
+            }
+            CanvasUpdate::Regions(regions) => {
+                let mut buf = vec![BINARY_REGIONS];
+                for region in regions {
+                    let data = rle_encode(&region.data);
+                    format::push_i32(&mut buf, region.x);
+                    format::push_i32(&mut buf, region.y);
+                    format::push_u32(&mut buf, region.w);
+                    format::push_u32(&mut buf, region.h);
+                    format::push_u32(&mut buf, data.len() as u32);
+                    buf.extend_from_slice(&data);
+                }
+                buf
+            }
+        }
+    }
+}
+
+/// Run-length encodes RGB data. A control byte `n < 128` is followed by `n + 1` literal pixels,
+/// and `n >= 128` by a single pixel that repeats `n - 125` (3 to 130) times.
+pub fn rle_encode(rgb: &[u8]) -> Vec<u8> {
+    let pixel = |i: usize| &rgb[i * 3..i * 3 + 3];
+    let count = rgb.len() / 3;
+    let mut out = Vec::new();
+    let mut literal_start = 0;
+    let mut i = 0;
+
+    while i < count {
+        let mut run = 1;
+        while run < 130 && i + run < count && pixel(i + run) == pixel(i) {
+            run += 1;
+        }
+        if run >= 3 {
+            push_literals(&mut out, &rgb[literal_start * 3..i * 3]);
+            out.push((run + 125) as u8);
+            out.extend_from_slice(pixel(i));
+            literal_start = i + run;
+        }
+        i += run;
+    }
+    push_literals(&mut out, &rgb[literal_start * 3..]);
+    out
+}
+
+fn push_literals(out: &mut Vec<u8>, rgb: &[u8]) {
+    for chunk in rgb.chunks(128 * 3) {
+        out.push((chunk.len() / 3 - 1) as u8);
+        out.extend_from_slice(chunk);
+    }
+}
+
+/// A message for clients of either protocol, encoded for each protocol when it’s first needed.
+#[derive(Debug)]
+pub struct Outgoing {
+    update: Option<CanvasUpdate>,
+    text: RefCell<Option<ws::Message>>,
+    binary: RefCell<Option<ws::Message>>,
+}
+
+impl Outgoing {
+    /// Returns the message for a client.
+    pub fn for_client(&self, binary: bool) -> ws::Message {
+        self.prepare(binary);
+        match self.update {
+            Some(_) if binary => self.binary.borrow().clone().unwrap(),
+            _ => self.text.borrow().clone().unwrap(),
+        }
+    }
+
+    /// Encodes the message for clients of a protocol ahead of time.
+    pub fn prepare(&self, binary: bool) {
+        let update = match self.update {
+            Some(ref update) => update,
+            None => return,
+        };
+        let (cell, encode): (_, fn(&CanvasUpdate) -> ws::Message) = if binary {
+            (&self.binary, |u| ws::Message::Binary(u.to_binary()))
+        } else {
+            (&self.text, |u| u.to_json().into())
+        };
+        cell.borrow_mut().get_or_insert_with(|| encode(update));
+    }
+}
+
+impl From<ClientMessage> for Outgoing {
+    fn from(message: ClientMessage) -> Outgoing {
+        Outgoing {
+            update: None,
+            text: RefCell::new(Some(message.into())),
+            binary: RefCell::new(None),
+        }
+    }
+}
+
+impl From<CanvasUpdate> for Outgoing {
+    fn from(update: CanvasUpdate) -> Outgoing {
+        Outgoing {
+            update: Some(update),
+            text: RefCell::new(None),
+            binary: RefCell::new(None),
+        }
+    }
+}
+
+/// A pixel a client wants to set: x, y, r, g, b.
+pub type PixelRequest = (i32, i32, u8, u8, u8);
+
+/// Decodes a binary message from a client.
+pub fn decode_binary_request(data: &[u8]) -> Result<Vec<PixelRequest>, &'static str> {
+    match data.first() {
+        Some(&BINARY_SET_PIXELS) => (),
+        Some(_) => return Err("Unknown binary message type"),
+        None => return Err("Empty binary message"),
+    }
+    if !(data.len() - 1).is_multiple_of(11) {
+        return Err("Invalid set-pixels message length");
+    }
+
+    let mut pixels = Vec::with_capacity((data.len() - 1) / 11);
+    for record in data[1..].chunks(11) {
+        let mut reader = format::Reader::new(record);
+        let x = reader.i32().unwrap();
+        let y = reader.i32().unwrap();
+        pixels.push((x, y, record[8], record[9], record[10]));
+    }
+    Ok(pixels)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Decodes like `rleImageData` in `static/index.js`, but to RGB.
+    fn rle_decode(rle: &[u8], pixels: usize) -> Vec<u8> {
+        let mut out = Vec::new();
+        let mut i = 0;
+        while i < rle.len() && out.len() < pixels * 3 {
+            let n = rle[i] as usize;
+            i += 1;
+            if n < 128 {
+                out.extend_from_slice(&rle[i..i + (n + 1) * 3]);
+                i += (n + 1) * 3;
+            } else {
+                for _ in 0..n - 125 {
+                    out.extend_from_slice(&rle[i..i + 3]);
+                }
+                i += 3;
+            }
+        }
+        assert_eq!(i, rle.len(), "trailing data");
+        out
+    }
+
+    fn round_trip(rgb: &[u8]) {
+        let rle = rle_encode(rgb);
+        assert_eq!(rle_decode(&rle, rgb.len() / 3), rgb);
+    }
+
+    #[test]
+    fn runs_and_literals() {
+        assert_eq!(rle_encode(&[]), Vec::<u8>::new());
+        assert_eq!(rle_encode(&[1, 2, 3]), vec![0, 1, 2, 3]);
+        assert_eq!(rle_encode(&[1, 2, 3, 1, 2, 3]), vec![1, 1, 2, 3, 1, 2, 3]);
+        assert_eq!(rle_encode(&[1, 2, 3].repeat(3)), vec![128, 1, 2, 3]);
+        assert_eq!(rle_encode(&[1, 2, 3].repeat(130)), vec![255, 1, 2, 3]);
+        assert_eq!(
+            rle_encode(&[1, 2, 3].repeat(131)),
+            vec![255, 1, 2, 3, 0, 1, 2, 3]
+        );
+        let mut mixed = vec![9, 9, 9];
+        mixed.extend_from_slice(&[1, 2, 3].repeat(4));
+        assert_eq!(rle_encode(&mixed), vec![0, 9, 9, 9, 129, 1, 2, 3]);
+    }
+
+    #[test]
+    fn long_literals_are_split() {
+        let rgb: Vec<u8> = (0..300 * 3).map(|i| (i / 3) as u8).collect();
+        let rle = rle_encode(&rgb);
+        assert_eq!(rle[0], 127);
+        round_trip(&rgb);
+    }
+
+    #[test]
+    fn round_trips() {
+        let mut seed = 1u32;
+        let mut rgb = Vec::new();
+        for _ in 0..5000 {
+            seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            // runs of all lengths, between single pixels
+            let color = (seed >> 16) as u8 % 3;
+            let run = if color == 0 { 1 } else { 1 + (seed >> 8) as usize % 200 };
+            for _ in 0..run {
+                rgb.extend_from_slice(&[color, color * 2, 255 - color]);
+            }
+        }
+        round_trip(&rgb);
+        round_trip(&[1, 2, 3, 1, 2, 3, 4, 5, 6]);
+    }
+
+    #[test]
+    fn only_binary_updates_are_run_length_encoded() {
+        let region = Region {
+            x: -1,
+            y: 2,
+            w: 4,
+            h: 1,
+            data: [7, 8, 9].repeat(4),
+        };
+        let update = CanvasUpdate::Regions(vec![region]);
+
+        let json = serde_json::to_value(update.to_json()).unwrap();
+        assert_eq!(json["type"], "regions");
+        assert_eq!(json["data"][0]["x"], -1);
+        let data = base64::decode(json["data"][0]["data"].as_str().unwrap()).unwrap();
+        assert_eq!(data, [7, 8, 9, 255].repeat(4));
+
+        let binary = update.to_binary();
+        assert_eq!(binary[0], BINARY_REGIONS);
+        assert_eq!(&binary[binary.len() - 4..], &[129, 7, 8, 9][..]);
+    }
+
+    #[test]
+    fn binary_pixels_decode() {
+        let mut data = vec![BINARY_SET_PIXELS];
+        format::push_i32(&mut data, -2);
+        format::push_i32(&mut data, 3);
+        data.extend_from_slice(&[4, 5, 6]);
+        assert_eq!(decode_binary_request(&data), Ok(vec![(-2, 3, 4, 5, 6)]));
+        assert!(decode_binary_request(&data[..5]).is_err());
+        assert!(decode_binary_request(&[]).is_err());
+    }
+
+    #[test]
+    fn only_small_finite_canvases_are_sent_whole() {
+        assert!(CanvasUpdate::full(&Canvas::blank(1024, 1024)).is_some());
+        assert!(CanvasUpdate::full(&Canvas::blank(1025, 1024)).is_none());
     }
 }
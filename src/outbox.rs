@@ -0,0 +1,286 @@
+//! Per-client queues for what the update thread sends.
+//!
+//! The event loop buffers whatever is handed to a `ws::Sender` without limit, so a client that
+//! reads slower than the canvas changes would make the server hold on to every update it hasn’t
+//! read yet. Instead, only so many bytes may be on their way to a client before it confirms
+//! receiving them; the rest waits in a bounded queue. Confirmations are pongs: after sending, a
+//! ping carrying the number of bytes sent so far goes out, which clients answer by themselves
+//! once they have read everything before it.
+//!
+//! A client whose queue overflows is collapsed to a single pending full update, and one that
+//! confirms nothing for too long is disconnected.
+
+use messages::ClientMessage;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use ws;
+
+/// How many bytes may be on their way to a client before it confirms receiving them.
+const WINDOW: u64 = 1 << 20;
+
+/// How many bytes may be left waiting in a client’s queue before it gets the whole canvas again
+/// instead.
+const QUEUE_LIMIT: usize = 4 << 20;
+
+/// How long a client may take to confirm anything before it’s disconnected.
+const STALL_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How many pings may wait for an answer; older ones are forgotten (later pongs cover them).
+const MAX_PENDING_PINGS: usize = 64;
+
+/// What a client confirmed receiving, shared between its outbox and its connection handler.
+#[derive(Debug, Default)]
+pub struct Receipts {
+    /// Payloads of the pings that weren’t answered yet, oldest first.
+    pending: VecDeque<u64>,
+    /// Bytes the client confirmed receiving.
+    received: u64,
+}
+
+impl Receipts {
+    fn ping(&mut self, sent: u64) {
+        if self.pending.len() >= MAX_PENDING_PINGS {
+            self.pending.pop_front();
+        }
+        self.pending.push_back(sent);
+    }
+
+    /// Records a pong. Only answers to pings that were actually sent count, since the payload
+    /// comes from the client.
+    pub fn confirm(&mut self, payload: &[u8]) {
+        if payload.len() != 8 {
+            return;
+        }
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(payload);
+        let value = u64::from_be_bytes(bytes);
+        if let Some(index) = self.pending.iter().position(|sent| *sent == value) {
+            // pongs come in order, so earlier pings won’t be answered anymore
+            self.pending.drain(..=index);
+            self.received = self.received.max(value);
+        }
+    }
+}
+
+pub struct Outbox {
+    out: Arc<ws::Sender>,
+    queue: VecDeque<ws::Message>,
+    /// Size of the queued messages.
+    queued: usize,
+    /// Bytes handed to the event loop so far.
+    sent: u64,
+    /// Updated by the client’s connection handler.
+    receipts: Arc<Mutex<Receipts>>,
+    /// When the client last confirmed something or had nothing left to confirm.
+    last_progress: Instant,
+    last_received: u64,
+    /// Set if the queue overflowed; nothing is queued until the client gets the canvas again.
+    behind: bool,
+    closed: bool,
+}
+
+impl Outbox {
+    pub fn new(out: Arc<ws::Sender>, receipts: Arc<Mutex<Receipts>>) -> Outbox {
+        Outbox {
+            out,
+            queue: VecDeque::new(),
+            queued: 0,
+            sent: 0,
+            receipts,
+            last_progress: Instant::now(),
+            last_received: 0,
+            behind: false,
+            closed: false,
+        }
+    }
+
+    /// Queues a message, or drops it if the client needs the whole canvas again anyway.
+    pub fn push<M: Into<ws::Message>>(&mut self, message: M) {
+        if self.behind || self.closed {
+            return;
+        }
+        let message = message.into();
+        self.queued += message.len();
+        self.queue.push_back(message);
+    }
+
+    fn received(&self) -> u64 {
+        self.receipts.lock().unwrap().received
+    }
+
+    fn in_flight(&self) -> u64 {
+        self.sent - self.received()
+    }
+
+    /// Returns true once a client that fell behind is ready to get the whole canvas again, and
+    /// lets messages be queued again. The caller should queue everything a new client gets.
+    pub fn catch_up(&mut self) -> bool {
+        if self.behind && !self.closed && self.in_flight() < WINDOW {
+            self.behind = false;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Sends as much of the queue as the client may receive, and collapses what’s left if there’s
+    /// too much of it. Returns false if the client is disconnected because it confirmed nothing
+    /// for too long.
+    pub fn flush(&mut self, now: Instant) -> bool {
+        if self.closed {
+            return false;
+        }
+        let received = self.received();
+        if received != self.last_received || self.sent <= received {
+            self.last_received = received;
+            self.last_progress = now;
+        }
+
+        let mut sent_any = false;
+        while self.sent - received < WINDOW {
+            let message = match self.queue.pop_front() {
+                Some(message) => message,
+                None => break,
+            };
+            self.queued -= message.len();
+            self.sent += message.len() as u64;
+            if let Err(err) = self.out.send(message) {
+                eprintln!("Send error: {:?}", err);
+            }
+            sent_any = true;
+        }
+        if sent_any {
+            self.receipts.lock().unwrap().ping(self.sent);
+            if let Err(err) = self.out.ping(self.sent.to_be_bytes().to_vec()) {
+                eprintln!("Send error: {:?}", err);
+            }
+        }
+
+        // only what couldn’t be sent counts, so a message larger than the limit still goes out
+        if self.queued > QUEUE_LIMIT {
+            self.queue.clear();
+            self.queued = 0;
+            self.behind = true;
+        }
+
+        if now.duration_since(self.last_progress) > STALL_TIMEOUT {
+            self.close(None, ws::CloseCode::Policy, "Connection too slow");
+            return false;
+        }
+        true
+    }
+
+    /// Returns true if there is nothing left to send or to wait for.
+    pub fn is_idle(&self) -> bool {
+        self.closed || (self.queue.is_empty() && !self.behind && self.in_flight() == 0)
+    }
+
+    /// Drops the queue and disconnects the client, telling it why first if there’s a notice.
+    pub fn close(&mut self, notice: Option<&str>, code: ws::CloseCode, reason: &'static str) {
+        self.queue.clear();
+        self.queued = 0;
+        self.closed = true;
+        if let Some(text) = notice {
+            let _ = self.out.send(ClientMessage::Broadcast { text: text.into() });
+        }
+        let _ = self.out.close_with_reason(code, reason);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Ignore;
+
+    impl ws::Handler for Ignore {}
+
+    /// Makes an outbox whose messages go to an event loop that never runs.
+    fn outbox() -> (Outbox, Arc<Mutex<Receipts>>) {
+        let socket = ws::WebSocket::new(|_| Ignore).unwrap();
+        let receipts = Arc::new(Mutex::new(Receipts::default()));
+        let outbox = Outbox::new(Arc::new(socket.broadcaster()), Arc::clone(&receipts));
+        (outbox, receipts)
+    }
+
+    fn message(len: usize) -> ws::Message {
+        ws::Message::Binary(vec![0; len])
+    }
+
+    fn pong(sent: u64) -> [u8; 8] {
+        sent.to_be_bytes()
+    }
+
+    #[test]
+    fn only_pongs_to_sent_pings_count() {
+        let mut receipts = Receipts::default();
+        receipts.ping(10);
+        receipts.ping(20);
+        receipts.ping(30);
+        receipts.confirm(&pong(15));
+        receipts.confirm(&[0; 4]);
+        assert_eq!(receipts.received, 0);
+
+        receipts.confirm(&pong(20));
+        assert_eq!(receipts.received, 20);
+        // the ping before it won’t be answered anymore
+        receipts.confirm(&pong(10));
+        assert_eq!(receipts.received, 20);
+        assert_eq!(receipts.pending, vec![30]);
+    }
+
+    #[test]
+    fn sends_at_most_a_window_before_confirmation() {
+        let (mut outbox, receipts) = outbox();
+        let len = WINDOW as usize / 2;
+        for _ in 0..3 {
+            outbox.push(message(len));
+        }
+        assert!(outbox.flush(Instant::now()));
+        assert_eq!(outbox.sent, WINDOW);
+        assert_eq!(outbox.queue.len(), 1);
+        assert!(!outbox.is_idle());
+
+        // nothing more goes out until the client confirms
+        assert!(outbox.flush(Instant::now()));
+        assert_eq!(outbox.queue.len(), 1);
+        receipts.lock().unwrap().confirm(&pong(WINDOW));
+        assert!(outbox.flush(Instant::now()));
+        assert!(outbox.queue.is_empty());
+
+        receipts.lock().unwrap().confirm(&pong(WINDOW + len as u64));
+        assert!(outbox.is_idle());
+    }
+
+    #[test]
+    fn overflowing_clients_catch_up_later() {
+        let (mut outbox, receipts) = outbox();
+        outbox.push(message(WINDOW as usize));
+        for _ in 0..5 {
+            outbox.push(message(QUEUE_LIMIT / 4));
+        }
+        assert!(outbox.flush(Instant::now()));
+        assert!(outbox.behind);
+        assert!(outbox.queue.is_empty());
+        outbox.push(message(1));
+        assert!(outbox.queue.is_empty());
+
+        assert!(!outbox.catch_up());
+        receipts.lock().unwrap().confirm(&pong(WINDOW));
+        assert!(outbox.catch_up());
+        outbox.push(message(1));
+        assert_eq!(outbox.queue.len(), 1);
+    }
+
+    #[test]
+    fn stalled_clients_are_disconnected() {
+        let (mut outbox, _) = outbox();
+        outbox.push(message(10));
+        let start = Instant::now();
+        assert!(outbox.flush(start));
+        assert!(outbox.flush(start + STALL_TIMEOUT));
+        assert!(!outbox.flush(start + STALL_TIMEOUT + Duration::from_secs(1)));
+        assert!(outbox.is_idle());
+    }
+}
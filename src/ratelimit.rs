@@ -0,0 +1,207 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// Pixel placement limits.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct RateLimits {
+    /// Minimum time between two pixels from one connection.
+    pub cooldown: Duration,
+
+    /// Burst size and refill rate (tokens per second) of each connection’s bucket.
+    /// A rate of zero disables the bucket.
+    pub conn_burst: f64,
+    pub conn_rate: f64,
+
+    /// Burst size and refill rate of each IP address’ bucket, shared by its connections.
+    pub ip_burst: f64,
+    pub ip_rate: f64,
+}
+
+impl Default for RateLimits {
+    fn default() -> RateLimits {
+        RateLimits {
+            cooldown: Duration::from_millis(100),
+            conn_burst: 20.,
+            conn_rate: 2.,
+            ip_burst: 60.,
+            ip_rate: 6.,
+        }
+    }
+}
+
+/// A token bucket.
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    /// Creates a full bucket.
+    pub fn new() -> TokenBucket {
+        TokenBucket {
+            // clamped to the burst size on the first refill
+            tokens: f64::INFINITY,
+            last_refill: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self, burst: f64, rate: f64, now: Instant) {
+        let elapsed = now.duration_since(self.last_refill);
+        let elapsed = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1e9;
+        self.tokens = (self.tokens + elapsed * rate).min(burst);
+        self.last_refill = now;
+    }
+
+    /// Returns the time until a token is available (zero if there is one).
+    pub fn wait_time(&mut self, burst: f64, rate: f64, now: Instant) -> Duration {
+        self.refill(burst, rate, now);
+        if self.tokens >= 1. || rate <= 0. {
+            return Duration::from_secs(0);
+        }
+        let secs = (1. - self.tokens) / rate;
+        Duration::new(secs as u64, (secs.fract() * 1e9) as u32)
+    }
+
+    /// Takes a token. Call `wait_time` first.
+    pub fn take(&mut self) {
+        self.tokens -= 1.;
+    }
+
+    /// Returns true if the bucket is full, i.e. it carries no state worth keeping.
+    pub fn is_full(&mut self, burst: f64, rate: f64, now: Instant) -> bool {
+        self.refill(burst, rate, now);
+        self.tokens >= burst
+    }
+}
+
+/// Per-connection placement state.
+#[derive(Debug, Clone)]
+pub struct ConnLimiter {
+    last_placement: Option<Instant>,
+    bucket: TokenBucket,
+}
+
+impl ConnLimiter {
+    pub fn new() -> ConnLimiter {
+        ConnLimiter {
+            last_placement: None,
+            bucket: TokenBucket::new(),
+        }
+    }
+
+    /// Tries to place a pixel, using up a token from the connection’s and the IP’s bucket.
+    ///
+    /// Returns Ok with the time until the next placement is possible, or Err with the time until
+    /// this one would have been.
+    pub fn try_place(
+        &mut self,
+        ip_buckets: &mut IpBuckets,
+        ip: &str,
+        limits: &RateLimits,
+        now: Instant,
+    ) -> Result<Duration, Duration> {
+        let wait = self.wait_time(ip_buckets, ip, limits, now);
+        if wait > Duration::from_secs(0) {
+            return Err(wait);
+        }
+
+        self.last_placement = Some(now);
+        self.bucket.take();
+        ip_buckets.get(ip, limits).take();
+        Ok(self.wait_time(ip_buckets, ip, limits, now))
+    }
+
+    fn wait_time(
+        &mut self,
+        ip_buckets: &mut IpBuckets,
+        ip: &str,
+        limits: &RateLimits,
+        now: Instant,
+    ) -> Duration {
+        let cooldown = match self.last_placement {
+            Some(last) => limits.cooldown - limits.cooldown.min(now.duration_since(last)),
+            None => Duration::from_secs(0),
+        };
+        let conn = self
+            .bucket
+            .wait_time(limits.conn_burst, limits.conn_rate, now);
+        let ip = ip_buckets
+            .get(ip, limits)
+            .wait_time(limits.ip_burst, limits.ip_rate, now);
+        cooldown.max(conn).max(ip)
+    }
+}
+
+/// Token buckets shared by all connections from an IP address.
+#[derive(Debug, Default)]
+pub struct IpBuckets {
+    buckets: HashMap<String, TokenBucket>,
+}
+
+impl IpBuckets {
+    fn get(&mut self, ip: &str, limits: &RateLimits) -> &mut TokenBucket {
+        if self.buckets.len() > 4096 && !self.buckets.contains_key(ip) {
+            self.prune(limits);
+        }
+        self.buckets
+            .entry(ip.to_string())
+            .or_insert_with(TokenBucket::new)
+    }
+
+    /// Removes buckets that have refilled completely.
+    fn prune(&mut self, limits: &RateLimits) {
+        let now = Instant::now();
+        self.buckets
+            .retain(|_, bucket| !bucket.is_full(limits.ip_burst, limits.ip_rate, now));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn millis(ms: u64) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    #[test]
+    fn buckets_refill_up_to_their_burst() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new();
+        for _ in 0..3 {
+            assert_eq!(bucket.wait_time(3., 2., start), millis(0));
+            bucket.take();
+        }
+        assert_eq!(bucket.wait_time(3., 2., start), millis(500));
+        assert_eq!(bucket.wait_time(3., 2., start + millis(250)), millis(250));
+        assert!(!bucket.is_full(3., 2., start + millis(1000)));
+        assert!(bucket.is_full(3., 2., start + millis(10_000)));
+        // a rate of zero never makes anyone wait
+        bucket.take();
+        assert_eq!(bucket.wait_time(3., 0., start + millis(10_000)), millis(0));
+    }
+
+    #[test]
+    fn connections_wait_for_their_cooldown_and_their_ip() {
+        let limits = RateLimits {
+            cooldown: millis(100),
+            conn_burst: 10.,
+            conn_rate: 10.,
+            ip_burst: 2.,
+            ip_rate: 1.,
+        };
+        let start = Instant::now();
+        let mut ips = IpBuckets::default();
+        let mut a = ConnLimiter::new();
+        let mut b = ConnLimiter::new();
+
+        assert_eq!(a.try_place(&mut ips, "ip", &limits, start), Ok(millis(100)));
+        assert_eq!(a.try_place(&mut ips, "ip", &limits, start + millis(40)), Err(millis(60)));
+        // the other connection has no cooldown, but uses up the IP’s last token
+        let later = start + millis(500);
+        assert_eq!(b.try_place(&mut ips, "ip", &limits, later), Ok(millis(500)));
+        assert_eq!(a.try_place(&mut ips, "ip", &limits, later), Err(millis(500)));
+        assert!(a.try_place(&mut ips, "other ip", &limits, later).is_ok());
+    }
+}
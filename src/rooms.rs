@@ -0,0 +1,130 @@
+//! Named canvases (rooms).
+//!
+//! The default canvas lives in the working directory and is served at `/canvas`. Every other
+//! canvas has a directory `rooms/<name>/` with its own `canvas.place` and `canvas.log`, and is
+//! served at `/canvas/<name>`. Archived canvases are moved to `archive/<name>-<time>/` and are
+//! no longer served.
+
+use canvas::Extent;
+use ratelimit::{IpBuckets, RateLimits};
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::thread;
+use {ClientSender, UpdateMsg};
+
+/// Name of the default canvas.
+pub const DEFAULT: &str = "";
+
+/// A canvas being served, with its own clients and limits.
+pub struct Room {
+    /// Sends messages to the room’s update thread.
+    pub update_tx: mpsc::Sender<UpdateMsg>,
+    pub thread: Option<thread::JoinHandle<()>>,
+    pub clients: HashMap<u64, ClientSender>,
+    /// The canvas extent, once loaded.
+    pub extent: Option<Extent>,
+    /// Set once the room stops accepting pixels.
+    pub shutting_down: bool,
+    pub rate_limits: RateLimits,
+    pub ip_buckets: IpBuckets,
+}
+
+impl Room {
+    pub fn new(update_tx: mpsc::Sender<UpdateMsg>, rate_limits: RateLimits) -> Room {
+        Room {
+            update_tx,
+            thread: None,
+            clients: HashMap::new(),
+            extent: None,
+            shutting_down: false,
+            rate_limits,
+            ip_buckets: IpBuckets::default(),
+        }
+    }
+}
+
+/// Returns true if the name can be used for a room (and thus in paths and URLs).
+pub fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= 32
+        && name
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '_')
+}
+
+/// Returns a room name for display.
+pub fn display_name(name: &str) -> &str {
+    if name == DEFAULT {
+        "(default)"
+    } else {
+        name
+    }
+}
+
+/// Returns the directory of a room’s files.
+pub fn room_dir(base: &Path, name: &str) -> PathBuf {
+    if name == DEFAULT {
+        base.to_path_buf()
+    } else {
+        base.join("rooms").join(name)
+    }
+}
+
+fn archive_dir(base: &Path) -> PathBuf {
+    base.join("archive")
+}
+
+/// Lists the directory names in a directory, sorted.
+fn list_dirs(dir: &Path) -> io::Result<Vec<String>> {
+    let mut names = Vec::new();
+    if !dir.exists() {
+        return Ok(names);
+    }
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            if let Some(name) = entry.file_name().to_str() {
+                names.push(name.to_string());
+            }
+        }
+    }
+    names.sort();
+    Ok(names)
+}
+
+/// Lists the named rooms on disk.
+pub fn list_rooms(base: &Path) -> io::Result<Vec<String>> {
+    Ok(list_dirs(&base.join("rooms"))?
+        .into_iter()
+        .filter(|name| is_valid_name(name))
+        .collect())
+}
+
+/// Lists archived rooms (as `<name>-<UNIX seconds>`).
+pub fn list_archived(base: &Path) -> io::Result<Vec<String>> {
+    list_dirs(&archive_dir(base))
+}
+
+/// Moves a room’s directory to the archive, returning the archived name.
+pub fn archive(base: &Path, name: &str, now: u64) -> io::Result<String> {
+    let archived = format!("{}-{}", name, now);
+    fs::create_dir_all(archive_dir(base))?;
+    fs::rename(room_dir(base, name), archive_dir(base).join(&archived))?;
+    Ok(archived)
+}
+
+/// Deletes an archived room.
+pub fn delete_archived(base: &Path, archived: &str) -> io::Result<()> {
+    // archived names are made of room names and digits, so this can’t leave the archive
+    let valid = archived.rsplit_once('-').is_some_and(|(name, _)| is_valid_name(name));
+    if !valid {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            "no such archived canvas",
+        ));
+    }
+    fs::remove_dir_all(archive_dir(base).join(archived))
+}
@@ -0,0 +1,432 @@
+//! Running the server from other programs.
+//!
+//! ```no_run
+//! use place::{Config, Server};
+//!
+//! let (config, _) = Config::from_args(&[]).unwrap();
+//! let server = Server::builder(config).bind("127.0.0.1:9000").build().unwrap();
+//! let handle = server.handle();
+//! server.spawn();
+//! // ...
+//! handle.shutdown();
+//! ```
+
+use canvas::Canvas;
+use config::Config;
+use hooks::Hooks;
+use login::{AuthProvider, Logins};
+use std::fmt;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Arc, Mutex, Weak};
+use std::thread;
+use std::time::Duration;
+use store::{CanvasStore, Storage, StoreFactory};
+use ws;
+use {open_room, rooms, shut_down, ConnHandler, GlobalState, UpdateMsg};
+
+/// How long to wait for an update thread to send a copy of its canvas.
+const CANVAS_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Sets up a server; see `Server::builder`.
+pub struct ServerBuilder {
+    config: Config,
+    auth: Option<Box<dyn AuthProvider>>,
+    hooks: Vec<Arc<dyn Hooks>>,
+    stores: Option<StoreFactory>,
+}
+
+impl ServerBuilder {
+    /// Sets the address to listen on, overriding the configuration.
+    pub fn bind(mut self, addr: &str) -> ServerBuilder {
+        self.config.bind = addr.into();
+        self
+    }
+
+    /// Sets the directory canvases are kept in, overriding the configuration.
+    pub fn data_dir<P: Into<PathBuf>>(mut self, dir: P) -> ServerBuilder {
+        self.config.data_dir = dir.into();
+        self
+    }
+
+    /// Sets which of the built-in stores canvases are kept in, overriding the configuration.
+    pub fn storage(mut self, storage: Storage) -> ServerBuilder {
+        self.config.storage = storage;
+        self.stores = None;
+        self
+    }
+
+    /// Keeps canvases in stores of your own, which are opened with the directory of the canvas.
+    pub fn store<F>(mut self, open: F) -> ServerBuilder
+    where
+        F: Fn(&Path) -> Box<dyn CanvasStore> + Send + 'static,
+    {
+        self.stores = Some(Box::new(open));
+        self
+    }
+
+    /// Checks admin logins with something other than the configured logins file.
+    pub fn auth<A: AuthProvider + 'static>(mut self, auth: A) -> ServerBuilder {
+        self.auth = Some(Box::new(auth));
+        self
+    }
+
+    /// Adds hooks that get told about events.
+    pub fn hook(mut self, hooks: Arc<dyn Hooks>) -> ServerBuilder {
+        self.hooks.push(hooks);
+        self
+    }
+
+    /// Starts listening and opens every canvas. Clients are served once the server is run.
+    pub fn build(self) -> Result<Server, ServerError> {
+        let config = self.config;
+        let auth = match self.auth {
+            Some(auth) => auth,
+            None => Box::new(Logins::load(&config.logins).map_err(ServerError::Auth)?),
+        };
+        let stores = match self.stores {
+            Some(stores) => stores,
+            None => {
+                let storage = config.storage;
+                Box::new(move |dir: &Path| storage.open(dir)) as StoreFactory
+            }
+        };
+        let data_dir = config.data_dir.clone();
+        let bind = config.bind.clone();
+        let global = GlobalState::new(config, auth, self.hooks, stores);
+        let global = Arc::new(Mutex::new(global));
+
+        let factory = ConnFactory {
+            global: Arc::downgrade(&global),
+            next_id: 0,
+        };
+        let listen_error = |err: ws::Error| ServerError::Listen(bind.clone(), Box::new(err));
+        let socket = ws::WebSocket::new(factory).map_err(listen_error)?;
+        let socket = socket.bind(&bind).map_err(listen_error)?;
+        let local_addr = socket.local_addr().ok();
+
+        let open = |name: &str| {
+            let store = global.lock().unwrap().open_store(name);
+            open_room(&global, name, store)
+        };
+        let mut names = vec![rooms::DEFAULT.to_string()];
+        match rooms::list_rooms(&data_dir) {
+            Ok(rooms) => names.extend(rooms),
+            Err(err) => eprintln!("Failed to list canvases: {}", err),
+        }
+        for name in names {
+            if let Err(err) = open(&name) {
+                // stop the canvases that were already opened
+                shut_down(&global, &socket.broadcaster());
+                return Err(ServerError::Load(err));
+            }
+        }
+
+        let handle = ServerHandle {
+            global,
+            broadcaster: Arc::new(Mutex::new(socket.broadcaster())),
+            local_addr,
+        };
+        Ok(Server { socket, handle })
+    }
+}
+
+/// Creates a handler for every connection.
+struct ConnFactory {
+    global: Weak<Mutex<GlobalState>>,
+    next_id: u64,
+}
+
+impl ws::Factory for ConnFactory {
+    type Handler = ConnHandler;
+
+    fn connection_made(&mut self, out: ws::Sender) -> ConnHandler {
+        self.next_id += 1;
+        ConnHandler::new(self.global.clone(), out, self.next_id)
+    }
+}
+
+/// A server that is listening but not yet serving clients.
+pub struct Server {
+    socket: ws::WebSocket<ConnFactory>,
+    handle: ServerHandle,
+}
+
+impl Server {
+    /// Sets up a server with the given configuration.
+    pub fn builder(config: Config) -> ServerBuilder {
+        ServerBuilder {
+            config,
+            auth: None,
+            hooks: Vec::new(),
+            stores: None,
+        }
+    }
+
+    /// Returns a handle for controlling the server while it runs.
+    pub fn handle(&self) -> ServerHandle {
+        self.handle.clone()
+    }
+
+    /// Serves clients until the server is shut down.
+    pub fn run(self) -> Result<(), ServerError> {
+        self.socket.run().map(|_| ()).map_err(|err| ServerError::Run(Box::new(err)))
+    }
+
+    /// Serves clients on another thread.
+    pub fn spawn(self) -> thread::JoinHandle<Result<(), ServerError>> {
+        thread::spawn(move || self.run())
+    }
+}
+
+/// Controls a running server.
+#[derive(Clone)]
+pub struct ServerHandle {
+    global: Arc<Mutex<GlobalState>>,
+    broadcaster: Arc<Mutex<ws::Sender>>,
+    local_addr: Option<SocketAddr>,
+}
+
+impl ServerHandle {
+    /// Saves every canvas, disconnects all clients and stops the server. Returns once everything
+    /// is saved.
+    pub fn shutdown(&self) {
+        shut_down(&self.global, &self.broadcaster.lock().unwrap());
+    }
+
+    /// Returns the address the server listens on.
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.local_addr
+    }
+
+    /// Lists the names of the open canvases.
+    pub fn canvases(&self) -> Vec<String> {
+        let mut names: Vec<_> = self.global.lock().unwrap().rooms.keys().cloned().collect();
+        names.sort();
+        names
+    }
+
+    /// Returns a copy of a canvas as it is now, or None if there is no such canvas (or it is
+    /// closing).
+    pub fn canvas(&self, name: &str) -> Option<Canvas> {
+        let update_tx = match self.global.lock().unwrap().rooms.get(name) {
+            Some(room) => room.update_tx.clone(),
+            None => return None,
+        };
+        let (reply_tx, reply_rx) = mpsc::channel();
+        update_tx.send(UpdateMsg::GetCanvas { reply: reply_tx }).ok()?;
+        reply_rx.recv_timeout(CANVAS_TIMEOUT).ok()
+    }
+}
+
+/// An error that stopped the server from starting or running.
+#[derive(Debug)]
+pub enum ServerError {
+    /// The logins file couldn’t be read.
+    Auth(String),
+    /// The server couldn’t listen on the address.
+    Listen(String, Box<ws::Error>),
+    /// A canvas or its locks couldn’t be loaded.
+    Load(String),
+    /// The event loop failed.
+    Run(Box<ws::Error>),
+}
+
+impl fmt::Display for ServerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ServerError::Auth(err) => write!(f, "{}", err),
+            ServerError::Listen(addr, err) => write!(f, "Failed to listen on {}: {:?}", addr, err),
+            ServerError::Load(err) => write!(f, "{}", err),
+            ServerError::Run(err) => write!(f, "Server error: {:?}", err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+    use std::time::Instant;
+    use std::{env, fs, process};
+    use history::Actor;
+    use hooks::{Pixel, PixelAction};
+    use store::MemoryStore;
+
+    /// Sends a request and returns the status code and the body.
+    fn get(addr: SocketAddr, path: &str) -> (u16, Vec<u8>) {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        write!(stream, "GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).unwrap();
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).unwrap();
+        let end = response.windows(4).position(|w| w == b"\r\n\r\n").unwrap();
+        let status = String::from_utf8_lossy(&response[9..12]).parse().unwrap();
+        (status, response[end + 4..].to_vec())
+    }
+
+    /// Retries a request until it stops being answered with 503.
+    fn get_ready(addr: SocketAddr, path: &str) -> (u16, Vec<u8>) {
+        let start = Instant::now();
+        loop {
+            let response = get(addr, path);
+            if response.0 != 503 || start.elapsed() > Duration::from_secs(10) {
+                return response;
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+    }
+
+    /// Opens a WebSocket connection to a canvas.
+    fn connect(addr: SocketAddr, path: &str) -> TcpStream {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        write!(
+            stream,
+            "GET {} HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+             Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n",
+            path
+        ).unwrap();
+        let mut response = Vec::new();
+        let mut byte = [0];
+        while !response.ends_with(b"\r\n\r\n") {
+            stream.read_exact(&mut byte).unwrap();
+            response.push(byte[0]);
+        }
+        assert!(response.starts_with(b"HTTP/1.1 101"));
+        stream
+    }
+
+    /// Sends a short text frame, masked (with a zero key) as clients must.
+    fn send_text(stream: &mut TcpStream, text: &str) {
+        assert!(text.len() < 126);
+        let mut frame = vec![0x81, 0x80 | text.len() as u8, 0, 0, 0, 0];
+        frame.extend_from_slice(text.as_bytes());
+        stream.write_all(&frame).unwrap();
+    }
+
+    /// Makes a configuration for a server with a small canvas, listening on any port.
+    fn test_config(dir: &Path) -> Config {
+        let args: Vec<String> = vec![
+            "--data-dir",
+            dir.to_str().unwrap(),
+            "--bind",
+            "127.0.0.1:0",
+            "--default-width",
+            "30",
+            "--default-height",
+            "20",
+        ].into_iter()
+        .map(String::from)
+        .collect();
+        Config::from_args(&args).unwrap().0
+    }
+
+    #[test]
+    fn serves_a_canvas_from_memory() {
+        let dir = env::temp_dir().join(format!("place-test-{}-server", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let server = Server::builder(test_config(&dir))
+            .storage(Storage::Memory)
+            .build()
+            .unwrap();
+        let handle = server.handle();
+        let addr = handle.local_addr().unwrap();
+        let running = server.spawn();
+        assert_eq!(handle.canvases(), vec![rooms::DEFAULT.to_string()]);
+
+        let mut client = connect(addr, "/canvas");
+        send_text(
+            &mut client,
+            r#"{"type":"set-pixel","data":{"x":3,"y":4,"r":10,"g":20,"b":30}}"#,
+        );
+        let start = Instant::now();
+        while handle.canvas(rooms::DEFAULT).unwrap().pixel(3, 4) != (10, 20, 30) {
+            assert!(start.elapsed() < Duration::from_secs(10), "pixel wasn’t placed");
+            thread::sleep(Duration::from_millis(20));
+        }
+
+        let (status, png) = get_ready(addr, "/canvas.png");
+        assert_eq!(status, 200);
+        assert!(png.starts_with(b"\x89PNG"));
+        assert_eq!(get(addr, "/canvas/nope.png").0, 404);
+
+        handle.shutdown();
+        assert!(running.join().unwrap().is_ok());
+        // nothing was written
+        assert!(!dir.join("canvas.place").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn opens_canvases_with_custom_stores() {
+        let dir = env::temp_dir().join(format!("place-test-{}-custom-store", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let opened = Arc::new(Mutex::new(Vec::new()));
+        let record = Arc::clone(&opened);
+        let server = Server::builder(test_config(&dir))
+            .store(move |dir: &Path| {
+                record.lock().unwrap().push(dir.to_path_buf());
+                Box::new(MemoryStore::default()) as Box<dyn CanvasStore>
+            }).build()
+            .unwrap();
+        assert_eq!(
+            *opened.lock().unwrap(),
+            vec![rooms::room_dir(&dir, rooms::DEFAULT)]
+        );
+        server.handle().shutdown();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// Paints a pixel on every tick, and panics about pixels from users.
+    struct Bot;
+
+    impl Hooks for Bot {
+        fn on_set_pixel(&self, _: &str, _: Pixel, actor: &Actor) -> PixelAction {
+            match actor {
+                Actor::Server(_) => PixelAction::Allow,
+                _ => panic!("no users allowed"),
+            }
+        }
+
+        fn on_tick(&self, _: &str, _: &Canvas) -> Vec<Pixel> {
+            vec![Pixel {
+                x: 1,
+                y: 1,
+                color: (10, 20, 30),
+            }]
+        }
+    }
+
+    #[test]
+    fn hooks_place_pixels_and_survive_panics() {
+        let dir = env::temp_dir().join(format!("place-test-{}-hooks", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let server = Server::builder(test_config(&dir))
+            .storage(Storage::Memory)
+            .hook(Arc::new(Bot))
+            .build()
+            .unwrap();
+        let handle = server.handle();
+        let addr = handle.local_addr().unwrap();
+        let running = server.spawn();
+
+        let mut client = connect(addr, "/canvas");
+        send_text(
+            &mut client,
+            r#"{"type":"set-pixel","data":{"x":3,"y":4,"r":10,"g":20,"b":30}}"#,
+        );
+        let start = Instant::now();
+        while handle.canvas(rooms::DEFAULT).unwrap().pixel(1, 1) != (10, 20, 30) {
+            assert!(start.elapsed() < Duration::from_secs(10), "pixel wasn’t placed");
+            thread::sleep(Duration::from_millis(20));
+        }
+        // the canvas is still served after the hook panicked
+        thread::sleep(Duration::from_millis(200));
+        assert_eq!(handle.canvas(rooms::DEFAULT).unwrap().pixel(3, 4), (255, 255, 255));
+        assert_eq!(get_ready(addr, "/canvas.png").0, 200);
+
+        handle.shutdown();
+        assert!(running.join().unwrap().is_ok());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,213 @@
+//! Periodic snapshots of a canvas.
+//!
+//! Snapshots are complete copies of a canvas, kept by its store and named after the time they
+//! were taken (UNIX seconds; the file stores keep them as `snapshots/<time>.place`). A policy
+//! decides how often they are taken and which of them are kept: for example, one per hour for a
+//! day and then one per day for a month.
+
+use canvas::Canvas;
+use std::collections::HashSet;
+use store::{CanvasStore, Job, StoreError};
+
+/// Keeps one snapshot per `every` milliseconds among those younger than `keep_for`.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Tier {
+    pub every: u64,
+    pub keep_for: u64,
+}
+
+/// When snapshots are taken and how long they are kept.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Policy {
+    /// Time between snapshots (milliseconds), or None if none are taken automatically.
+    pub interval: Option<u64>,
+    /// Retention tiers. Snapshots older than every tier are deleted.
+    pub keep: Vec<Tier>,
+}
+
+impl Default for Policy {
+    fn default() -> Policy {
+        const HOUR: u64 = 60 * 60 * 1000;
+        Policy {
+            interval: Some(HOUR),
+            keep: vec![
+                Tier {
+                    every: HOUR,
+                    keep_for: 24 * HOUR,
+                },
+                Tier {
+                    every: 24 * HOUR,
+                    keep_for: 30 * 24 * HOUR,
+                },
+            ],
+        }
+    }
+}
+
+impl Policy {
+    /// Returns which of the given snapshot times (UNIX seconds) to keep at time `now`. In every
+    /// tier, the oldest snapshot in each period is kept, so it doesn’t change as newer ones
+    /// move into the tier. Snapshots younger than the shortest period are all kept, so one taken
+    /// by hand doesn’t disappear right away.
+    fn retained(&self, times: &[u64], now: u64) -> HashSet<u64> {
+        let mut keep = HashSet::new();
+        let mut periods = HashSet::new();
+        let mut tiers = self.keep.clone();
+        tiers.sort_by_key(|tier| tier.keep_for);
+        let grace = tiers.iter().map(|tier| tier.every).min().unwrap_or(0);
+
+        let mut times = times.to_vec();
+        times.sort();
+        for time in times {
+            let age = now.saturating_sub(time * 1000);
+            if age < grace {
+                keep.insert(time);
+                continue;
+            }
+            let tier = tiers
+                .iter()
+                .enumerate()
+                .find(|(_, tier)| age < tier.keep_for);
+            if let Some((index, tier)) = tier {
+                if periods.insert((index, time * 1000 / tier.every.max(1))) {
+                    keep.insert(time);
+                }
+            }
+        }
+        keep
+    }
+}
+
+/// The snapshot policy of one canvas and when it last took one.
+#[derive(Debug)]
+pub struct Snapshots {
+    policy: Policy,
+    /// Time of the newest snapshot (UNIX seconds).
+    last: Option<u64>,
+}
+
+impl Snapshots {
+    /// Reads the policy and the newest snapshot from the store.
+    pub fn open(store: &dyn CanvasStore) -> Snapshots {
+        let policy = match store.snapshot_policy() {
+            Ok(policy) => policy.unwrap_or_default(),
+            Err(err) => {
+                eprintln!("Failed to read the snapshot policy: {}", err);
+                Policy::default()
+            }
+        };
+        let last = store.snapshots().ok().and_then(|times| times.last().cloned());
+        Snapshots { policy, last }
+    }
+
+    pub fn policy(&self) -> &Policy {
+        &self.policy
+    }
+
+    /// Sets and saves a new policy.
+    pub fn set_policy(
+        &mut self,
+        store: &mut dyn CanvasStore,
+        policy: Policy,
+    ) -> Result<(), StoreError> {
+        self.policy = policy;
+        store.set_snapshot_policy(&self.policy)
+    }
+
+    /// Returns true if a snapshot should be taken at time `now` (UNIX milliseconds).
+    pub fn is_due(&self, now: u64) -> bool {
+        match (self.policy.interval, self.last) {
+            (Some(interval), Some(last)) => now >= (last * 1000).saturating_add(interval),
+            (Some(_), None) => true,
+            (None, _) => false,
+        }
+    }
+
+    /// Prepares storing a snapshot of the canvas taken at time `now` (UNIX milliseconds) and
+    /// deletes the ones the policy no longer keeps. Returns the snapshot’s time and the job that
+    /// writes it.
+    pub fn take(
+        &mut self,
+        store: &mut dyn CanvasStore,
+        canvas: &Canvas,
+        now: u64,
+    ) -> Result<(u64, Job<()>), StoreError> {
+        let time = now / 1000;
+        let job = store.snapshot(canvas, time)?;
+        self.last = Some(time);
+        self.prune(store, now)?;
+        Ok((time, job))
+    }
+
+    /// Deletes the snapshots the policy doesn’t keep at time `now`, except the newest one.
+    fn prune(&self, store: &mut dyn CanvasStore, now: u64) -> Result<(), StoreError> {
+        let times = store.snapshots()?;
+        let keep = self.policy.retained(&times, now);
+        for time in times {
+            if !keep.contains(&time) && Some(time) != self.last {
+                store.delete_snapshot(time)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tiers_keep_the_oldest_snapshot_per_period() {
+        let policy = Policy {
+            interval: Some(10_000),
+            keep: vec![
+                Tier {
+                    every: 60_000,
+                    keep_for: 300_000,
+                },
+                Tier {
+                    every: 10_000,
+                    keep_for: 60_000,
+                },
+            ],
+        };
+        let times = [600, 800, 910, 930, 950, 983, 985, 995, 999];
+        let mut kept: Vec<_> = policy.retained(&times, 1_000_000).into_iter().collect();
+        kept.sort();
+        // 600 is older than every tier, 930 and 985 share a period with an older snapshot, and
+        // 995 and 999 are younger than the shortest period
+        assert_eq!(kept, vec![800, 910, 950, 983, 995, 999]);
+    }
+
+    #[test]
+    fn kept_snapshots_stay_kept() {
+        let policy = Policy::default();
+        let hour = 60 * 60;
+        let start = 100 * 24 * hour;
+        let mut times = Vec::new();
+        let mut kept = HashSet::new();
+        for i in 0..(3 * 24 * 6) {
+            let now = start + i * 600;
+            times.push(now);
+            let retained = policy.retained(&times, now * 1000);
+            // once a snapshot is past the grace period, only age can make it go away
+            for time in &kept {
+                if now - time < 24 * hour && now - time > hour {
+                    assert!(retained.contains(time), "{} dropped at {}", time, now);
+                }
+            }
+            kept = retained.clone();
+            times.retain(|time| retained.contains(time));
+        }
+        assert!(times.len() <= 24 + 3 + 6);
+    }
+
+    #[test]
+    fn no_tiers_keep_nothing() {
+        let policy = Policy {
+            interval: None,
+            keep: Vec::new(),
+        };
+        assert!(policy.retained(&[1, 2, 3], 4_000).is_empty());
+    }
+}
@@ -0,0 +1,164 @@
+use canvas::TiledSave;
+use std::collections::HashSet;
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+/// Returns `path` with `suffix` appended to the file name.
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = OsString::from(path.file_name().unwrap_or_default());
+    name.push(suffix);
+    path.with_file_name(name)
+}
+
+/// Returns the path of the nth backup generation (1 is the most recent).
+pub fn backup_path(path: &Path, generation: usize) -> PathBuf {
+    with_suffix(path, &format!(".{}", generation))
+}
+
+/// Shifts backups up by one generation and links the current file in as generation 1.
+fn rotate_backups(path: &Path, generations: usize) -> io::Result<()> {
+    if generations == 0 || !path.exists() {
+        return Ok(());
+    }
+
+    for generation in (1..generations).rev() {
+        let from = backup_path(path, generation);
+        if from.exists() {
+            fs::rename(&from, backup_path(path, generation + 1))?;
+        }
+    }
+
+    // the live file stays in place until the rename in `save_atomic` replaces it
+    let newest = backup_path(path, 1);
+    if fs::hard_link(path, &newest).is_err() {
+        fs::copy(path, &newest)?;
+    }
+    Ok(())
+}
+
+/// Writes data to a temporary file, syncs it, rotates backups and then renames it into place,
+/// so `path` always contains either the previous or the new data in full.
+pub fn save_atomic(path: &Path, data: &[u8], backup_generations: usize) -> io::Result<()> {
+    let tmp_path = with_suffix(path, ".tmp");
+    {
+        let mut file = File::create(&tmp_path)?;
+        file.write_all(data)?;
+        file.sync_all()?;
+    }
+
+    rotate_backups(path, backup_generations)?;
+    fs::rename(&tmp_path, path)?;
+
+    // make the rename itself durable (not possible on all platforms, hence no error)
+    if let Some(dir) = path.parent() {
+        if let Ok(dir) = File::open(dir) {
+            let _ = dir.sync_all();
+        }
+    }
+    Ok(())
+}
+
+/// Reads the file at `path`, or the newest readable backup for which `parse` succeeds.
+///
+/// Returns None if there is no file at all, and the error for `path` if nothing could be parsed.
+pub fn load_with_backups<T, E, F>(
+    path: &Path,
+    backup_generations: usize,
+    parse: F,
+) -> Option<Result<T, E>>
+where
+    F: Fn(Vec<u8>) -> Result<T, E>,
+    E: ::std::fmt::Display,
+{
+    let mut first_err = None;
+    let candidates = Some(path.to_path_buf())
+        .into_iter()
+        .chain((1..backup_generations + 1).map(|g| backup_path(path, g)));
+
+    for candidate in candidates {
+        let is_backup = candidate != path;
+        let data = match fs::read(&candidate) {
+            Ok(data) => data,
+            Err(_) => continue,
+        };
+        match parse(data) {
+            Ok(value) => {
+                if is_backup {
+                    eprintln!("Recovered from backup {}", candidate.display());
+                }
+                return Some(Ok(value));
+            }
+            Err(err) => {
+                eprintln!("Failed to read {}: {}", candidate.display(), err);
+                if first_err.is_none() {
+                    first_err = Some(err);
+                }
+            }
+        }
+    }
+
+    first_err.map(Err)
+}
+
+/// Returns the tile directory that belongs to a canvas file (`canvas.place.tiles`).
+pub fn tile_dir(path: &Path) -> PathBuf {
+    with_suffix(path, ".tiles")
+}
+
+/// Returns the generation of a tile file from its name (`x_y.generation.tile`, or
+/// `layer.x_y.generation.tile` for tiles of layers), or None if it isn’t a tile file.
+fn tile_generation(name: &str) -> Option<u64> {
+    let mut parts = name.strip_suffix(".tile")?.rsplit('.');
+    let generation = parts.next()?.parse().ok()?;
+    let (x, y) = parts.next()?.split_once('_')?;
+    if x.parse::<i32>().is_ok() && y.parse::<i32>().is_ok() {
+        Some(generation)
+    } else {
+        None
+    }
+}
+
+/// Writes changed tiles to the tile directory and then saves the canvas file, which refers to
+/// them. Tile files are never overwritten, so until the canvas file is renamed into place, it
+/// and its backups still refer to complete sets of tiles (see `remove_stale_tiles`).
+pub fn save_tiled(path: &Path, save: &TiledSave, backup_generations: usize) -> io::Result<()> {
+    let dir = tile_dir(path);
+    fs::create_dir_all(&dir)?;
+    for (name, data) in &save.tiles {
+        save_atomic(&dir.join(name), data, 0)?;
+    }
+    save_atomic(path, &save.manifest, backup_generations)
+}
+
+/// Removes tile files that neither the canvas file nor its backups refer to.
+pub fn remove_stale_tiles(path: &Path, keep: &HashSet<String>) -> io::Result<()> {
+    for entry in fs::read_dir(tile_dir(path))? {
+        let entry = entry?;
+        let name = entry.file_name();
+        let name = match name.to_str() {
+            Some(name) => name,
+            None => continue,
+        };
+        if tile_generation(name).is_some() && !keep.contains(name) {
+            fs::remove_file(entry.path())?;
+        }
+    }
+    Ok(())
+}
+
+/// Returns the newest generation of the files in the tile directory of a canvas file.
+pub fn latest_tile_generation(path: &Path) -> io::Result<u64> {
+    let dir = tile_dir(path);
+    let mut latest = 0;
+    if !dir.exists() {
+        return Ok(latest);
+    }
+    for entry in fs::read_dir(&dir)? {
+        if let Some(generation) = entry?.file_name().to_str().and_then(tile_generation) {
+            latest = latest.max(generation);
+        }
+    }
+    Ok(latest)
+}
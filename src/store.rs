@@ -0,0 +1,694 @@
+//! Where canvases are kept.
+//!
+//! A store holds one canvas along with its event log, locks and snapshots. The file stores keep
+//! `canvas.place` (with `canvas.place.tiles/` if tiled), `canvas.log`, `canvas.locks`,
+//! `canvas.limits` and `snapshots/` in the canvas directory; the memory store keeps everything in memory and is meant for tests.
+
+use canvas::Canvas;
+use describe_extent;
+use format::FileError;
+use history::{self, Event, EventLog, LogContents, LogError};
+use locks::Lock;
+use ratelimit::RateLimits;
+use serde_json;
+use snapshots::Policy;
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use storage;
+
+/// Number of backup generations of `canvas.place` to keep (`canvas.place.1` being the newest).
+pub const BACKUP_GENERATIONS: usize = 3;
+
+/// Work prepared by a store that may be done on another thread, such as writing a save to disk.
+pub type Job<T> = Box<dyn FnOnce() -> Result<T, StoreError> + Send>;
+
+/// Storage for a canvas, its history, its locks, its rate limits and its snapshots.
+///
+/// The update thread of the canvas owns its store, so none of this needs to be thread-safe.
+pub trait CanvasStore: Send {
+    /// Loads the canvas and brings it up to date with the history, or creates a blank one with
+    /// the given size if there is nothing stored yet.
+    fn load(&mut self, default_size: (u32, u32)) -> Result<Canvas, StoreError>;
+
+    /// Prepares saving the entire canvas, and records in it how much of the history it includes.
+    fn save(&mut self, canvas: &mut Canvas) -> Result<Job<()>, StoreError>;
+
+    /// Prepares saving whatever changed since the last save. If a save fails, the next one must
+    /// be a full save.
+    fn save_changes(&mut self, canvas: &mut Canvas) -> Result<Job<()>, StoreError> {
+        self.save(canvas)
+    }
+
+    /// Appends an event to the history. It may be buffered until the next `flush`.
+    fn append(&mut self, event: &Event) -> Result<(), StoreError>;
+
+    /// Writes out buffered events.
+    fn flush(&mut self) -> Result<(), StoreError>;
+
+    /// Prepares reading the entire history, which may be slow.
+    fn read_history(&mut self) -> Result<Job<LogContents>, StoreError>;
+
+    /// Reads the entire history.
+    fn history(&mut self) -> Result<LogContents, StoreError> {
+        (self.read_history()?)()
+    }
+
+    /// Returns the region locks, which are empty if none were set.
+    fn locks(&self) -> Result<Vec<Lock>, StoreError>;
+
+    fn set_locks(&mut self, locks: &[Lock]) -> Result<(), StoreError>;
+
+    /// Returns the rate limits, if they were set for this canvas.
+    fn rate_limits(&self) -> Result<Option<RateLimits>, StoreError>;
+
+    fn set_rate_limits(&mut self, limits: &RateLimits) -> Result<(), StoreError>;
+
+    /// Prepares storing a snapshot of the canvas taken at the given time (UNIX seconds).
+    fn snapshot(&mut self, canvas: &Canvas, time: u64) -> Result<Job<()>, StoreError>;
+
+    /// Lists the times of all snapshots (UNIX seconds), oldest first.
+    fn snapshots(&self) -> Result<Vec<u64>, StoreError>;
+
+    /// Prepares reading a snapshot, which may be slow.
+    fn load_snapshot(&self, time: u64) -> Result<Job<Canvas>, StoreError>;
+
+    fn delete_snapshot(&mut self, time: u64) -> Result<(), StoreError>;
+
+    /// Returns the snapshot policy, if one was set.
+    fn snapshot_policy(&self) -> Result<Option<Policy>, StoreError>;
+
+    fn set_snapshot_policy(&mut self, policy: &Policy) -> Result<(), StoreError>;
+}
+
+/// Opens the store of the canvas in a directory; see `ServerBuilder::store`.
+pub type StoreFactory = Box<dyn Fn(&Path) -> Box<dyn CanvasStore> + Send>;
+
+/// Which store canvases are kept in.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Storage {
+    /// `canvas.place` with tiles in a directory of their own, so only changed tiles are written.
+    Tiles,
+    /// `canvas.place` as a single file, which is written in full on every save.
+    File,
+    /// Nothing is written to disk, and canvases are gone once they are closed.
+    Memory,
+}
+
+impl Storage {
+    pub fn parse(name: &str) -> Option<Storage> {
+        match name {
+            "tiles" => Some(Storage::Tiles),
+            "file" => Some(Storage::File),
+            "memory" => Some(Storage::Memory),
+            _ => None,
+        }
+    }
+
+    /// Opens the store of the canvas in a directory.
+    pub fn open(self, dir: &Path) -> Box<dyn CanvasStore> {
+        match self {
+            Storage::Tiles => Box::new(FileStore::new(dir, true)),
+            Storage::File => Box::new(FileStore::new(dir, false)),
+            Storage::Memory => Box::new(MemoryStore::default()),
+        }
+    }
+}
+
+impl fmt::Display for Storage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Storage::Tiles => write!(f, "tiles"),
+            Storage::File => write!(f, "file"),
+            Storage::Memory => write!(f, "memory"),
+        }
+    }
+}
+
+/// Keeps a canvas in its directory, as a single file or with a tile directory.
+pub struct FileStore {
+    canvas_path: PathBuf,
+    log_path: PathBuf,
+    locks_path: PathBuf,
+    limits_path: PathBuf,
+    snapshot_dir: PathBuf,
+    tiled: bool,
+    /// The event log, once loaded.
+    log: Option<EventLog>,
+}
+
+impl FileStore {
+    pub fn new(dir: &Path, tiled: bool) -> FileStore {
+        FileStore {
+            canvas_path: dir.join("canvas.place"),
+            log_path: dir.join("canvas.log"),
+            locks_path: dir.join("canvas.locks"),
+            limits_path: dir.join("canvas.limits"),
+            snapshot_dir: dir.join("snapshots"),
+            tiled,
+            log: None,
+        }
+    }
+
+    fn log(&mut self) -> Result<&mut EventLog, StoreError> {
+        self.log.as_mut().ok_or(StoreError::NotLoaded)
+    }
+
+    /// Makes the log durable before a canvas file that refers to it is written.
+    fn sync_log(&mut self, canvas: &mut Canvas) -> Result<(), StoreError> {
+        if let Some(ref mut log) = self.log {
+            log.sync()?;
+            canvas.meta.log_position = Some(log.position());
+        }
+        Ok(())
+    }
+
+    fn snapshot_path(&self, time: u64) -> PathBuf {
+        self.snapshot_dir.join(format!("{}.place", time))
+    }
+}
+
+/// Reads a canvas file and the tiles it refers to. A missing or unreadable tile makes the whole
+/// file unreadable, since replaying the event log can only bring complete saves up to date.
+fn load_tiled(path: &Path, data: Vec<u8>) -> Result<Canvas, StoreError> {
+    let mut canvas = Canvas::from_file(data)?;
+    if !canvas.has_external_tiles() {
+        return Ok(canvas);
+    }
+    let tile_error = |tile: &Path, err: &dyn fmt::Display| {
+        StoreError::Tile(tile.display().to_string(), err.to_string())
+    };
+    let dir = storage::tile_dir(path);
+    for name in canvas.tile_files() {
+        let tile = dir.join(name);
+        let data = fs::read(&tile).map_err(|err| tile_error(&tile, &err))?;
+        canvas
+            .read_tile_file(&data)
+            .map_err(|err| tile_error(&tile, &err))?;
+    }
+    canvas.skip_tile_generations(storage::latest_tile_generation(path)?);
+    Ok(canvas)
+}
+
+/// Collects the tile files that the backups of a canvas file refer to.
+fn backup_tiles(path: &Path) -> HashSet<String> {
+    let mut names = HashSet::new();
+    for generation in 1..=BACKUP_GENERATIONS {
+        let canvas = fs::read(storage::backup_path(path, generation))
+            .ok()
+            .and_then(|data| Canvas::from_file(data).ok());
+        if let Some(canvas) = canvas {
+            names.extend(canvas.tile_files());
+        }
+    }
+    names
+}
+
+impl CanvasStore for FileStore {
+    fn load(&mut self, default_size: (u32, u32)) -> Result<Canvas, StoreError> {
+        let path = self.canvas_path.clone();
+        let loaded = storage::load_with_backups(&self.canvas_path, BACKUP_GENERATIONS, |data| {
+            load_tiled(&path, data)
+        });
+        let mut canvas = match loaded {
+            Some(Ok(c)) => {
+                eprintln!(
+                    "Loaded {} canvas.place ({} pixels placed)",
+                    describe_extent(c.extent()),
+                    c.meta.pixels_placed
+                );
+                Some(c)
+            }
+            Some(Err(err)) => return Err(err),
+            None if self.log_path.exists() => {
+                eprintln!("Failed to read canvas.place, rebuilding from canvas.log");
+                None
+            }
+            None => {
+                let (width, height) = default_size;
+                eprintln!("Failed to read canvas.place, creating blank {}×{}", width, height);
+                Some(Canvas::blank(width, height))
+            }
+        };
+
+        let (log, replayed) = history::open_and_replay(&self.log_path, &mut canvas)?;
+        if replayed > 0 {
+            eprintln!("Replayed {} events from canvas.log", replayed);
+        }
+        self.log = Some(log);
+        Ok(canvas.unwrap())
+    }
+
+    fn save(&mut self, canvas: &mut Canvas) -> Result<Job<()>, StoreError> {
+        if self.tiled {
+            canvas.mark_all_dirty();
+            return self.save_changes(canvas);
+        }
+        self.sync_log(canvas)?;
+        let data = canvas.to_file();
+        let path = self.canvas_path.clone();
+        Ok(Box::new(move || {
+            storage::save_atomic(&path, &data, BACKUP_GENERATIONS)?;
+            // left over from when the canvas was kept in tiles
+            let tile_dir = storage::tile_dir(&path);
+            if tile_dir.exists() {
+                fs::remove_dir_all(tile_dir)?;
+            }
+            Ok(())
+        }))
+    }
+
+    fn save_changes(&mut self, canvas: &mut Canvas) -> Result<Job<()>, StoreError> {
+        if !self.tiled {
+            return self.save(canvas);
+        }
+        self.sync_log(canvas)?;
+        let save = canvas.tiled_save();
+        let path = self.canvas_path.clone();
+        Ok(Box::new(move || {
+            storage::save_tiled(&path, &save, BACKUP_GENERATIONS)?;
+            let mut keep = backup_tiles(&path);
+            keep.extend(save.live_tiles);
+            storage::remove_stale_tiles(&path, &keep)?;
+            Ok(())
+        }))
+    }
+
+    fn append(&mut self, event: &Event) -> Result<(), StoreError> {
+        self.log()?.append(event)?;
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), StoreError> {
+        self.log()?.flush()?;
+        Ok(())
+    }
+
+    fn read_history(&mut self) -> Result<Job<LogContents>, StoreError> {
+        // the log can be read without loading the canvas, e.g. for rendering a timelapse
+        if let Some(ref mut log) = self.log {
+            log.flush()?;
+        }
+        let path = self.log_path.clone();
+        Ok(Box::new(move || Ok(LogContents::read(&path)?)))
+    }
+
+    fn locks(&self) -> Result<Vec<Lock>, StoreError> {
+        match fs::read(&self.locks_path) {
+            Ok(file) => serde_json::from_slice(&file).map_err(|err| {
+                let err = format!("canvas.locks: {}", err);
+                StoreError::Io(io::Error::new(io::ErrorKind::InvalidData, err))
+            }),
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+            Err(err) => Err(StoreError::Io(err)),
+        }
+    }
+
+    fn set_locks(&mut self, locks: &[Lock]) -> Result<(), StoreError> {
+        let data = serde_json::to_vec_pretty(locks).expect("failed to serialize locks");
+        storage::save_atomic(&self.locks_path, &data, 0)?;
+        Ok(())
+    }
+
+    fn rate_limits(&self) -> Result<Option<RateLimits>, StoreError> {
+        match fs::read(&self.limits_path) {
+            Ok(file) => serde_json::from_slice(&file).map(Some).map_err(|err| {
+                let err = format!("canvas.limits: {}", err);
+                StoreError::Io(io::Error::new(io::ErrorKind::InvalidData, err))
+            }),
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(StoreError::Io(err)),
+        }
+    }
+
+    fn set_rate_limits(&mut self, limits: &RateLimits) -> Result<(), StoreError> {
+        let data = serde_json::to_vec_pretty(limits).expect("failed to serialize rate limits");
+        storage::save_atomic(&self.limits_path, &data, 0)?;
+        Ok(())
+    }
+
+    fn snapshot(&mut self, canvas: &Canvas, time: u64) -> Result<Job<()>, StoreError> {
+        let canvas = canvas.clone();
+        let dir = self.snapshot_dir.clone();
+        let path = self.snapshot_path(time);
+        Ok(Box::new(move || {
+            fs::create_dir_all(&dir)?;
+            storage::save_atomic(&path, &canvas.to_file(), 0)?;
+            Ok(())
+        }))
+    }
+
+    fn snapshots(&self) -> Result<Vec<u64>, StoreError> {
+        let mut times = Vec::new();
+        if !self.snapshot_dir.exists() {
+            return Ok(times);
+        }
+        for entry in fs::read_dir(&self.snapshot_dir)? {
+            let name = entry?.file_name();
+            let time = name
+                .to_str()
+                .filter(|name| name.ends_with(".place"))
+                .and_then(|name| name.trim_end_matches(".place").parse().ok());
+            if let Some(time) = time {
+                times.push(time);
+            }
+        }
+        times.sort();
+        Ok(times)
+    }
+
+    fn load_snapshot(&self, time: u64) -> Result<Job<Canvas>, StoreError> {
+        let path = self.snapshot_path(time);
+        if !path.exists() {
+            return Err(StoreError::NoSnapshot(time));
+        }
+        Ok(Box::new(move || Ok(Canvas::from_file(fs::read(path)?)?)))
+    }
+
+    fn delete_snapshot(&mut self, time: u64) -> Result<(), StoreError> {
+        fs::remove_file(self.snapshot_path(time))?;
+        Ok(())
+    }
+
+    fn snapshot_policy(&self) -> Result<Option<Policy>, StoreError> {
+        match fs::read(self.snapshot_dir.join("policy.json")) {
+            Ok(file) => serde_json::from_slice(&file)
+                .map(Some)
+                .map_err(|err| StoreError::Io(err.into())),
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(StoreError::Io(err)),
+        }
+    }
+
+    fn set_snapshot_policy(&mut self, policy: &Policy) -> Result<(), StoreError> {
+        fs::create_dir_all(&self.snapshot_dir)?;
+        let data = serde_json::to_vec_pretty(policy).expect("failed to serialize policy");
+        storage::save_atomic(&self.snapshot_dir.join("policy.json"), &data, 0)?;
+        Ok(())
+    }
+}
+
+/// Keeps a canvas in memory only.
+#[derive(Default)]
+pub struct MemoryStore {
+    /// The last saved canvas.
+    saved: Option<Canvas>,
+    /// The canvas the history starts from, once loaded.
+    base: Option<Canvas>,
+    /// The history; log positions are indices into it.
+    events: Vec<Event>,
+    locks: Vec<Lock>,
+    rate_limits: Option<RateLimits>,
+    snapshots: BTreeMap<u64, Canvas>,
+    policy: Option<Policy>,
+}
+
+impl CanvasStore for MemoryStore {
+    fn load(&mut self, default_size: (u32, u32)) -> Result<Canvas, StoreError> {
+        let mut canvas = match self.saved.clone() {
+            Some(canvas) => canvas,
+            None => {
+                let (width, height) = default_size;
+                Canvas::blank(width, height)
+            }
+        };
+        if self.base.is_none() {
+            self.base = Some(canvas.clone());
+        }
+
+        let position = canvas.meta.log_position.unwrap_or(0) as usize;
+        for event in self.events.iter().skip(position) {
+            event.apply(&mut canvas);
+        }
+        canvas.meta.log_position = Some(self.events.len() as u64);
+        Ok(canvas)
+    }
+
+    fn save(&mut self, canvas: &mut Canvas) -> Result<Job<()>, StoreError> {
+        canvas.meta.log_position = Some(self.events.len() as u64);
+        self.saved = Some(canvas.clone());
+        Ok(Box::new(|| Ok(())))
+    }
+
+    fn append(&mut self, event: &Event) -> Result<(), StoreError> {
+        if self.base.is_none() {
+            return Err(StoreError::NotLoaded);
+        }
+        self.events.push(event.clone());
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), StoreError> {
+        Ok(())
+    }
+
+    fn read_history(&mut self) -> Result<Job<LogContents>, StoreError> {
+        let contents = LogContents {
+            base: self.base.clone().ok_or(StoreError::NotLoaded)?,
+            events: self
+                .events
+                .iter()
+                .enumerate()
+                .map(|(i, event)| (i as u64 + 1, event.clone()))
+                .collect(),
+            len: self.events.len() as u64,
+        };
+        Ok(Box::new(move || Ok(contents)))
+    }
+
+    fn locks(&self) -> Result<Vec<Lock>, StoreError> {
+        Ok(self.locks.clone())
+    }
+
+    fn set_locks(&mut self, locks: &[Lock]) -> Result<(), StoreError> {
+        self.locks = locks.to_vec();
+        Ok(())
+    }
+
+    fn rate_limits(&self) -> Result<Option<RateLimits>, StoreError> {
+        Ok(self.rate_limits)
+    }
+
+    fn set_rate_limits(&mut self, limits: &RateLimits) -> Result<(), StoreError> {
+        self.rate_limits = Some(*limits);
+        Ok(())
+    }
+
+    fn snapshot(&mut self, canvas: &Canvas, time: u64) -> Result<Job<()>, StoreError> {
+        self.snapshots.insert(time, canvas.clone());
+        Ok(Box::new(|| Ok(())))
+    }
+
+    fn snapshots(&self) -> Result<Vec<u64>, StoreError> {
+        Ok(self.snapshots.keys().cloned().collect())
+    }
+
+    fn load_snapshot(&self, time: u64) -> Result<Job<Canvas>, StoreError> {
+        let canvas = self
+            .snapshots
+            .get(&time)
+            .cloned()
+            .ok_or(StoreError::NoSnapshot(time))?;
+        Ok(Box::new(move || Ok(canvas)))
+    }
+
+    fn delete_snapshot(&mut self, time: u64) -> Result<(), StoreError> {
+        self.snapshots.remove(&time);
+        Ok(())
+    }
+
+    fn snapshot_policy(&self) -> Result<Option<Policy>, StoreError> {
+        Ok(self.policy.clone())
+    }
+
+    fn set_snapshot_policy(&mut self, policy: &Policy) -> Result<(), StoreError> {
+        self.policy = Some(policy.clone());
+        Ok(())
+    }
+}
+
+/// A store error.
+#[derive(Debug)]
+pub enum StoreError {
+    Io(io::Error),
+    /// A canvas file is damaged.
+    File(FileError),
+    /// A tile file that the canvas file refers to is missing or damaged.
+    Tile(String, String),
+    /// The history is damaged.
+    Log(LogError),
+    /// There is no snapshot with this time.
+    NoSnapshot(u64),
+    /// The canvas hasn’t been loaded yet.
+    NotLoaded,
+}
+
+impl fmt::Display for StoreError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StoreError::Io(err) => write!(f, "{}", err),
+            StoreError::File(err) => write!(f, "{}", err),
+            StoreError::Tile(path, err) => write!(f, "{}: {}", path, err),
+            StoreError::Log(err) => write!(f, "canvas.log: {}", err),
+            StoreError::NoSnapshot(time) => write!(f, "there is no snapshot {}", time),
+            StoreError::NotLoaded => write!(f, "the canvas has not been loaded"),
+        }
+    }
+}
+
+impl From<io::Error> for StoreError {
+    fn from(err: io::Error) -> StoreError {
+        StoreError::Io(err)
+    }
+}
+
+impl From<FileError> for StoreError {
+    fn from(err: FileError) -> StoreError {
+        StoreError::File(err)
+    }
+}
+
+impl From<LogError> for StoreError {
+    fn from(err: LogError) -> StoreError {
+        StoreError::Log(err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use canvas::Rect;
+    use history::Actor;
+    use locks::LockPolicy;
+    use std::{env, process};
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("place-test-{}-{}", process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn paint(store: &mut dyn CanvasStore, canvas: &mut Canvas, x: i32, y: i32, r: u8) {
+        let event = Event::SetPixel {
+            time: 1_000,
+            x,
+            y,
+            r,
+            g: 0,
+            b: 0,
+            actor: Actor::Login("someone".into()),
+        };
+        store.append(&event).unwrap();
+        event.apply(canvas);
+    }
+
+    /// Saves a canvas with two tiles in full, and then again after changing one of them.
+    fn save_twice(dir: &Path) -> Canvas {
+        let mut store = FileStore::new(dir, true);
+        let mut canvas = store.load((600, 300)).unwrap();
+        paint(&mut store, &mut canvas, 1, 1, 10);
+        paint(&mut store, &mut canvas, 300, 1, 20);
+        (store.save(&mut canvas).unwrap())().unwrap();
+        paint(&mut store, &mut canvas, 2, 2, 30);
+        (store.save_changes(&mut canvas).unwrap())().unwrap();
+        store.flush().unwrap();
+        canvas
+    }
+
+    fn tile_files(dir: &Path) -> Vec<String> {
+        let mut names: Vec<_> = fs::read_dir(dir.join("canvas.place.tiles"))
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn tiled_saves_only_add_changed_tiles() {
+        let dir = temp_dir("tiled-save");
+        let canvas = save_twice(&dir);
+        // the backup still refers to the first version of the changed tile
+        assert_eq!(tile_files(&dir), vec!["0_0.1.tile", "0_0.2.tile", "1_0.1.tile"]);
+
+        let loaded = FileStore::new(&dir, true).load((1, 1)).unwrap();
+        assert!(loaded.diff(&canvas).is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn damaged_tiles_fall_back_to_a_backup() {
+        let dir = temp_dir("damaged-tile");
+        let canvas = save_twice(&dir);
+        let tiles = dir.join("canvas.place.tiles");
+        fs::write(tiles.join("0_0.2.tile"), b"garbage").unwrap();
+
+        // the backup comes with its own tiles, and the log has what happened since
+        let loaded = FileStore::new(&dir, true).load((1, 1)).unwrap();
+        assert!(loaded.diff(&canvas).is_empty());
+
+        fs::remove_file(tiles.join("0_0.1.tile")).unwrap();
+        fs::remove_file(dir.join("canvas.place")).unwrap();
+        assert!(FileStore::new(&dir, true).load((1, 1)).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn memory_stores_replay_unsaved_events() {
+        let mut store = MemoryStore::default();
+        let mut canvas = store.load((10, 10)).unwrap();
+        (store.save(&mut canvas).unwrap())().unwrap();
+        paint(&mut store, &mut canvas, 1, 1, 10);
+        let loaded = store.load((10, 10)).unwrap();
+        assert_eq!(loaded.pixel(1, 1), (10, 0, 0));
+        assert_eq!(store.history().unwrap().events.len(), 1);
+    }
+
+    #[test]
+    fn snapshots_are_written_by_their_job() {
+        let dir = temp_dir("snapshot-job");
+        let mut store = FileStore::new(&dir, true);
+        let mut canvas = store.load((10, 10)).unwrap();
+        paint(&mut store, &mut canvas, 1, 1, 10);
+        let job = store.snapshot(&canvas, 5).unwrap();
+        assert!(store.load_snapshot(5).is_err());
+
+        // the job writes the canvas as it was when the snapshot was taken
+        paint(&mut store, &mut canvas, 1, 1, 20);
+        job().unwrap();
+        assert_eq!(store.snapshots().unwrap(), vec![5]);
+        let snapshot = (store.load_snapshot(5).unwrap())().unwrap();
+        assert_eq!(snapshot.pixel(1, 1), (10, 0, 0));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn locks_are_kept_by_the_store() {
+        let rect = Rect {
+            x: 1,
+            y: 2,
+            w: 3,
+            h: 4,
+        };
+        let locks = vec![Lock {
+            name: "logo".into(),
+            rect,
+            policy: LockPolicy::Admins,
+        }];
+        let dir = temp_dir("locks");
+        let mut store = FileStore::new(&dir, true);
+        assert!(store.locks().unwrap().is_empty());
+        store.set_locks(&locks).unwrap();
+        let loaded = FileStore::new(&dir, true).locks().unwrap();
+        assert_eq!(loaded[0].name, "logo");
+        assert_eq!(loaded[0].rect, rect);
+        fs::remove_dir_all(&dir).unwrap();
+
+        let mut store = MemoryStore::default();
+        store.set_locks(&locks).unwrap();
+        assert_eq!(store.locks().unwrap().len(), 1);
+    }
+}
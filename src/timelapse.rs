@@ -0,0 +1,330 @@
+//! Timelapses, replayed from the event log.
+
+use canvas::{Canvas, Rect, Region};
+use history::LogContents;
+use image;
+use messages::{CanvasUpdate, ClientMessage};
+use png;
+use std::io::Write;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+use store::StoreError;
+use ws;
+
+/// The most frames a timelapse may have.
+pub const MAX_FRAMES: u64 = 3000;
+
+/// How many frames a timelapse has if no interval is given.
+const DEFAULT_FRAMES: u64 = 300;
+
+/// The most pixels a streamed frame may have.
+pub const MAX_STREAM_PIXELS: u64 = 1 << 20;
+
+/// What to render. Anything left out covers the whole log or canvas.
+#[derive(Debug, Clone, Default)]
+pub struct Options {
+    pub region: Option<Rect>,
+    /// Time between frames (milliseconds).
+    pub interval: Option<u64>,
+    /// First and last frame (UNIX milliseconds).
+    pub from: Option<u64>,
+    pub to: Option<u64>,
+}
+
+/// The frames a timelapse will consist of.
+#[derive(Debug, Clone, Copy)]
+pub struct Plan {
+    pub region: Rect,
+    pub from: u64,
+    pub interval: u64,
+    pub frames: u64,
+}
+
+impl Plan {
+    /// Works out the frames of a timelapse, checking them against the limits.
+    pub fn new(
+        contents: &LogContents,
+        options: &Options,
+        max_pixels: u64,
+    ) -> Result<Plan, &'static str> {
+        let first = contents.events.first().map(|(_, event)| event.time());
+        let last = contents.events.last().map(|(_, event)| event.time());
+        let (from, to) = match (options.from.or(first), options.to.or(last)) {
+            (Some(from), Some(to)) if from <= to => (from, to),
+            (Some(_), Some(_)) => return Err("The timelapse would end before it starts"),
+            _ => return Err("Nothing has happened yet"),
+        };
+        let interval = match options.interval {
+            Some(0) => return Err("The interval must be positive"),
+            Some(interval) => interval,
+            // rounded up so there are no more than the default number of frames
+            None => ((to - from + DEFAULT_FRAMES - 2) / (DEFAULT_FRAMES - 1)).max(1),
+        };
+        // the last frame includes the last event
+        let frames = (to - from).div_ceil(interval) + 1;
+        if frames > MAX_FRAMES {
+            return Err("That would be too many frames; use a longer interval");
+        }
+
+        let region = match options.region {
+            Some(region) => region,
+            None => {
+                let end = contents.state_at(to);
+                match end.bounds().or_else(|| end.populated()) {
+                    Some(region) => region,
+                    None => return Err("The canvas is empty"),
+                }
+            }
+        };
+        if region.is_empty() {
+            return Err("That region is empty");
+        }
+        if region.w as u64 * region.h as u64 > max_pixels {
+            return Err("That region is too large");
+        }
+
+        Ok(Plan {
+            region,
+            from,
+            interval,
+            frames,
+        })
+    }
+
+    /// Replays the log and calls `frame` with the time and contents of each frame, until it
+    /// returns false.
+    pub fn render<F>(&self, contents: &LogContents, mut frame: F)
+    where
+        F: FnMut(u64, Region) -> bool,
+    {
+        let mut canvas = contents.base.clone();
+        let mut events = contents.events.iter().peekable();
+        for i in 0..self.frames {
+            let time = self.from + i * self.interval;
+            while let Some((_, event)) = events.peek() {
+                if event.time() > time {
+                    break;
+                }
+                event.apply(&mut canvas);
+                events.next();
+            }
+            if !frame(time, region_or_white(&canvas, self.region)) {
+                break;
+            }
+        }
+    }
+}
+
+/// Returns a region of the canvas, with white wherever it’s outside the canvas (which may have
+/// been smaller at the time).
+fn region_or_white(canvas: &Canvas, rect: Rect) -> Region {
+    let mut region = Region {
+        x: rect.x,
+        y: rect.y,
+        w: rect.w,
+        h: rect.h,
+        data: vec![255; rect.w as usize * rect.h as usize * 3],
+    };
+    let inside = match canvas.bounds() {
+        Some(bounds) => bounds.intersection(&rect),
+        None => rect,
+    };
+    if inside.is_empty() {
+        return region;
+    }
+    if let Some(part) = canvas.region(inside.x, inside.y, inside.w, inside.h) {
+        let row_len = part.w as usize * 3;
+        for (i, row) in part.data.chunks(row_len).enumerate() {
+            let y = (inside.y as i64 - rect.y as i64) as usize + i;
+            let x = (inside.x as i64 - rect.x as i64) as usize;
+            let start = (y * rect.w as usize + x) * 3;
+            region.data[start..start + row_len].copy_from_slice(row);
+        }
+    }
+    region
+}
+
+/// Writes a timelapse as an animated PNG, showing each frame for `delay` milliseconds.
+pub fn write_apng<W: Write>(
+    out: W,
+    contents: &LogContents,
+    plan: &Plan,
+    scale: u32,
+    delay: u16,
+) -> Result<(), png::EncodingError> {
+    let (w, h) = (plan.region.w * scale, plan.region.h * scale);
+    let mut encoder = png::Encoder::new(out, w, h);
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder.set_animated(plan.frames as u32, 0)?;
+    encoder.set_frame_delay(delay, 1000)?;
+    let mut writer = encoder.write_header()?;
+
+    let mut result = Ok(());
+    plan.render(contents, |_, region| {
+        result = writer.write_image_data(&image::scale_rgb(&region, scale));
+        result.is_ok()
+    });
+    result?;
+    writer.finish()
+}
+
+/// Streams a timelapse to a client as bounds, then a `timelapse-frame` message and a region
+/// for each frame, `delay` apart. Stops early once `cancel` is set.
+pub fn stream(
+    out: &ws::Sender,
+    binary: bool,
+    contents: Result<LogContents, StoreError>,
+    options: &Options,
+    delay: Duration,
+    cancel: &AtomicBool,
+) {
+    let send = |message: ws::Message| {
+        if let Err(err) = out.send(message) {
+            eprintln!("Send error: {:?}", err);
+        }
+    };
+    let fail = |message: String| {
+        send(
+            ClientMessage::Error {
+                code: "timelapse".into(),
+                message,
+            }.into(),
+        );
+        let _ = out.close(ws::CloseCode::Normal);
+    };
+
+    let contents = match contents {
+        Ok(contents) => contents,
+        Err(err) => return fail(format!("Failed to read the history: {}", err)),
+    };
+    let plan = match Plan::new(&contents, options, MAX_STREAM_PIXELS) {
+        Ok(plan) => plan,
+        Err(err) => return fail(err.into()),
+    };
+
+    send(
+        ClientMessage::Bounds {
+            infinite: false,
+            world: Some(plan.region),
+            populated: Some(plan.region),
+        }.into(),
+    );
+    let mut index = 0;
+    plan.render(&contents, |time, region| {
+        if cancel.load(Ordering::Relaxed) {
+            return false;
+        }
+        send(
+            ClientMessage::TimelapseFrame {
+                time,
+                index,
+                frames: plan.frames,
+            }.into(),
+        );
+        let update = CanvasUpdate::Regions(vec![region]);
+        send(if binary {
+            ws::Message::Binary(update.to_binary())
+        } else {
+            update.to_json().into()
+        });
+        index += 1;
+        thread::sleep(delay);
+        true
+    });
+    if !cancel.load(Ordering::Relaxed) {
+        let _ = out.close_with_reason(ws::CloseCode::Normal, "End of timelapse");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use history::{Actor, Event};
+
+    fn contents(times: &[u64]) -> LogContents {
+        let events = times
+            .iter()
+            .enumerate()
+            .map(|(i, &time)| {
+                let event = Event::SetPixel {
+                    time,
+                    x: i as i32,
+                    y: 0,
+                    r: 0,
+                    g: 0,
+                    b: 0,
+                    actor: Actor::Anonymous("abc".into()),
+                };
+                (i as u64, event)
+            })
+            .collect();
+        LogContents {
+            base: Canvas::blank(4, 4),
+            events,
+            len: times.len() as u64,
+        }
+    }
+
+    fn options(interval: Option<u64>, from: Option<u64>, to: Option<u64>) -> Options {
+        Options {
+            region: None,
+            interval,
+            from,
+            to,
+        }
+    }
+
+    #[test]
+    fn plans_cover_the_log_by_default() {
+        let contents = contents(&[1_000, 2_000, 4_000]);
+        let plan = Plan::new(&contents, &Options::default(), 16).unwrap();
+        assert_eq!(plan.from, 1_000);
+        assert_eq!(plan.interval, 11);
+        assert_eq!(plan.frames, 274);
+        assert!(plan.frames <= DEFAULT_FRAMES);
+        assert!(plan.from + (plan.frames - 1) * plan.interval >= 4_000);
+        assert_eq!(plan.region, Canvas::blank(4, 4).bounds().unwrap());
+
+        let plan = Plan::new(&contents, &options(Some(1_000), None, None), 16).unwrap();
+        assert_eq!(plan.frames, 4);
+        let mut frames = Vec::new();
+        plan.render(&contents, |time, region| {
+            frames.push((time, region.data[..9].to_vec()));
+            true
+        });
+        let white = [255, 255, 255];
+        let black = [0, 0, 0];
+        assert_eq!(frames[0], (1_000, [black, white, white].concat()));
+        assert_eq!(frames[2], (3_000, [black, black, white].concat()));
+        assert_eq!(frames[3], (4_000, [black; 3].concat()));
+    }
+
+    #[test]
+    fn plans_are_checked_against_the_limits() {
+        let log = contents(&[1_000, 4_000]);
+        let plan = |options, max_pixels| Plan::new(&log, &options, max_pixels).map(|_| ());
+        assert_eq!(plan(options(Some(0), None, None), 16), Err("The interval must be positive"));
+        assert_eq!(
+            plan(options(None, Some(5_000), None), 16),
+            Err("The timelapse would end before it starts")
+        );
+        assert!(plan(options(Some(1), None, None), 16).is_err());
+        assert!(plan(options(Some(1), Some(2_000), Some(4_000)), 16).is_ok());
+        assert_eq!(plan(Options::default(), 15), Err("That region is too large"));
+        let empty = Options {
+            region: Some(Rect {
+                x: 0,
+                y: 0,
+                w: 0,
+                h: 3,
+            }),
+            ..Options::default()
+        };
+        assert_eq!(plan(empty, 16), Err("That region is empty"));
+
+        let nothing = Plan::new(&contents(&[]), &Options::default(), 16);
+        assert_eq!(nothing.map(|_| ()), Err("Nothing has happened yet"));
+    }
+}
@@ -7,9 +7,82 @@
 let canvas = document.querySelector('#canvas');
 let ctx = canvas.getContext('2d');
 
-// data canvas
-let dCanvas = document.createElement('canvas');
-let dCtx = dCanvas.getContext('2d');
+// canvas data in tiles (keyed by `tx,ty`), only for areas the server sent; the rest is white
+const TILE_SIZE = 256;
+let tiles = new Map();
+
+// canvas bounds (see the bounds message): where pixels can be placed (null if unlimited), and the
+// painted area
+let infinite = false;
+// whether the server sent the whole canvas (it only does so for finite canvases that aren't too large)
+let wholeCanvas = false;
+let world = null;
+let populated = null;
+
+// locked regions (see the locks message), outlined on the canvas
+let locks = [];
+
+// returns a data tile, creating it if necessary
+let getTile = function (tx, ty, create) {
+    let key = tx + ',' + ty;
+    let tile = tiles.get(key);
+    if (!tile && create) {
+        let tCanvas = document.createElement('canvas');
+        tCanvas.width = tCanvas.height = TILE_SIZE;
+        let tCtx = tCanvas.getContext('2d');
+        tCtx.fillStyle = '#fff';
+        tCtx.fillRect(0, 0, TILE_SIZE, TILE_SIZE);
+        tile = { tx, ty, canvas: tCanvas, ctx: tCtx };
+        tiles.set(key, tile);
+    }
+    return tile;
+};
+
+// draws image data at canvas coordinates, splitting it across tiles
+let putImageData = function (idata, x, y) {
+    let tx1 = Math.floor(x / TILE_SIZE);
+    let ty1 = Math.floor(y / TILE_SIZE);
+    let tx2 = Math.floor((x + idata.width - 1) / TILE_SIZE);
+    let ty2 = Math.floor((y + idata.height - 1) / TILE_SIZE);
+    for (let ty = ty1; ty <= ty2; ty++) {
+        for (let tx = tx1; tx <= tx2; tx++) {
+            let dx = x - tx * TILE_SIZE;
+            let dy = y - ty * TILE_SIZE;
+            // the part of the image data that lands on this tile
+            let sx = Math.max(0, -dx);
+            let sy = Math.max(0, -dy);
+            let sw = Math.min(idata.width, TILE_SIZE - dx) - sx;
+            let sh = Math.min(idata.height, TILE_SIZE - dy) - sy;
+            getTile(tx, ty, true).ctx.putImageData(idata, dx, dy, sx, sy, sw, sh);
+        }
+    }
+};
+
+// drops data outside the world boundary, which the server cleared
+let cropTiles = function () {
+    if (!world) return;
+    for (let [key, tile] of tiles) {
+        let x = tile.tx * TILE_SIZE;
+        let y = tile.ty * TILE_SIZE;
+        let kx = Math.max(world.x, x) - x;
+        let ky = Math.max(world.y, y) - y;
+        let kw = Math.min(world.x + world.w, x + TILE_SIZE) - x - kx;
+        let kh = Math.min(world.y + world.h, y + TILE_SIZE) - y - ky;
+        if (kw <= 0 || kh <= 0) {
+            tiles.delete(key);
+        } else if (kw < TILE_SIZE || kh < TILE_SIZE) {
+            let keep = tile.ctx.getImageData(kx, ky, kw, kh);
+            tile.ctx.fillStyle = '#fff';
+            tile.ctx.fillRect(0, 0, TILE_SIZE, TILE_SIZE);
+            tile.ctx.putImageData(keep, kx, ky);
+        }
+    }
+};
+
+// returns true if a pixel can be placed at the coordinates
+let inWorld = function (x, y) {
+    return !world || (x >= world.x && y >= world.y && x < world.x + world.w && y < world.y + world.h);
+};
 
 // view offset
 let offset = [0, 0];
@@ -25,18 +98,28 @@ let scaleByFactor = function (factor, x, y) {
 
 // clamps the view parameters
 let clampView = function () {
-    let maxScale = Math.hypot(dCanvas.width, dCanvas.height) / 20;
-    if (scale < 0.1) scaleByFactor(0.1 / scale, window.innerWidth / 2, window.innerHeight / 2);
+    // the area to keep in view; without a boundary, it’s whatever has been painted so far
+    let area = world || populated;
+    let maxScale = area ? Math.hypot(area.w, area.h) / 20 : 50;
+    // the server only sends data for viewports up to 4096 pixels across
+    let minScale = wholeCanvas ? 0.1 : Math.max(0.1, window.innerWidth / 4000, window.innerHeight / 4000);
+    if (scale < minScale) scaleByFactor(minScale / scale, window.innerWidth / 2, window.innerHeight / 2);
     if (scale > maxScale) scaleByFactor(maxScale / scale, window.innerWidth / 2, window.innerHeight / 2);
-    let padding = 20;
-    let minX = 0;
-    let minY = 0;
-    let maxX = window.innerWidth - dCanvas.width * scale;
-    let maxY = window.innerHeight - dCanvas.height * scale;
-    if (maxX < minX) [minX, maxX] = [maxX, minX];
-    if (maxY < minY) [minY, maxY] = [maxY, minY];
-    offset[0] = Math.max(minX - padding, Math.min(maxX + padding, offset[0]));
-    offset[1] = Math.max(minY - padding, Math.min(maxY + padding, offset[1]));
+    if (area) {
+        // leave room to paint past the populated area
+        let padding = world ? 20 : Math.min(window.innerWidth, window.innerHeight) / 2;
+        let minX = 0;
+        let minY = 0;
+        let maxX = window.innerWidth - area.w * scale;
+        let maxY = window.innerHeight - area.h * scale;
+        if (maxX < minX) [minX, maxX] = [maxX, minX];
+        if (maxY < minY) [minY, maxY] = [maxY, minY];
+        let areaX = offset[0] + area.x * scale;
+        let areaY = offset[1] + area.y * scale;
+        offset[0] += Math.max(minX - padding, Math.min(maxX + padding, areaX)) - areaX;
+        offset[1] += Math.max(minY - padding, Math.min(maxY + padding, areaY)) - areaY;
+    }
+    viewportDidChange();
 }
 
 // cursor position on the screen
@@ -108,7 +191,37 @@ let redraw = function () {
 
     ctx.translate(offset[0], offset[1]);
     ctx.scale(scale, scale);
-    ctx.drawImage(dCanvas, 0, 0);
+    {
+        // visible area in canvas coordinates
+        let viewX = -offset[0] / scale;
+        let viewY = -offset[1] / scale;
+        let viewW = window.innerWidth / scale;
+        let viewH = window.innerHeight / scale;
+
+        ctx.save();
+        ctx.beginPath();
+        if (world) ctx.rect(world.x, world.y, world.w, world.h);
+        else ctx.rect(viewX, viewY, viewW, viewH);
+        ctx.clip();
+        ctx.fillStyle = '#fff';
+        ctx.fill();
+        for (let tile of tiles.values()) {
+            let x = tile.tx * TILE_SIZE;
+            let y = tile.ty * TILE_SIZE;
+            if (x + TILE_SIZE < viewX || y + TILE_SIZE < viewY || x > viewX + viewW || y > viewY + viewH) continue;
+            ctx.drawImage(tile.canvas, x, y);
+        }
+        ctx.restore();
+
+        ctx.save();
+        ctx.strokeStyle = 'rgba(255, 0, 0, 0.6)';
+        ctx.lineWidth = 2 / scale;
+        ctx.setLineDash([4 / scale, 4 / scale]);
+        for (let lock of locks) {
+            ctx.strokeRect(lock.rect.x, lock.rect.y, lock.rect.w, lock.rect.h);
+        }
+        ctx.restore();
+    }
 
     let now = Date.now();
 
@@ -147,7 +260,7 @@ let redraw = function () {
     if (drawCursor) {
         ctx.lineWidth = 1 / scale;
         ctx.strokeStyle = `rgb(${currentColor[0]}, ${currentColor[1]}, ${currentColor[2]}`;
-        ctx.strokeRect(cursorX | 0, cursorY | 0, 1, 1);
+        ctx.strokeRect(Math.floor(cursorX), Math.floor(cursorY), 1, 1);
     }
 
     let removeBubbles = [];
@@ -278,30 +391,101 @@ let resizeCanvas = function () {
 resizeCanvas();
 window.addEventListener('resize', resizeCanvas);
 
-// handles full update
-let fullUpdate = function (w, h, data) {
-    dCanvas.width = w;
-    dCanvas.height = h;
-    dCtx.clearRect(0, 0, w, h);
-
-    let ibuf = Uint8ClampedArray.from(atob(data), x => x.charCodeAt(0));
-    let idata = new ImageData(ibuf, w, h);
-    dCtx.putImageData(idata, 0, 0);
-    redraw()
+// handles full update (of a finite canvas)
+let fullUpdate = function (w, h, idata) {
+    tiles.clear();
+    infinite = false;
+    wholeCanvas = true;
+    world = { x: 0, y: 0, w, h };
+    if (w && h) putImageData(idata, 0, 0);
+    clampView();
+    redraw();
 };
 
 // handles single pixel update
 let drawPixel = function (x, y, r, g, b) {
-    dCtx.fillStyle = `rgb(${r}, ${g}, ${b})`;
-    dCtx.fillRect(x, y, 1, 1);
+    let tile = getTile(Math.floor(x / TILE_SIZE), Math.floor(y / TILE_SIZE), true);
+    tile.ctx.fillStyle = `rgb(${r}, ${g}, ${b})`;
+    tile.ctx.fillRect(x - tile.tx * TILE_SIZE, y - tile.ty * TILE_SIZE, 1, 1);
 };
 
-let drawRegion = function (x, y, w, h, data) {
+let base64ImageData = function (data, w, h) {
     let ibuf = Uint8ClampedArray.from(atob(data), x => x.charCodeAt(0));
-    let idata = new ImageData(ibuf, w, h);
-    dCtx.putImageData(idata, x, y);
+    return new ImageData(ibuf, w, h);
+};
+
+let drawRegion = function (x, y, w, h, data) {
+    if (w && h) putImageData(base64ImageData(data, w, h), x, y);
 }
 
+// decodes run-length encoded RGB data from a binary message to ImageData (see messages.rs)
+let rleImageData = function (rle, w, h) {
+    let idata = new ImageData(w, h);
+    let out = idata.data;
+    let i = 0;
+    let j = 0;
+    while (i < rle.length && j < out.length) {
+        let n = rle[i++];
+        if (n < 128) {
+            for (let k = 0; k <= n; k++, i += 3, j += 4) {
+                out[j] = rle[i];
+                out[j + 1] = rle[i + 1];
+                out[j + 2] = rle[i + 2];
+                out[j + 3] = 255;
+            }
+        } else {
+            for (let k = 0; k < n - 125; k++, j += 4) {
+                out[j] = rle[i];
+                out[j + 1] = rle[i + 1];
+                out[j + 2] = rle[i + 2];
+                out[j + 3] = 255;
+            }
+            i += 3;
+        }
+    }
+    return idata;
+};
+
+// handles binary messages (see messages.rs)
+let handleBinaryMessage = function (buffer) {
+    let view = new DataView(buffer);
+    let bytes = new Uint8Array(buffer);
+    let type = view.getUint8(0);
+    if (type === 0x01) {
+        let w = view.getUint32(1);
+        let h = view.getUint32(5);
+        fullUpdate(w, h, w && h ? rleImageData(bytes.subarray(9), w, h) : null);
+    } else if (type === 0x02) {
+        let offset = 1;
+        while (offset < bytes.length) {
+            let x = view.getInt32(offset);
+            let y = view.getInt32(offset + 4);
+            let w = view.getUint32(offset + 8);
+            let h = view.getUint32(offset + 12);
+            let len = view.getUint32(offset + 16);
+            offset += 20;
+            if (w && h) putImageData(rleImageData(bytes.subarray(offset, offset + len), w, h), x, y);
+            offset += len;
+        }
+        redraw();
+    } else {
+        console.log('Unknown binary message', type);
+    }
+};
+
+// shows a chat bubble
+let showBubble = function (data) {
+    chatBubbles.push({
+        data,
+        time: Date.now(),
+        scale: Spring.presetDefault(100),
+        offsetX: Spring.presetCritical(100),
+        offsetY: Spring.presetCritical(100),
+    });
+    startDrawLoop();
+    redraw();
+};
+
 // if true, will log chat messages
 let logChatMessages = false;
 
@@ -310,19 +494,63 @@ let ws
 
 let consoleWSDidOpen, consoleWSDidClose, consoleWsOnMessage;
 
+// last viewport sent to the server
+let lastViewport = null;
+let viewportTimeout = null;
+
+// tells the server which part of the canvas is visible, so it only sends deltas for that area
+function viewportDidChange () {
+    if (!isConnected || viewportTimeout) return;
+    viewportTimeout = setTimeout(() => {
+        viewportTimeout = null;
+        if (!isConnected) return;
+        // some margin so panning a little doesn't need new data
+        let margin = 32;
+        let x = Math.floor(-offset[0] / scale) - margin;
+        let y = Math.floor(-offset[1] / scale) - margin;
+        let viewport = {
+            x,
+            y,
+            w: Math.max(0, Math.ceil((window.innerWidth - offset[0]) / scale) + margin - x),
+            h: Math.max(0, Math.ceil((window.innerHeight - offset[1]) / scale) + margin - y),
+        };
+        if (lastViewport && ['x', 'y', 'w', 'h'].every(k => lastViewport[k] === viewport[k])) return;
+        lastViewport = viewport;
+        ws.send(JSON.stringify({ type: 'viewport', data: viewport }));
+    }, 100);
+}
+
 // connects websocket
 let init = function initWS () {
     let protocol = location.protocol === 'https:' ? 'wss://' : 'ws://';
-    ws = new WebSocket(`${protocol}${location.host}${location.pathname}canvas`);
+    // ?canvas=<name> opens a named canvas
+    let params = new URLSearchParams(location.search);
+    let room = params.get('canvas');
+    // ?timelapse plays back its history instead (the server reads x, y, w, h, interval, from,
+    // to and delay from the same query)
+    let timelapse = params.has('timelapse');
+    let base = timelapse ? 'timelapse' : 'canvas';
+    let path = room ? `${base}/${encodeURIComponent(room)}` : base;
+    if (timelapse) path += location.search;
+    ws = new WebSocket(`${protocol}${location.host}${location.pathname}${path}`, ['place.binary']);
+    ws.binaryType = 'arraybuffer';
     ws.onopen = () => {
         isConnected = true;
+        wholeCanvas = false;
+        lastViewport = null;
+        viewportDidChange();
         redraw();
         consoleWSDidOpen();
     };
     ws.onmessage = msg => {
+        if (msg.data instanceof ArrayBuffer) {
+            handleBinaryMessage(msg.data);
+            return;
+        }
         msg = JSON.parse(msg.data);
         if (msg.type === 'full-update') {
-            fullUpdate(msg.data.w, msg.data.h, msg.data.data);
+            let { w, h, data } = msg.data;
+            fullUpdate(w, h, w && h ? base64ImageData(data, w, h) : null);
         } else if (msg.type === 'regions') {
             for (let region of msg.data) {
                 drawRegion(region.x, region.y, region.w, region.h, region.data);
@@ -332,15 +560,7 @@ let init = function initWS () {
             if (logChatMessages) {
                 console.info(`[CHAT] (${msg.data.x}, ${msg.data.y} h ${msg.data.id_hue}) ${msg.data.text}`);
             }
-            chatBubbles.push({
-                data: msg.data,
-                time: Date.now(),
-                scale: Spring.presetDefault(100),
-                offsetX: Spring.presetCritical(100),
-                offsetY: Spring.presetCritical(100),
-            });
-            startDrawLoop();
-            redraw();
+            showBubble(msg.data);
         } else if (msg.type === 'broadcast') {
             if (logChatMessages) {
                 console.info(`[BROADCAST] ${msg.data.text}`);
@@ -353,15 +573,62 @@ let init = function initWS () {
 
             startDrawLoop();
             redraw();
+        } else if (msg.type === 'bounds') {
+            infinite = msg.data.infinite;
+            world = msg.data.world;
+            populated = msg.data.populated;
+            cropTiles();
+            clampView();
+            redraw();
+        } else if (msg.type === 'resize') {
+            // keep looking at the same content; new data follows
+            offset[0] -= msg.data.dx * scale;
+            offset[1] -= msg.data.dy * scale;
+            world = msg.data.world;
+            clampView();
+            redraw();
+        } else if (msg.type === 'reset') {
+            // the canvas changed as a whole; get whatever is in view again
+            tiles.clear();
+            wholeCanvas = false;
+            lastViewport = null;
+            viewportDidChange();
+            redraw();
+        } else if (msg.type === 'timelapse-frame') {
+            updateFrameDisp(msg.data);
+        } else if (msg.type === 'locks') {
+            locks = msg.data;
+            redraw();
+        } else if (msg.type === 'palette') {
+            colors = msg.data.colors || defaultColors;
+            paletteIsStrict = !!msg.data.colors && !msg.data.snap;
+            updatePalette();
+        } else if (msg.type === 'cooldown') {
+            nextPlacement = Date.now() + msg.data.wait;
+            updateCooldownDisp();
+        } else if (msg.type === 'error') {
+            console.warn(`[${msg.data.code}] ${msg.data.message}`);
+        } else if (msg.type === 'pixel-info') {
+            let text = 'Never placed';
+            if (msg.data.placed_by !== null) {
+                let time = new Date(msg.data.time).toLocaleString();
+                text = `${msg.data.placed_by} at ${time}`;
+            }
+            showBubble({ x: msg.data.x + 0.5, y: msg.data.y + 0.5, text, id_hue: null, is_admin: false });
         } else if (msg.type === 'auth' || msg.type === 'console') {
             consoleWsOnMessage(msg);
         } else {
             console.log(msg);
         }
     };
-    ws.onclose = () => {
+    ws.onclose = e => {
         isConnected = false;
-        setTimeout(init, 1000);
+        // a finished timelapse stays on its last frame
+        if (timelapse) {
+            if (e.reason) console.info(`[TIMELAPSE] ${e.reason}`);
+        } else {
+            setTimeout(init, 1000);
+        }
         redraw();
         consoleWSDidClose();
     };
@@ -388,8 +655,8 @@ canvas.addEventListener('wheel', e => {
     redraw();
 });
 
-// color palette
-let colors = [
+// default color palette, used if the server doesn’t restrict colors
+let defaultColors = [
     [0x00, 0x00, 0x00],
     [0xc9, 0x1b, 0x00],
     [0x00, 0xc2, 0x00],
@@ -407,16 +674,53 @@ let colors = [
     [0x60, 0xfd, 0xff],
     [0xff, 0xff, 0xff]
 ];
+// color palette
+let colors = defaultColors;
+// if true, only palette colors can be used
+let paletteIsStrict = false;
 currentColor = colors[0];
 let updateColorDisp;
+let updatePalette;
+let updateCooldownDisp;
+let updateFrameDisp;
+
+// time after which the server will accept another pixel
+let nextPlacement = 0;
+
+// pixels waiting to be sent in one binary message
+let pendingPixels = [];
+
+let sendPendingPixels = function () {
+    let buffer = new ArrayBuffer(1 + pendingPixels.length * 11);
+    let view = new DataView(buffer);
+    view.setUint8(0, 0x10);
+    pendingPixels.forEach(([x, y, r, g, b], i) => {
+        let offset = 1 + i * 11;
+        view.setInt32(offset, x);
+        view.setInt32(offset + 4, y);
+        view.setUint8(offset + 8, r);
+        view.setUint8(offset + 9, g);
+        view.setUint8(offset + 10, b);
+    });
+    pendingPixels = [];
+    ws.send(buffer);
+};
 
 // sets a pixel to the current color
 let setPixel = function (x, y) {
+    x = Math.floor(x);
+    y = Math.floor(y);
+    if (Date.now() < nextPlacement || !inWorld(x, y)) return;
+    if (ws.protocol === 'place.binary') {
+        if (!pendingPixels.length) setTimeout(sendPendingPixels, 16);
+        pendingPixels.push([x, y, ...currentColor.slice(0, 3)]);
+        return;
+    }
     ws.send(JSON.stringify({
         type: 'set-pixel',
         data: {
-            x: x | 0,
-            y: y | 0,
+            x,
+            y,
             r: currentColor[0],
             g: currentColor[1],
             b: currentColor[2]
@@ -424,24 +728,23 @@ let setPixel = function (x, y) {
     }));
 }
 
-// gets snapshot of all data pixels
-let getPixels = function () {
-    let iData = dCtx.getImageData(0, 0, dCanvas.width, dCanvas.height);
-
-    return {
-        imageData: iData,
-        getPixel: function (x, y) {
-            x |= 0;
-            y |= 0;
-            if (x < 0 || y < 0 || x >= this.imageData.width || y >= this.imageData.height) return undefined;
-            let i = (this.imageData.width * y + x) * 4;
-            return [
-                this.imageData.data[i],
-                this.imageData.data[i + 1],
-                this.imageData.data[i + 2]
-            ];
-        }
-    };
+// asks who placed a pixel
+let requestPixelInfo = function (x, y) {
+    ws.send(JSON.stringify({
+        type: 'pixel-info',
+        data: { x: Math.floor(x), y: Math.floor(y) }
+    }));
+}
+
+// gets a data pixel
+let getPixel = function (x, y) {
+    x = Math.floor(x);
+    y = Math.floor(y);
+    if (!inWorld(x, y)) return undefined;
+    let tile = getTile(Math.floor(x / TILE_SIZE), Math.floor(y / TILE_SIZE), false);
+    if (!tile) return [255, 255, 255];
+    let data = tile.ctx.getImageData(x - tile.tx * TILE_SIZE, y - tile.ty * TILE_SIZE, 1, 1).data;
+    return [data[0], data[1], data[2]];
 };
 
 let downPos = null;
@@ -516,8 +819,10 @@ canvas.addEventListener('mouseup', e => {
         let x = (e.offsetX - offset[0]) / scale;
         let y = (e.offsetY - offset[1]) / scale;
         if (e.altKey || e.ctrlKey) {
-            currentColor = getPixels().getPixel(x, y) || currentColor;
+            currentColor = getPixel(x, y) || currentColor;
             updateColorDisp();
+        } else if (e.shiftKey) {
+            requestPixelInfo(x, y);
         } else {
             setPixel(x, y);
         }
@@ -558,16 +863,27 @@ canvas.addEventListener('touchend', e => {
         hexI.value = pad(color[0]) + pad(color[1]) + pad(color[2]);
     };
 
-    for (let color of colors) {
-        let btn = document.createElement('button');
-        btn.className = 'color-btn';
-        controls.appendChild(btn);
-        btn.style.background = `rgb(${color[0]}, ${color[1]}, ${color[2]})`;
-        btn.addEventListener('click', e => {
-            currentColor = color;
+    let paletteWrap = document.createElement('span');
+    controls.appendChild(paletteWrap);
+    let hexIW;
+    updatePalette = function () {
+        paletteWrap.innerHTML = '';
+        for (let color of colors) {
+            let btn = document.createElement('button');
+            btn.className = 'color-btn';
+            paletteWrap.appendChild(btn);
+            btn.style.background = `rgb(${color[0]}, ${color[1]}, ${color[2]})`;
+            btn.addEventListener('click', e => {
+                currentColor = color;
+                updateColorDisp();
+            });
+        }
+        hexIW.style.display = paletteIsStrict ? 'none' : '';
+        if (paletteIsStrict && !colors.some(c => c + '' === currentColor + '')) {
+            currentColor = colors[0];
             updateColorDisp();
-        });
-    }
+        }
+    };
 
     let zoomIn = document.createElement('button');
     let zoomOut = document.createElement('button');
@@ -588,7 +904,7 @@ canvas.addEventListener('touchend', e => {
         redraw();
     });
 
-    let hexIW = document.createElement('div');
+    hexIW = document.createElement('div');
     controls.appendChild(hexIW);
     hexIW.className = 'hex-input-wrap';
     hexIW.textContent = '#';
@@ -614,6 +930,31 @@ canvas.addEventListener('touchend', e => {
     hexI.addEventListener('blur', applyHexI);
 
     updateColorDisp();
+    updatePalette();
+
+    let cooldownDisp = document.createElement('div');
+    cooldownDisp.className = 'cooldown-disp';
+    controls.appendChild(cooldownDisp);
+    let cooldownTimeout = null;
+    updateCooldownDisp = function () {
+        clearTimeout(cooldownTimeout);
+        let remaining = nextPlacement - Date.now();
+        if (remaining > 0) {
+            cooldownDisp.textContent = (remaining / 1000).toFixed(1) + 's';
+            cooldownTimeout = setTimeout(updateCooldownDisp, 100);
+        } else {
+            cooldownDisp.textContent = '';
+        }
+    };
+
+    // shows the time of the current timelapse frame
+    let frameDisp = document.createElement('div');
+    frameDisp.className = 'cooldown-disp';
+    controls.appendChild(frameDisp);
+    updateFrameDisp = function (frame) {
+        let time = new Date(frame.time).toLocaleString();
+        frameDisp.textContent = `${time} (${frame.index + 1}/${frame.frames})`;
+    };
 
     let helpBtn = document.createElement('button');
     helpBtn.className = 'help-btn';